@@ -14,3 +14,5 @@ int32_t bwt_start(const char* json_config,
                   void** shutdown_out);
 
 int32_t bwt_shutdown(void* shutdown_ptr);
+
+int32_t bwt_set_network(void* shutdown_ptr, const char* network);
@@ -73,6 +73,8 @@ function init(options) {
         services[msg_type.substr(6)] = detail_s
       } else if (msg_type == 'progress:sync') {
         opt_progress && opt_progress('sync', progress, { tip_time: detail_n })
+      } else if (msg_type == 'progress:import') {
+        opt_progress && opt_progress('import', progress, { total: detail_n })
       } else if (msg_type == 'progress:scan') {
         opt_progress && opt_progress('scan', progress, { eta: detail_n })
       } else if (['booting', 'ready'].includes(msg_type)) {
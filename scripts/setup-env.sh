@@ -21,6 +21,7 @@ WALLET2=$ELECTRUM_DIR/wallet2
 BWT_HTTP_ADDR=127.0.0.1:3060
 BWT_ELECTRUM_ADDR=127.0.0.1:30602
 BWT_SOCKET=$DIR/bwt-socket
+BWT_EVENTS_SOCKET=$DIR/bwt-events-socket
 
 alias btc="bitcoin-cli -datadir=$BTC_DIR -rpcwallet=internal"
 alias ele="electrum --regtest --dir $ELECTRUM_DIR"
@@ -117,7 +118,7 @@ echo Setting up bwt
 runbwt --no-startup-banner --network regtest \
   --bitcoind-dir $BTC_DIR --bitcoind-url http://localhost:$BTC_RPC_PORT/ --bitcoind-wallet bwt \
   --electrum-addr $BWT_ELECTRUM_ADDR \
-  --unix-listener-path $BWT_SOCKET --poll-interval ${INTERVAL:=120} \
+  --unix-listener-path $BWT_SOCKET --unix-events-path $BWT_EVENTS_SOCKET --poll-interval ${INTERVAL:=120} \
   --initial-import-size 30 \
   --xpub `ele1 getmpk` --xpub `ele2 getmpk` \
   $([[ $FEATURES == *"http"* ]] && echo "--http-addr $BWT_HTTP_ADDR --http-cors any") \
@@ -1,9 +1,11 @@
 use std::sync::{mpsc, Arc, RwLock};
 use std::{net, thread};
 
-use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
+use bitcoin::Network;
+use bitcoincore_rpc::{self as rpc, RpcApi};
 
-use crate::util::bitcoincore_ext::{Progress, RpcApiExt};
+use crate::types::set_amount_format;
+use crate::util::bitcoincore_ext::{chain_network, Progress, RpcApiExt, RpcClient};
 use crate::util::{banner, debounce_sender};
 use crate::{Config, Indexer, Query, Result, WalletWatcher};
 
@@ -13,10 +15,14 @@ use crate::electrum::ElectrumServer;
 use crate::http::HttpServer;
 #[cfg(unix)]
 use crate::listener;
+#[cfg(unix)]
+use crate::unix_notifier::UnixSocketNotifier;
 #[cfg(feature = "webhooks")]
 use crate::webhooks::WebHookNotifier;
 
 const DEBOUNCE_SEC: u64 = 2;
+// trigger a sync even if notifications keep coming in once they've been pending for this long
+const DEBOUNCE_MAX_WAIT_SEC: u64 = 10;
 
 pub struct App {
     config: Config,
@@ -30,52 +36,146 @@ pub struct App {
     http: Option<HttpServer>,
     #[cfg(feature = "webhooks")]
     webhook: Option<WebHookNotifier>,
+    #[cfg(unix)]
+    unix_notifier: Option<UnixSocketNotifier>,
 }
 
 impl App {
     pub fn boot(config: Config, progress_tx: Option<mpsc::Sender<Progress>>) -> Result<Self> {
         debug!("{:?}", config);
 
+        // signet is tracked as testnet, which shares its key and address encoding
+        ensure!(
+            config.network != Network::Signet,
+            "signet is not supported as a separate network, use `--network testnet` instead"
+        );
+
+        set_amount_format(config.amount_format);
+
         let watcher = WalletWatcher::from_config(&config)?;
 
-        let rpc = Arc::new(RpcClient::new(
-            config.bitcoind_url(),
-            config.bitcoind_auth()?,
-        )?);
-        let indexer = Arc::new(RwLock::new(Indexer::new(rpc.clone(), watcher)));
+        let mut rpc = RpcClient::new(config.bitcoind_url(), config.bitcoind_auth()?)?;
+        rpc.set_rate_limit(config.rpc_rate_limit);
+        let rpc = Arc::new(rpc);
+        let mut indexer = Indexer::new(rpc.clone(), watcher);
+        indexer.set_max_reorg_depth(config.max_reorg_depth);
+        if let Some(wal_path) = &config.wal_path {
+            indexer.open_wal(wal_path)?;
+        }
+        let indexer = Arc::new(RwLock::new(indexer));
         let query = Arc::new(Query::new((&config).into(), rpc.clone(), indexer.clone()));
 
+        // bound early to stream the initial sync progress too
+        #[cfg(unix)]
+        let unix_notifier = config
+            .unix_events_path
+            .clone()
+            .map(UnixSocketNotifier::start)
+            .transpose()?;
+        #[cfg(unix)]
+        let progress_tx = match &unix_notifier {
+            Some(notifier) => Some(notifier.progress_sender(progress_tx)),
+            None => progress_tx,
+        };
+
+        rpc.wait_warmup()?;
+
         if let Some(bitcoind_wallet) = &config.bitcoind_wallet {
             load_wallet(&rpc, bitcoind_wallet)?;
         }
 
-        wait_bitcoind(&rpc, progress_tx.clone())?;
+        wait_bitcoind(&rpc, config.network, progress_tx.clone())?;
 
         if config.startup_banner {
-            println!("{}", banner::get_welcome_banner(&query, false)?);
+            let banner = banner::get_welcome_banner(&query, false)?;
+            // stdout is reserved for the index dump in one-shot mode
+            if config.one_shot {
+                eprintln!("{}", banner);
+            } else {
+                println!("{}", banner);
+            }
         }
 
         // do an initial sync without keeping track of updates
         indexer.write().unwrap().initial_sync(progress_tx)?;
 
-        let (sync_tx, sync_rx) = mpsc::channel();
+        let mut app = App {
+            config,
+            indexer,
+            query,
+            sync_chan: mpsc::channel(),
+            #[cfg(feature = "electrum")]
+            electrum: None,
+            #[cfg(feature = "http")]
+            http: None,
+            #[cfg(feature = "webhooks")]
+            webhook: None,
+            #[cfg(unix)]
+            unix_notifier,
+        };
+
+        // in one-shot mode, the index is only used by the caller and isn't kept up-to-date
+        if !app.config.one_shot {
+            app.start_services()?;
+        }
+
+        Ok(app)
+    }
+
+    // Start the servers and notifiers used for real-time syncing
+    fn start_services(&mut self) -> Result<()> {
+        let config = &self.config;
         // debounce sync message rate to avoid excessive indexing when bitcoind catches up
-        let debounced_sync_tx = debounce_sender(sync_tx.clone(), DEBOUNCE_SEC);
+        let debounced_sync_tx = debounce_sender(
+            self.sync_chan.0.clone(),
+            DEBOUNCE_SEC,
+            Some(DEBOUNCE_MAX_WAIT_SEC),
+        );
 
         #[cfg(feature = "electrum")]
-        let electrum = config
-            .electrum_addr()
-            .map(|addr| ElectrumServer::start(addr, config.electrum_skip_merkle, query.clone()));
+        {
+            let query = self.query.clone();
+            self.electrum = config
+                .electrum_addr()
+                .map(|addr| ElectrumServer::start(addr, config.electrum_skip_merkle, query));
+        }
+
+        // progress updates for imports and rescans initiated through the http api are streamed to the unix socket clients
+        #[cfg(all(unix, feature = "http"))]
+        let http_progress_tx = self
+            .unix_notifier
+            .as_ref()
+            .map(|notifier| notifier.progress_sender(None));
+        #[cfg(all(not(unix), feature = "http"))]
+        let http_progress_tx = None;
+
+        #[cfg(all(unix, feature = "http"))]
+        {
+            if let Some(socket_path) = &config.http_unix_socket {
+                self.http = Some(HttpServer::start_unix(
+                    socket_path.clone(),
+                    config.http_cors.clone(),
+                    self.query.clone(),
+                    debounced_sync_tx.clone(),
+                    http_progress_tx.clone(),
+                )?);
+            }
+        }
 
         #[cfg(feature = "http")]
-        let http = config.http_addr().map(|addr| {
-            HttpServer::start(
-                addr,
-                config.http_cors.clone(),
-                query.clone(),
-                debounced_sync_tx.clone(),
-            )
-        });
+        {
+            if self.http.is_none() {
+                self.http = config.http_addr().map(|addr| {
+                    HttpServer::start(
+                        addr,
+                        config.http_cors.clone(),
+                        self.query.clone(),
+                        debounced_sync_tx.clone(),
+                        http_progress_tx,
+                    )
+                });
+            }
+        }
 
         #[cfg(unix)]
         {
@@ -85,20 +185,14 @@ impl App {
         }
 
         #[cfg(feature = "webhooks")]
-        let webhook = config.webhook_urls.clone().map(WebHookNotifier::start);
+        {
+            self.webhook = config.webhook_urls.clone().map(|urls| {
+                let secret = config.webhook_secret.clone();
+                WebHookNotifier::start(urls, secret, config.webhook_max_attempts)
+            });
+        }
 
-        Ok(App {
-            config,
-            indexer,
-            query,
-            sync_chan: (sync_tx, sync_rx),
-            #[cfg(feature = "electrum")]
-            electrum,
-            #[cfg(feature = "http")]
-            http,
-            #[cfg(feature = "webhooks")]
-            webhook,
-        })
+        Ok(())
     }
 
     /// Start a sync loop blocking the current thread
@@ -115,14 +209,37 @@ impl App {
                 }
             }
 
+            let sync_result = self.indexer.write().unwrap().sync();
+
             #[allow(clippy::option_map_unit_fn)]
-            match self.indexer.write().unwrap().sync() {
+            match sync_result {
                 Ok(updates) if !updates.is_empty() => {
                     #[cfg(feature = "electrum")]
                     self.electrum
                         .as_ref()
                         .map(|electrum| electrum.send_updates(&updates));
 
+                    // electrum clients always get the full set of updates, which they rely on to stay in sync
+                    #[cfg(any(feature = "http", feature = "webhooks", unix))]
+                    let updates = match self.config.notify_confirm_delay {
+                        0 => updates,
+                        delay => self
+                            .indexer
+                            .write()
+                            .unwrap()
+                            .delay_confirmations(updates, delay),
+                    };
+
+                    #[cfg(any(feature = "http", feature = "webhooks", unix))]
+                    let updates = match self.config.notify_min_amount {
+                        Some(min_amount) => self
+                            .indexer
+                            .read()
+                            .unwrap()
+                            .filter_min_amount(&updates, min_amount),
+                        None => updates,
+                    };
+
                     #[cfg(feature = "http")]
                     self.http.as_ref().map(|http| http.send_updates(&updates));
 
@@ -130,11 +247,24 @@ impl App {
                     self.webhook
                         .as_ref()
                         .map(|webhook| webhook.send_updates(&updates));
+
+                    #[cfg(unix)]
+                    self.unix_notifier
+                        .as_ref()
+                        .map(|notifier| notifier.send_updates(&updates));
                 }
                 Ok(_) => (), // no updates
                 Err(e) => warn!("error while updating index: {:#?}", e),
             }
 
+            // rescan for the history of addresses imported by extending the watched window, then
+            // re-sync right away to index it
+            match self.query.rescan_extended_window() {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(e) => warn!("error while rescanning the extended window: {:#?}", e),
+            }
+
             // wait for poll_interval seconds, or until we receive a sync notification message,
             // or until the shutdown signal is emitted
             self.sync_chan
@@ -156,6 +286,11 @@ impl App {
         self.query.clone()
     }
 
+    /// Get a sender for triggering an immediate sync run
+    pub fn sync_sender(&self) -> mpsc::Sender<()> {
+        self.sync_chan.0.clone()
+    }
+
     #[cfg(feature = "electrum")]
     pub fn electrum_addr(&self) -> Option<net::SocketAddr> {
         Some(self.electrum.as_ref()?.addr())
@@ -163,7 +298,7 @@ impl App {
 
     #[cfg(feature = "http")]
     pub fn http_addr(&self) -> Option<net::SocketAddr> {
-        Some(self.http.as_ref()?.addr())
+        self.http.as_ref()?.addr()
     }
 
     // Pipe the shutdown receiver `rx` to trigger `sync_tx`. This is needed to start the next
@@ -215,8 +350,21 @@ fn load_wallet(rpc: &RpcClient, name: &str) -> Result<()> {
 }
 
 // wait for bitcoind to sync and finish rescanning
-fn wait_bitcoind(rpc: &RpcClient, progress_tx: Option<mpsc::Sender<Progress>>) -> Result<()> {
+fn wait_bitcoind(
+    rpc: &RpcClient,
+    network: Network,
+    progress_tx: Option<mpsc::Sender<Progress>>,
+) -> Result<()> {
     let bcinfo = rpc.wait_blockchain_sync(progress_tx.clone())?;
+
+    match chain_network(&bcinfo.chain) {
+        Some(chain_network) if chain_network != network => warn!(
+            "bitcoind is running on {}, but bwt is configured for {}",
+            bcinfo.chain, network
+        ),
+        None => warn!("bitcoind is running on an unknown chain {}", bcinfo.chain),
+        Some(_) => (),
+    }
     let walletinfo = rpc.wait_wallet_scan(progress_tx)?;
 
     let netinfo = rpc.get_network_info()?;
@@ -5,10 +5,10 @@ use bitcoincore_rpc::Auth as RpcAuth;
 
 use crate::error::{Context, OptionExt, Result};
 use crate::query::QueryConfig;
-use crate::types::RescanSince;
-use crate::util::descriptor::ExtendedDescriptor;
+use crate::types::{AmountFormat, DescriptorRange, ImmatureCoinbaseMode, RescanSince};
+use crate::util::descriptor::{Checksum, TrackedDescriptor};
 use crate::util::xpub::XyzPubKey;
-use crate::util::BoolThen;
+use crate::util::{BoolThen, FeeHistogramMode, VsizeField};
 
 #[cfg(any(feature = "pretty_env_logger", feature = "android_logger"))]
 use log::Level;
@@ -130,14 +130,14 @@ pub struct Config {
     #[cfg_attr(feature = "cli", structopt(
         short = "d",
         long = "descriptor",
-        help = "Add a descriptor to track",
+        help = "Add a descriptor to track (including `addr(<address>)` descriptors)",
         parse(try_from_str = parse_desc),
         env, hide_env_values(true),
         use_delimiter(true), value_delimiter(";"),
         display_order(20)
     ))]
     #[serde(default)]
-    pub descriptors: Vec<ExtendedDescriptor>,
+    pub descriptors: Vec<TrackedDescriptor>,
 
     #[cfg_attr(
         feature = "cli",
@@ -155,6 +155,36 @@ pub struct Config {
     #[serde(default)]
     pub xpubs: Vec<XyzPubKey>,
 
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long = "descriptor-range",
+            help = "Track a fixed range of derivation indexes for the descriptor with the given checksum (formatted as <checksum>:<start>-<end>), instead of extending it by the gap limit",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            value_delimiter(";"),
+            display_order(22)
+        )
+    )]
+    #[serde(default)]
+    pub descriptor_ranges: Vec<DescriptorRange>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long = "descriptor-active",
+            help = "Import the ranged descriptor with the given checksum as an active descriptor, used by bitcoind for address generation (descriptor wallets only)",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            value_delimiter(";"),
+            display_order(25)
+        )
+    )]
+    #[serde(default)]
+    pub descriptor_active: Vec<Checksum>,
+
     #[cfg_attr(
         feature = "cli",
         structopt(
@@ -285,6 +315,19 @@ pub struct Config {
     )]
     pub http_cors: Option<String>,
 
+    #[cfg(all(unix, feature = "http"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to bind the http api server on a unix socket (used instead of --http-addr)",
+            env,
+            hide_env_values(true),
+            display_order(47)
+        )
+    )]
+    pub http_unix_socket: Option<path::PathBuf>,
+
     #[cfg_attr(feature = "cli", structopt(
         short = "i",
         long,
@@ -310,6 +353,118 @@ pub struct Config {
     )]
     pub broadcast_cmd: Option<String>,
 
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Binning mode for the fee histogram, 'linear' for fixed vsize bins or 'exponential' for exponential feerate bands",
+            default_value = "linear",
+            possible_values(&["linear", "exponential"]),
+            env,
+            hide_env_values(true),
+            display_order(94)
+        )
+    )]
+    #[serde(default = "default_fee_histogram_mode")]
+    pub fee_histogram_mode: FeeHistogramMode,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Mempool entry field used as the transaction vsize for the fee histogram, 'vsize' (falling back to 'size'), 'size' or 'weight' (divided by 4)",
+            default_value = "vsize",
+            possible_values(&["vsize", "size", "weight"]),
+            env,
+            hide_env_values(true),
+            display_order(106)
+        )
+    )]
+    #[serde(default = "default_mempool_vsize_field")]
+    pub mempool_vsize_field: VsizeField,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Number of confirmations after which outputs are considered settled (coinbase outputs always require at least 100)",
+            default_value = "6",
+            env,
+            hide_env_values(true),
+            display_order(95)
+        )
+    )]
+    #[serde(default = "default_settled_confs")]
+    pub settled_confs: u32,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Whether to include immature coinbase outputs in the wallet balances (included in the confirmed balance and reported separately) or to exclude them entirely",
+            default_value = "include",
+            possible_values(&["include", "exclude"]),
+            env,
+            hide_env_values(true),
+            display_order(97)
+        )
+    )]
+    #[serde(default = "default_immature_coinbase")]
+    pub immature_coinbase: ImmatureCoinbaseMode,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "How to represent amounts in query results, as integer satoshis or as BTC decimal strings",
+            default_value = "sats",
+            possible_values(&["sats", "btc"]),
+            env,
+            hide_env_values(true),
+            display_order(114)
+        )
+    )]
+    #[serde(default = "default_amount_format")]
+    pub amount_format: AmountFormat,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Hide immature coinbase outputs from the wallet balances until they reach this many confirmations",
+            default_value = "0",
+            env,
+            hide_env_values(true),
+            display_order(113)
+        )
+    )]
+    #[serde(default)]
+    pub coinbase_min_confs: u32,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Exclude unconfirmed mempool transactions from the wallet balance (the confirmed and unconfirmed balances are still available separately)",
+            display_order(112)
+        )
+    )]
+    #[serde(default)]
+    pub balance_exclude_mempool: bool,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Use heuristics to detect change outputs not owned by the wallet when classifying transactions (round amounts and matching script types)",
+            display_order(96)
+        )
+    )]
+    #[serde(default)]
+    pub change_heuristics: bool,
+
     // XXX this is not settable as an env var due to https://github.com/clap-rs/clap/issues/1476
     #[cfg_attr(feature = "cli", structopt(
         long = "no-startup-banner",
@@ -330,6 +485,19 @@ pub struct Config {
     #[serde(default)]
     pub require_addresses: bool,
 
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to a write-ahead log for persisting the index across restarts [default: disabled]",
+            env,
+            hide_env_values(true),
+            display_order(100)
+        )
+    )]
+    #[serde(default)]
+    pub wal_path: Option<path::PathBuf>,
+
     #[cfg(unix)]
     #[cfg_attr(
         feature = "cli",
@@ -344,6 +512,19 @@ pub struct Config {
     )]
     pub unix_listener_path: Option<path::PathBuf>,
 
+    #[cfg(unix)]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to bind a unix socket streaming index events and sync progress as newline-delimited JSON",
+            env,
+            hide_env_values(true),
+            display_order(107)
+        )
+    )]
+    pub unix_events_path: Option<path::PathBuf>,
+
     #[cfg(feature = "webhooks")]
     #[cfg_attr(
         feature = "cli",
@@ -359,6 +540,98 @@ pub struct Config {
         )
     )]
     pub webhook_urls: Option<Vec<String>>,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Shared secret for signing webhook payloads with HMAC-SHA256, sent in the X-BWT-Signature header",
+            env,
+            hide_env_values(true),
+            display_order(111)
+        )
+    )]
+    pub webhook_secret: Option<String>,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Maximum number of attempts for delivering each webhook notification, retried with exponential backoff",
+            default_value = "5",
+            env,
+            hide_env_values(true),
+            display_order(110)
+        )
+    )]
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Only notify webhooks, http stream and unix socket clients about transactions with a net wallet amount of at least this many satoshis",
+            env,
+            hide_env_values(true),
+            display_order(103)
+        )
+    )]
+    pub notify_min_amount: Option<u64>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Only notify webhooks, http stream and unix socket clients about confirmations once buried under this many additional blocks",
+            default_value = "0",
+            env,
+            hide_env_values(true),
+            display_order(104)
+        )
+    )]
+    #[serde(default)]
+    pub notify_confirm_delay: u32,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Stop updating the index when encountering reorgs deeper than this many blocks (up to 100) [default: disabled]",
+            env,
+            hide_env_values(true),
+            display_order(105)
+        )
+    )]
+    #[serde(default)]
+    pub max_reorg_depth: Option<u32>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Limit the bitcoind RPC calls to this many calls per second, to avoid starving other users of a shared node [default: unlimited]",
+            env,
+            hide_env_values(true),
+            display_order(108)
+        )
+    )]
+    #[serde(default)]
+    pub rpc_rate_limit: Option<u32>,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Exit after the initial sync, printing the index to stdout as JSON, instead of syncing in real-time and starting the servers",
+            display_order(109)
+        )
+    )]
+    #[serde(default)]
+    pub one_shot: bool,
 }
 
 impl Config {
@@ -376,7 +649,7 @@ impl Config {
                         "http://localhost:{}",
                         match self.network {
                             Network::Bitcoin => 8332,
-                            Network::Testnet => 18332,
+                            Network::Testnet | Network::Signet => 18332,
                             Network::Regtest => 18443,
                         }
                     )
@@ -407,6 +680,12 @@ impl Config {
     pub fn addresses(&self) -> Result<Vec<Address>> {
         let mut addresses = self.addresses.clone();
 
+        // addr() descriptors are tracked as standalone addresses
+        addresses.extend(self.descriptors.iter().filter_map(|desc| match desc {
+            TrackedDescriptor::Addr(address) => Some(address.clone()),
+            TrackedDescriptor::Descriptor(_) => None,
+        }));
+
         if let Some(addresses_file) = &self.addresses_file {
             let file = fs::File::open(addresses_file).context("failed opening addresses file")?;
             let reader = io::BufReader::new(file);
@@ -434,7 +713,7 @@ impl Config {
                 [127, 0, 0, 1].into(),
                 match self.network {
                     Network::Bitcoin => 50001,
-                    Network::Testnet => 60001,
+                    Network::Testnet | Network::Signet => 60001,
                     Network::Regtest => 60401,
                 },
             ));
@@ -539,9 +818,8 @@ fn apply_log_env(mut builder: LogBuilder) -> LogBuilder {
 }
 
 #[cfg(feature = "cli")]
-fn parse_desc(s: &str) -> Result<ExtendedDescriptor> {
-    use crate::util::descriptor::DescriptorChecksum;
-    Ok(ExtendedDescriptor::parse_with_checksum(s)?)
+fn parse_desc(s: &str) -> Result<TrackedDescriptor> {
+    s.parse()
 }
 
 #[cfg(feature = "cli")]
@@ -585,7 +863,7 @@ fn get_cookie(config: &Config) -> Option<path::PathBuf> {
     let mut dir = config.bitcoind_dir.clone().or_else(bitcoind_default_dir)?;
     match config.network {
         Network::Bitcoin => (),
-        Network::Testnet => dir.push("testnet3"),
+        Network::Testnet | Network::Signet => dir.push("testnet3"),
         Network::Regtest => dir.push("regtest"),
     }
     let cookie = dir.join(".cookie");
@@ -621,6 +899,13 @@ impl From<&Config> for QueryConfig {
         QueryConfig {
             network: config.network,
             broadcast_cmd: config.broadcast_cmd.clone(),
+            fee_histogram_mode: config.fee_histogram_mode,
+            mempool_vsize_field: config.mempool_vsize_field,
+            settled_confs: config.settled_confs,
+            change_heuristics: config.change_heuristics,
+            immature_coinbase: config.immature_coinbase,
+            coinbase_min_confs: config.coinbase_min_confs,
+            balance_exclude_mempool: config.balance_exclude_mempool,
         }
     }
 }
@@ -631,14 +916,19 @@ impl From<&Config> for QueryConfig {
 defaultable!(Config,
   @default(
     verbose, timestamp, broadcast_cmd, startup_banner, require_addresses,
-    descriptors, xpubs, addresses, addresses_file,
+    descriptors, xpubs, descriptor_ranges, descriptor_active, addresses, addresses_file,
     bitcoind_wallet, bitcoind_dir, bitcoind_url, bitcoind_auth, bitcoind_cookie,
     #[cfg(feature = "electrum")] electrum_addr,
     #[cfg(feature = "electrum")] electrum_skip_merkle,
     #[cfg(feature = "http")] http_addr,
     #[cfg(feature = "http")] http_cors,
+    #[cfg(all(unix, feature = "http"))] http_unix_socket,
     #[cfg(feature = "webhooks")] webhook_urls,
+    #[cfg(feature = "webhooks")] webhook_secret,
     #[cfg(unix)] unix_listener_path,
+    #[cfg(unix)] unix_events_path,
+    wal_path, max_reorg_depth, rpc_rate_limit, one_shot,
+    notify_min_amount, notify_confirm_delay, coinbase_min_confs, change_heuristics, balance_exclude_mempool,
   )
   @custom(
     network=Network::Bitcoin,
@@ -646,6 +936,12 @@ defaultable!(Config,
     gap_limit=20,
     initial_import_size=350,
     poll_interval=time::Duration::from_secs(5),
+    fee_histogram_mode=FeeHistogramMode::Linear,
+    mempool_vsize_field=VsizeField::Vsize,
+    settled_confs=6,
+    immature_coinbase=ImmatureCoinbaseMode::Include,
+    amount_format=AmountFormat::Sats,
+    #[cfg(feature = "webhooks")] webhook_max_attempts=5,
   )
 );
 
@@ -666,3 +962,23 @@ fn default_initial_import_size() -> u32 {
 fn default_poll_interval() -> time::Duration {
     time::Duration::from_secs(5)
 }
+fn default_fee_histogram_mode() -> FeeHistogramMode {
+    FeeHistogramMode::Linear
+}
+fn default_mempool_vsize_field() -> VsizeField {
+    VsizeField::Vsize
+}
+fn default_settled_confs() -> u32 {
+    6
+}
+fn default_immature_coinbase() -> ImmatureCoinbaseMode {
+    ImmatureCoinbaseMode::Include
+}
+fn default_amount_format() -> AmountFormat {
+    AmountFormat::Sats
+}
+
+#[cfg(feature = "webhooks")]
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
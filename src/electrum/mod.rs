@@ -4,7 +4,7 @@ use bitcoin_hashes::{sha256d, Hash, HashEngine};
 use crate::error::{OptionExt, Result};
 use crate::query::Query;
 use crate::types::{MempoolEntry, ScriptHash, StatusHash, TxStatus};
-use crate::util::BoolThen;
+use crate::util::{create_merkle_branch_and_root, BoolThen};
 
 mod server;
 pub use server::ElectrumServer;
@@ -138,29 +138,3 @@ impl QueryExt for Query {
         Ok((txid, branch))
     }
 }
-
-fn merklize(left: sha256d::Hash, right: sha256d::Hash) -> sha256d::Hash {
-    let data = [&left[..], &right[..]].concat();
-    sha256d::Hash::hash(&data)
-}
-
-fn create_merkle_branch_and_root(
-    mut hashes: Vec<sha256d::Hash>,
-    mut index: usize,
-) -> (Vec<sha256d::Hash>, sha256d::Hash) {
-    let mut merkle = vec![];
-    while hashes.len() > 1 {
-        if hashes.len() % 2 != 0 {
-            let last = *hashes.last().unwrap();
-            hashes.push(last);
-        }
-        index = if index % 2 == 0 { index + 1 } else { index - 1 };
-        merkle.push(hashes[index]);
-        index /= 2;
-        hashes = hashes
-            .chunks(2)
-            .map(|pair| merklize(pair[0], pair[1]))
-            .collect()
-    }
-    (merkle, hashes[0])
-}
@@ -14,6 +14,7 @@ use crate::electrum::{electrum_height, QueryExt};
 use crate::error::{fmt_error_chain, BwtError, Context, Result};
 use crate::indexer::IndexChange;
 use crate::query::Query;
+use crate::store::HistoryEntry;
 use crate::types::{BlockId, MempoolEntry, ScriptHash, StatusHash};
 use crate::util::{banner, BoolThen};
 
@@ -23,6 +24,8 @@ use crate::util::{banner, BoolThen};
 const BWT_VERSION: &str = env!("CARGO_PKG_VERSION");
 const PROTOCOL_VERSION: &str = "1.4";
 const MAX_HEADERS: u32 = 2016;
+// the default and maximum number of entries returned by `bwt.scripthash.get_history_page`
+const HISTORY_PAGE_SIZE: usize = 1000;
 
 const LT: &str = "bwt::electrum"; // log target name
 
@@ -189,33 +192,54 @@ impl Connection {
     fn blockchain_scripthash_get_history(&self, params: Value) -> Result<Value> {
         let (script_hash,): (ScriptHash,) = from_value(params)?;
 
-        let txs: Vec<Value> = self.query.map_history(&script_hash, |txhist| {
-            // unlike other electrum server implementations that return the direct fee paid by the tx itself, we
-            // return the "effective fee rate", which takes unconfirmed ancestor transactions into account.
-            let (effective_fee, has_unconfirmed_parents) = txhist
-                .status
-                .is_unconfirmed()
-                .and_then(|| {
-                    self.query
-                        .with_mempool_entry(&txhist.txid, |mempool_entry| {
-                            // report the fee as the effective feerate multiplied by the size, to get electrum to
-                            // display the effective feerate when it divides this back by the size.
-                            let effective_fee = (mempool_entry.effective_feerate()
-                                * mempool_entry.vsize as f64)
-                                as u64;
-                            let has_unconfirmed_parents = mempool_entry.has_unconfirmed_parents();
-                            (Some(effective_fee), Some(has_unconfirmed_parents))
-                        })
-                })
-                .unwrap_or((None, None));
+        let txs: Vec<Value> = self
+            .query
+            .map_history(&script_hash, |txhist| self.history_entry_json(txhist));
+        Ok(json!(txs))
+    }
 
-            json!({
-                "height": electrum_height(txhist.status, has_unconfirmed_parents),
-                "tx_hash": txhist.txid,
-                "fee": effective_fee,
+    // Non-standard extension method, returning the history in bounded chunks for scripthashes
+    // with very large histories. Follows the format of `blockchain.scripthash.get_history`.
+    fn bwt_scripthash_get_history_page(&self, params: Value) -> Result<Value> {
+        let (script_hash, from_height, count): (ScriptHash, Option<u32>, Option<usize>) =
+            from_value(pad_params(params, 3))?;
+        let count = count.unwrap_or(HISTORY_PAGE_SIZE).min(HISTORY_PAGE_SIZE);
+
+        let (txs, next_height) =
+            self.query
+                .map_history_page(&script_hash, from_height.unwrap_or(0), count, |txhist| {
+                    self.history_entry_json(txhist)
+                });
+        Ok(json!({
+            "history": txs,
+            "next_height": next_height,
+        }))
+    }
+
+    fn history_entry_json(&self, txhist: &HistoryEntry) -> Value {
+        // unlike other electrum server implementations that return the direct fee paid by the tx itself, we
+        // return the "effective fee rate", which takes unconfirmed ancestor transactions into account.
+        let (effective_fee, has_unconfirmed_parents) = txhist
+            .status
+            .is_unconfirmed()
+            .and_then(|| {
+                self.query
+                    .with_mempool_entry(&txhist.txid, |mempool_entry| {
+                        // report the fee as the effective feerate multiplied by the size, to get electrum to
+                        // display the effective feerate when it divides this back by the size.
+                        let effective_fee =
+                            (mempool_entry.effective_feerate() * mempool_entry.vsize as f64) as u64;
+                        let has_unconfirmed_parents = mempool_entry.has_unconfirmed_parents();
+                        (Some(effective_fee), Some(has_unconfirmed_parents))
+                    })
             })
-        });
-        Ok(json!(txs))
+            .unwrap_or((None, None));
+
+        json!({
+            "height": electrum_height(txhist.status, has_unconfirmed_parents),
+            "tx_hash": txhist.txid,
+            "fee": effective_fee,
+        })
     }
 
     fn blockchain_scripthash_listunspent(&self, params: Value) -> Result<Value> {
@@ -334,6 +358,7 @@ impl Connection {
             "blockchain.transaction.get" => self.blockchain_transaction_get(params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(params),
             "blockchain.transaction.id_from_pos" => self.blockchain_transaction_id_from_pos(params),
+            "bwt.scripthash.get_history_page" => self.bwt_scripthash_get_history_page(params),
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
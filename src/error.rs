@@ -2,7 +2,7 @@ use core::fmt::Display;
 
 pub use anyhow::{Context, Error, Result};
 
-use bitcoin::{BlockHash, Txid};
+use bitcoin::{BlockHash, OutPoint, Txid};
 use bitcoincore_rpc as rpc;
 
 use crate::types::ScriptHash;
@@ -15,15 +15,33 @@ pub enum BwtError {
     #[error("Reorg detected at height {0} (previous={1} current={2})")]
     ReorgDetected(u32, BlockHash, BlockHash),
 
+    #[error("Reorg deeper than the maximum of {0} blocks detected below height {1}, refusing to update the index. If this is expected, restart bwt to re-sync it from scratch")]
+    ReorgTooDeep(u32, u32),
+
     #[error("Transaction not found: {0}")]
     TxNotFound(Txid),
 
+    #[error("Transaction not confirmed: {0}")]
+    TxNotConfirmed(Txid),
+
+    #[error("Wallet output not found: {0}")]
+    TxoNotFound(OutPoint),
+
+    #[error("Output is not unspent: {0}")]
+    TxoNotUnspent(OutPoint),
+
     #[error("Address or script hash not found: {0}")]
     ScriptHashNotFound(ScriptHash),
 
     #[error("Blocks unavailable due to pruning")]
     PrunedBlocks,
 
+    #[error("Hardened wildcard derivation is not supported in `{0}`. bwt is watch-only and cannot derive hardened children from a public key, use an unhardened wildcard (`/*`) instead")]
+    UnsupportedHardenedWildcard(String),
+
+    #[error("A rescan is already in progress")]
+    RescanInProgress,
+
     #[error("Custom broadcast command failed with {0}")]
     BroadcastCmdFailed(std::process::ExitStatus),
 
@@ -41,7 +59,11 @@ impl BwtError {
             BwtError::ReorgDetected(..) => StatusCode::GONE,
             BwtError::PrunedBlocks => StatusCode::GONE,
             BwtError::TxNotFound(_) => StatusCode::NOT_FOUND,
+            BwtError::TxNotConfirmed(_) => StatusCode::BAD_REQUEST,
             BwtError::ScriptHashNotFound(_) => StatusCode::NOT_FOUND,
+            BwtError::TxoNotFound(_) => StatusCode::NOT_FOUND,
+            BwtError::TxoNotUnspent(_) => StatusCode::BAD_REQUEST,
+            BwtError::RescanInProgress => StatusCode::CONFLICT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -1,5 +1,9 @@
+use std::collections::HashSet;
 use std::sync::{mpsc, Arc, Mutex};
-use std::{net, thread};
+use std::{iter, net, thread};
+
+#[cfg(unix)]
+use std::{fs, io, os::unix::net::UnixListener, path::PathBuf};
 
 use serde::{Deserialize, Deserializer};
 use tokio::stream::{self, Stream, StreamExt};
@@ -8,24 +12,40 @@ use warp::http::{header, StatusCode};
 use warp::sse::ServerSentEvent;
 use warp::{self, reply, Filter, Reply};
 
-use bitcoin::{Address, BlockHash, OutPoint, Txid};
+use bitcoin::consensus::deserialize;
+use bitcoin::{Address, BlockHash, Network, OutPoint, Transaction, Txid};
 use bitcoin_hashes::hex::{FromHex, ToHex};
 
 use crate::error::{fmt_error_chain, BwtError, Error, OptionExt};
-use crate::types::{BlockId, ScriptHash};
-use crate::util::{block_on_future, descriptor::Checksum};
+use crate::query::{BlockTxidsCache, HistoryCursor};
+use crate::types::{BlockId, FormattedAmount, ScriptHash};
+use crate::util::descriptor::{Checksum, DescriptorChecksum};
+use crate::util::{bitcoincore_ext::Progress, block_on_future, xpub::XyzPubKeyError};
 use crate::{store, util::banner, IndexChange, Query};
 
+#[cfg(unix)]
+mod uds;
+
+// The default number of entries per page for paginated endpoints
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const DEFAULT_DUST_THRESHOLD: u64 = 1000;
+// The maximum number of calls and body size (in bytes) accepted by `POST /batch`
+const MAX_BATCH_SIZE: usize = 100;
+const MAX_BATCH_BODY: u64 = 64 * 1024;
+
 type SyncChanSender = Arc<Mutex<mpsc::Sender<()>>>;
+type ProgressChanSender = Arc<Mutex<Option<mpsc::Sender<Progress>>>>;
 
 fn setup(
     cors: Option<String>,
     query: Arc<Query>,
     sync_tx: SyncChanSender,
+    progress_tx: ProgressChanSender,
     listeners: Listeners,
 ) -> warp::Server<impl warp::Filter<Extract = impl warp::Reply> + Clone> {
     let query = warp::any().map(move || Arc::clone(&query));
     let sync_tx = warp::any().map(move || Arc::clone(&sync_tx));
+    let progress_tx = warp::any().map(move || Arc::clone(&progress_tx));
     let listeners = warp::any().map(move || Arc::clone(&listeners));
 
     let mut headers = header::HeaderMap::new();
@@ -44,6 +64,66 @@ fn setup(
             reply::json(&wallets)
         });
 
+    // GET /wallets/summary
+    let wallets_summary_handler = warp::get()
+        .and(warp::path!("wallets" / "summary"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let wallets = query.list_wallets()?;
+            Ok(reply::json(&wallets))
+        })
+        .map(handle_error);
+
+    // GET /wallets/fiat-value?rate=<btc_rate>
+    let wallets_fiat_value_handler = warp::get()
+        .and(warp::path!("wallets" / "fiat-value"))
+        .and(warp::query::<FiatRateOptions>())
+        .and(query.clone())
+        .map(|options: FiatRateOptions, query: Arc<Query>| {
+            let value = query.portfolio_fiat_value(options.rate)?;
+            Ok(reply::json(&value))
+        })
+        .map(handle_error);
+
+    // GET /wallets/path-balance?prefix=<derivation path>
+    let wallets_path_balance_handler = warp::get()
+        .and(warp::path!("wallets" / "path-balance"))
+        .and(warp::query::<PathPrefixOptions>())
+        .and(query.clone())
+        .map(|options: PathPrefixOptions, query: Arc<Query>| {
+            let prefix = options
+                .prefix
+                .parse()
+                .ok()
+                .or_err(StatusCode::BAD_REQUEST)?;
+            let (confirmed_balance, unconfirmed_balance) = query.path_prefix_balance(&prefix)?;
+            Ok(reply::json(&json!({
+                "confirmed_balance": FormattedAmount(confirmed_balance),
+                "unconfirmed_balance": FormattedAmount(unconfirmed_balance),
+            })))
+        })
+        .map(handle_error);
+
+    // GET /wallets/path-txs?prefix=<derivation path>
+    let wallets_path_txs_handler = warp::get()
+        .and(warp::path!("wallets" / "path-txs"))
+        .and(warp::query::<PathPrefixOptions>())
+        .and(query.clone())
+        .map(|options: PathPrefixOptions, query: Arc<Query>| {
+            let prefix = options
+                .prefix
+                .parse()
+                .ok()
+                .or_err(StatusCode::BAD_REQUEST)?;
+            let txs: Vec<_> = query
+                .path_prefix_history(&prefix)
+                .iter()
+                .filter_map(|txhist| query.get_tx_detail(&txhist.txid))
+                .collect();
+            Ok(reply::json(&txs))
+        })
+        .map(handle_error);
+
     // GET /wallet/:checksum
     let wallet_handler = warp::get()
         .and(warp::path!("wallet" / Checksum))
@@ -54,6 +134,33 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/fiat-value?rate=<btc_rate>
+    let wallet_fiat_value_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "fiat-value"))
+        .and(warp::query::<FiatRateOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: FiatRateOptions, query: Arc<Query>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                let value = query.fiat_value(&checksum, options.rate)?;
+                Ok(reply::json(&value))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/utxo-counts
+    let wallet_utxo_counts_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "utxo-counts"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            let (confirmed, unconfirmed) = query.utxo_counts(&checksum);
+            Ok(reply::json(
+                &json!({ "confirmed": confirmed, "unconfirmed": unconfirmed }),
+            ))
+        })
+        .map(handle_error);
+
     // GET /wallet/:checksum/:index
     let wallet_key_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / u32))
@@ -78,6 +185,166 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/observed-gap
+    let wallet_observed_gap_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "observed-gap"))
+        .and(warp::query::<ChainOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: ChainOptions, query: Arc<Query>| {
+                let gap = query
+                    .observed_max_gap(&checksum, options.change)
+                    .or_err(StatusCode::NOT_FOUND)?;
+                Ok(reply::json(&gap))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/utilization
+    let wallet_utilization_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "utilization"))
+        .and(warp::query::<ChainOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: ChainOptions, query: Arc<Query>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                Ok(reply::json(&query.utilization(&checksum, options.change)))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/balance
+    let wallet_balance_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "balance"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            let balance = query.spendable_balance(&checksum)?;
+            Ok(reply::json(&FormattedAmount(balance.as_sat())))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/last-activity
+    let wallet_last_activity_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "last-activity"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            let activity = query.wallet_last_activity(&checksum)?;
+            Ok(reply::json(&activity))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/used-addresses
+    let wallet_used_addresses_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "used-addresses"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            let addresses = query
+                .used_addresses(&checksum)
+                .into_iter()
+                .map(|(index, address, is_change)| {
+                    json!({ "index": index, "address": address, "is_change": is_change })
+                })
+                .collect::<Vec<_>>();
+            Ok(reply::json(&addresses))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/net-flow
+    let wallet_net_flow_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "net-flow"))
+        .and(warp::query::<NetFlowOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: NetFlowOptions, query: Arc<Query>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                let to = options.to.unwrap_or(u64::MAX);
+                let net_flow = query.net_flow(&checksum, options.from, to)?;
+                Ok(reply::json(&FormattedAmount(net_flow)))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/overpaid-txs?feerate=<sat/vB>
+    let wallet_overpaid_txs_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "overpaid-txs"))
+        .and(warp::query::<OverpaidOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: OverpaidOptions, query: Arc<Query>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                let txs = query
+                    .overpaid_txs(&checksum, options.feerate)?
+                    .into_iter()
+                    .map(|(txid, feerate)| json!({ "txid": txid, "feerate": feerate }))
+                    .collect::<Vec<_>>();
+                Ok(reply::json(&txs))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/consolidation-txs?min_inputs=<count>
+    let wallet_consolidation_txs_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "consolidation-txs"))
+        .and(warp::query::<ConsolidationOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: ConsolidationOptions, query: Arc<Query>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                let txids = query.consolidation_txs(&checksum, options.min_inputs)?;
+                Ok(reply::json(&txids))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/psbt-utxos
+    let wallet_psbt_utxos_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "psbt-utxos"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&query.export_utxos_for_psbt(&checksum)?))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/unconfirmed-vsize
+    let wallet_unconfirmed_vsize_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "unconfirmed-vsize"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&query.unconfirmed_wallet_vsize(&checksum)))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/pending
+    let wallet_pending_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "pending"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            let pending = query.pending_transactions(&checksum)?;
+            let pending: Vec<_> = pending
+                .into_iter()
+                .map(|(txid, age, feerate)| json!({ "txid": txid, "age": age.as_secs(), "feerate": feerate }))
+                .collect();
+            Ok(reply::json(&pending))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/external-addresses
+    let wallet_external_addresses_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "external-addresses"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+            let count = query.distinct_external_addresses(&checksum)?;
+            Ok(reply::json(&count))
+        })
+        .map(handle_error);
+
     // GET /wallet/:checksum/next
     let wallet_next_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / "next"))
@@ -96,6 +363,40 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/tx/:txid/direction
+    let wallet_tx_direction_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "tx" / Txid / "direction"))
+        .and(query.clone())
+        .map(|checksum: Checksum, txid: Txid, query: Arc<Query>| {
+            let direction = query
+                .classify_tx(&txid, &checksum)?
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&direction))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/txs
+    let wallet_txs_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "txs"))
+        .and(warp::query::<HistoryPageOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: HistoryPageOptions, query: Arc<Query>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                let limit = options.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+                let (entries, next_cursor) =
+                    query.history_page(&checksum, options.cursor.as_ref(), limit);
+                let txs: Vec<_> = entries
+                    .iter()
+                    .filter_map(|txhist| query.get_tx_detail(&txhist.txid))
+                    .collect();
+                Ok(reply::json(
+                    &json!({ "txs": txs, "next_cursor": next_cursor }),
+                ))
+            },
+        )
+        .map(handle_error);
+
     // GET /scripthash/:scripthash/*
     let scripthash_route = warp::path!("scripthash" / ScriptHash / ..);
 
@@ -150,6 +451,24 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/:index/descriptor
+    // GET /address/:address/descriptor
+    // GET /scripthash/:scripthash/descriptor
+    let spk_descriptor_handler = warp::get()
+        .and(spk_route.clone())
+        .and(warp::path!("descriptor"))
+        .and(query.clone())
+        .map(|scripthash: ScriptHash, query: Arc<Query>| {
+            let script_info = query
+                .get_script_info(&scripthash)
+                .or_err(StatusCode::NOT_FOUND)?;
+            let desc = query
+                .descriptor_for_script(&script_info.address.script_pubkey())
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(desc.to_string_with_checksum())
+        })
+        .map(handle_error);
+
     // GET /wallet/:checksum/:index/utxos
     // GET /address/:address/utxos
     // GET /scripthash/:scripthash/utxos
@@ -202,7 +521,9 @@ fn setup(
         .and(warp::path::end())
         .and(query.clone())
         .map(|txid: Txid, query: Arc<Query>| {
-            let tx_info = query.get_tx_detail(&txid).or_err(StatusCode::NOT_FOUND)?;
+            let tx_info = query
+                .get_tx_detail_full(&txid)
+                .or_err(StatusCode::NOT_FOUND)?;
             Ok(reply::json(&tx_info))
         })
         .map(handle_error);
@@ -224,8 +545,63 @@ fn setup(
         .and(warp::path!("hex"))
         .and(query.clone())
         .map(|txid: Txid, query: Arc<Query>| {
-            let tx_raw = query.get_tx_raw(&txid)?;
-            Ok(tx_raw.to_hex())
+            let tx_hex = query.get_tx_hex(&txid)?;
+            Ok(tx_hex)
+        })
+        .map(handle_error);
+
+    // GET /tx/:txid/wallet-outputs
+    // POST /tx/wallet-outputs
+    let tx_wallet_outputs_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("wallet-outputs"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query>| {
+            let tx: Transaction = deserialize(&query.get_tx_raw(&txid)?)?;
+            Ok((tx, query))
+        })
+        .or(warp::post()
+            .and(warp::path!("tx" / "wallet-outputs"))
+            .and(warp::body::json())
+            .and(query.clone())
+            .map(|body: BroadcastBody, query: Arc<Query>| {
+                let tx = Vec::from_hex(&body.tx_hex)
+                    .map_err(Error::from)
+                    .and_then(|tx_raw| Ok(deserialize::<Transaction>(&tx_raw)?))
+                    .map_err(|e| e.context(StatusCode::BAD_REQUEST))?;
+                Ok((tx, query))
+            }))
+        .unify()
+        .map(|result: Result<(Transaction, Arc<Query>), Error>| {
+            let (tx, query) = result?;
+            let outputs: Vec<_> = query
+                .classify_outputs(&tx)
+                .into_iter()
+                .map(|owner| {
+                    owner.map(|(checksum, index, is_change)| {
+                        json!({ "wallet": checksum, "derivation_index": index, "is_change": is_change })
+                    })
+                })
+                .collect();
+            Ok(reply::json(&outputs))
+        })
+        .map(handle_error);
+
+    // GET /tx/:txid/wallet-inputs
+    let tx_wallet_inputs_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("wallet-inputs"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query>| {
+            let inputs = query
+                .wallet_inputs_of(&txid)
+                .or_err(StatusCode::NOT_FOUND)?
+                .into_iter()
+                .map(|(prevout, amount, index)| {
+                    json!({ "prevout": prevout, "amount": FormattedAmount(amount), "derivation_index": index })
+                })
+                .collect::<Vec<_>>();
+            Ok(reply::json(&inputs))
         })
         .map(handle_error);
 
@@ -240,6 +616,17 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /tx/:txid/merkle-proof
+    let tx_merkle_proof_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("merkle-proof"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query>| {
+            let proof = query.get_tx_merkle_proof(&txid, &mut BlockTxidsCache::default())?;
+            Ok(reply::json(&proof))
+        })
+        .map(handle_error);
+
     // GET /txs
     // GET /txs/since/:block_height
     let txs_since_handler = warp::get()
@@ -288,6 +675,45 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /txo/:txid/:vout/confirmations-until-settled
+    let txo_settled_handler = warp::get()
+        .and(warp::path!(
+            "txo" / Txid / u32 / "confirmations-until-settled"
+        ))
+        .and(query.clone())
+        .map(|txid: Txid, vout: u32, query: Arc<Query>| {
+            let confs = query
+                .confirmations_until_settled(&OutPoint::new(txid, vout))?
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&confs))
+        })
+        .map(handle_error);
+
+    // POST /txo/:txid/:vout/freeze
+    // DELETE /txo/:txid/:vout/freeze
+    let txo_freeze_handler = warp::post()
+        .map(|| true)
+        .or(warp::delete().map(|| false))
+        .unify()
+        .and(warp::path!("txo" / Txid / u32 / "freeze"))
+        .and(query.clone())
+        .map(|freeze: bool, txid: Txid, vout: u32, query: Arc<Query>| {
+            let outpoint = OutPoint::new(txid, vout);
+            let changed = if freeze {
+                query.freeze_txo(outpoint)?
+            } else {
+                query.unfreeze_txo(&outpoint)?
+            };
+            Ok(reply::json(&changed))
+        })
+        .map(handle_error);
+
+    // GET /txos/frozen
+    let txos_frozen_handler = warp::get()
+        .and(warp::path!("txos" / "frozen"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| reply::json(&query.list_frozen_txos()));
+
     // GET /utxos
     let utxos_handler = warp::get()
         .and(warp::path!("utxos"))
@@ -299,6 +725,28 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /utxos/dust
+    let utxos_dust_handler = warp::get()
+        .and(warp::path!("utxos" / "dust"))
+        .and(warp::query::<DustOptions>())
+        .and(query.clone())
+        .map(|options: DustOptions, query: Arc<Query>| {
+            let threshold = options.threshold.unwrap_or(DEFAULT_DUST_THRESHOLD);
+            let utxos = query.dust_attack_utxos(threshold)?;
+            Ok(reply::json(&utxos))
+        })
+        .map(handle_error);
+
+    // GET /utxos/immature-coinbase
+    let utxos_immature_coinbase_handler = warp::get()
+        .and(warp::path!("utxos" / "immature-coinbase"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let coinbases = query.list_immature_coinbase()?;
+            Ok(reply::json(&coinbases))
+        })
+        .map(handle_error);
+
     // GET /stream
     let sse_handler = warp::get()
         .and(warp::path!("stream"))
@@ -327,13 +775,24 @@ fn setup(
              mut filter: ChangelogFilter,
              listeners: Listeners,
              query: Arc<Query>| {
-                filter.scripthash = Some(scripthash);
+                filter.scripthashes = Some(iter::once(scripthash).collect());
                 let stream = make_sse_stream(filter, listeners, &query)?;
                 Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
             },
         )
         .map(handle_error);
 
+    // GET /activity-span
+    let activity_span_handler = warp::get()
+        .and(warp::path!("activity-span"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let span = query.activity_span().map(|(first_height, last_height)| {
+                json!({ "first_height": first_height, "last_height": last_height })
+            });
+            reply::json(&span)
+        });
+
     // GET /block/tip
     let block_tip_handler = warp::get()
         .and(warp::path!("block" / "tip"))
@@ -345,6 +804,19 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /blocks/recent
+    let blocks_recent_handler = warp::get()
+        .and(warp::path!("blocks" / "recent"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let blocks = query
+                .recent_chain()
+                .into_iter()
+                .map(|(height, blockhash)| json!({ "height": height, "hash": blockhash }))
+                .collect::<Vec<_>>();
+            reply::json(&blocks)
+        });
+
     // GET /block/:hash
     let block_header_handler = warp::get()
         .and(warp::path!("block" / BlockHash))
@@ -391,6 +863,26 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /mempool/min-feerate
+    let mempool_min_feerate_handler = warp::get()
+        .and(warp::path!("mempool" / "min-feerate"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let min_feerate = query.mempool_min_feerate()?;
+            Ok(reply::json(&min_feerate))
+        })
+        .map(handle_error);
+
+    // GET /mempool/fees
+    let mempool_fees_handler = warp::get()
+        .and(warp::path!("mempool" / "fees"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let fees = query.mempool_fees()?;
+            Ok(reply::json(&fees))
+        })
+        .map(handle_error);
+
     // GET /fee-estimate/:confirmation-target
     let fee_estimate_handler = warp::get()
         .and(warp::path!("fee-estimate" / u16))
@@ -416,52 +908,203 @@ fn setup(
     // GET /banner.txt
     let banner_handler = warp::get()
         .and(warp::path!("banner.txt"))
-        .and(query)
+        .and(query.clone())
         .map(|query: Arc<Query>| banner::get_welcome_banner(&query, true))
         .map(handle_error);
 
     // POST /sync
     let sync_handler = warp::post()
         .and(warp::path!("sync"))
-        .and(sync_tx)
+        .and(sync_tx.clone())
         .map(|sync_tx: SyncChanSender| {
             sync_tx.lock().unwrap().send(())?;
             Ok(reply::with_status("syncing queued", StatusCode::ACCEPTED))
         })
         .map(handle_error);
 
+    // GET /rescan
+    let rescan_status_handler = warp::get()
+        .and(warp::path!("rescan"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            reply::json(&json!({
+                "in_progress": query.is_rescanning(),
+                "progress": query.wallet_scan_progress(),
+            }))
+        });
+
+    // POST /rescan?start_height=<height>
+    let rescan_handler = warp::post()
+        .and(warp::path!("rescan"))
+        .and(warp::query::<RescanOptions>())
+        .and(query.clone())
+        .and(sync_tx.clone())
+        .and(progress_tx.clone())
+        .map(
+            |options: RescanOptions,
+             query: Arc<Query>,
+             sync_tx: SyncChanSender,
+             progress_tx: ProgressChanSender| {
+                ensure!(!query.is_rescanning(), BwtError::RescanInProgress);
+                let sync_tx = sync_tx.lock().unwrap().clone();
+                let progress_tx = progress_tx.lock().unwrap().clone();
+                thread::spawn(move || {
+                    if let Err(e) = query.rescan(options.start_height, progress_tx) {
+                        warn!("rescan failed: {:?}", e);
+                    }
+                    // sync right away to fetch the rescanned history
+                    sync_tx.send(()).ok();
+                });
+                Ok(reply::with_status("rescan started", StatusCode::ACCEPTED))
+            },
+        )
+        .map(handle_error);
+
+    // POST /gap-limit?gap_limit=<gap-limit>
+    let gap_limit_handler = warp::post()
+        .and(warp::path!("gap-limit"))
+        .and(warp::query::<GapLimitOptions>())
+        .and(query.clone())
+        .and(progress_tx)
+        .map(
+            |options: GapLimitOptions, query: Arc<Query>, progress_tx: ProgressChanSender| {
+                ensure!(!query.is_rescanning(), BwtError::RescanInProgress);
+                let progress_tx = progress_tx.lock().unwrap().clone();
+                thread::spawn(move || {
+                    if let Err(e) = query.set_gap_limit(options.gap_limit, progress_tx) {
+                        warn!("gap limit increase failed: {:?}", e);
+                    }
+                });
+                Ok(reply::with_status(
+                    "gap limit increase started",
+                    StatusCode::ACCEPTED,
+                ))
+            },
+        )
+        .map(handle_error);
+
+    // POST /network?network=<network>
+    let network_handler = warp::post()
+        .and(warp::path!("network"))
+        .and(warp::query::<NetworkOptions>())
+        .and(query.clone())
+        .and(sync_tx)
+        .map(
+            |options: NetworkOptions, query: Arc<Query>, sync_tx: SyncChanSender| {
+                ensure!(!query.is_rescanning(), BwtError::RescanInProgress);
+                query
+                    .set_network(options.network)
+                    .map_err(|e| e.context(StatusCode::BAD_REQUEST))?;
+                // sync right away to re-import the addresses
+                sync_tx.lock().unwrap().send(()).ok();
+                Ok(reply::with_status("network switched", StatusCode::OK))
+            },
+        )
+        .map(handle_error);
+
+    // POST /batch
+    let batch_handler = warp::post()
+        .and(warp::path!("batch"))
+        .and(warp::body::content_length_limit(MAX_BATCH_BODY))
+        .and(warp::body::json())
+        .and(query)
+        .map(|calls: Vec<serde_json::Value>, query: Arc<Query>| {
+            if calls.len() > MAX_BATCH_SIZE {
+                let err = anyhow!("batch exceeds the maximum of {} calls", MAX_BATCH_SIZE);
+                return Err(err.context(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            // block txids are shared between the calls, as merkle proofs for the same block are common
+            let mut cache = BlockTxidsCache::default();
+            let results: Vec<_> = calls
+                .into_iter()
+                .map(|call| {
+                    // report failures per-call, without failing the entire batch
+                    let result = serde_json::from_value(call)
+                        .map_err(|e| Error::from(e).context(StatusCode::BAD_REQUEST))
+                        .and_then(|call| batch_call(&query, call, &mut cache));
+                    match result {
+                        Ok(result) => json!({ "result": result }),
+                        Err(e) => json!({ "error": {
+                            "code": get_error_status(&e).as_u16(),
+                            "message": fmt_error_chain(&e),
+                        } }),
+                    }
+                })
+                .collect();
+            Ok(reply::json(&results))
+        })
+        .map(handle_error);
+
     let handlers = balanced_or_tree!(
         wallets_handler,
+        wallets_summary_handler,
+        wallets_fiat_value_handler,
+        wallets_path_balance_handler,
+        wallets_path_txs_handler,
         wallet_handler,
+        wallet_fiat_value_handler,
+        wallet_utxo_counts_handler,
         wallet_key_handler, // needs to be before spk_handler to work with keys that don't have any indexed history
         wallet_gap_handler,
+        wallet_observed_gap_handler,
+        wallet_utilization_handler,
+        wallet_balance_handler,
+        wallet_last_activity_handler,
+        wallet_used_addresses_handler,
+        wallet_net_flow_handler,
+        wallet_overpaid_txs_handler,
+        wallet_consolidation_txs_handler,
+        wallet_psbt_utxos_handler,
+        wallet_unconfirmed_vsize_handler,
+        wallet_pending_handler,
+        wallet_external_addresses_handler,
         wallet_next_handler,
+        wallet_tx_direction_handler,
+        wallet_txs_handler,
         spk_handler,
         spk_utxo_handler,
         spk_stats_handler,
+        spk_descriptor_handler,
         spk_txs_handler,
         spk_txs_compact_handler,
         tx_handler,
         tx_verbose_handler,
         tx_hex_handler,
+        tx_wallet_inputs_handler,
+        tx_wallet_outputs_handler,
         tx_proof_handler,
+        tx_merkle_proof_handler,
         txs_since_handler,
         txs_since_compact_handler,
         tx_broadcast_handler,
         txo_handler,
+        txo_settled_handler,
+        txo_freeze_handler,
+        txos_frozen_handler,
         utxos_handler,
+        utxos_dust_handler,
+        utxos_immature_coinbase_handler,
         sse_handler,
         spk_sse_handler,
+        activity_span_handler,
         block_tip_handler,
+        blocks_recent_handler,
         block_header_handler,
         block_hex_handler,
         block_height_handler,
         mempool_histogram_handler,
+        mempool_min_feerate_handler,
+        mempool_fees_handler,
         fee_estimate_handler,
         dump_handler,
         debug_handler,
         banner_handler,
         sync_handler,
+        rescan_status_handler,
+        rescan_handler,
+        gap_limit_handler,
+        network_handler,
+        batch_handler,
         warp::any().map(|| StatusCode::NOT_FOUND)
     )
     .with(warp::log("bwt::http"))
@@ -490,9 +1133,41 @@ async fn spawn<S>(
     server_ft.await
 }
 
+#[cfg(unix)]
+#[tokio::main]
+async fn spawn_unix<S>(
+    warp_server: warp::Server<S>,
+    listener: UnixListener,
+    ready_tx: oneshot::Sender<io::Result<()>>,
+    shutdown_rx: oneshot::Receiver<()>,
+) where
+    S: warp::Filter + Clone + Send + Sync + 'static,
+    S::Extract: warp::Reply,
+{
+    // the listener has to be registered from within the runtime. send back the result,
+    // to report registration errors to the caller
+    let incoming = match uds::Incoming::new(listener) {
+        Ok(incoming) => {
+            ready_tx.send(Ok(())).unwrap();
+            incoming
+        }
+        Err(e) => return ready_tx.send(Err(e)).unwrap(),
+    };
+
+    let server_ft = warp_server.serve_incoming_with_graceful_shutdown(incoming, async {
+        shutdown_rx.await.ok();
+    });
+
+    server_ft.await
+}
+
 pub struct HttpServer {
-    addr: net::SocketAddr,
+    // None when bound on a unix socket
+    addr: Option<net::SocketAddr>,
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
     listeners: Listeners,
+    query: Arc<Query>,
     shutdown_tx: Option<oneshot::Sender<()>>,
     thread: Option<thread::JoinHandle<()>>,
 }
@@ -503,10 +1178,12 @@ impl HttpServer {
         cors: Option<String>,
         query: Arc<Query>,
         sync_tx: mpsc::Sender<()>,
+        progress_tx: Option<mpsc::Sender<Progress>>,
     ) -> Self {
         let listeners = Arc::new(Mutex::new(Vec::new()));
         let sync_tx = Arc::new(Mutex::new(sync_tx));
-        let warp_server = setup(cors, query, sync_tx, listeners.clone());
+        let progress_tx = Arc::new(Mutex::new(progress_tx));
+        let warp_server = setup(cors, query.clone(), sync_tx, progress_tx, listeners.clone());
 
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         let (addr_tx, addr_rx) = oneshot::channel();
@@ -520,12 +1197,58 @@ impl HttpServer {
 
         HttpServer {
             listeners,
-            addr: bound_addr,
+            query,
+            addr: Some(bound_addr),
+            #[cfg(unix)]
+            socket_path: None,
             shutdown_tx: Some(shutdown_tx),
             thread: Some(thread),
         }
     }
 
+    /// Start the server bound on a unix socket at `socket_path` instead of a TCP address.
+    /// A socket file left behind by a previous run gets replaced.
+    #[cfg(unix)]
+    pub fn start_unix(
+        socket_path: PathBuf,
+        cors: Option<String>,
+        query: Arc<Query>,
+        sync_tx: mpsc::Sender<()>,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> Result<Self, Error> {
+        let listener = uds::bind(&socket_path)?;
+
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+        let sync_tx = Arc::new(Mutex::new(sync_tx));
+        let progress_tx = Arc::new(Mutex::new(progress_tx));
+        let warp_server = setup(cors, query.clone(), sync_tx, progress_tx, listeners.clone());
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let thread = thread::spawn(move || {
+            spawn_unix(warp_server, listener, ready_tx, shutdown_rx);
+        });
+
+        block_on_future(ready_rx)
+            .unwrap()
+            .map_err(|e| Error::from(e).context("failed registering http unix socket"))?;
+
+        info!(
+            "HTTP REST API server running on unix socket {:?}",
+            socket_path
+        );
+
+        Ok(HttpServer {
+            listeners,
+            query,
+            addr: None,
+            socket_path: Some(socket_path),
+            shutdown_tx: Some(shutdown_tx),
+            thread: Some(thread),
+        })
+    }
+
     pub fn send_updates(&self, changelog: &[IndexChange]) {
         let mut listeners = self.listeners.lock().unwrap();
         if listeners.is_empty() {
@@ -540,12 +1263,13 @@ impl HttpServer {
         listeners.retain(|listener| {
             changelog
                 .iter()
-                .filter(|change| listener.filter.matches(change))
+                .filter(|change| listener.filter.matches(change, &self.query))
                 .all(|change| listener.tx.send(change.clone()).is_ok())
         })
     }
 
-    pub fn addr(&self) -> net::SocketAddr {
+    /// The bound TCP address, or None when bound on a unix socket
+    pub fn addr(&self) -> Option<net::SocketAddr> {
         self.addr
     }
 }
@@ -555,6 +1279,11 @@ impl Drop for HttpServer {
         trace!("HTTP server shutting down");
         self.shutdown_tx.take().unwrap().send(()).unwrap();
         self.thread.take().unwrap().join().unwrap();
+
+        #[cfg(unix)]
+        if let Some(socket_path) = &self.socket_path {
+            fs::remove_file(socket_path).ok();
+        }
     }
 }
 
@@ -581,12 +1310,13 @@ fn make_sse_stream(
     });
 
     // fetch historical changelog since the requested start point (if requesed)
-    let changelog = match &filter.synced_tip {
+    let changelog: Vec<IndexChange> = match &filter.synced_tip {
         Some(synced_tip) => query.get_changelog_after(synced_tip)?,
         None => vec![],
     }
     .into_iter()
-    .filter(move |change| filter.matches(change));
+    .filter(|change| filter.matches(change, query))
+    .collect();
     // TODO don't produce unwanted events to begin with instead of filtering them
 
     Ok(stream::iter(changelog).chain(rx).map(make_sse_msg).map(Ok))
@@ -609,26 +1339,38 @@ struct ChangelogFilter {
     #[serde(default, deserialize_with = "deser_synced_tip")]
     synced_tip: Option<BlockId>,
 
-    scripthash: Option<ScriptHash>,
+    // set from the (possibly repeated) `scripthash` and `address` query params, see param()
+    #[serde(skip)]
+    scripthashes: Option<HashSet<ScriptHash>>,
     outpoint: Option<OutPoint>,
     category: Option<String>,
     // warp::query() does not support nested arrays
-    //pub scripthash: Option<Vec<ScriptHash>>,
     //pub category: Option<Vec<String>>,
 }
 
 impl ChangelogFilter {
-    fn matches(&self, change: &IndexChange) -> bool {
-        self.scripthash_matches(change)
+    fn matches(&self, change: &IndexChange, query: &Query) -> bool {
+        self.scripthash_matches(change, query)
             && self.category_matches(change)
             && self.outpoint_matches(change)
     }
-    fn scripthash_matches(&self, change: &IndexChange) -> bool {
-        self.scripthash.as_ref().map_or(true, |filter_sh| {
-            change
-                .scripthash()
-                .map_or(false, |change_sh| filter_sh == change_sh)
-            //.map_or(false, |change_sh| filter_sh.contains(change_sh))
+    fn scripthash_matches(&self, change: &IndexChange, query: &Query) -> bool {
+        self.scripthashes.as_ref().map_or(true, |filter_shs| {
+            match change {
+                // match transactions against the scripthashes of their indexed inputs and outputs
+                IndexChange::Transaction(txid, _) => query
+                    .with_tx_entry(txid, |tx_entry| {
+                        let funding_shs = tx_entry.funding.values().map(|f| &f.0);
+                        let spending_shs = tx_entry.spending.values().map(|s| &s.0);
+                        funding_shs
+                            .chain(spending_shs)
+                            .any(|tx_sh| filter_shs.contains(tx_sh))
+                    })
+                    .unwrap_or(false),
+                _ => change
+                    .scripthash()
+                    .map_or(false, |change_sh| filter_shs.contains(change_sh)),
+            }
         })
     }
     fn category_matches(&self, change: &IndexChange) -> bool {
@@ -648,18 +1390,37 @@ impl ChangelogFilter {
 
     fn param() -> impl Filter<Extract = (ChangelogFilter,), Error = warp::Rejection> + Clone {
         warp::query::<ChangelogFilter>()
+            // the raw query params, for the repeated ones
+            .and(warp::query::<Vec<(String, String)>>())
             .and(warp::sse::last_event_id::<String>())
             .map(
-                |mut filter: ChangelogFilter, last_event_id: Option<String>| {
+                |mut filter: ChangelogFilter,
+                 params: Vec<(String, String)>,
+                 last_event_id: Option<String>| {
+                    let mut scripthashes = HashSet::new();
+                    for (key, val) in params {
+                        match key.as_str() {
+                            "scripthash" => scripthashes.insert(val.parse()?),
+                            "address" => {
+                                scripthashes.insert(ScriptHash::from(&val.parse::<Address>()?))
+                            }
+                            _ => continue,
+                        };
+                    }
+                    if !scripthashes.is_empty() {
+                        filter.scripthashes = Some(scripthashes);
+                    }
+
                     // When available, use the Server-Sent-Events Last-Event-Id header as the synced tip
                     if let Some(last_event_id) = last_event_id {
                         if let Ok(synced_tip) = parse_synced_tip(&last_event_id) {
                             filter.synced_tip = Some(synced_tip);
                         }
                     }
-                    filter
+                    Ok(filter)
                 },
             )
+            .and_then(reject_error)
     }
 }
 
@@ -688,11 +1449,141 @@ struct UtxoOptions {
     include_unsafe: Option<bool>,
 }
 
+#[derive(Deserialize, Debug)]
+struct FiatRateOptions {
+    rate: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChainOptions {
+    #[serde(default)]
+    change: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct PathPrefixOptions {
+    prefix: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OverpaidOptions {
+    feerate: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConsolidationOptions {
+    min_inputs: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct DustOptions {
+    threshold: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NetFlowOptions {
+    #[serde(default)]
+    from: u64,
+    to: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryPageOptions {
+    #[serde(default, deserialize_with = "deser_cursor")]
+    cursor: Option<HistoryCursor>,
+    limit: Option<usize>,
+}
+
+fn deser_cursor<'de, D>(deserializer: D) -> std::result::Result<Option<HistoryCursor>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let cursor = s
+        .parse()
+        .map_err(|err: Error| serde::de::Error::custom(err.to_string()))?;
+    Ok(Some(cursor))
+}
+
+#[derive(Deserialize, Debug)]
+struct RescanOptions {
+    start_height: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GapLimitOptions {
+    gap_limit: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct NetworkOptions {
+    network: Network,
+}
+
 #[derive(Deserialize, Debug)]
 struct BroadcastBody {
     tx_hex: String,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(tag = "method", content = "params")]
+enum BatchCall {
+    #[serde(rename = "scripthash")]
+    ScriptInfo { scripthash: ScriptHash },
+    #[serde(rename = "scripthash.stats")]
+    ScriptStats { scripthash: ScriptHash },
+    #[serde(rename = "scripthash.utxos")]
+    ScriptUtxos {
+        scripthash: ScriptHash,
+        #[serde(flatten)]
+        options: UtxoOptions,
+    },
+    #[serde(rename = "scripthash.txs")]
+    ScriptTxs { scripthash: ScriptHash },
+    #[serde(rename = "scripthash.txs.compact")]
+    ScriptTxsCompact { scripthash: ScriptHash },
+    #[serde(rename = "tx")]
+    Tx { txid: Txid },
+    #[serde(rename = "tx.merkle-proof")]
+    TxMerkleProof { txid: Txid },
+}
+
+// Process a single `POST /batch` call, using the same query methods as the equivalent endpoints
+fn batch_call(
+    query: &Query,
+    call: BatchCall,
+    cache: &mut BlockTxidsCache,
+) -> Result<serde_json::Value, Error> {
+    Ok(match call {
+        BatchCall::ScriptInfo { scripthash } => json!(query
+            .get_script_info(&scripthash)
+            .or_err(StatusCode::NOT_FOUND)?),
+        BatchCall::ScriptStats { scripthash } => json!(query
+            .get_script_stats(&scripthash)?
+            .or_err(StatusCode::NOT_FOUND)?),
+        BatchCall::ScriptUtxos {
+            scripthash,
+            options,
+        } => json!(query.list_unspent(
+            Some(&scripthash),
+            options.min_conf,
+            options.include_unsafe
+        )?),
+        BatchCall::ScriptTxs { scripthash } => json!(query.map_history(&scripthash, |txhist| {
+            query.get_tx_detail(&txhist.txid).unwrap()
+        })),
+        BatchCall::ScriptTxsCompact { scripthash } => {
+            json!(query.map_history(&scripthash, compact_history))
+        }
+        BatchCall::Tx { txid } => {
+            json!(query
+                .get_tx_detail_full(&txid)
+                .or_err(StatusCode::NOT_FOUND)?)
+        }
+        BatchCall::TxMerkleProof { txid } => json!(query.get_tx_merkle_proof(&txid, cache)?),
+    })
+}
+
 fn compact_history(tx_hist: &store::HistoryEntry) -> serde_json::Value {
     json!([tx_hist.txid, tx_hist.status])
 }
@@ -724,6 +1615,8 @@ fn get_error_status(e: &Error) -> StatusCode {
         *status_code
     } else if let Some(bwt_err) = e.downcast_ref::<BwtError>() {
         bwt_err.status_code()
+    } else if e.downcast_ref::<XyzPubKeyError>().is_some() {
+        StatusCode::BAD_REQUEST
     } else {
         StatusCode::INTERNAL_SERVER_ERROR
     }
@@ -0,0 +1,185 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use tokio::io::PollEvented;
+use tokio::stream::Stream;
+
+use crate::error::{Context as _, OptionExt, Result};
+
+// Owner and group read/write access, to allow access by a reverse proxy running under a different user
+const SOCKET_MODE: u32 = 0o660;
+
+/// Bind a non-blocking unix socket listener, replacing the socket file left behind by a previous run (if any)
+pub fn bind(socket_path: &Path) -> Result<net::UnixListener> {
+    if let Ok(meta) = fs::metadata(socket_path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(socket_path)?;
+        }
+    }
+
+    // bind within a private directory and move the socket into place once its permissions are
+    // set, so that it is never reachable with the default umask-based permissions
+    let file_name = socket_path
+        .file_name()
+        .or_err("invalid http unix socket path")?;
+    let private_dir = socket_path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .with_context(|| format!("failed creating directory {:?}", private_dir))?;
+
+    let private_path = private_dir.join(file_name);
+    let listener = net::UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(SOCKET_MODE))?;
+        fs::rename(&private_path, socket_path)?;
+        Ok(listener)
+    });
+    if listener.is_err() {
+        fs::remove_file(&private_path).ok();
+    }
+    fs::remove_dir(&private_dir)?;
+
+    let listener = listener
+        .with_context(|| format!("failed binding http unix socket on {:?}", socket_path))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// A stream of the connections accepted by the listener, for use with warp's `serve_incoming`.
+/// Must be created from within the tokio runtime.
+pub struct Incoming(PollEvented<EventedUnix<net::UnixListener>>);
+
+impl Incoming {
+    pub fn new(listener: net::UnixListener) -> io::Result<Self> {
+        Ok(Incoming(PollEvented::new(EventedUnix(listener))?))
+    }
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<PollEvented<EventedUnix<net::UnixStream>>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let listener = &self.0;
+        match listener.poll_read_ready(cx, Ready::readable()) {
+            Poll::Ready(Ok(_)) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        Poll::Ready(Some(match listener.get_ref().0.accept() {
+            Ok((stream, _)) => stream
+                .set_nonblocking(true)
+                .and_then(|()| PollEvented::new(EventedUnix(stream))),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return match listener.clear_read_ready(cx, Ready::readable()) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Some(Err(e))),
+                };
+            }
+            Err(e) => Err(e),
+        }))
+    }
+}
+
+/// Registers the unix socket file descriptor with the tokio reactor
+pub struct EventedUnix<T>(T);
+
+impl<T: AsRawFd> Evented for EventedUnix<T> {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl Read for EventedUnix<net::UnixStream> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for EventedUnix<net::UnixStream> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn test_serve_unix_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("bwt-test-http-{}.sock", std::process::id()));
+        // a stale socket file gets replaced
+        drop(bind(&socket_path).unwrap());
+        let listener = bind(&socket_path).unwrap();
+        let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+        // the private directory used for binding is removed
+        let file_name = socket_path.file_name().unwrap().to_string_lossy();
+        let private_dir = format!(".{}.{}", file_name, std::process::id());
+        assert!(!socket_path.with_file_name(private_dir).exists());
+
+        let incoming = Incoming::new(listener).unwrap();
+        let filter = warp::path!("hello" / String).map(|name| format!("hello {}", name));
+        tokio::spawn(warp::serve(filter).serve_incoming(incoming));
+
+        let client_path = socket_path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let mut stream = net::UnixStream::connect(client_path).unwrap();
+            write!(
+                stream,
+                "GET /hello/bwt HTTP/1.1\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+            io::BufReader::new(stream)
+                .lines()
+                .collect::<io::Result<Vec<String>>>()
+                .unwrap()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(response.first().unwrap(), "HTTP/1.1 200 OK");
+        assert_eq!(response.last().unwrap(), "hello bwt");
+        fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -1,26 +1,82 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::sync::{mpsc, Arc};
 use std::{fmt, thread, time};
 
 use serde::Serialize;
 
-use bitcoin::{Address, BlockHash, OutPoint, Txid};
+use bitcoin::{Address, BlockHash, Network, OutPoint, Transaction, Txid};
 use bitcoincore_rpc::json::{
     GetTransactionResultDetailCategory as TxCategory, ListTransactionResult,
 };
-use bitcoincore_rpc::{Client as RpcClient, RpcApi};
+use bitcoincore_rpc::RpcApi;
 
-use crate::error::Result;
+use crate::error::{BwtError, Result};
 use crate::store::{FundingInfo, MemoryStore, SpendingInfo, TxEntry};
-use crate::types::{BlockId, InPoint, RescanSince, ScriptHash, TxStatus};
-use crate::util::bitcoincore_ext::{Progress, RpcApiExt};
-use crate::wallet::{KeyOrigin, WalletWatcher};
+use crate::types::{
+    serialize_amount, BlockId, InPoint, RescanSince, ScriptHash, TxStatus, COINBASE_MATURITY,
+};
+use crate::util::bitcoincore_ext::{Progress, RpcApiExt, RpcClient};
+use crate::util::descriptor::Checksum;
+use crate::util::BoolThen;
+use crate::wal::{Wal, WalEntry};
+use crate::wallet::{input_script_candidates, KeyOrigin, WalletWatcher};
+
+// The number of recent blocks to keep track of for reorg auditing
+const RECENT_CHAIN_LEN: usize = 100;
 
 pub struct Indexer {
     rpc: Arc<RpcClient>,
     watcher: WalletWatcher,
     store: MemoryStore,
     tip: Option<BlockId>,
+    pending_resync: bool,
+    immature_coinbase: HashMap<OutPoint, ImmatureCoinbase>,
+    recent_chain: VecDeque<BlockId>,
+    wal: Option<Wal>,
+    delayed_confirms: Vec<IndexChange>,
+    scan_progress: Option<f32>,
+    sync_paused: bool,
+    max_reorg_depth: Option<u32>,
+    // the lowest block height to rescan from, following usage that moved a watched window past the imported range
+    window_extended_at: Option<u32>,
+    // the height the blocks were rescanned from following a window extension, to re-sync on the next run
+    window_rescanned_at: Option<u32>,
+    // outputs excluded from the wallet balances, for coin control
+    frozen_txos: HashSet<OutPoint>,
+    // the immature coinbase outputs last persisted to the wal
+    wal_coinbase_outpoints: HashSet<OutPoint>,
+    utxo_counter: UtxoCounter,
+}
+
+// Keeps the per-wallet counts of confirmed and unconfirmed unspent outputs up to date as outputs
+// get funded and spent, so that they don't have to be tallied from `listunspent`
+#[derive(Default)]
+struct UtxoCounter {
+    // (confirmed, unconfirmed)
+    counts: HashMap<Checksum, (u64, u64)>,
+    unspent: HashMap<OutPoint, Checksum>,
+    spent: HashSet<OutPoint>,
+}
+
+/// An immature coinbase output paying to a wallet
+#[derive(Debug, Clone, Serialize)]
+pub struct ImmatureCoinbase {
+    pub checksum: Checksum,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: u64,
+    pub block_height: u32,
+}
+
+impl ImmatureCoinbase {
+    // coinbase outputs become spendable once they reach COINBASE_MATURITY+1 confirmations
+    pub fn is_immature(&self, tip_height: u32) -> bool {
+        self.confirmations(tip_height) <= COINBASE_MATURITY
+    }
+
+    pub fn confirmations(&self, tip_height: u32) -> u32 {
+        (tip_height + 1).saturating_sub(self.block_height)
+    }
 }
 
 impl Indexer {
@@ -30,9 +86,140 @@ impl Indexer {
             watcher,
             store: MemoryStore::new(),
             tip: None,
+            pending_resync: false,
+            immature_coinbase: HashMap::new(),
+            recent_chain: VecDeque::with_capacity(RECENT_CHAIN_LEN),
+            wal: None,
+            delayed_confirms: vec![],
+            scan_progress: None,
+            sync_paused: false,
+            max_reorg_depth: None,
+            window_extended_at: None,
+            window_rescanned_at: None,
+            frozen_txos: HashSet::new(),
+            wal_coinbase_outpoints: HashSet::new(),
+            utxo_counter: UtxoCounter::default(),
         }
     }
 
+    /// Persist index mutations to a write-ahead log at `path`, restoring the index state
+    /// from it if it already exists. Must be called before the initial sync.
+    pub fn open_wal(&mut self, path: &Path) -> Result<()> {
+        assert!(self.tip.is_none(), "the wal must be opened before syncing");
+
+        let (wal, entries) = Wal::open(path)?;
+        self.wal = Some(wal);
+
+        if !entries.is_empty() {
+            let mut unknown_wallet = false;
+            for entry in entries {
+                match entry {
+                    WalEntry::SyncedTip(tip) => self.tip = tip,
+                    WalEntry::FrozenTxo(outpoint, frozen) => {
+                        self.apply_txo_frozen(outpoint, frozen);
+                    }
+                    WalEntry::ImmatureCoinbase(coinbases) => {
+                        self.immature_coinbase.clear();
+                        for (outpoint, checksum, amount, block_height) in coinbases {
+                            let checksum: Checksum = checksum.parse()?;
+                            unknown_wallet |= self.watcher.get(&checksum).is_none();
+                            let coinbase = ImmatureCoinbase {
+                                checksum,
+                                amount,
+                                block_height,
+                            };
+                            self.immature_coinbase.insert(outpoint, coinbase);
+                        }
+                        self.wal_coinbase_outpoints =
+                            self.immature_coinbase.keys().copied().collect();
+                    }
+                    entry => {
+                        if let WalEntry::Scripthash(_, label, _) = &entry {
+                            // restore the wallets' funded index, to keep the import window ahead of it
+                            match KeyOrigin::from_label(label) {
+                                Some(KeyOrigin::Descriptor(checksum, _))
+                                    if self.watcher.get(&checksum).is_none() =>
+                                {
+                                    unknown_wallet = true
+                                }
+                                Some(origin) => {
+                                    self.watcher.mark_funded(&origin);
+                                }
+                                None => (),
+                            }
+                        }
+                        self.store.apply_wal_entry(entry)?
+                    }
+                }
+            }
+            if unknown_wallet {
+                // the set of tracked wallets changed, indexed history of removed ones would linger
+                warn!("the wal references wallets that are no longer tracked, syncing from scratch...");
+                return self.reset_wal_state();
+            }
+            let stats = self.store.stats();
+            info!(
+                "restored {} transactions and {} addresses from the wal, synced up to {}",
+                stats.transaction_count,
+                stats.scripthash_count,
+                self.tip.map_or("none".into(), |tip| tip.to_string()),
+            );
+            let entries = self.wal_entries();
+            self.wal.as_mut().unwrap().compact(&entries)?;
+            self.utxo_counter = UtxoCounter::from_store(&self.store);
+        }
+        // enabled only now, to avoid journaling the replayed entries again
+        self.store.enable_journal();
+        Ok(())
+    }
+
+    // discard the indexed state, along with the state persisted to the wal (if any).
+    // frozen outputs are kept, as they are not derived from the indexed history.
+    fn reset_wal_state(&mut self) -> Result<()> {
+        self.store = MemoryStore::new();
+        self.store.enable_journal();
+        self.utxo_counter = UtxoCounter::default();
+        self.tip = None;
+        self.window_rescanned_at = None;
+        self.immature_coinbase.clear();
+        self.wal_coinbase_outpoints.clear();
+        if let Some(wal) = &mut self.wal {
+            wal.compact(&frozen_wal_entries(&self.frozen_txos))?;
+        }
+        Ok(())
+    }
+
+    // persist the mutations recorded since the last sync, followed by the synced tip
+    fn write_wal(&mut self) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            let mut entries = self.store.take_journal();
+            // the immature coinbase outputs are not part of the store, their full set is
+            // persisted whenever it changes
+            let coinbase_outpoints: HashSet<OutPoint> =
+                self.immature_coinbase.keys().copied().collect();
+            if coinbase_outpoints != self.wal_coinbase_outpoints {
+                entries.push(immature_coinbase_wal_entry(&self.immature_coinbase));
+                self.wal_coinbase_outpoints = coinbase_outpoints;
+            }
+            entries.push(WalEntry::SyncedTip(self.tip));
+            wal.append(&entries)?;
+
+            if wal.needs_compaction() {
+                let entries = self.wal_entries();
+                self.wal.as_mut().unwrap().compact(&entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn wal_entries(&self) -> Vec<WalEntry> {
+        let mut entries = self.store.wal_entries();
+        entries.extend(frozen_wal_entries(&self.frozen_txos));
+        entries.push(immature_coinbase_wal_entry(&self.immature_coinbase));
+        entries.push(WalEntry::SyncedTip(self.tip));
+        entries
+    }
+
     pub fn store(&self) -> &MemoryStore {
         &self.store
     }
@@ -41,6 +228,57 @@ impl Indexer {
         &self.watcher
     }
 
+    /// Get the progress of the bitcoind wallet rescan that indexing is paused for, if any
+    pub fn scan_progress(&self) -> Option<f32> {
+        self.scan_progress
+    }
+
+    pub fn immature_coinbase(&self) -> &HashMap<OutPoint, ImmatureCoinbase> {
+        &self.immature_coinbase
+    }
+
+    pub fn frozen_txos(&self) -> &HashSet<OutPoint> {
+        &self.frozen_txos
+    }
+
+    /// Get the number of the wallet's confirmed and unconfirmed unspent outputs, as a tuple of
+    /// `(confirmed, unconfirmed)`. Outputs spent by unconfirmed transactions are not included,
+    /// frozen outputs are.
+    pub fn utxo_counts(&self, checksum: &Checksum) -> (u64, u64) {
+        self.utxo_counter
+            .counts
+            .get(checksum)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Freeze or unfreeze an output, persisting the change to the wal (if enabled) right away.
+    /// Returns whether the freeze status changed.
+    pub fn set_txo_frozen(&mut self, outpoint: OutPoint, frozen: bool) -> Result<bool> {
+        let changed = self.apply_txo_frozen(outpoint, frozen);
+        if let (true, Some(wal)) = (changed, &mut self.wal) {
+            wal.append(&[WalEntry::FrozenTxo(outpoint, frozen)])?;
+        }
+        Ok(changed)
+    }
+
+    fn apply_txo_frozen(&mut self, outpoint: OutPoint, frozen: bool) -> bool {
+        if frozen {
+            self.frozen_txos.insert(outpoint)
+        } else {
+            self.frozen_txos.remove(&outpoint)
+        }
+    }
+
+    /// Get the (height, blockhash) of the most recent blocks processed by the indexer,
+    /// ordered by height. Useful for diagnosing reorgs by comparing against the node's chain.
+    pub fn recent_chain(&self) -> Vec<(u32, BlockHash)> {
+        self.recent_chain
+            .iter()
+            .map(|BlockId(height, blockhash)| (*height, *blockhash))
+            .collect()
+    }
+
     // continue to sync transactions and import addresses (with rescan) until no more new addresses
     // need to be imported. the initial sync does not collect the Changelog and does not emit updates.
     pub fn initial_sync(&mut self, progress_tx: Option<mpsc::Sender<Progress>>) -> Result<()> {
@@ -49,17 +287,32 @@ impl Indexer {
         info!("starting initial sync");
         self.watcher.check_imports(&self.rpc)?;
 
+        // the tip restored from the wal may have been reorged out while we were offline
+        if let Some(BlockId(tip_height, tip_hash)) = self.tip {
+            let still_active = tip_height as u64 <= self.rpc.get_block_count()?
+                && self.rpc.get_block_hash(tip_height as u64)? == tip_hash;
+            if !still_active {
+                warn!("the tip restored from the wal is no longer active, syncing from scratch...");
+                self.reset_wal_state()?;
+            }
+        }
+
         let mut changelog = Changelog::new(false);
         let mut synced_tip;
 
-        spawn_send_progress_thread(self.rpc.clone(), progress_tx);
+        let import_progress_tx = spawn_send_progress_thread(self.rpc.clone(), progress_tx);
 
         while {
             synced_tip = self.sync_transactions(&mut changelog)?;
-            self.watcher.do_imports(&self.rpc, /*rescan=*/ true)?
+            self.watcher
+                .do_imports(&self.rpc, /*rescan=*/ true, Some(&import_progress_tx))?
         } { /* do while */ }
 
         self.sync_mempool(/*force_refresh=*/ true)?;
+        // the history was already re-fetched following the imports
+        self.pending_resync = false;
+        self.window_extended_at = None;
+        self.window_rescanned_at = None;
 
         let stats = self.store.stats();
         info!(
@@ -69,47 +322,149 @@ impl Indexer {
             stats.transaction_count,
             stats.scripthash_count,
         );
+        self.update_recent_chain(synced_tip)?;
         self.tip = Some(synced_tip);
+        self.write_wal()?;
         Ok(())
     }
 
     // initiate a regular sync to catch up with updates and import new addresses (no rescan)
     pub fn sync(&mut self) -> Result<Vec<IndexChange>> {
-        let mut changelog = Changelog::new(self.tip.is_some());
+        // indexing was paused for a rescan initiated through bwt, keep the index untouched until
+        // it gets resumed and only track the scan progress
+        if self.sync_paused {
+            self.scan_progress = self.rpc.wallet_scan_progress()?;
+            return Ok(vec![]);
+        }
 
-        // detect reorgs and sync the whole history from scratch when they happen
-        // XXX the reorg test is racey
-        if let Some(BlockId(tip_height, ref tip_hash)) = self.tip {
-            let best_chain_hash = self.rpc.get_block_hash(tip_height as u64)?;
-            if best_chain_hash != *tip_hash {
+        // a rescan could get triggered in bitcoind while we're running (e.g. a manual `rescanblockchain`),
+        // pause indexing until it finishes to avoid indexing a partial history
+        if let Some(progress) = self.rpc.wallet_scan_progress()? {
+            if self.scan_progress.is_none() {
                 warn!(
-                    "reorg detected, block height {} was {} and now is {}. fetching history from scratch...",
-                    tip_height, tip_hash, best_chain_hash
+                    "bitcoind wallet is scanning [done {:.1}%], pausing indexing until it finishes...",
+                    progress * 100.0
                 );
+            }
+            self.scan_progress = Some(progress);
+            return Ok(vec![]);
+        } else if self.scan_progress.take().is_some() {
+            // the rescan may have found history in blocks we already synced past
+            info!("bitcoind wallet scan finished, fetching history from scratch...");
+            self.tip = None;
+        }
 
-                // notify clients about the reorg, but don't collect additional events (apart from
-                // ChainTip, added below)
-                changelog.push(|| IndexChange::Reorg(tip_height, *tip_hash, best_chain_hash));
-                changelog.track = false;
+        let mut changelog = Changelog::new(self.tip.is_some());
+        let mut reorged_txs = vec![];
 
-                // XXX start syncing from N blocks backs instead of from the beginning?
-                self.tip = None;
+        // detect reorgs by comparing the hashes of the blocks we synced against the active chain,
+        // then re-process the transactions that were confirmed in the orphaned blocks
+        // XXX the reorg test is racey
+        if let Some(BlockId(tip_height, tip_hash)) = self.tip {
+            let best_height = self.rpc.get_block_count()? as u32;
+            let rpc = &self.rpc;
+            // the active chain may end below our tip, if it got reorged onto a shorter chain
+            let active_hash = |height: u32| -> Result<Option<BlockHash>> {
+                Ok((height <= best_height)
+                    .do_then(|| rpc.get_block_hash(height as u64))
+                    .transpose()?)
+            };
+
+            let tip_active_hash = active_hash(tip_height)?;
+            if tip_active_hash != Some(tip_hash) {
+                let new_hash = match tip_active_hash {
+                    Some(blockhash) => blockhash,
+                    None => self.rpc.get_best_block_hash()?,
+                };
+                changelog.push(|| IndexChange::Reorg(tip_height, tip_hash, new_hash));
+
+                // the reorg depth can only be determined within the recent chain. refuse to rewrite
+                // the index if it's deeper than the configured maximum, or sync the whole history
+                // from scratch if no maximum is configured.
+                let max_depth = self.max_reorg_depth.unwrap_or(RECENT_CHAIN_LEN as u32);
+                match check_reorg_depth(&self.recent_chain, tip_height, max_depth, active_hash) {
+                    Ok(depth) => {
+                        let fork_height = tip_height - depth;
+                        warn!(
+                            "reorg detected, block height {} was {} and now is {}. re-processing transactions since the fork point at height {}...",
+                            tip_height, tip_hash, new_hash, fork_height
+                        );
+                        // listsinceblock is still given the orphaned tip, which has bitcoind look
+                        // for the transactions since the fork point and report the removed ones
+                        reorged_txs = self
+                            .store
+                            .get_history_since(fork_height + 1)
+                            .into_iter()
+                            .filter(|txhist| txhist.status.is_confirmed())
+                            .map(|txhist| txhist.txid)
+                            .collect();
+                    }
+                    Err(e) if self.max_reorg_depth.is_some() => return Err(e),
+                    Err(e) if !matches!(e.downcast_ref(), Some(BwtError::ReorgTooDeep(..))) => {
+                        return Err(e)
+                    }
+                    Err(_) => {
+                        warn!(
+                            "reorg detected, block height {} was {} and now is {}. fetching history from scratch...",
+                            tip_height, tip_hash, new_hash
+                        );
+                        // notify clients about the reorg, but don't collect additional events
+                        // (apart from ChainTip, added below)
+                        changelog.track = false;
+                        self.tip = None;
+                    }
+                }
             }
         }
 
-        let synced_tip = self.sync_transactions(&mut changelog)?;
+        let mut synced_tip = self.sync_transactions(&mut changelog)?;
+        for txid in reorged_txs {
+            self.reprocess_reorged_tx(&txid, synced_tip.0, &mut changelog)?;
+        }
+
+        // the blocks since the watched window got extended were rescanned, re-sync them to pick up
+        // payments to the newly imported addresses
+        if let Some(since_height) = self.window_rescanned_at {
+            let since_block = self
+                .rpc
+                .get_block_hash(since_height.saturating_sub(1) as u64)?;
+            synced_tip = self.sync_transactions_since(Some(&since_block), &mut changelog)?;
+            self.window_rescanned_at = None;
+        }
+
+        // using addresses near the edge of the watched window extends it with another batch of
+        // imports, without a rescan. other payments to the newly imported addresses could've been
+        // made in the blocks since, which get rescanned outside of the sync run (see
+        // `start_window_rescan()`) and re-synced on the next one. for unconfirmed usage, this is
+        // deferred until the next block is synced. this repeats for as long as the newly imported
+        // addresses are found to be in use too.
+        self.watcher
+            .do_imports(&self.rpc, /*rescan=*/ false, None)?;
+
         let tip_updated = self.tip != Some(synced_tip);
         self.sync_mempool(/*force_refresh=*/ tip_updated)?;
-        self.watcher.do_imports(&self.rpc, /*rescan=*/ false)?;
 
         let mut changelog = changelog.into_vec();
 
         if tip_updated {
             info!("synced up to height {}", synced_tip.0);
             changelog.push(IndexChange::ChainTip(synced_tip));
+            self.update_recent_chain(synced_tip)?;
             self.tip = Some(synced_tip);
         }
 
+        // out-of-window spends were found and their missing history got imported with a rescan,
+        // sync the whole history from scratch on the next run to index it
+        if self.pending_resync {
+            warn!(
+                "out-of-window spends detected, fetching history from scratch on the next sync..."
+            );
+            self.tip = None;
+            self.pending_resync = false;
+        }
+
+        self.write_wal()?;
+
         if !changelog.is_empty() && log_enabled!(log::Level::Debug) {
             debug!(
                 "sync resulted in {} index changelog events",
@@ -123,8 +478,49 @@ impl Indexer {
         Ok(changelog)
     }
 
+    // walk back from the new tip until reaching a block that's already part of the recent chain,
+    // dropping blocks that got reorged out along the way
+    fn update_recent_chain(&mut self, tip: BlockId) -> Result<()> {
+        let BlockId(mut height, mut blockhash) = tip;
+        let mut new_blocks = vec![];
+        loop {
+            while let Some(last) = self.recent_chain.back() {
+                if last.0 > height || (last.0 == height && last.1 != blockhash) {
+                    self.recent_chain.pop_back();
+                } else {
+                    break;
+                }
+            }
+            if self.recent_chain.back() == Some(&BlockId(height, blockhash)) {
+                break;
+            }
+            new_blocks.push(BlockId(height, blockhash));
+            if height == 0 || new_blocks.len() == RECENT_CHAIN_LEN {
+                // the new blocks are not connected to the ones we have (if any), start over
+                self.recent_chain.clear();
+                break;
+            }
+            blockhash = self.rpc.get_block_header(&blockhash)?.prev_blockhash;
+            height -= 1;
+        }
+
+        self.recent_chain.extend(new_blocks.into_iter().rev());
+        while self.recent_chain.len() > RECENT_CHAIN_LEN {
+            self.recent_chain.pop_front();
+        }
+        Ok(())
+    }
+
     fn sync_transactions(&mut self, changelog: &mut Changelog) -> Result<BlockId> {
-        let since_block = self.tip.as_ref().map(|tip| &tip.1);
+        let since_block = self.tip.as_ref().map(|tip| tip.1);
+        self.sync_transactions_since(since_block.as_ref(), changelog)
+    }
+
+    fn sync_transactions_since(
+        &mut self,
+        since_block: Option<&BlockHash>,
+        changelog: &mut Changelog,
+    ) -> Result<BlockId> {
         let tip_height = self.rpc.get_block_count()? as u32;
         let tip_hash = self.rpc.get_block_hash(tip_height as u64)?;
 
@@ -132,32 +528,116 @@ impl Indexer {
             .rpc
             .list_since_block(since_block, Some(1), Some(true), Some(true))?;
 
+        // unconfirmed transactions that are no longer in the node's mempool (expired or evicted)
+        // are removed from the index. bitcoind keeps listing them as unconfirmed wallet transactions,
+        // so they are skipped below until they make it back into the mempool. only the unconfirmed
+        // transactions are looked up, to avoid fetching the whole mempool on every sync.
+        let listed_confirmed: HashSet<Txid> = result
+            .transactions
+            .iter()
+            .filter(|ltx| ltx.info.confirmations != 0)
+            .map(|ltx| ltx.info.txid)
+            .collect();
+        let unconfirmed: HashSet<Txid> = result
+            .transactions
+            .iter()
+            .filter(|ltx| ltx.info.confirmations == 0)
+            .map(|ltx| ltx.info.txid)
+            .chain(self.store.mempool().keys().copied())
+            .filter(|txid| !listed_confirmed.contains(txid))
+            .collect();
+        let mut not_in_mempool = HashSet::new();
+        for txid in unconfirmed {
+            if !self.is_in_node_mempool(&txid)? {
+                not_in_mempool.insert(txid);
+            }
+        }
+
         // Workaround for https://github.com/bitcoin/bitcoin/issues/19338,
         // listsinceblock is not atomic and could provide inconsistent results.
-        if result.lastblock != tip_hash {
+        // the tip is checked again after checking the mempool, so that transactions that got
+        // confirmed in the meanwhile are not mistaken for dropped ones.
+        if result.lastblock != tip_hash || self.rpc.get_best_block_hash()? != tip_hash {
             warn!("chain tip moved while reading listsinceblock, retrying...");
-            return self.sync_transactions(changelog);
+            return self.sync_transactions_since(since_block, changelog);
         }
 
         for ltx in result.removed {
             // transactions that were re-added in the active chain will appear in `removed`
             // but with a positive confirmation count, ignore these.
             if ltx.info.confirmations < 0 {
-                let tx_deleted = self.store.purge_tx(&ltx.info.txid);
+                let tx_deleted = self.purge_tx(&ltx.info.txid);
                 if tx_deleted {
                     changelog.push(|| IndexChange::TransactionReplaced(ltx.info.txid));
                 }
             }
         }
 
+        // transactions marked as abandoned (via `abandontransaction`) are removed from the index,
+        // which releases the wallet outputs they spent. only the "send" entries carry the abandoned
+        // flag, so they are collected first to skip over the "receive" entries of the same tx too.
+        let abandoned: HashSet<Txid> = result
+            .transactions
+            .iter()
+            .filter(|ltx| ltx.detail.abandoned == Some(true))
+            .map(|ltx| ltx.info.txid)
+            .collect();
+
+        for txid in &abandoned {
+            let tx_deleted = self.purge_tx(txid);
+            if tx_deleted {
+                changelog.push(|| IndexChange::TransactionAbandoned(*txid));
+            }
+        }
+
+        let dropped: HashSet<Txid> = not_in_mempool
+            .into_iter()
+            .filter(|txid| !abandoned.contains(txid))
+            .collect();
+
+        // keep the inputs spent by dropped transactions, to link them to their replacements (RBF)
+        let dropped_spends: HashMap<OutPoint, Txid> = dropped
+            .iter()
+            .filter_map(|txid| Some((txid, self.store.get_tx_entry(txid)?)))
+            .flat_map(|(txid, tx_entry)| {
+                tx_entry
+                    .spending
+                    .values()
+                    .map(move |SpendingInfo(_, prevout, _)| (*prevout, *txid))
+            })
+            .collect();
+
+        for txid in &dropped {
+            let tx_deleted = self.purge_tx(txid);
+            if tx_deleted {
+                changelog.push(|| IndexChange::TransactionDropped(*txid));
+            }
+        }
+
+        // immature coinbase outputs are re-collected when syncing from scratch, and
+        // dropped once they mature
+        if since_block.is_none() {
+            self.immature_coinbase.clear();
+        }
+        self.immature_coinbase
+            .retain(|_, coinbase| coinbase.is_immature(tip_height));
+
         let mut buffered_outgoing: HashMap<Txid, i32> = HashMap::new();
+        let mut new_incoming: HashSet<Txid> = HashSet::new();
 
         for ltx in result.transactions {
+            if abandoned.contains(&ltx.info.txid) || dropped.contains(&ltx.info.txid) {
+                continue;
+            }
+
             // "listtransactions"/"listsinceblock" in fact lists transaction outputs and not transactions.
             // for "receive" txs, it returns one entry per wallet-owned output in the tx.
             // for "send" txs, it returns one entry for every output in the tx, owned or not.
             match ltx.detail.category {
                 TxCategory::Receive => {
+                    if self.store.get_tx_entry(&ltx.info.txid).is_none() {
+                        new_incoming.insert(ltx.info.txid);
+                    }
                     // incoming txouts are easy: bitcoind tells us the associated
                     // address and label, giving us all the information we need in
                     // order to save the txo to the index.
@@ -171,14 +651,25 @@ impl Indexer {
                     // the prevouts are guarranted to be indexed.
                     buffered_outgoing.insert(ltx.info.txid, ltx.info.confirmations);
                 }
-                // ignore mining-related transactions
-                TxCategory::Generate | TxCategory::Immature | TxCategory::Orphan => (),
+                TxCategory::Immature => self.process_immature_coinbase(ltx, tip_height),
+                // ignore other mining-related transactions
+                TxCategory::Generate | TxCategory::Orphan => (),
             };
         }
 
+        // newly seen incoming transactions are checked for inputs spending from wallet addresses
+        // beyond the imported range. outgoing transactions are checked in process_outgoing_tx().
+        for txid in new_incoming {
+            if !buffered_outgoing.contains_key(&txid) {
+                self.check_incoming_tx(&txid)
+                    .map_err(|err| warn!("failed checking incoming payment: {:?}", err))
+                    .ok();
+            }
+        }
+
         for (txid, confirmations) in buffered_outgoing {
             let status = TxStatus::from_confirmations(confirmations, tip_height);
-            self.process_outgoing_tx(txid, status, changelog)
+            self.process_outgoing_tx(txid, status, &dropped_spends, changelog)
                 .map_err(|err| warn!("failed processing outgoing payment: {:?}", err))
                 .ok();
         }
@@ -186,9 +677,65 @@ impl Indexer {
         Ok(BlockId(tip_height, tip_hash))
     }
 
+    fn is_in_node_mempool(&self, txid: &Txid) -> Result<bool> {
+        match self.rpc.get_mempool_entry(txid) {
+            Ok(_) => Ok(true),
+            // "Transaction not in mempool"
+            Err(bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)))
+                if e.code == -5 =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // update the status of a transaction that was confirmed in a block that got reorged out,
+    // in case listsinceblock didn't already. transactions that were not re-confirmed either get
+    // back to the mempool, or get removed if they were dropped from it or conflict with the new chain.
+    fn reprocess_reorged_tx(
+        &mut self,
+        txid: &Txid,
+        tip_height: u32,
+        changelog: &mut Changelog,
+    ) -> Result<()> {
+        if self.store.get_tx_entry(txid).is_none() {
+            // already removed while syncing
+            return Ok(());
+        }
+        let confirmations = self
+            .rpc
+            .get_transaction(txid, Some(true))?
+            .info
+            .confirmations;
+        match TxStatus::from_confirmations(confirmations, tip_height) {
+            TxStatus::Conflicted => {
+                if self.purge_tx(txid) {
+                    changelog.push(|| IndexChange::TransactionReplaced(*txid));
+                }
+            }
+            TxStatus::Unconfirmed if self.rpc.get_mempool_entry(txid).is_err() => {
+                if self.purge_tx(txid) {
+                    changelog.push(|| IndexChange::TransactionDropped(*txid));
+                }
+            }
+            status => self.upsert_tx(txid, status, changelog),
+        }
+        Ok(())
+    }
+
     // upsert the transaction while collecting the changelog
     fn upsert_tx(&mut self, txid: &Txid, status: TxStatus, changelog: &mut Changelog) {
+        let old_status = self.store.get_tx_status(txid);
         let tx_updated = self.store.upsert_tx(txid, status);
+        if let Some(old_status) = old_status.filter(|old_status| *old_status != status) {
+            let tx_entry = self.store.get_tx_entry(txid).unwrap();
+            for vout in tx_entry.funding.keys() {
+                let outpoint = OutPoint::new(*txid, *vout);
+                self.utxo_counter
+                    .update_status(&outpoint, old_status, status);
+            }
+        }
         if tx_updated {
             changelog.with(|changelog| {
                 let tx_entry = self.store.get_tx_entry(txid).unwrap();
@@ -197,6 +744,59 @@ impl Indexer {
         }
     }
 
+    // remove the transaction from the index, releasing the wallet outputs it spent
+    fn purge_tx(&mut self, txid: &Txid) -> bool {
+        let tx_entry = some_or_ret!(self.store.get_tx_entry(txid).cloned(), false);
+        self.store.purge_tx(txid);
+
+        for vout in tx_entry.funding.keys() {
+            self.utxo_counter
+                .remove(&OutPoint::new(*txid, *vout), tx_entry.status);
+        }
+        for SpendingInfo(scripthash, prevout, _) in tx_entry.spending.values() {
+            self.utxo_counter.spent.remove(prevout);
+            // the funding transaction may have been purged too
+            if let (Some(KeyOrigin::Descriptor(checksum, _)), Some(status)) = (
+                self.store
+                    .get_script_info(scripthash)
+                    .map(|info| info.origin),
+                self.store.get_tx_status(&prevout.txid),
+            ) {
+                self.utxo_counter.add(*prevout, checksum, status);
+            }
+        }
+        true
+    }
+
+    // index the funded output, counting it as unspent if it belongs to a wallet
+    fn index_tx_output_funding(
+        &mut self,
+        txid: &Txid,
+        vout: u32,
+        funding_info: FundingInfo,
+        origin: &KeyOrigin,
+        status: TxStatus,
+    ) -> bool {
+        let txo_added = self.store.index_tx_output_funding(txid, vout, funding_info);
+        if let (true, KeyOrigin::Descriptor(checksum, _)) = (txo_added, origin) {
+            let outpoint = OutPoint::new(*txid, vout);
+            // the spending transaction may have been indexed first
+            if !self.utxo_counter.spent.contains(&outpoint) {
+                self.utxo_counter.add(outpoint, checksum.clone(), status);
+            }
+        }
+        txo_added
+    }
+
+    // index the spent wallet outputs, uncounting them as unspent
+    fn index_tx_inputs_spending(&mut self, txid: &Txid, spending: HashMap<u32, SpendingInfo>) {
+        for SpendingInfo(_, prevout, _) in spending.values() {
+            let funding_status = self.store.get_tx_status(&prevout.txid).unwrap();
+            self.utxo_counter.spend(*prevout, funding_status);
+        }
+        self.store.index_tx_inputs_spending(txid, spending);
+    }
+
     fn process_incoming_txo(
         &mut self,
         ltx: ListTransactionResult,
@@ -224,15 +824,47 @@ impl Indexer {
 
         self.store.index_scripthash(&scripthash, &origin, &address);
 
-        let txo_added =
-            self.store
-                .index_tx_output_funding(&txid, vout, FundingInfo(scripthash, amount));
+        let txo_added = self.index_tx_output_funding(
+            &txid,
+            vout,
+            FundingInfo(scripthash, amount),
+            &origin,
+            status,
+        );
 
         if txo_added {
             changelog.push(|| {
                 IndexChange::TxoFunded(OutPoint::new(txid, vout), scripthash, amount, status)
             });
-            self.watcher.mark_funded(&origin);
+            if self.watcher.mark_funded(&origin) {
+                let height = match status {
+                    TxStatus::Confirmed(height) => height,
+                    _ => tip_height + 1,
+                };
+                self.window_extended_at = Some(
+                    self.window_extended_at
+                        .map_or(height, |current| current.min(height)),
+                );
+            }
+        }
+    }
+
+    // immature coinbase outputs are not indexed as wallet history, but are kept track of so that
+    // they can be accounted for in the wallet balances
+    fn process_immature_coinbase(&mut self, ltx: ListTransactionResult, tip_height: u32) {
+        let label = ltx.detail.label.as_ref();
+        let origin = label.and_then(|l| KeyOrigin::from_label(l));
+        if let (Some(KeyOrigin::Descriptor(checksum, _)), true) =
+            (origin, ltx.info.confirmations > 0)
+        {
+            let outpoint = OutPoint::new(ltx.info.txid, ltx.detail.vout);
+            let coinbase = ImmatureCoinbase {
+                checksum,
+                amount: ltx.detail.amount.to_unsigned().unwrap().as_sat(), // safe to unwrap, coinbase outputs cannot have negative amounts
+                block_height: tip_height + 1 - ltx.info.confirmations as u32,
+            };
+            trace!("processing immature coinbase {} {:?}", outpoint, coinbase);
+            self.immature_coinbase.insert(outpoint, coinbase);
         }
     }
 
@@ -240,6 +872,7 @@ impl Indexer {
         &mut self,
         txid: Txid,
         status: TxStatus,
+        dropped_spends: &HashMap<OutPoint, Txid>,
         changelog: &mut Changelog,
     ) -> Result<()> {
         trace!("processing outgoing tx txid={} status={:?}", txid, status);
@@ -257,6 +890,10 @@ impl Indexer {
         // TODO use batch rpc to fetch all buffered outgoing txs
         let tx = self.rpc.get_transaction(&txid, Some(true))?.transaction()?;
 
+        if self.check_out_of_window_inputs(&txid, &tx) {
+            return Ok(());
+        }
+
         let spending: HashMap<u32, SpendingInfo> = tx
             .input
             .iter()
@@ -279,13 +916,60 @@ impl Indexer {
             .collect();
 
         if !spending.is_empty() {
+            // a transaction spending the same wallet inputs as a dropped one is its replacement
+            let replaced_txid = spending
+                .values()
+                .find_map(|SpendingInfo(_, prevout, _)| dropped_spends.get(prevout))
+                .copied();
+
             self.upsert_tx(&txid, status, changelog);
-            self.store.index_tx_inputs_spending(&txid, spending);
+            self.index_tx_inputs_spending(&txid, spending);
+
+            if let Some(replaced_txid) = replaced_txid {
+                info!("transaction {} replaced {}", txid, replaced_txid);
+                self.store.index_tx_replacement(&txid, replaced_txid);
+            }
         }
 
         Ok(())
     }
 
+    fn check_incoming_tx(&mut self, txid: &Txid) -> Result<()> {
+        let tx = self.rpc.get_transaction(txid, Some(true))?.transaction()?;
+        self.check_out_of_window_inputs(txid, &tx);
+        Ok(())
+    }
+
+    /// Detect inputs spending from wallet addresses beyond the imported range (when the gap limit is
+    /// too small), extend the watched window to include them and purge the transaction, so that it
+    /// gets re-indexed once the missing history is imported. Returns whether any were found.
+    fn check_out_of_window_inputs(&mut self, txid: &Txid, tx: &Transaction) -> bool {
+        let store = &self.store;
+        let watcher = &mut self.watcher;
+
+        let origins: Vec<KeyOrigin> = tx
+            .input
+            .iter()
+            .filter(|input| store.lookup_txo_fund(&input.previous_output).is_none())
+            .filter_map(|input| {
+                input_script_candidates(input)
+                    .iter()
+                    .find_map(|script| watcher.find_out_of_window(script))
+            })
+            .collect();
+
+        if origins.is_empty() {
+            return false;
+        }
+
+        for origin in &origins {
+            self.watcher.extend_window(origin);
+        }
+        self.purge_tx(txid);
+        self.pending_resync = true;
+        true
+    }
+
     /// Update missing/outdated mempool entries for unconfirmed mempool transactions (or all mempool
     /// entries when force_refresh is set, during the initial sync or following a chain tip update)
     fn sync_mempool(&mut self, force_refresh: bool) -> Result<()> {
@@ -324,6 +1008,225 @@ impl Indexer {
     pub fn track_address(&mut self, address: Address, rescan_since: RescanSince) -> Result<()> {
         self.watcher.track_address(address, rescan_since)
     }
+
+    /// Filter out events belonging to transactions with a net wallet amount below `min_amount`.
+    /// Events that are not associated with a specific transaction are always kept.
+    pub fn filter_min_amount(
+        &self,
+        changelog: &[IndexChange],
+        min_amount: u64,
+    ) -> Vec<IndexChange> {
+        changelog
+            .iter()
+            .filter(|change| {
+                change.txid().map_or(true, |txid| {
+                    self.store.get_tx_entry(txid).map_or(true, |tx_entry| {
+                        tx_entry.balance_change().abs() >= min_amount as i64
+                    })
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Hold back confirmation events until the confirming block is buried under `delay` more
+    /// blocks, releasing them in a later call. Events of confirmations that got reorged out in
+    /// the meanwhile are discarded.
+    pub fn delay_confirmations(
+        &mut self,
+        changelog: Vec<IndexChange>,
+        delay: u32,
+    ) -> Vec<IndexChange> {
+        let tip_height = self.tip.as_ref().map_or(0, |tip| tip.0);
+        let store = &self.store;
+        let is_settled = |change: &IndexChange| match change.confirmed_height() {
+            Some(height) => tip_height >= height.saturating_add(delay),
+            None => true,
+        };
+        let still_confirmed = |change: &IndexChange| {
+            let (txid, height) = (change.txid().unwrap(), change.confirmed_height().unwrap());
+            store.get_tx_status(txid) == Some(TxStatus::Confirmed(height))
+        };
+
+        let (mut released, delayed): (Vec<_>, Vec<_>) = self
+            .delayed_confirms
+            .drain(..)
+            .filter(still_confirmed)
+            .partition(is_settled);
+        let (settled, new_delayed): (Vec<_>, Vec<_>) = changelog.into_iter().partition(is_settled);
+
+        if !new_delayed.is_empty() {
+            debug!("delaying {} confirmation events", new_delayed.len());
+        }
+        self.delayed_confirms = delayed;
+        self.delayed_confirms.extend(new_delayed);
+
+        released.extend(settled);
+        released
+    }
+
+    /// Pause real-time indexing until `resume_sync()` is called. Sync runs while paused are
+    /// no-ops, so the index keeps its current state.
+    pub fn pause_sync(&mut self) {
+        self.sync_paused = true;
+    }
+
+    /// Resume real-time indexing paused by `pause_sync()`. The history gets re-synced from scratch
+    /// on the next sync run, to pick up anything found in blocks we already synced past.
+    pub fn resume_sync(&mut self) {
+        self.sync_paused = false;
+        self.scan_progress = None;
+        self.tip = None;
+    }
+
+    pub fn is_sync_paused(&self) -> bool {
+        self.sync_paused
+    }
+
+    /// Get the height to rescan the blocks from following a watched window extension found during
+    /// the last sync run (if any), pausing real-time indexing until `finish_window_rescan()` is called.
+    pub fn start_window_rescan(&mut self) -> Option<u32> {
+        let tip_height = self.tip?.0;
+        if self.sync_paused {
+            return None;
+        }
+        let since_height = self
+            .window_extended_at
+            .filter(|height| *height <= tip_height)?;
+        self.window_extended_at = None;
+        self.pause_sync();
+        Some(since_height)
+    }
+
+    /// Resume real-time indexing paused by `start_window_rescan()`. The rescanned blocks get
+    /// re-synced on the next sync run, or rescanned again if the rescan failed.
+    pub fn finish_window_rescan(&mut self, since_height: u32, rescanned: bool) {
+        self.sync_paused = false;
+        self.scan_progress = None;
+        if rescanned {
+            self.window_rescanned_at = Some(since_height);
+        } else {
+            self.window_extended_at = Some(since_height);
+        }
+    }
+
+    /// Increase the gap limit for all wallets (see `WalletWatcher::set_gap_limit`) and import the
+    /// newly added indexes right away, reporting `Progress::Import` updates to `progress_tx`.
+    /// Returns whether any addresses were imported.
+    pub fn set_gap_limit(
+        &mut self,
+        gap_limit: u32,
+        progress_tx: Option<&mpsc::Sender<Progress>>,
+    ) -> Result<bool> {
+        self.watcher.set_gap_limit(gap_limit);
+        self.watcher
+            .do_imports(&self.rpc, /*rescan=*/ false, progress_tx)
+    }
+
+    /// Switch the tracked wallets to `network` (see `WalletWatcher::set_network`). The index is
+    /// cleared and gets re-synced from scratch, once the re-derived addresses are re-imported.
+    pub fn set_network(&mut self, network: Network) -> Result<()> {
+        self.watcher.set_network(network)?;
+        self.reset_wal_state()?;
+        self.immature_coinbase.clear();
+        self.recent_chain.clear();
+        self.delayed_confirms.clear();
+        // the history of the re-imported addresses is only available once the rescan completes
+        self.pending_resync = true;
+        Ok(())
+    }
+
+    /// Stop updating the index when encountering reorgs deeper than `max_depth` blocks.
+    /// The depth is measured against the recent chain, so values above 100 are treated as 100.
+    pub fn set_max_reorg_depth(&mut self, max_depth: Option<u32>) {
+        self.max_reorg_depth = max_depth.map(|depth| depth.min(RECENT_CHAIN_LEN as u32));
+    }
+}
+
+impl UtxoCounter {
+    // rebuild the counts from scratch, used once the store gets restored from the wal
+    fn from_store(store: &MemoryStore) -> Self {
+        let mut counter = UtxoCounter {
+            spent: store
+                .tx_entries()
+                .flat_map(|(_, tx_entry)| tx_entry.spending.values())
+                .map(|SpendingInfo(_, prevout, _)| *prevout)
+                .collect(),
+            ..Default::default()
+        };
+        for (txid, tx_entry) in store.tx_entries() {
+            for (vout, FundingInfo(scripthash, _)) in &tx_entry.funding {
+                let outpoint = OutPoint::new(*txid, *vout);
+                if let (false, Some(KeyOrigin::Descriptor(checksum, _))) = (
+                    counter.spent.contains(&outpoint),
+                    store.get_script_info(scripthash).map(|info| info.origin),
+                ) {
+                    counter.add(outpoint, checksum, tx_entry.status);
+                }
+            }
+        }
+        counter
+    }
+
+    fn add(&mut self, outpoint: OutPoint, checksum: Checksum, status: TxStatus) {
+        if self.unspent.insert(outpoint, checksum.clone()).is_none() {
+            self.adjust(&checksum, status, true);
+        }
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint, status: TxStatus) {
+        if let Some(checksum) = self.unspent.remove(outpoint) {
+            self.adjust(&checksum, status, false);
+        }
+    }
+
+    fn spend(&mut self, prevout: OutPoint, funding_status: TxStatus) {
+        self.spent.insert(prevout);
+        self.remove(&prevout, funding_status);
+    }
+
+    fn update_status(&mut self, outpoint: &OutPoint, old_status: TxStatus, new_status: TxStatus) {
+        if let Some(checksum) = self.unspent.get(outpoint).cloned() {
+            self.adjust(&checksum, old_status, false);
+            self.adjust(&checksum, new_status, true);
+        }
+    }
+
+    // conflicted outputs are not counted
+    fn adjust(&mut self, checksum: &Checksum, status: TxStatus, increment: bool) {
+        let counts = self.counts.entry(checksum.clone()).or_default();
+        let count = match status {
+            TxStatus::Confirmed(_) => &mut counts.0,
+            TxStatus::Unconfirmed => &mut counts.1,
+            TxStatus::Conflicted => return,
+        };
+        if increment {
+            *count += 1;
+        } else {
+            *count -= 1;
+        }
+    }
+}
+
+// walk back the recent chain to find the number of blocks below `tip_height` that got reorged out,
+// using `get_active_hash` to lookup the active chain. fails if the fork point is deeper than
+// `max_depth` (or not part of the recent chain at all).
+fn check_reorg_depth(
+    recent_chain: &VecDeque<BlockId>,
+    tip_height: u32,
+    max_depth: u32,
+    get_active_hash: impl Fn(u32) -> Result<Option<BlockHash>>,
+) -> Result<u32> {
+    for BlockId(height, blockhash) in recent_chain.iter().rev() {
+        let depth = tip_height.saturating_sub(*height);
+        if depth > max_depth {
+            break;
+        }
+        if get_active_hash(*height)? == Some(*blockhash) {
+            return Ok(depth);
+        }
+    }
+    bail!(BwtError::ReorgTooDeep(max_depth, tip_height))
 }
 
 #[derive(Clone, Serialize, Debug)]
@@ -334,6 +1237,8 @@ pub enum IndexChange {
 
     Transaction(Txid, TxStatus),
     TransactionReplaced(Txid),
+    TransactionAbandoned(Txid),
+    TransactionDropped(Txid),
 
     TxoFunded(OutPoint, ScriptHash, u64, TxStatus),
     TxoSpent(InPoint, ScriptHash, OutPoint, TxStatus),
@@ -375,6 +1280,27 @@ impl IndexChange {
         }
     }
 
+    // the transaction that triggered the update, if any. `TransactionReplaced`, `TransactionAbandoned`
+    // and `TransactionDropped` are not included, as the removed transaction is no longer available in the index.
+    pub fn txid(&self) -> Option<&Txid> {
+        match self {
+            IndexChange::Transaction(ref txid, _) => Some(txid),
+            IndexChange::TxoFunded(ref outpoint, ..) => Some(&outpoint.txid),
+            IndexChange::TxoSpent(ref inpoint, ..) => Some(&inpoint.txid),
+            _ => None,
+        }
+    }
+
+    // the confirmation height of transaction events, if confirmed
+    pub fn confirmed_height(&self) -> Option<u32> {
+        match self {
+            IndexChange::Transaction(_, TxStatus::Confirmed(height))
+            | IndexChange::TxoFunded(.., TxStatus::Confirmed(height))
+            | IndexChange::TxoSpent(.., TxStatus::Confirmed(height)) => Some(*height),
+            _ => None,
+        }
+    }
+
     // the outpoint created or spent, if any
     pub fn outpoint(&self) -> Option<&OutPoint> {
         match self {
@@ -391,6 +1317,8 @@ impl IndexChange {
 
             Self::Transaction(..) => "Transaction",
             Self::TransactionReplaced(..) => "TransactionReplaced",
+            Self::TransactionAbandoned(..) => "TransactionAbandoned",
+            Self::TransactionDropped(..) => "TransactionDropped",
 
             Self::TxoFunded(..) => "TxoFunded",
             Self::TxoSpent(..) => "TxoSpent",
@@ -423,17 +1351,380 @@ impl fmt::Display for IndexChange {
     }
 }
 
-// Spawn a thread to poll getwalletinfo, log progress and send progress updates via mpsc
+fn immature_coinbase_wal_entry(
+    immature_coinbase: &HashMap<OutPoint, ImmatureCoinbase>,
+) -> WalEntry {
+    WalEntry::ImmatureCoinbase(
+        immature_coinbase
+            .iter()
+            .map(|(outpoint, coinbase)| {
+                let checksum = coinbase.checksum.to_string();
+                (*outpoint, checksum, coinbase.amount, coinbase.block_height)
+            })
+            .collect(),
+    )
+}
+
+fn frozen_wal_entries(frozen_txos: &HashSet<OutPoint>) -> Vec<WalEntry> {
+    frozen_txos
+        .iter()
+        .map(|outpoint| WalEntry::FrozenTxo(*outpoint, true))
+        .collect()
+}
+
+// Spawn a thread to forward the import progress updates sent to the returned Sender, then poll
+// getwalletinfo once the first set of imports is submitted to log progress and send progress updates
 fn spawn_send_progress_thread(
     rpc: Arc<RpcClient>,
     progress_tx: Option<mpsc::Sender<Progress>>,
-) -> thread::JoinHandle<()> {
+) -> mpsc::Sender<Progress> {
+    let (import_progress_tx, import_progress_rx) = mpsc::channel();
+
     thread::spawn(move || {
-        // allow some time for the indexer to start the first set of imports
+        // the channel gets closed without any updates if there was nothing to import
+        for progress in import_progress_rx.iter() {
+            if let Some(progress_tx) = &progress_tx {
+                if progress_tx.send(progress).is_err() {
+                    return;
+                }
+            }
+            if let Progress::Import { done, total } = progress {
+                if done == total {
+                    break;
+                }
+            }
+        }
+
+        // allow some time for the rescan to start following the imports
         thread::sleep(time::Duration::from_millis(250));
 
         if let Err(e) = rpc.wait_wallet_scan(progress_tx) {
             warn!("getwalletinfo failed: {:?}", e);
         }
-    })
+    });
+
+    import_progress_tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::hex::FromHex;
+    use bitcoincore_rpc::Auth;
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // An indexer with no tracked wallets, connected to an unreachable bitcoind
+    fn test_indexer() -> Indexer {
+        let rpc = RpcClient::new("http://127.0.0.1:1".into(), Auth::None).unwrap();
+        let watcher = WalletWatcher::new(bitcoin::Network::Bitcoin, vec![], vec![]).unwrap();
+        Indexer::new(Arc::new(rpc), watcher)
+    }
+
+    // A mock bitcoind RPC server that reports an ongoing wallet scan while `scanning` is set.
+    // Calls to other methods fail.
+    fn scanning_rpc(scanning: Arc<AtomicBool>) -> RpcClient {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            // the rpc client opens a new connection for every request
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    let header = line.to_lowercase();
+                    if let Some(len) = header.strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+
+                let response = if request["method"] == "getwalletinfo" {
+                    let scanning = if scanning.load(Ordering::SeqCst) {
+                        json!({ "duration": 30, "progress": 0.25 })
+                    } else {
+                        json!(false)
+                    };
+                    json!({ "id": request["id"], "error": null, "result": {
+                        "walletname": "bwt", "walletversion": 169900, "balance": 0.0,
+                        "unconfirmed_balance": 0.0, "immature_balance": 0.0, "txcount": 0,
+                        "keypoololdest": 0, "keypoolsize": 0, "keypoolsize_hd_internal": 0,
+                        "paytxfee": 0.0, "private_keys_enabled": false, "avoid_reuse": false,
+                        "scanning": scanning,
+                    } })
+                } else {
+                    json!({ "id": request["id"], "result": null, "error": {
+                        "code": -32601, "message": "Method not found",
+                    } })
+                };
+                let response = response.to_string();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+
+        RpcClient::new(url, Auth::None).unwrap()
+    }
+
+    #[test]
+    fn test_filter_min_amount() {
+        let mut indexer = test_indexer();
+
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        let status = TxStatus::Confirmed(100);
+        let small_txid = Txid::from_hex(&"01".repeat(32)).unwrap();
+        let large_txid = Txid::from_hex(&"02".repeat(32)).unwrap();
+
+        let store = &mut indexer.store;
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        for (txid, amount) in &[(small_txid, 500), (large_txid, 50_000)] {
+            store.upsert_tx(txid, status);
+            store.index_tx_output_funding(txid, 0, FundingInfo(scripthash, *amount));
+        }
+
+        let changelog: Vec<IndexChange> = [small_txid, large_txid]
+            .iter()
+            .flat_map(|txid| IndexChange::from_tx(txid, indexer.store.get_tx_entry(txid).unwrap()))
+            .chain(Some(IndexChange::ChainTip(BlockId(
+                100,
+                BlockHash::default(),
+            ))))
+            .collect();
+
+        let filtered = indexer.filter_min_amount(&changelog, 10_000);
+        assert_eq!(filtered.len(), 3);
+        assert!(filtered
+            .iter()
+            .all(|change| change.txid().map_or(true, |txid| *txid == large_txid)));
+    }
+
+    #[test]
+    fn test_delay_confirmations() {
+        let mut indexer = test_indexer();
+
+        let reorged_txid = Txid::from_hex(&"01".repeat(32)).unwrap();
+        let stable_txid = Txid::from_hex(&"02".repeat(32)).unwrap();
+        let tip = |height| IndexChange::ChainTip(BlockId(height, BlockHash::default()));
+
+        // both transactions get confirmed at height 100
+        indexer.tip = Some(BlockId(100, BlockHash::default()));
+        let changelog = vec![
+            IndexChange::Transaction(reorged_txid, TxStatus::Confirmed(100)),
+            IndexChange::Transaction(stable_txid, TxStatus::Confirmed(100)),
+            tip(100),
+        ];
+        for txid in &[reorged_txid, stable_txid] {
+            indexer.store.upsert_tx(txid, TxStatus::Confirmed(100));
+        }
+        let released = indexer.delay_confirmations(changelog, 2);
+        assert!(released.iter().all(|c| c.confirmed_height().is_none()));
+        assert_eq!(released.len(), 1);
+
+        // the first transaction gets reorged out and returns to the mempool before the delay is up
+        indexer.tip = Some(BlockId(101, BlockHash::default()));
+        indexer
+            .store
+            .upsert_tx(&reorged_txid, TxStatus::Unconfirmed);
+        let released = indexer.delay_confirmations(vec![tip(101)], 2);
+        assert!(released.iter().all(|c| c.confirmed_height().is_none()));
+
+        indexer.tip = Some(BlockId(102, BlockHash::default()));
+        let released = indexer.delay_confirmations(vec![tip(102)], 2);
+        let confirmed: Vec<_> = released.iter().filter_map(IndexChange::txid).collect();
+        assert_eq!(confirmed, vec![&stable_txid]);
+        assert!(indexer.delayed_confirms.is_empty());
+
+        // a delay reaching past the maximum height never settles
+        let changelog = vec![IndexChange::Transaction(
+            stable_txid,
+            TxStatus::Confirmed(102),
+        )];
+        indexer
+            .store
+            .upsert_tx(&stable_txid, TxStatus::Confirmed(102));
+        assert!(indexer.delay_confirmations(changelog, u32::MAX).is_empty());
+        assert_eq!(indexer.delayed_confirms.len(), 1);
+    }
+
+    #[test]
+    fn test_utxo_counts() {
+        let mut indexer = test_indexer();
+        let mut changelog = Changelog::new(false);
+
+        let checksum: Checksum = "qqqqqqqq".parse().unwrap();
+        let origin = KeyOrigin::Descriptor(checksum.clone(), 0);
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        let funding_txid = Txid::from_hex(&"01".repeat(32)).unwrap();
+        let spending_txid = Txid::from_hex(&"02".repeat(32)).unwrap();
+        let outpoint = |vout| OutPoint::new(funding_txid, vout);
+
+        let counts = |indexer: &Indexer| {
+            // the incremental counts should always match a full rebuild from the store
+            let rebuilt = UtxoCounter::from_store(&indexer.store);
+            let rebuilt = rebuilt.counts.get(&checksum).copied().unwrap_or_default();
+            assert_eq!(indexer.utxo_counts(&checksum), rebuilt);
+            rebuilt
+        };
+
+        // two outputs get funded in the mempool
+        indexer.upsert_tx(&funding_txid, TxStatus::Unconfirmed, &mut changelog);
+        indexer
+            .store
+            .index_scripthash(&scripthash, &origin, &address);
+        for vout in 0..2 {
+            let funding_info = FundingInfo(scripthash, 10_000);
+            let status = TxStatus::Unconfirmed;
+            indexer.index_tx_output_funding(&funding_txid, vout, funding_info, &origin, status);
+        }
+        assert_eq!(counts(&indexer), (0, 2));
+
+        // then get confirmed
+        indexer.upsert_tx(&funding_txid, TxStatus::Confirmed(100), &mut changelog);
+        assert_eq!(counts(&indexer), (2, 0));
+
+        // one of them gets spent
+        let spending = SpendingInfo(scripthash, outpoint(0), 10_000);
+        indexer.upsert_tx(&spending_txid, TxStatus::Unconfirmed, &mut changelog);
+        #[cfg(feature = "track-spends")]
+        indexer
+            .store
+            .index_txo_spend(outpoint(0), InPoint::new(spending_txid, 0));
+        indexer.index_tx_inputs_spending(&spending_txid, vec![(0, spending)].into_iter().collect());
+        assert_eq!(counts(&indexer), (1, 0));
+
+        // the spending transaction gets dropped, releasing the spent output
+        indexer.purge_tx(&spending_txid);
+        assert_eq!(counts(&indexer), (2, 0));
+
+        // a reorg returns the funding transaction to the mempool, then it gets replaced
+        indexer.upsert_tx(&funding_txid, TxStatus::Unconfirmed, &mut changelog);
+        assert_eq!(counts(&indexer), (0, 2));
+        indexer.purge_tx(&funding_txid);
+        assert_eq!(counts(&indexer), (0, 0));
+        assert!(indexer.utxo_counter.unspent.is_empty());
+    }
+
+    #[test]
+    fn test_window_rescan() {
+        let mut indexer = test_indexer();
+        indexer.tip = Some(BlockId(100, BlockHash::default()));
+
+        // unconfirmed usage is deferred until the next block is synced
+        indexer.window_extended_at = Some(101);
+        assert_eq!(indexer.start_window_rescan(), None);
+        assert!(!indexer.is_sync_paused());
+
+        // a failed rescan is retried
+        indexer.window_extended_at = Some(90);
+        assert_eq!(indexer.start_window_rescan(), Some(90));
+        assert!(indexer.is_sync_paused());
+        indexer.finish_window_rescan(90, false);
+        assert!(!indexer.is_sync_paused());
+        assert_eq!(indexer.start_window_rescan(), Some(90));
+
+        // a successful one gets re-synced on the next sync run
+        indexer.finish_window_rescan(90, true);
+        assert_eq!(indexer.window_rescanned_at, Some(90));
+        assert_eq!(indexer.start_window_rescan(), None);
+    }
+
+    #[test]
+    fn test_sync_paused_while_scanning() {
+        let scanning = Arc::new(AtomicBool::new(true));
+        let mut indexer = test_indexer();
+        indexer.rpc = Arc::new(scanning_rpc(scanning.clone()));
+        let tip = BlockId(100, BlockHash::default());
+        indexer.tip = Some(tip);
+
+        // while paused, sync runs only track the scan progress and keep the index untouched
+        indexer.pause_sync();
+        assert!(indexer.sync().unwrap().is_empty());
+        assert_eq!(indexer.scan_progress, Some(0.25));
+        assert_eq!(indexer.tip, Some(tip));
+
+        // and keep doing so after the scan finishes, until indexing gets resumed
+        scanning.store(false, Ordering::SeqCst);
+        assert!(indexer.sync().unwrap().is_empty());
+        assert_eq!(indexer.scan_progress, None);
+        assert_eq!(indexer.tip, Some(tip));
+
+        // resuming has the history fetched from scratch
+        indexer.resume_sync();
+        assert!(!indexer.is_sync_paused());
+        assert_eq!(indexer.tip, None);
+
+        // a scan initiated outside of bwt pauses indexing too
+        indexer.tip = Some(tip);
+        scanning.store(true, Ordering::SeqCst);
+        assert!(indexer.sync().unwrap().is_empty());
+        assert_eq!(indexer.scan_progress, Some(0.25));
+        assert_eq!(indexer.tip, Some(tip));
+
+        // once it finishes, the history is fetched from scratch. the mock does not serve the
+        // other calls needed to complete the sync.
+        scanning.store(false, Ordering::SeqCst);
+        assert!(indexer.sync().is_err());
+        assert_eq!(indexer.scan_progress, None);
+        assert_eq!(indexer.tip, None);
+    }
+
+    #[test]
+    fn test_check_reorg_depth() {
+        let hash = |height: u32, fork: u8| {
+            BlockHash::from_hex(&format!("{:02x}{:08x}", fork, height).repeat(32)[..64]).unwrap()
+        };
+        // the recent chain we synced up to height 100
+        let recent_chain: VecDeque<BlockId> = (50..=100)
+            .map(|height| BlockId(height, hash(height, 0)))
+            .collect();
+        // the active chain forked off after height `fork_height`
+        let active_chain = |fork_height: u32| {
+            move |height: u32| Ok(Some(hash(height, (height > fork_height) as u8)))
+        };
+
+        assert_eq!(
+            check_reorg_depth(&recent_chain, 100, 10, active_chain(97)).unwrap(),
+            3
+        );
+        assert_eq!(
+            check_reorg_depth(&recent_chain, 100, 10, active_chain(90)).unwrap(),
+            10
+        );
+
+        // deeper than the cap
+        let err = check_reorg_depth(&recent_chain, 100, 10, active_chain(89)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BwtError>(),
+            Some(BwtError::ReorgTooDeep(10, 100))
+        ));
+
+        // deeper than the recent chain
+        let err = check_reorg_depth(&recent_chain, 100, 100, active_chain(20)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BwtError>(),
+            Some(BwtError::ReorgTooDeep(100, 100))
+        ));
+
+        // reorged onto a shorter chain that ends at height 98, forking off after height 95
+        let shorter_chain =
+            |height: u32| Ok((height <= 98).do_then(|| hash(height, (height > 95) as u8)));
+        assert_eq!(
+            check_reorg_depth(&recent_chain, 100, 10, shorter_chain).unwrap(),
+            5
+        );
+    }
 }
@@ -2,11 +2,13 @@
 mod ffi {
     use std::ffi::{CStr, CString};
     use std::os::raw::c_char;
-    use std::sync::{mpsc, Once};
+    use std::sync::{mpsc, Arc, Once};
     use std::thread;
 
+    use bitcoin::Network;
+
     use crate::util::bitcoincore_ext::Progress;
-    use crate::{App, Config, Result};
+    use crate::{App, Config, Query, Result};
 
     const OK: i32 = 0;
     const ERR: i32 = -1;
@@ -14,7 +16,11 @@ mod ffi {
     type Callback = extern "C" fn(*const c_char, f32, u32, *const c_char);
 
     #[repr(C)]
-    pub struct ShutdownHandler(mpsc::SyncSender<()>);
+    pub struct ShutdownHandler {
+        shutdown_tx: mpsc::SyncSender<()>,
+        sync_tx: mpsc::Sender<()>,
+        query: Arc<Query>,
+    }
 
     static INIT_LOGGER: Once = Once::new();
 
@@ -55,9 +61,14 @@ mod ffi {
 
             notify(callback_fn, "ready", 1.0, 0, "");
 
+            let (query, sync_tx) = (app.query(), app.sync_sender());
             let shutdown_tx = app.sync_background();
 
-            Ok(ShutdownHandler(shutdown_tx))
+            Ok(ShutdownHandler {
+                shutdown_tx,
+                sync_tx,
+                query,
+            })
         };
 
         match start() {
@@ -83,6 +94,34 @@ mod ffi {
         OK
     }
 
+    /// Switch the tracked wallets to another network, to recover from a misconfigured network.
+    /// Accepts the shutdown handler returned by bwt_start() and the network name.
+    #[no_mangle]
+    pub extern "C" fn bwt_set_network(
+        shutdown_ptr: *const ShutdownHandler,
+        network: *const c_char,
+    ) -> i32 {
+        assert!(!shutdown_ptr.is_null());
+        let handler = unsafe { &*shutdown_ptr };
+        let network = unsafe { CStr::from_ptr(network) }.to_str().unwrap();
+
+        let set_network = || -> Result<()> {
+            let network: Network = network.parse()?;
+            handler.query.set_network(network)?;
+            // sync right away to re-import the addresses
+            handler.sync_tx.send(()).ok();
+            Ok(())
+        };
+
+        match set_network() {
+            Ok(()) => OK,
+            Err(e) => {
+                warn!("{:?}", e);
+                ERR
+            }
+        }
+    }
+
     fn notify(callback_fn: Callback, msg_type: &str, progress: f32, detail_n: u64, detail_s: &str) {
         let msg_type = CString::new(msg_type).unwrap();
         let detail_s = CString::new(detail_s).unwrap();
@@ -108,6 +147,10 @@ mod ffi {
                 Ok(Progress::Scan { progress_n, eta }) => {
                     notify(callback_fn, "progress:scan", progress_n, eta, "")
                 }
+                Ok(Progress::Import { done, total }) => {
+                    let progress_n = done as f32 / total as f32;
+                    notify(callback_fn, "progress:import", progress_n, total as u64, "")
+                }
                 Err(mpsc::RecvError) => break,
             }
         })
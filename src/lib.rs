@@ -25,10 +25,13 @@ pub mod interface;
 pub mod query;
 pub mod store;
 pub mod types;
+pub mod wal;
 pub mod wallet;
 
 #[cfg(unix)]
 pub mod listener;
+#[cfg(unix)]
+pub mod unix_notifier;
 
 #[cfg(feature = "electrum")]
 pub mod electrum;
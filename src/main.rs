@@ -8,8 +8,14 @@ fn main() -> Result<()> {
 
     config.setup_logger();
 
+    let one_shot = config.one_shot;
     let app = App::boot(config, None)?;
-    app.sync(None);
+
+    if one_shot {
+        println!("{}", app.query().dump_index());
+    } else {
+        app.sync(None);
+    }
 
     Ok(())
 }
@@ -1,42 +1,71 @@
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, BTreeSet, HashMap, HashSet};
 use std::process::Command;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 use serde_json::Value;
 
-use bitcoin::{BlockHash, BlockHeader, Network, OutPoint, Transaction, Txid};
-use bitcoin_hashes::hex::FromHex;
-use bitcoincore_rpc::{json as rpcjson, Client as RpcClient, RpcApi};
-
-use crate::error::{BwtError, Context, OptionExt, Result};
-use crate::indexer::{IndexChange, Indexer};
-use crate::store::{FundingInfo, HistoryEntry, ScriptInfo, SpendingInfo, TxEntry};
-use crate::types::{BlockId, MempoolEntry, ScriptHash, TxStatus};
-use crate::util::descriptor::Checksum;
-use crate::util::{make_fee_histogram, BoolThen};
-use crate::wallet::{KeyOrigin, Wallet};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::address::AddressType;
+use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{
+    Address, Amount, BlockHash, BlockHeader, Network, OutPoint, PublicKey, Script, Transaction,
+    Txid,
+};
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d;
+use bitcoincore_rpc::{self as rpc, json as rpcjson, RpcApi};
+
+use crate::error::{BwtError, Context, Error, OptionExt, Result};
+use crate::indexer::{ImmatureCoinbase, IndexChange, Indexer};
+use crate::store::{FundingInfo, HistoryEntry, IndexerSnapshot, ScriptInfo, SpendingInfo, TxEntry};
+use crate::types::{
+    serialize_amount, serialize_opt_amount, BlockId, ConfStatus, ImmatureCoinbaseMode,
+    MempoolEntry, ScriptHash, TxStatus, COINBASE_MATURITY,
+};
+use crate::util::descriptor::{Checksum, ExtendedDescriptor};
+use crate::util::xpub::Bip32Origin;
+use crate::util::{
+    bitcoincore_ext::{chain_network, is_timeout_error, GetMempoolInfoResult, Progress},
+    create_merkle_branch_and_root, make_fee_histogram_with_width, BoolThen, FeeHistogramMode,
+    RpcApiExt, RpcClient, VsizeField, VSIZE_BIN_WIDTH,
+};
+use crate::wallet::{is_coinjoin_like, is_likely_change, KeyOrigin, Wallet, WalletWatcher};
 
 #[cfg(feature = "track-spends")]
 use crate::types::InPoint;
 
 const FEE_HISTOGRAM_TTL: Duration = Duration::from_secs(120);
 const FEE_ESTIMATES_TTL: Duration = Duration::from_secs(120);
+const MEMPOOL_INFO_TTL: Duration = Duration::from_secs(10);
+const COINJOIN_CACHE_SIZE: usize = 10_000;
 
 pub struct Query {
-    config: QueryConfig,
+    config: RwLock<QueryConfig>,
     rpc: Arc<RpcClient>,
     indexer: Arc<RwLock<Indexer>>,
 
     cached_relayfee: RwLock<Option<f64>>,
     cached_histogram: RwLock<Option<(FeeHistogram, Instant)>>,
     cached_estimates: RwLock<HashMap<u16, (Option<f64>, Instant)>>,
+    cached_mempool_info: RwLock<Option<(GetMempoolInfoResult, Instant)>>,
+    cached_coinjoin_like: RwLock<HashMap<Txid, bool>>,
 }
 
 pub struct QueryConfig {
     pub network: Network,
     pub broadcast_cmd: Option<String>,
+    pub fee_histogram_mode: FeeHistogramMode,
+    pub mempool_vsize_field: VsizeField,
+    pub settled_confs: u32,
+    pub change_heuristics: bool,
+    pub immature_coinbase: ImmatureCoinbaseMode,
+    pub coinbase_min_confs: u32,
+    pub balance_exclude_mempool: bool,
 }
 
 type FeeHistogram = Vec<(f32, u32)>;
@@ -44,12 +73,14 @@ type FeeHistogram = Vec<(f32, u32)>;
 impl Query {
     pub fn new(config: QueryConfig, rpc: Arc<RpcClient>, indexer: Arc<RwLock<Indexer>>) -> Self {
         Query {
-            config,
+            config: RwLock::new(config),
             rpc,
             indexer,
             cached_relayfee: RwLock::new(None),
             cached_histogram: RwLock::new(None),
             cached_estimates: RwLock::new(HashMap::new()),
+            cached_mempool_info: RwLock::new(None),
+            cached_coinjoin_like: RwLock::new(HashMap::new()),
         }
     }
 
@@ -57,6 +88,10 @@ impl Query {
         &self.rpc
     }
 
+    fn config(&self) -> RwLockReadGuard<'_, QueryConfig> {
+        self.config.read().unwrap()
+    }
+
     pub fn debug_index(&self) -> String {
         format!("{:#?}", self.indexer.read().unwrap().store())
     }
@@ -65,6 +100,11 @@ impl Query {
         json!(self.indexer.read().unwrap().store())
     }
 
+    /// Take a snapshot of the index state, which can later be diffed against another snapshot
+    pub fn snapshot_index(&self) -> IndexerSnapshot {
+        self.indexer.read().unwrap().store().snapshot()
+    }
+
     //
     // Blocks
     //
@@ -75,6 +115,160 @@ impl Query {
         Ok(BlockId(tip_height, tip_hash))
     }
 
+    pub fn recent_chain(&self) -> Vec<(u32, BlockHash)> {
+        self.indexer.read().unwrap().recent_chain()
+    }
+
+    /// Get the progress of the bitcoind wallet rescan that indexing is currently paused for, if any
+    pub fn wallet_scan_progress(&self) -> Option<f32> {
+        self.indexer.read().unwrap().scan_progress()
+    }
+
+    /// Rescan the bitcoind wallet history from `start_height` (or from the genesis block), with
+    /// real-time indexing paused until the rescan completes to avoid indexing a partial history.
+    /// The index keeps serving its pre-rescan state in the meanwhile, and gets re-synced from
+    /// scratch on the next sync run. Blocks until the rescan finishes, reporting its progress to
+    /// `progress_tx` (if provided).
+    pub fn rescan(
+        &self,
+        start_height: Option<u32>,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> Result<()> {
+        {
+            let mut indexer = self.indexer.write().unwrap();
+            ensure!(!indexer.is_sync_paused(), BwtError::RescanInProgress);
+            indexer.pause_sync();
+        }
+        info!(
+            "rescanning the bitcoind wallet from height {}, pausing indexing until it finishes...",
+            start_height.unwrap_or(0)
+        );
+
+        let result = self.rescan_blockchain(start_height, progress_tx);
+
+        // resume even if the rescan failed, some of the history may have been found before it did
+        self.indexer.write().unwrap().resume_sync();
+
+        result.context("rescan failed")?;
+        info!("rescan completed, fetching history from scratch...");
+        Ok(())
+    }
+
+    // Run `rescanblockchain` and wait for bitcoind to finish scanning, reporting its progress to
+    // `progress_tx`. Long rescans outlive the RPC read timeout, in which case bitcoind keeps
+    // scanning in the background and its completion is detected using `getwalletinfo`.
+    fn rescan_blockchain(
+        &self,
+        start_height: Option<u32>,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> Result<()> {
+        let rpc = self.rpc.clone();
+        let (result_tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let result = rpc.rescan_blockchain(start_height.map(|height| height as usize), None);
+            result_tx.send(result).ok();
+        });
+
+        // short rescans complete right away, longer ones are tracked until bitcoind reports them as done
+        let result = match result_rx.recv_timeout(Duration::from_millis(250)) {
+            Ok(result) => result,
+            Err(_) => {
+                if let Err(e) = self.rpc.wait_wallet_scan(progress_tx.clone()) {
+                    warn!("getwalletinfo failed: {:?}", e);
+                }
+                result_rx.recv()?
+            }
+        };
+
+        match result {
+            Ok((rescan_start, rescan_stop)) => {
+                info!(
+                    "rescanned blocks {}-{}",
+                    rescan_start,
+                    rescan_stop.map_or("tip".into(), |height| height.to_string())
+                );
+            }
+            Err(e) if is_timeout_error(&e) => {
+                debug!("rescanblockchain timed out, waiting for bitcoind to finish scanning");
+                self.rpc.wait_wallet_scan(progress_tx)?;
+            }
+            Err(e) => bail!(e),
+        }
+        Ok(())
+    }
+
+    /// Rescan the blocks since the watched window got extended during the last sync run, if needed
+    /// (see `Indexer::start_window_rescan()`). Like `rescan()`, real-time indexing is paused while
+    /// the rescan runs, without holding the indexer lock. Returns whether a rescan took place.
+    pub fn rescan_extended_window(&self) -> Result<bool> {
+        let since_height = some_or_ret!(
+            self.indexer.write().unwrap().start_window_rescan(),
+            Ok(false)
+        );
+        info!(
+            "watched addresses window extended, rescanning blocks since height {}...",
+            since_height
+        );
+
+        let result = self.rescan_blockchain(Some(since_height), None);
+
+        self.indexer
+            .write()
+            .unwrap()
+            .finish_window_rescan(since_height, result.is_ok());
+        result.context("rescan failed")?;
+        Ok(true)
+    }
+
+    /// Check whether a rescan initiated through `rescan()` is currently in progress
+    pub fn is_rescanning(&self) -> bool {
+        self.indexer.read().unwrap().is_sync_paused()
+    }
+
+    /// Increase the gap limit of all wallets at runtime, importing only the newly added indexes
+    /// and reporting `Progress::Import` updates to `progress_tx` (if provided). The new addresses
+    /// are imported without a rescan, use `rescan()` to look up their past history.
+    pub fn set_gap_limit(
+        &self,
+        gap_limit: u32,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> Result<()> {
+        let mut indexer = self.indexer.write().unwrap();
+        ensure!(!indexer.is_sync_paused(), BwtError::RescanInProgress);
+        if indexer.set_gap_limit(gap_limit, progress_tx.as_ref())? {
+            info!("imported the addresses added by the gap limit increase");
+        }
+        Ok(())
+    }
+
+    /// Switch the tracked wallets to `network`, to recover from a misconfigured network without
+    /// restarting (see `WalletWatcher::set_network`). The network has to match bitcoind's chain.
+    /// The re-derived addresses get re-imported with a rescan and the index is re-synced from
+    /// scratch on the next sync run.
+    pub fn set_network(&self, network: Network) -> Result<()> {
+        let bcinfo = self.rpc.get_blockchain_info()?;
+        if let Some(chain_network) = chain_network(&bcinfo.chain) {
+            ensure!(
+                chain_network == network,
+                "cannot switch to {}, bitcoind is running on {}",
+                network,
+                bcinfo.chain
+            );
+        }
+
+        let mut indexer = self.indexer.write().unwrap();
+        ensure!(!indexer.is_sync_paused(), BwtError::RescanInProgress);
+        indexer.set_network(network)?;
+        self.config.write().unwrap().network = network;
+        // the estimates may have been taken from the relay fee for regtest
+        self.cached_estimates.write().unwrap().clear();
+        info!(
+            "switched to {}, re-importing the wallet addresses...",
+            network
+        );
+        Ok(())
+    }
+
     pub fn get_tip_height(&self) -> Result<u32> {
         Ok(self.rpc.get_block_count()? as u32)
     }
@@ -115,7 +309,7 @@ impl Query {
 
         // regtest typically doesn't have fee estimates, just use the relay fee instead.
         // this stops electrum from complanining about unavailable dynamic fees.
-        if self.config.network == Network::Regtest {
+        if self.config().network == Network::Regtest {
             return self.relay_fee().map(Some);
         }
 
@@ -152,11 +346,60 @@ impl Query {
             FEE_HISTOGRAM_TTL,
             || -> Result<FeeHistogram> {
                 let mempool_entries = self.get_raw_mempool()?;
-                Ok(make_fee_histogram(mempool_entries))
+                let config = self.config();
+                Ok(make_fee_histogram_with_width(
+                    mempool_entries,
+                    config.fee_histogram_mode,
+                    config.mempool_vsize_field,
+                    VSIZE_BIN_WIDTH,
+                ))
             }
         );
     }
 
+    /// Get the average of the median feerates (in sat/vB) of the `num_blocks` most recent blocks,
+    /// as a baseline of what's actually getting confirmed. Returns `None` if the block stats are
+    /// unavailable due to pruning.
+    pub fn recent_block_median_feerate(&self, num_blocks: u32) -> Result<Option<f32>> {
+        Ok(self.rpc.recent_block_median_feerate(num_blocks)?)
+    }
+
+    pub fn mempool_info(&self) -> Result<GetMempoolInfoResult> {
+        ttl_cache!(
+            self.cached_mempool_info,
+            MEMPOOL_INFO_TTL,
+            || -> Result<GetMempoolInfoResult> { Ok(self.rpc.get_mempool_info()?) }
+        );
+    }
+
+    /// Get the minimum feerate (in sat/vB) needed to get into the mempool at all
+    ///
+    /// This is normally bitcoind's `mempoolminfee`. When the mempool is full, the lowest feerate
+    /// present in the fee histogram is used if higher, as lower feerate transactions would not
+    /// survive getting evicted.
+    pub fn mempool_min_feerate(&self) -> Result<f32> {
+        let mempool_info = self.mempool_info()?;
+        let min_feerate = mempoolminfee_feerate(&mempool_info);
+
+        if mempool_info.usage >= mempool_info.max_mempool {
+            if let Some((histogram_floor, _)) = self.fee_histogram()?.last() {
+                return Ok(min_feerate.max(*histogram_floor));
+            }
+        }
+        Ok(min_feerate)
+    }
+
+    /// Get the mempool fee histogram along with the feerate floors that transactions must meet
+    /// to get relayed, so that clients can pick a feerate without querying bitcoind directly
+    pub fn mempool_fees(&self) -> Result<MempoolFees> {
+        Ok(MempoolFees {
+            histogram: self.fee_histogram()?,
+            relay_fee: self.relay_fee()?,
+            mempool_min_fee: mempoolminfee_feerate(&self.mempool_info()?),
+            min_feerate: self.mempool_min_feerate()?,
+        })
+    }
+
     pub fn get_mempool_entry<T>(&self, txid: &Txid) -> Option<MempoolEntry> {
         let indexer = self.indexer.read().unwrap();
         indexer.store().get_mempool_entry(txid).cloned()
@@ -184,11 +427,64 @@ impl Query {
         // If that fails, try with getrawtransaction. This requires txindex (except for mempool transactions)
         // and is incompatible with pruning, but works for non-wallet transactions too.
         else {
-            let tx_hex = self.rpc.get_raw_transaction_hex(txid, None)?;
-            Ok(Vec::from_hex(&tx_hex)?)
+            match self.rpc.get_raw_transaction_hex(txid, None) {
+                Ok(tx_hex) => Ok(Vec::from_hex(&tx_hex)?),
+                // "No such mempool or blockchain transaction"
+                Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(e))) if e.code == -5 => {
+                    bail!(BwtError::TxNotFound(*txid))
+                }
+                Err(e) => Err(e.into()),
+            }
         }
     }
 
+    pub fn get_tx_hex(&self, txid: &Txid) -> Result<String> {
+        Ok(self.get_tx_raw(txid)?.to_hex())
+    }
+
+    /// Get the feerate paid by the transaction (in sat/vB), or `None` if it cannot be determined
+    ///
+    /// Unconfirmed transactions use their mempool entry. For confirmed ones, the prevouts are resolved
+    /// from the index or from bitcoind, which requires txindex for non-wallet transactions.
+    pub fn get_tx_feerate(&self, txid: &Txid) -> Result<Option<f32>> {
+        if let Some(feerate) = self.with_mempool_entry(txid, |entry| entry.own_feerate()) {
+            return Ok(Some(feerate as f32));
+        }
+
+        let tx: Transaction = deserialize(&self.get_tx_raw(txid)?)?;
+        if tx.is_coin_base() {
+            return Ok(None);
+        }
+
+        let mut input_value = 0;
+        for input in &tx.input {
+            let prevout = input.previous_output;
+            let indexed_amount = {
+                let indexer = self.indexer.read().unwrap();
+                indexer.store().lookup_txo_fund(&prevout).map(|f| f.1)
+            };
+            let amount = match indexed_amount {
+                Some(amount) => Some(amount),
+                None => match self.get_tx_raw(&prevout.txid) {
+                    Ok(prev_raw) => deserialize::<Transaction>(&prev_raw)?
+                        .output
+                        .get(prevout.vout as usize)
+                        .map(|txout| txout.value),
+                    Err(e) if matches!(e.downcast_ref(), Some(BwtError::TxNotFound(_))) => None,
+                    Err(e) => bail!(e),
+                },
+            };
+            input_value += some_or_ret!(amount, Ok(None));
+        }
+
+        let output_value: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        let fee = input_value
+            .checked_sub(output_value)
+            .or_err("invalid fee")?;
+        let vsize = (tx.weight() as f32 / 4.0).ceil();
+        Ok(Some(fee as f32 / vsize))
+    }
+
     pub fn get_tx_json(&self, txid: &Txid) -> Result<Value> {
         let blockhash = self.find_tx_blockhash(txid)?;
 
@@ -198,13 +494,52 @@ impl Query {
         )?)
     }
 
+    /// Get the merkle inclusion proof of a confirmed wallet transaction, built from the txids of its
+    /// block. The block txids are kept in the `cache`, which may be reused to get multiple proofs
+    /// (for example, for the duration of a request batch).
+    pub fn get_tx_merkle_proof(
+        &self,
+        txid: &Txid,
+        cache: &mut BlockTxidsCache,
+    ) -> Result<MerkleProof> {
+        let status = {
+            let indexer = self.indexer.read().unwrap();
+            indexer.store().get_tx_status(txid)
+        };
+        let block_height = match status {
+            Some(TxStatus::Confirmed(height)) => height,
+            Some(_) => bail!(BwtError::TxNotConfirmed(*txid)),
+            None => bail!(BwtError::TxNotFound(*txid)),
+        };
+
+        let blockhash = self.get_block_hash(block_height)?;
+        let block_txids = match cache.0.entry(blockhash) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.get_block_txids(&blockhash)?),
+        };
+        // could happen if the index is lagging behind a reorg
+        let pos = block_txids
+            .iter()
+            .position(|block_txid| block_txid == txid)
+            .or_err("missing tx")?;
+
+        let hashes = block_txids.iter().map(|&txid| txid.into()).collect();
+        let (merkle, _root) = create_merkle_branch_and_root(hashes, pos);
+        Ok(MerkleProof {
+            block_height,
+            merkle,
+            pos,
+        })
+    }
+
     pub fn get_tx_proof(&self, txid: &Txid) -> Result<Vec<u8>> {
         let blockhash = self.find_tx_blockhash(txid)?;
         Ok(self.rpc.get_tx_out_proof(&[*txid], blockhash.as_ref())?)
     }
 
     pub fn broadcast(&self, tx_hex: &str) -> Result<Txid> {
-        if let Some(broadcast_cmd) = &self.config.broadcast_cmd {
+        let broadcast_cmd = self.config().broadcast_cmd.clone();
+        if let Some(broadcast_cmd) = broadcast_cmd {
             // deserialize the tx to ensure validity (preventing potential code injection) and to determine the txid
             let tx: Transaction = bitcoin::consensus::deserialize(&Vec::from_hex(tx_hex)?)?;
             let cmd = broadcast_cmd.replacen("{tx_hex}", tx_hex, 1);
@@ -240,7 +575,138 @@ impl Query {
     }
 
     pub fn get_tx_detail(&self, txid: &Txid) -> Option<TxDetail> {
-        TxDetail::make(txid, &self)
+        TxDetail::make(txid, self)
+    }
+
+    /// Like get_tx_detail(), with the `coinjoin_like` heuristic and the `feerate` filled in. This
+    /// requires fetching the raw transactions from bitcoind, and is only done for single transaction lookups.
+    pub fn get_tx_detail_full(&self, txid: &Txid) -> Option<TxDetail> {
+        let mut tx_detail = TxDetail::make(txid, self)?;
+        tx_detail.coinjoin_like = self
+            .is_coinjoin_like(txid)
+            .map_err(|err| warn!("failed checking for coinjoin {}: {:?}", txid, err))
+            .ok();
+        tx_detail.feerate = self
+            .get_tx_feerate(txid)
+            .map_err(|err| warn!("failed getting the feerate of {}: {:?}", txid, err))
+            .ok()
+            .flatten();
+        Some(tx_detail)
+    }
+
+    /// Get the wallet outputs spent by the transaction as a list of (prevout, amount, derivation index)
+    /// tuples, ordered by input index. Standalone addresses have no derivation index and are not included.
+    /// Returns `None` if the transaction is not a wallet transaction.
+    pub fn wallet_inputs_of(&self, txid: &Txid) -> Option<Vec<(OutPoint, u64, u32)>> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let tx_entry = store.get_tx_entry(txid)?;
+
+        let mut spending: Vec<_> = tx_entry.spending.iter().collect();
+        spending.sort_by_key(|(vin, _)| **vin);
+
+        Some(
+            spending
+                .into_iter()
+                .filter_map(|(_, SpendingInfo(scripthash, prevout, amount))| {
+                    match store.get_script_info(scripthash)?.origin {
+                        KeyOrigin::Descriptor(_, index) => Some((*prevout, *amount, index)),
+                        KeyOrigin::Standalone => None,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Map each output of the transaction to the tracked wallet that owns it, as a `(checksum,
+    /// derivation index, is_change)` tuple, or `None` for external outputs. The transaction doesn't
+    /// have to be a wallet transaction. Addresses are recognized if they have indexed history or
+    /// fall within the imported range of a wallet. Standalone addresses have no derivation index and are considered external.
+    pub fn classify_outputs(&self, tx: &Transaction) -> Vec<Option<(Checksum, u32, bool)>> {
+        let indexer = self.indexer.read().unwrap();
+        let (store, watcher) = (indexer.store(), indexer.watcher());
+
+        tx.output
+            .iter()
+            .map(|txout| {
+                let scripthash = ScriptHash::from(&txout.script_pubkey);
+                let origin = match store.get_script_info(&scripthash) {
+                    Some(script_info) => script_info.origin,
+                    // addresses without history are looked up in the wallets' imported range
+                    None => watcher.find_imported(&txout.script_pubkey)?,
+                };
+                match origin {
+                    KeyOrigin::Descriptor(checksum, index) => {
+                        let is_change = watcher.get(&checksum)?.is_internal_chain();
+                        Some((checksum, index, is_change))
+                    }
+                    KeyOrigin::Standalone => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Heuristically determine whether the transaction looks like a coinjoin, see
+    /// `wallet::is_coinjoin_like`. The result is cached, as it only depends on the transaction itself.
+    /// The cache is cleared once it reaches `COINJOIN_CACHE_SIZE` entries.
+    pub fn is_coinjoin_like(&self, txid: &Txid) -> Result<bool> {
+        if let Some(coinjoin_like) = self.cached_coinjoin_like.read().unwrap().get(txid) {
+            return Ok(*coinjoin_like);
+        }
+        let tx: Transaction = deserialize(&self.get_tx_raw(txid)?)?;
+        let coinjoin_like = is_coinjoin_like(&tx);
+
+        let mut cache = self.cached_coinjoin_like.write().unwrap();
+        if cache.len() >= COINJOIN_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(*txid, coinjoin_like);
+        Ok(coinjoin_like)
+    }
+
+    /// Determine whether the transaction is incoming, outgoing or a self-transfer from the
+    /// perspective of the wallet. The external and internal chains of the same account are
+    /// treated as a single wallet. Returns `None` if the transaction does not affect the wallet.
+    ///
+    /// With `change_heuristics` enabled, outgoing transactions where all the outputs not owned by
+    /// the wallet are likely to be change (see `wallet::is_likely_change`) are considered self-transfers.
+    pub fn classify_tx(&self, txid: &Txid, checksum: &Checksum) -> Result<Option<TxDirection>> {
+        let (wallet, owns_inputs, owned_vouts) = {
+            let indexer = self.indexer.read().unwrap();
+            let (store, watcher) = (indexer.store(), indexer.watcher());
+            let wallet = some_or_ret!(watcher.get(checksum), Ok(None));
+            let tx_entry = some_or_ret!(store.get_tx_entry(txid), Ok(None));
+            let is_owned = |scripthash: &ScriptHash| owned_by_account(&indexer, wallet, scripthash);
+
+            let owns_inputs = tx_entry
+                .spending
+                .values()
+                .any(|SpendingInfo(scripthash, ..)| is_owned(scripthash));
+            let owned_vouts = tx_entry
+                .funding
+                .iter()
+                .filter(|(_, FundingInfo(scripthash, _))| is_owned(scripthash))
+                .map(|(vout, _)| *vout)
+                .collect::<Vec<u32>>();
+            (wallet.clone(), owns_inputs, owned_vouts)
+        };
+
+        Ok(if !owns_inputs {
+            (!owned_vouts.is_empty()).do_then(|| TxDirection::Incoming)
+        } else {
+            // compare against the full list of outputs to detect payments to external scripts
+            let tx: Transaction = bitcoin::consensus::deserialize(&self.get_tx_raw(txid)?)?;
+            let all_owned = (0..tx.output.len() as u32).all(|vout| {
+                owned_vouts.contains(&vout)
+                    || (self.config().change_heuristics
+                        && is_likely_change(vout, &tx, &wallet, &owned_vouts))
+            });
+            Some(if all_owned {
+                TxDirection::SelfTransfer
+            } else {
+                TxDirection::Outgoing
+            })
+        })
     }
 
     //
@@ -265,6 +731,24 @@ impl Query {
             .map_or_else(Vec::new, |history| history.iter().map(f).collect())
     }
 
+    /// Map a page of the scripthash history confirmed at `from_height` or later (plus all
+    /// unconfirmed) as refs through `f`, ordered with oldest first. Returns the height to fetch
+    /// the next page from, if there are more entries. See `MemoryStore::get_history_page()`.
+    pub fn map_history_page<T>(
+        &self,
+        scripthash: &ScriptHash,
+        from_height: u32,
+        count: usize,
+        f: impl Fn(&HistoryEntry) -> T,
+    ) -> (Vec<T>, Option<u32>) {
+        let indexer = self.indexer.read().unwrap();
+        let (entries, next_height) =
+            indexer
+                .store()
+                .get_history_page(scripthash, from_height, count);
+        (entries.into_iter().map(f).collect(), next_height)
+    }
+
     /// Call `f` with each history iterm as ref
     pub fn for_each_history(&self, scripthash: &ScriptHash, f: impl FnMut(&HistoryEntry)) -> bool {
         let indexer = self.indexer.read().unwrap();
@@ -276,12 +760,44 @@ impl Query {
         }
     }
 
+    /// Get a page of up to `limit` wallet history entries following the `cursor`, ordered with
+    /// oldest first. Returns the cursor for fetching the next page, if there are more entries.
+    ///
+    /// Unlike offset-based pagination, transactions arriving in between pages don't cause
+    /// entries to be skipped or duplicated. Entries that change status in between pages do move
+    /// to their new position: a transaction that gets confirmed lands before a cursor pointing
+    /// at an unconfirmed entry and is not returned, while one that gets reorged into a later
+    /// block may be returned twice.
+    pub fn history_page(
+        &self,
+        checksum: &Checksum,
+        cursor: Option<&HistoryCursor>,
+        limit: usize,
+    ) -> (Vec<HistoryEntry>, Option<HistoryCursor>) {
+        let indexer = self.indexer.read().unwrap();
+        let after = cursor.map(|cursor| &cursor.0);
+        let (entries, has_more) = indexer
+            .store()
+            .get_wallet_history_page(checksum, after, limit);
+
+        let next_cursor = has_more
+            .and_then(|| entries.last())
+            .map(|last| HistoryCursor((*last).clone()));
+        (entries.into_iter().cloned().collect(), next_cursor)
+    }
+
     /// Get a copy of all history entries for all scripthashes since `min_block_height` (inclusive,
     /// including all unconfirmed), ordered with oldest first.
     pub fn get_history_since(&self, min_block_height: u32) -> Vec<HistoryEntry> {
         self.map_history_since(min_block_height, Clone::clone)
     }
 
+    /// Get the (earliest, latest) block heights with confirmed activity across all tracked
+    /// wallets and addresses, or None if there's no confirmed activity
+    pub fn activity_span(&self) -> Option<(u32, u32)> {
+        self.indexer.read().unwrap().store().get_activity_span()
+    }
+
     /// Map all history entries for all scripthashes since `min_block_height` (inclusive, including
     /// all unconfirmed) as refs through `f`, ordered with oldest first.
     pub fn map_history_since<T>(
@@ -395,6 +911,26 @@ impl Query {
         }
     }
 
+    /// List the immature coinbase outputs paying to the wallets, ordered by their block height.
+    /// Unlike the wallet balances, this includes outputs below the `coinbase_min_confs` threshold.
+    pub fn list_immature_coinbase(&self) -> Result<Vec<ImmatureCoinbaseTxo>> {
+        let tip_height = self.get_tip_height()?;
+        let indexer = self.indexer.read().unwrap();
+        let mut coinbases: Vec<_> = indexer
+            .immature_coinbase()
+            .iter()
+            .filter(|(_, coinbase)| coinbase.is_immature(tip_height))
+            .map(|(outpoint, coinbase)| ImmatureCoinbaseTxo {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                confirmations: coinbase.confirmations(tip_height),
+                coinbase: coinbase.clone(),
+            })
+            .collect();
+        coinbases.sort_unstable_by_key(|c| (c.coinbase.block_height, c.txid, c.vout));
+        Ok(coinbases)
+    }
+
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Option<Txo> {
         let indexer = self.indexer.read().unwrap();
         let store = indexer.store();
@@ -414,6 +950,49 @@ impl Query {
         })
     }
 
+    /// Get the number of additional confirmations needed for the unspent output to be considered
+    /// settled, accounting for coinbase maturity. Returns `None` if the output is spent or unknown.
+    pub fn confirmations_until_settled(&self, outpoint: &OutPoint) -> Result<Option<u32>> {
+        let txout = some_or_ret!(
+            self.rpc
+                .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?,
+            Ok(None)
+        );
+        let required_confs = if txout.coinbase {
+            // coinbase outputs become spendable once they reach COINBASE_MATURITY+1 confirmations
+            self.config().settled_confs.max(COINBASE_MATURITY + 1)
+        } else {
+            self.config().settled_confs
+        };
+        Ok(Some(required_confs.saturating_sub(txout.confirmations)))
+    }
+
+    /// Get the confirmation status for the given number of confirmations, using the configured
+    /// `settled_confs` as the threshold for being fully confirmed.
+    pub fn confirmation_status(&self, confirmations: u32) -> ConfStatus {
+        ConfStatus::from_confirmations(confirmations, self.config().settled_confs)
+    }
+
+    /// Get the height at which a timelocked wallet output becomes spendable. Returns `None` for
+    /// outputs that are not timelocked, as well as for unknown or unconfirmed outputs.
+    pub fn utxo_spendable_at(&self, outpoint: &OutPoint) -> Option<u32> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+
+        let FundingInfo(scripthash, _) = store.lookup_txo_fund(outpoint)?;
+        let funding_height = match store.get_tx_status(&outpoint.txid)? {
+            TxStatus::Confirmed(height) => height,
+            TxStatus::Unconfirmed | TxStatus::Conflicted => return None,
+        };
+        match store.get_script_info(&scripthash)?.origin {
+            KeyOrigin::Descriptor(checksum, _) => indexer
+                .watcher()
+                .get(&checksum)?
+                .spendable_at(funding_height),
+            KeyOrigin::Standalone => None,
+        }
+    }
+
     //
     // Scripthashes
     //
@@ -426,6 +1005,19 @@ impl Query {
         Some(script_info)
     }
 
+    /// Get the fixed (non-wildcard) descriptor that derives the tracked `script`, at the derivation
+    /// index it was found at. Returns `None` for unknown scripts and for standalone addresses.
+    pub fn descriptor_for_script(&self, script: &Script) -> Option<ExtendedDescriptor> {
+        let indexer = self.indexer.read().unwrap();
+        let script_info = indexer.store().get_script_info(&ScriptHash::from(script))?;
+        match script_info.origin {
+            KeyOrigin::Descriptor(checksum, index) => {
+                Some(indexer.watcher().get(&checksum)?.derive_desc(index))
+            }
+            KeyOrigin::Standalone => None,
+        }
+    }
+
     // returns a tuple of (confirmed_balance, unconfirmed_balance)
     pub fn get_script_balance(&self, scripthash: &ScriptHash) -> Result<(u64, u64)> {
         let (_, _, unspents) = some_or_ret!(
@@ -467,6 +1059,535 @@ impl Query {
         self.indexer.read().unwrap().watcher().wallets().clone()
     }
 
+    /// List all tracked wallets along with their current balances
+    pub fn list_wallets(&self) -> Result<Vec<WalletSummary>> {
+        let (BlockId(tip_height, _), _, unspents) =
+            some_or_ret!(self.list_unspent_raw(None, 0, None)?, Ok(vec![]));
+
+        let indexer = self.indexer.read().unwrap();
+        let balances = tally_balances(&unspents, indexer.frozen_txos());
+
+        // immature coinbase outputs are not reported by listunspent and are tracked by the indexer
+        let immature_balances = tally_immature_coinbase(
+            indexer.immature_coinbase().values(),
+            tip_height,
+            self.config().coinbase_min_confs,
+        );
+        Ok(indexer
+            .watcher()
+            .wallets()
+            .iter()
+            .map(|(checksum, wallet)| {
+                let (confirmed_balance, unconfirmed_balance) =
+                    balances.get(checksum).cloned().unwrap_or_default();
+                let immature = immature_balances.get(checksum).cloned().unwrap_or(0);
+                let (confirmed_balance, immature_balance) = apply_immature_coinbase(
+                    self.config().immature_coinbase,
+                    confirmed_balance,
+                    immature,
+                );
+                WalletSummary {
+                    checksum: checksum.clone(),
+                    wallet: wallet.clone(),
+                    confirmed_balance,
+                    unconfirmed_balance,
+                    immature_balance,
+                }
+            })
+            .collect())
+    }
+
+    /// Get the value of the wallet's (confirmed and unconfirmed) balance in a fiat unit, based on
+    /// the BTC/fiat `btc_rate` supplied by the caller. Unknown wallets are valued at zero.
+    pub fn fiat_value(&self, checksum: &Checksum, btc_rate: f64) -> Result<f64> {
+        Ok(self
+            .list_wallets()?
+            .iter()
+            .find(|summary| summary.checksum == *checksum)
+            .map_or(0.0, |summary| summary.fiat_value(btc_rate)))
+    }
+
+    /// Get the aggregate fiat value of all tracked wallets, based on the supplied `btc_rate`
+    pub fn portfolio_fiat_value(&self, btc_rate: f64) -> Result<f64> {
+        Ok(self
+            .list_wallets()?
+            .iter()
+            .map(|summary| summary.fiat_value(btc_rate))
+            .sum())
+    }
+
+    /// Get the number of the wallet's confirmed and unconfirmed unspent outputs, as a tuple of
+    /// `(confirmed, unconfirmed)`. Frozen outputs are counted too.
+    pub fn utxo_counts(&self, checksum: &Checksum) -> (u64, u64) {
+        self.indexer.read().unwrap().utxo_counts(checksum)
+    }
+
+    /// Get the wallet's outgoing transactions (including self-transfers) that paid a feerate above
+    /// `feerate_threshold` (in sat/vB), along with their actual feerate. The transactions of the
+    /// other chain of the same account are included too.
+    pub fn overpaid_txs(
+        &self,
+        checksum: &Checksum,
+        feerate_threshold: f32,
+    ) -> Result<Vec<(Txid, f32)>> {
+        let txids: Vec<Txid> = self
+            .account_history(checksum)
+            .into_iter()
+            .map(|txhist| txhist.txid)
+            .collect();
+
+        let mut overpaid = vec![];
+        for txid in txids {
+            match self.classify_tx(&txid, checksum)? {
+                Some(TxDirection::Outgoing) | Some(TxDirection::SelfTransfer) => (),
+                Some(TxDirection::Incoming) | None => continue,
+            }
+            if let Some(feerate) = self.get_tx_feerate(&txid)? {
+                if feerate > feerate_threshold {
+                    overpaid.push((txid, feerate));
+                }
+            }
+        }
+        Ok(overpaid)
+    }
+
+    /// Export the wallet's unspent outputs with the information needed to populate PSBT inputs
+    /// spending them, for constructing transactions externally (for example, to sweep the wallet).
+    /// Outputs of the other chain of the same account are included.
+    pub fn export_utxos_for_psbt(&self, checksum: &Checksum) -> Result<Vec<PsbtInputInfo>> {
+        let utxos = self.list_unspent(None, 0, None)?;
+        let indexer = self.indexer.read().unwrap();
+        let watcher = indexer.watcher();
+        let wallet = some_or_ret!(watcher.get(checksum), Ok(vec![]));
+
+        Ok(utxos
+            .into_iter()
+            .filter_map(|txo| {
+                let (utxo_wallet, index) = match &txo.script_info.origin {
+                    KeyOrigin::Descriptor(checksum, index) => (watcher.get(checksum)?, *index),
+                    KeyOrigin::Standalone => return None,
+                };
+                if !wallet.is_same_account(utxo_wallet) {
+                    return None;
+                }
+                let bip32_derivation = utxo_wallet
+                    .derive_key_origins(index)
+                    .into_iter()
+                    .map(
+                        |(pubkey, Bip32Origin(fingerprint, path))| PsbtKeyDerivation {
+                            pubkey,
+                            fingerprint,
+                            path,
+                        },
+                    )
+                    .collect();
+                Some(PsbtInputInfo {
+                    outpoint: OutPoint::new(txo.txid, txo.vout),
+                    value: txo.amount,
+                    script_pubkey: txo.script_info.address.script_pubkey(),
+                    bip32_derivation,
+                })
+            })
+            .collect())
+    }
+
+    /// Get the wallet's consolidation (fan-in) transactions, which spent at least `min_inputs`
+    /// wallet inputs into fewer outputs, ordered with oldest first. Inputs owned by the other
+    /// chain of the same account are counted too.
+    pub fn consolidation_txs(&self, checksum: &Checksum, min_inputs: usize) -> Result<Vec<Txid>> {
+        let candidates: Vec<(Txid, usize)> = {
+            let history = self.account_history(checksum);
+            let indexer = self.indexer.read().unwrap();
+            let (store, watcher) = (indexer.store(), indexer.watcher());
+            let wallet = some_or_ret!(watcher.get(checksum), Ok(vec![]));
+            history
+                .into_iter()
+                .filter_map(|txhist| {
+                    let tx_entry = store.get_tx_entry(&txhist.txid)?;
+                    let wallet_inputs = tx_entry
+                        .spending
+                        .values()
+                        .filter(|SpendingInfo(scripthash, ..)| {
+                            owned_by_account(&indexer, wallet, scripthash)
+                        })
+                        .count();
+                    (wallet_inputs >= min_inputs.max(1)).do_then(|| (txhist.txid, wallet_inputs))
+                })
+                .collect()
+        };
+
+        // the number of outputs is only available from the raw transaction
+        let mut consolidations = vec![];
+        for (txid, wallet_inputs) in candidates {
+            let tx: Transaction = deserialize(&self.get_tx_raw(&txid)?)?;
+            if tx.output.len() < wallet_inputs {
+                consolidations.push(txid);
+            }
+        }
+        Ok(consolidations)
+    }
+
+    /// Count the distinct external scripts (typically addresses) paid by the wallet's outgoing
+    /// transactions, as a rough indication of the number of counterparties it transacted with.
+    /// Outputs owned by the other chain of the same account are not considered external, nor
+    /// are outputs that are likely to be change when `change_heuristics` is enabled.
+    pub fn distinct_external_addresses(&self, checksum: &Checksum) -> Result<usize> {
+        let txids: Vec<Txid> = self
+            .account_history(checksum)
+            .into_iter()
+            .map(|txhist| txhist.txid)
+            .collect();
+
+        let mut external_scripts = HashSet::new();
+        for txid in txids {
+            if self.classify_tx(&txid, checksum)? != Some(TxDirection::Outgoing) {
+                continue;
+            }
+            let tx: Transaction = deserialize(&self.get_tx_raw(&txid)?)?;
+
+            let indexer = self.indexer.read().unwrap();
+            let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(0));
+            let (owned_vouts, external_vouts): (Vec<u32>, Vec<u32>) = (0..tx.output.len() as u32)
+                .partition(|vout| {
+                    let scripthash = ScriptHash::from(&tx.output[*vout as usize].script_pubkey);
+                    owned_by_account(&indexer, wallet, &scripthash)
+                });
+            external_scripts.extend(
+                external_vouts
+                    .into_iter()
+                    .filter(|vout| {
+                        !self.config().change_heuristics
+                            || !is_likely_change(*vout, &tx, wallet, &owned_vouts)
+                    })
+                    .map(|vout| tx.output[vout as usize].script_pubkey.clone()),
+            );
+        }
+        Ok(external_scripts.len())
+    }
+
+    /// Get the total vsize of the wallet's unconfirmed transactions (including those of the other
+    /// chain of the same account), as reported by their mempool entries
+    pub fn unconfirmed_wallet_vsize(&self, checksum: &Checksum) -> u64 {
+        let history = self.account_history(checksum);
+        let indexer = self.indexer.read().unwrap();
+        history
+            .iter()
+            .filter(|txhist| txhist.status.is_unconfirmed())
+            .filter_map(|txhist| indexer.store().get_mempool_entry(&txhist.txid))
+            .map(|entry| entry.vsize)
+            .sum()
+    }
+
+    /// Get the wallet's pending transactions as (txid, age since first seen, feerate in sat/vB)
+    /// tuples, ordered with the oldest first. Useful for spotting stuck payments. Transactions
+    /// of the other chain of the same account are included.
+    pub fn pending_transactions(&self, checksum: &Checksum) -> Result<Vec<(Txid, Duration, f32)>> {
+        let history = self.account_history(checksum);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let indexer = self.indexer.read().unwrap();
+        let mut pending: Vec<_> = history
+            .iter()
+            .filter(|txhist| txhist.status.is_unconfirmed())
+            .filter_map(|txhist| {
+                let entry = indexer.store().get_mempool_entry(&txhist.txid)?;
+                // the node clock may be slightly ahead of ours
+                let age = Duration::from_secs(now.saturating_sub(entry.time));
+                Some((txhist.txid, age, entry.own_feerate() as f32))
+            })
+            .collect();
+        pending.sort_by_key(|(_, age, _)| std::cmp::Reverse(*age));
+        Ok(pending)
+    }
+
+    // Get the combined history of the wallet and the other chain of the same account
+    fn account_history(&self, checksum: &Checksum) -> Vec<HistoryEntry> {
+        let indexer = self.indexer.read().unwrap();
+        let (store, watcher) = (indexer.store(), indexer.watcher());
+        let wallet = some_or_ret!(watcher.get(checksum), vec![]);
+        let history: BTreeSet<&HistoryEntry> = watcher
+            .wallets()
+            .iter()
+            .filter(|(_, other)| wallet.is_same_account(other))
+            .flat_map(|(checksum, _)| store.get_wallet_history(checksum))
+            .collect();
+        history.into_iter().cloned().collect()
+    }
+
+    /// Get the (confirmed, unconfirmed) balance of the wallet addresses derived under the `prefix`
+    /// path, excluding frozen outputs. Useful for reporting sub-accounts separately.
+    pub fn path_prefix_balance(&self, prefix: &DerivationPath) -> Result<(u64, u64)> {
+        let (_, _, unspents) = some_or_ret!(self.list_unspent_raw(None, 0, None)?, Ok((0, 0)));
+        let indexer = self.indexer.read().unwrap();
+        let wallets = indexer.watcher().wallets();
+        Ok(tally_path_prefix_balance(
+            &unspents,
+            wallets,
+            prefix,
+            indexer.frozen_txos(),
+        ))
+    }
+
+    /// Get the history of the wallet addresses derived under the `prefix` path
+    pub fn path_prefix_history(&self, prefix: &DerivationPath) -> Vec<HistoryEntry> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let history: BTreeSet<&HistoryEntry> = indexer
+            .watcher()
+            .wallets()
+            .iter()
+            .flat_map(|(checksum, wallet)| {
+                store
+                    .get_wallet_used_addresses(checksum)
+                    .into_iter()
+                    .filter(move |(index, _)| wallet.derives_from_path(*index, prefix))
+            })
+            .filter_map(|(_, address)| store.get_history(&ScriptHash::from(&address)))
+            .flatten()
+            .collect();
+        history.into_iter().cloned().collect()
+    }
+
+    /// Get unspent outputs below the `threshold` amount (in satoshis) that were received from
+    /// transactions not funded by the wallet (i.e. that aren't change), which could be unsolicited
+    /// dust sent to deanonymize the wallet and that the user may choose to never spend.
+    pub fn dust_attack_utxos(&self, threshold: u64) -> Result<Vec<Txo>> {
+        let mut dust_utxos = vec![];
+        for utxo in self.list_unspent(None, 0, None)? {
+            if utxo.amount >= threshold {
+                continue;
+            }
+            if let KeyOrigin::Descriptor(ref checksum, _) = utxo.script_info.origin {
+                if self.classify_tx(&utxo.txid, checksum)? == Some(TxDirection::Incoming) {
+                    dust_utxos.push(utxo);
+                }
+            }
+        }
+        Ok(dust_utxos)
+    }
+
+    /// Check whether spending the output at `feerate` (in sat/vB) would cost less than its value,
+    /// based on the size of the input spending it. Returns `None` if the output is unknown or if
+    /// its spending size cannot be estimated (for standalone p2sh/p2wsh addresses).
+    pub fn is_economical_to_spend(&self, outpoint: &OutPoint, feerate: f32) -> Option<bool> {
+        let txo = self.lookup_txo(outpoint)?;
+        let satisfaction_weight = match txo.script_info.origin {
+            KeyOrigin::Descriptor(ref checksum, _) => {
+                self.get_wallet(checksum)?.max_satisfaction_weight()?
+            }
+            KeyOrigin::Standalone => match txo.script_info.address.address_type()? {
+                AddressType::P2pkh => P2PKH_SATISFACTION_WEIGHT,
+                AddressType::P2wpkh => P2WPKH_SATISFACTION_WEIGHT,
+                AddressType::P2tr => P2TR_SATISFACTION_WEIGHT,
+                AddressType::P2sh | AddressType::P2wsh => return None,
+            },
+        };
+        Some(is_economical(txo.amount, satisfaction_weight, feerate))
+    }
+
+    /// Get the total balance of the wallet's unspent outputs, excluding the `frozen` ones.
+    /// Unconfirmed outputs are excluded too if `balance_exclude_mempool` is enabled.
+    pub fn balance_excluding(
+        &self,
+        checksum: &Checksum,
+        frozen: &HashSet<OutPoint>,
+    ) -> Result<Amount> {
+        let (_, _, unspents) =
+            some_or_ret!(self.list_unspent_raw(None, 0, None)?, Ok(Amount::ZERO));
+        let include_mempool = !self.config().balance_exclude_mempool;
+        let balance = tally_balance(&unspents, checksum, frozen, include_mempool);
+        Ok(Amount::from_sat(balance))
+    }
+
+    /// Get the wallet's balance, excluding the outputs frozen via `freeze_txo()`
+    pub fn spendable_balance(&self, checksum: &Checksum) -> Result<Amount> {
+        let frozen_txos = self.indexer.read().unwrap().frozen_txos().clone();
+        self.balance_excluding(checksum, &frozen_txos)
+    }
+
+    /// Freeze an unspent wallet output, excluding it from the wallet balances. The frozen outputs
+    /// are persisted to the wal (if enabled). Returns false if it was already frozen.
+    pub fn freeze_txo(&self, outpoint: OutPoint) -> Result<bool> {
+        self.lookup_txo(&outpoint)
+            .or_err(BwtError::TxoNotFound(outpoint))?;
+        // gettxout only returns unspent outputs, including ones spent by mempool transactions
+        let is_unspent = self
+            .rpc
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .is_some();
+        ensure!(is_unspent, BwtError::TxoNotUnspent(outpoint));
+
+        self.indexer.write().unwrap().set_txo_frozen(outpoint, true)
+    }
+
+    /// Unfreeze an output. Returns false if it was not frozen.
+    pub fn unfreeze_txo(&self, outpoint: &OutPoint) -> Result<bool> {
+        self.indexer
+            .write()
+            .unwrap()
+            .set_txo_frozen(*outpoint, false)
+    }
+
+    /// List the frozen outputs
+    pub fn list_frozen_txos(&self) -> Vec<OutPoint> {
+        let indexer = self.indexer.read().unwrap();
+        indexer.frozen_txos().iter().cloned().collect()
+    }
+
+    /// Get the most recent transaction touching the wallet along with its block time (or the
+    /// current time for unconfirmed transactions). Returns `None` if the wallet has no history.
+    pub fn wallet_last_activity(&self, checksum: &Checksum) -> Result<Option<WalletActivity>> {
+        let last_history = {
+            let indexer = self.indexer.read().unwrap();
+            indexer.store().get_wallet_last_history(checksum).cloned()
+        };
+        let HistoryEntry { txid, status } = some_or_ret!(last_history, Ok(None));
+
+        let time = match status {
+            TxStatus::Confirmed(height) => {
+                self.get_header(&self.get_block_hash(height)?)?.time as u64
+            }
+            _ => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        Ok(Some(WalletActivity { txid, status, time }))
+    }
+
+    /// Get the average number of blocks it took the wallet's received transactions to confirm,
+    /// counted since bitcoind first saw them. Transactions that were already confirmed when first
+    /// seen (like those found by a rescan) are skipped. Returns `None` if there are none left.
+    pub fn avg_confirmation_blocks(&self, checksum: &Checksum) -> Result<Option<f32>> {
+        let mut received = vec![];
+        for HistoryEntry { txid, status } in self.account_history(checksum) {
+            let height = match status {
+                TxStatus::Confirmed(height) => height,
+                _ => continue,
+            };
+            if self.classify_tx(&txid, checksum)? != Some(TxDirection::Incoming) {
+                continue;
+            }
+            let first_seen = self
+                .rpc
+                .get_transaction(&txid, Some(true))?
+                .info
+                .timereceived;
+            received.push((first_seen, height));
+        }
+
+        let mut block_times = HashMap::new();
+        avg_confirmation_blocks(&received, |height| {
+            if let Some(time) = block_times.get(&height) {
+                return Ok(*time);
+            }
+            let time = self.get_header(&self.get_block_hash(height)?)?.time;
+            block_times.insert(height, time);
+            Ok(time)
+        })
+    }
+
+    /// Get the value-weighted average age (in blocks, relative to `tip_height`) of the wallet's
+    /// confirmed unspent outputs, as a rough "coin age" metric for assessing dormancy.
+    /// Returns `None` if the wallet has no confirmed unspent outputs.
+    pub fn average_coin_age_blocks(
+        &self,
+        checksum: &Checksum,
+        tip_height: u32,
+    ) -> Result<Option<f32>> {
+        let (BlockId(synced_height, _), _, unspents) =
+            some_or_ret!(self.list_unspent_raw(None, 1, None)?, Ok(None));
+
+        let utxos: Vec<(u64, u32)> = unspents
+            .iter()
+            .filter(|unspent| {
+                let origin = unspent
+                    .label
+                    .as_ref()
+                    .and_then(|l| KeyOrigin::from_label(l));
+                matches!(origin, Some(KeyOrigin::Descriptor(ref c, _)) if c == checksum)
+            })
+            // skip outputs reporting more confirmations than there are blocks, instead of underflowing
+            .filter_map(|unspent| {
+                let funding_height = (synced_height + 1).checked_sub(unspent.confirmations)?;
+                Some((unspent.amount.as_sat(), funding_height))
+            })
+            .collect();
+        Ok(average_coin_age(&utxos, tip_height))
+    }
+
+    /// Get every address with observed activity across the external and internal chains of the
+    /// wallet's account, as `(index, address, is_change)` tuples ordered by index
+    pub fn used_addresses(&self, checksum: &Checksum) -> Vec<(u32, Address, bool)> {
+        let indexer = self.indexer.read().unwrap();
+        let (store, watcher) = (indexer.store(), indexer.watcher());
+        let wallet = some_or_ret!(watcher.get(checksum), vec![]);
+
+        let mut addresses: Vec<_> = watcher
+            .wallets()
+            .iter()
+            .filter(|(_, other)| wallet.is_same_account(other))
+            .flat_map(|(other_checksum, other)| {
+                let is_change = other.is_internal_chain();
+                store
+                    .get_wallet_used_addresses(other_checksum)
+                    .into_iter()
+                    .map(move |(index, address)| (index, address, is_change))
+            })
+            .collect();
+        addresses.sort_unstable_by_key(|(index, _, is_change)| (*index, *is_change));
+        addresses
+    }
+
+    /// Sum the net amount that flowed in or out of the wallet through transactions that were confirmed
+    /// in blocks with a timestamp within `[from_time, to_time)`. Only the funding and spending of
+    /// scripts owned by the wallet's account (including the other chain of the same account) are
+    /// counted, other wallets that take part in the same transactions are not.
+    pub fn net_flow(&self, checksum: &Checksum, from_time: u64, to_time: u64) -> Result<i64> {
+        let history = self.account_history(checksum);
+        let confirmed: Vec<(u32, i64)> = {
+            let indexer = self.indexer.read().unwrap();
+            let store = indexer.store();
+            let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(0));
+            let is_owned = |scripthash: &ScriptHash| owned_by_account(&indexer, wallet, scripthash);
+            history
+                .iter()
+                .filter_map(|HistoryEntry { txid, status }| match status {
+                    TxStatus::Confirmed(height) => {
+                        let tx_entry = store.get_tx_entry(txid)?;
+                        let funded: u64 = tx_entry
+                            .funding
+                            .values()
+                            .filter(|FundingInfo(scripthash, _)| is_owned(scripthash))
+                            .map(|FundingInfo(_, amount)| amount)
+                            .sum();
+                        let spent: u64 = tx_entry
+                            .spending
+                            .values()
+                            .filter(|SpendingInfo(scripthash, ..)| is_owned(scripthash))
+                            .map(|SpendingInfo(_, _, amount)| amount)
+                            .sum();
+                        Some((*height, funded as i64 - spent as i64))
+                    }
+                    TxStatus::Unconfirmed | TxStatus::Conflicted => None,
+                })
+                .collect()
+        };
+
+        let mut block_times: HashMap<u32, u64> = HashMap::new();
+        let mut net_flow = 0;
+        for (height, balance_change) in confirmed {
+            let time = match block_times.get(&height) {
+                Some(time) => *time,
+                None => {
+                    let time = self.get_header(&self.get_block_hash(height)?)?.time as u64;
+                    block_times.insert(height, time);
+                    time
+                }
+            };
+            if time >= from_time && time < to_time {
+                net_flow += balance_change;
+            }
+        }
+        Ok(net_flow)
+    }
+
     pub fn get_wallet(&self, checksum: &Checksum) -> Option<Wallet> {
         self.indexer
             .read()
@@ -498,6 +1619,32 @@ impl Query {
         let wallet = indexer.watcher().get(checksum)?;
         wallet.find_gap(indexer.store())
     }
+
+    /// Get the largest run of unused addresses observed between used ones on the external or
+    /// internal (`is_change`) chain of the wallet's account, which can be compared against the
+    /// configured gap limit to right-size it. Returns 0 if the chain has no history.
+    pub fn observed_max_gap(&self, checksum: &Checksum, is_change: bool) -> Option<u32> {
+        let indexer = self.indexer.read().unwrap();
+        let chain_wallet = account_chain(indexer.watcher(), checksum, is_change)?;
+        Some(chain_wallet.find_gap(indexer.store()).unwrap_or(0) as u32)
+    }
+
+    /// Get the fraction of used addresses out of the imported window of addresses, for the
+    /// external or internal (`is_change`) chain of the wallet's account. Returns None if
+    /// there's no such chain or if its addresses were not imported yet.
+    pub fn utilization(&self, checksum: &Checksum, is_change: bool) -> Option<f32> {
+        let indexer = self.indexer.read().unwrap();
+        let chain_wallet = account_chain(indexer.watcher(), checksum, is_change)?;
+        chain_wallet.utilization(indexer.store())
+    }
+
+    /// Get the wallets that own inputs spent by the PSBT and can contribute signatures to it
+    pub fn psbt_signable_wallets(&self, psbt: &PartiallySignedTransaction) -> Vec<Checksum> {
+        let indexer = self.indexer.read().unwrap();
+        indexer
+            .watcher()
+            .psbt_signable_wallets(psbt, indexer.store())
+    }
 }
 
 // Attach descriptor and bip32 origin information when available
@@ -514,6 +1661,7 @@ fn attach_wallet_info(script_info: &mut ScriptInfo, indexer: &Indexer) {
 pub struct Txo {
     pub txid: Txid,
     pub vout: u32,
+    #[serde(serialize_with = "serialize_amount")]
     pub amount: u64,
     #[serde(flatten)]
     pub script_info: ScriptInfo,
@@ -548,7 +1696,13 @@ pub struct TxDetail {
     status: TxStatus,
     funding: Vec<TxDetailFunding>,
     spending: Vec<TxDetailSpending>,
+    #[serde(serialize_with = "serialize_amount")]
     balance_change: i64,
+    replaces: Option<Txid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coinjoin_like: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feerate: Option<f32>,
     #[serde(flatten)]
     mempool_info: Option<TxDetailMempool>,
 }
@@ -591,16 +1745,16 @@ impl TxDetail {
             })
             .collect::<Vec<TxDetailSpending>>();
 
-        let funding_sum = funding.iter().map(|f| f.amount).sum::<u64>();
-        let spending_sum = spending.iter().map(|s| s.amount).sum::<u64>();
-        let balance_change = funding_sum as i64 - spending_sum as i64;
-
         Some(TxDetail {
             txid: *txid,
             status: tx_entry.status,
             funding,
             spending,
-            balance_change,
+            balance_change: tx_entry.balance_change(),
+            replaces: tx_entry.replaces,
+            // filled in by Query::get_tx_detail_full(), outside of the indexer lock
+            coinjoin_like: None,
+            feerate: None,
             mempool_info: mempool_entry.map(Into::into),
         })
     }
@@ -611,6 +1765,7 @@ struct TxDetailFunding {
     vout: u32,
     #[serde(flatten)]
     script_info: ScriptInfo,
+    #[serde(serialize_with = "serialize_amount")]
     amount: u64,
     #[cfg(feature = "track-spends")]
     spent_by: Option<InPoint>,
@@ -621,6 +1776,7 @@ struct TxDetailSpending {
     vin: u32,
     #[serde(flatten)]
     script_info: ScriptInfo,
+    #[serde(serialize_with = "serialize_amount")]
     amount: u64,
     prevout: OutPoint,
 }
@@ -644,11 +1800,523 @@ impl From<&MempoolEntry> for TxDetailMempool {
     }
 }
 
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxDirection {
+    Incoming,
+    Outgoing,
+    SelfTransfer,
+}
+
+/// A pagination cursor pointing at the last history entry of the previous page.
+/// Encoded as `<block_height>:<txid>`, or as `unconfirmed:<txid>` for mempool transactions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryCursor(HistoryEntry);
+
+impl_string_serializer!(HistoryCursor, cursor, {
+    let HistoryEntry { txid, status } = &cursor.0;
+    match status {
+        TxStatus::Confirmed(height) => format!("{}:{}", height, txid),
+        TxStatus::Unconfirmed | TxStatus::Conflicted => format!("unconfirmed:{}", txid),
+    }
+});
+
+impl FromStr for HistoryCursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let status = match parts.next().or_err("invalid cursor")? {
+            "unconfirmed" => TxStatus::Unconfirmed,
+            height => TxStatus::Confirmed(height.parse().context("invalid cursor height")?),
+        };
+        let txid = parts.next().or_err("invalid cursor")?.parse()?;
+        Ok(HistoryCursor(HistoryEntry::new(txid, status)))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct PsbtInputInfo {
+    pub outpoint: OutPoint,
+    #[serde(serialize_with = "serialize_amount")]
+    pub value: u64,
+    pub script_pubkey: Script,
+    pub bip32_derivation: Vec<PsbtKeyDerivation>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PsbtKeyDerivation {
+    pub pubkey: PublicKey,
+    pub fingerprint: Fingerprint,
+    pub path: DerivationPath,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MerkleProof {
+    block_height: u32,
+    merkle: Vec<sha256d::Hash>,
+    pos: usize,
+}
+
+/// The txids of recently accessed blocks, used to avoid re-fetching them when getting multiple merkle proofs
+#[derive(Default)]
+pub struct BlockTxidsCache(HashMap<BlockHash, Vec<Txid>>);
+
+#[derive(Serialize, Debug)]
+pub struct MempoolFees {
+    histogram: FeeHistogram,
+    relay_fee: f64,
+    mempool_min_fee: f32,
+    min_feerate: f32,
+}
+
+// Get bitcoind's `mempoolminfee` as a sat/vB feerate
+fn mempoolminfee_feerate(mempool_info: &GetMempoolInfoResult) -> f32 {
+    // from BTC/kvB to sat/vB
+    mempool_info.mempool_min_fee.as_sat() as f32 / 1000f32
+}
+
+#[derive(Serialize, Debug)]
+pub struct WalletSummary {
+    checksum: Checksum,
+    #[serde(flatten)]
+    wallet: Wallet,
+    #[serde(serialize_with = "serialize_amount")]
+    confirmed_balance: u64,
+    #[serde(serialize_with = "serialize_amount")]
+    unconfirmed_balance: u64,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_opt_amount"
+    )]
+    immature_balance: Option<u64>,
+}
+
+// Iterate over the unspent outputs that are not frozen and were derived from a descriptor,
+// along with their key origin
+fn descriptor_unspents<'a>(
+    unspents: &'a [rpcjson::ListUnspentResultEntry],
+    frozen: &'a HashSet<OutPoint>,
+) -> impl Iterator<Item = (&'a rpcjson::ListUnspentResultEntry, Checksum, u32)> + 'a {
+    unspents.iter().filter_map(move |unspent| {
+        if frozen.contains(&OutPoint::new(unspent.txid, unspent.vout)) {
+            return None;
+        }
+        let label = unspent.label.as_ref()?;
+        match KeyOrigin::from_label(label)? {
+            KeyOrigin::Descriptor(checksum, index) => Some((unspent, checksum, index)),
+            KeyOrigin::Standalone => None,
+        }
+    })
+}
+
+// Add the unspent output's amount to the (confirmed, unconfirmed) balance
+fn add_to_balance(balance: &mut (u64, u64), unspent: &rpcjson::ListUnspentResultEntry) {
+    if unspent.confirmations > 0 {
+        balance.0 += unspent.amount.as_sat();
+    } else {
+        balance.1 += unspent.amount.as_sat();
+    }
+}
+
+// Tally up the (confirmed, unconfirmed) balance of each wallet.
+// the same assumption regarding "bwt/..." labels as in list_unspent() applies here.
+// frozen outputs are excluded from the balances
+fn tally_balances(
+    unspents: &[rpcjson::ListUnspentResultEntry],
+    frozen: &HashSet<OutPoint>,
+) -> HashMap<Checksum, (u64, u64)> {
+    let mut balances: HashMap<Checksum, (u64, u64)> = HashMap::new();
+    for (unspent, checksum, _) in descriptor_unspents(unspents, frozen) {
+        add_to_balance(balances.entry(checksum).or_default(), unspent);
+    }
+    balances
+}
+
+// Sum up the amounts of the wallet's unspent outputs that are not frozen
+fn tally_balance(
+    unspents: &[rpcjson::ListUnspentResultEntry],
+    checksum: &Checksum,
+    frozen: &HashSet<OutPoint>,
+    include_mempool: bool,
+) -> u64 {
+    descriptor_unspents(unspents, frozen)
+        .filter(|(unspent, unspent_checksum, _)| {
+            unspent_checksum == checksum && (include_mempool || unspent.confirmations > 0)
+        })
+        .map(|(unspent, _, _)| unspent.amount.as_sat())
+        .sum()
+}
+
+// Sum up the (confirmed, unconfirmed) amounts of the unspent outputs derived under the `prefix` path
+fn tally_path_prefix_balance(
+    unspents: &[rpcjson::ListUnspentResultEntry],
+    wallets: &HashMap<Checksum, Wallet>,
+    prefix: &DerivationPath,
+    frozen: &HashSet<OutPoint>,
+) -> (u64, u64) {
+    let mut balance = (0, 0);
+    for (unspent, checksum, index) in descriptor_unspents(unspents, frozen) {
+        if wallets
+            .get(&checksum)
+            .map_or(false, |wallet| wallet.derives_from_path(index, prefix))
+        {
+            add_to_balance(&mut balance, unspent);
+        }
+    }
+    balance
+}
+
+// Check whether the scripthash belongs to the wallet's account (either of its chains)
+fn owned_by_account(indexer: &Indexer, wallet: &Wallet, scripthash: &ScriptHash) -> bool {
+    let (store, watcher) = (indexer.store(), indexer.watcher());
+    store
+        .get_script_info(scripthash)
+        .map_or(false, |script_info| match script_info.origin {
+            KeyOrigin::Descriptor(ref checksum, _) => watcher
+                .get(checksum)
+                .map_or(false, |other| wallet.is_same_account(other)),
+            KeyOrigin::Standalone => false,
+        })
+}
+
+// Get the external or internal (`is_change`) chain wallet of the wallet's account
+fn account_chain<'a>(
+    watcher: &'a WalletWatcher,
+    checksum: &Checksum,
+    is_change: bool,
+) -> Option<&'a Wallet> {
+    let wallet = watcher.get(checksum)?;
+    if wallet.is_internal_chain() == is_change {
+        Some(wallet)
+    } else {
+        watcher
+            .wallets()
+            .values()
+            .find(|other| other.is_internal_chain() == is_change && wallet.is_same_account(other))
+    }
+}
+
+// The weight of the outpoint, sequence and script length fields of an input (4 * (36 + 4)), to which
+// the satisfaction weight (scriptSig and witness) is added to get the full input weight
+const TXIN_BASE_WEIGHT: usize = 160;
+// Satisfaction weights for standalone addresses, matching rust-miniscript's estimates for the
+// pkh() and wpkh() descriptors (a 73 bytes signature and a 33 bytes compressed public key) and
+// for key path spends of tr() descriptors (a 65 bytes schnorr signature)
+const P2PKH_SATISFACTION_WEIGHT: usize = 4 * (1 + 73 + 34);
+const P2WPKH_SATISFACTION_WEIGHT: usize = 4 + 1 + 73 + 34;
+const P2TR_SATISFACTION_WEIGHT: usize = 65;
+
+// Check whether the cost of an input with the given satisfaction weight at `feerate` (in sat/vB)
+// is lower than the spent `amount`
+fn is_economical(amount: u64, satisfaction_weight: usize, feerate: f32) -> bool {
+    let input_vsize = ((TXIN_BASE_WEIGHT + satisfaction_weight) as f32 / 4.0).ceil();
+    input_vsize * feerate < amount as f32
+}
+
+// Sum up the immature coinbase amounts of each wallet, skipping outputs with less than `min_confs`
+fn tally_immature_coinbase<'a>(
+    coinbases: impl Iterator<Item = &'a ImmatureCoinbase>,
+    tip_height: u32,
+    min_confs: u32,
+) -> HashMap<Checksum, u64> {
+    let mut balances = HashMap::new();
+    for coinbase in coinbases
+        .filter(|c| c.is_immature(tip_height))
+        .filter(|c| c.confirmations(tip_height) >= min_confs)
+    {
+        *balances.entry(coinbase.checksum.clone()).or_default() += coinbase.amount;
+    }
+    balances
+}
+
+// Returns the confirmed balance with the immature coinbase amount included (if enabled),
+// along with the immature amount that it includes
+fn apply_immature_coinbase(
+    mode: ImmatureCoinbaseMode,
+    confirmed_balance: u64,
+    immature: u64,
+) -> (u64, Option<u64>) {
+    match mode {
+        ImmatureCoinbaseMode::Include => (confirmed_balance + immature, Some(immature)),
+        ImmatureCoinbaseMode::Exclude => (confirmed_balance, None),
+    }
+}
+
+impl WalletSummary {
+    fn fiat_value(&self, btc_rate: f64) -> f64 {
+        let balance = self.confirmed_balance + self.unconfirmed_balance;
+        Amount::from_sat(balance).as_btc() * btc_rate
+    }
+}
+
+// Average the number of blocks between the first-seen time and the confirmation of the given
+// `(first_seen, block_height)` transactions, approximated using the block times. Counts the
+// confirming block along with the preceding blocks that were mined after the first-seen time.
+fn avg_confirmation_blocks(
+    txs: &[(u64, u32)],
+    mut block_time: impl FnMut(u32) -> Result<u32>,
+) -> Result<Option<f32>> {
+    let mut samples = vec![];
+    for (first_seen, height) in txs {
+        if block_time(*height)? as u64 <= *first_seen {
+            continue;
+        }
+        let mut blocks = 1;
+        while blocks <= *height && block_time(height - blocks)? as u64 > *first_seen {
+            blocks += 1;
+        }
+        samples.push(blocks);
+    }
+    Ok((!samples.is_empty()).do_then(|| samples.iter().sum::<u32>() as f32 / samples.len() as f32))
+}
+
+// Average the age of the given `(amount, funding_height)` outputs relative to `tip_height`,
+// weighted by their amounts
+fn average_coin_age(utxos: &[(u64, u32)], tip_height: u32) -> Option<f32> {
+    let total_amount: u64 = utxos.iter().map(|(amount, _)| amount).sum();
+    if total_amount == 0 {
+        return None;
+    }
+    let weighted_age: f64 = utxos
+        .iter()
+        .map(|(amount, funding_height)| {
+            *amount as f64 * tip_height.saturating_sub(*funding_height) as f64
+        })
+        .sum();
+    Some((weighted_age / total_amount as f64) as f32)
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImmatureCoinbaseTxo {
+    txid: Txid,
+    vout: u32,
+    #[serde(flatten)]
+    coinbase: ImmatureCoinbase,
+    confirmations: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WalletActivity {
+    txid: Txid,
+    #[serde(rename = "block_height")]
+    status: TxStatus,
+    time: u64,
+}
+
 #[derive(Serialize, Debug)]
 pub struct ScriptStats {
     #[serde(flatten)]
     script_info: ScriptInfo,
     tx_count: usize,
+    #[serde(serialize_with = "serialize_amount")]
     confirmed_balance: u64,
+    #[serde(serialize_with = "serialize_amount")]
     unconfirmed_balance: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RescanSince;
+    use crate::util::descriptor::ExtendedDescriptor;
+
+    #[test]
+    fn test_is_economical() {
+        // a 68 vbytes p2wpkh input and a 148 vbytes p2pkh input
+        assert!(is_economical(1000, P2WPKH_SATISFACTION_WEIGHT, 10.0));
+        assert!(!is_economical(1000, P2WPKH_SATISFACTION_WEIGHT, 20.0));
+        assert!(is_economical(1000, P2PKH_SATISFACTION_WEIGHT, 6.0));
+        assert!(!is_economical(1000, P2PKH_SATISFACTION_WEIGHT, 7.0));
+
+        // using the satisfaction weight of a descriptor
+        let desc: ExtendedDescriptor = "wpkh(xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC/0/*)".parse().unwrap();
+        let wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap();
+        let weight = wallet.max_satisfaction_weight().unwrap();
+        assert!(is_economical(1000, weight, 10.0));
+        assert!(!is_economical(1000, weight, 20.0));
+    }
+
+    #[test]
+    fn test_confirmation_status() {
+        // using the default settled_confs
+        assert_eq!(
+            ConfStatus::from_confirmations(0, 6),
+            ConfStatus::Unconfirmed
+        );
+        assert_eq!(ConfStatus::from_confirmations(3, 6), ConfStatus::Pending);
+        assert_eq!(ConfStatus::from_confirmations(10, 6), ConfStatus::Confirmed);
+        assert_eq!(ConfStatus::from_confirmations(6, 6), ConfStatus::Confirmed);
+        assert_eq!(ConfStatus::from_confirmations(1, 1), ConfStatus::Confirmed);
+    }
+
+    #[test]
+    fn test_immature_coinbase_balance() {
+        let checksum: Checksum = "xjm8w0el".parse().unwrap();
+        let coinbases = [
+            // mined at height 150, matures at 250
+            ImmatureCoinbase {
+                checksum: checksum.clone(),
+                amount: 50_0000_0000,
+                block_height: 150,
+            },
+            // mined at height 100, matures at 200
+            ImmatureCoinbase {
+                checksum: checksum.clone(),
+                amount: 25_0000_0000,
+                block_height: 100,
+            },
+        ];
+        let immature = tally_immature_coinbase(coinbases.iter(), 200, 0);
+        assert_eq!(immature.get(&checksum), Some(&50_0000_0000));
+
+        let immature = immature[&checksum];
+        assert_eq!(
+            apply_immature_coinbase(ImmatureCoinbaseMode::Include, 1000, immature),
+            (50_0000_1000, Some(50_0000_0000))
+        );
+        assert_eq!(
+            apply_immature_coinbase(ImmatureCoinbaseMode::Exclude, 1000, immature),
+            (1000, None)
+        );
+
+        // no longer counted once matured
+        assert_eq!(tally_immature_coinbase(coinbases.iter(), 249, 0).len(), 1);
+        assert!(tally_immature_coinbase(coinbases.iter(), 250, 0).is_empty());
+    }
+
+    #[test]
+    fn test_immature_coinbase_min_confs() {
+        let checksum: Checksum = "xjm8w0el".parse().unwrap();
+        // a freshly mined coinbase output, with a single confirmation at height 150
+        let coinbases = [ImmatureCoinbase {
+            checksum: checksum.clone(),
+            amount: 50_0000_0000,
+            block_height: 150,
+        }];
+
+        // hidden until reaching the threshold
+        assert!(tally_immature_coinbase(coinbases.iter(), 150, 90).is_empty());
+        assert!(tally_immature_coinbase(coinbases.iter(), 238, 90).is_empty());
+        let immature = tally_immature_coinbase(coinbases.iter(), 239, 90);
+        assert_eq!(immature.get(&checksum), Some(&50_0000_0000));
+        // shown right away without one
+        assert_eq!(tally_immature_coinbase(coinbases.iter(), 150, 0).len(), 1);
+    }
+
+    // A bwt-labeled unspent output of the wallet, at the derivation index `index`
+    fn make_unspent(
+        checksum: &Checksum,
+        index: u32,
+        amount: u64,
+        confirmations: u32,
+    ) -> rpcjson::ListUnspentResultEntry {
+        rpcjson::ListUnspentResultEntry {
+            txid: Txid::default(),
+            vout: index,
+            address: None,
+            label: Some(format!("bwt/{}/{}", checksum, index)),
+            redeem_script: None,
+            witness_script: None,
+            script_pub_key: bitcoin::Script::new(),
+            amount: bitcoin::Amount::from_sat(amount),
+            confirmations,
+            spendable: false,
+            solvable: true,
+            descriptor: None,
+            safe: confirmations > 0,
+        }
+    }
+
+    #[test]
+    fn test_balance_exclude_mempool() {
+        let checksum: Checksum = "xjm8w0el".parse().unwrap();
+        // a confirmed output and a pending incoming one
+        let unspents = vec![
+            make_unspent(&checksum, 0, 1000, 3),
+            make_unspent(&checksum, 1, 500, 0),
+        ];
+        let frozen = HashSet::new();
+
+        assert_eq!(tally_balance(&unspents, &checksum, &frozen, true), 1500);
+        assert_eq!(tally_balance(&unspents, &checksum, &frozen, false), 1000);
+
+        // the pending output is still counted as part of the unconfirmed balance
+        let (confirmed, unconfirmed) = tally_balances(&unspents, &frozen)[&checksum];
+        assert_eq!((confirmed, unconfirmed), (1000, 500));
+    }
+
+    #[test]
+    fn test_path_prefix_balance() {
+        const XPUB: &str = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+        // two sub-accounts under the same xpub
+        let make_wallet = |account: u32| {
+            let desc: ExtendedDescriptor = format!("wpkh({}/{}/*)", XPUB, account).parse().unwrap();
+            let checksum = Checksum::from(&desc);
+            let wallet =
+                Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap();
+            (checksum, wallet)
+        };
+        let wallets: HashMap<Checksum, Wallet> =
+            vec![make_wallet(5), make_wallet(6)].into_iter().collect();
+        let (checksum5, checksum6) = (make_wallet(5).0, make_wallet(6).0);
+
+        let unspents = vec![
+            make_unspent(&checksum5, 0, 1000, 1),
+            make_unspent(&checksum5, 1, 200, 0),
+            make_unspent(&checksum6, 2, 7000, 3),
+        ];
+        let frozen = HashSet::new();
+        let balance = |path: &str| {
+            tally_path_prefix_balance(&unspents, &wallets, &path.parse().unwrap(), &frozen)
+        };
+
+        assert_eq!(balance("m/5"), (1000, 200));
+        assert_eq!(balance("m/6"), (7000, 0));
+        assert_eq!(balance("m/5/1"), (0, 200));
+        assert_eq!(balance("m/7"), (0, 0));
+        assert_eq!(balance("m"), (8000, 200));
+    }
+
+    #[test]
+    fn test_average_coin_age() {
+        assert_eq!(average_coin_age(&[], 200), None);
+
+        // 1 BTC aged 100 blocks and 3 BTC aged 20 blocks
+        let utxos = [(1_0000_0000, 100), (3_0000_0000, 180)];
+        assert_eq!(average_coin_age(&utxos, 200), Some(40.0));
+        // a tip height below the funding height counts as no age
+        assert_eq!(average_coin_age(&utxos, 150), Some(12.5));
+    }
+
+    #[test]
+    fn test_avg_confirmation_blocks() {
+        // a block every 10 minutes
+        let block_time = |height: u32| Ok(1_600_000_000 + height * 600);
+        let seen_after = |height: u32| 1_600_000_000 + height as u64 * 600 + 60;
+
+        // no data to average
+        assert_eq!(avg_confirmation_blocks(&[], block_time).unwrap(), None);
+        // seen after its confirmation, ignored
+        let rescanned = (seen_after(20), 20);
+        assert_eq!(
+            avg_confirmation_blocks(&[rescanned], block_time).unwrap(),
+            None
+        );
+
+        // confirmed in the next block, and 3 blocks after being seen
+        let txs = [(seen_after(10), 11), rescanned, (seen_after(12), 15)];
+        assert_eq!(
+            avg_confirmation_blocks(&txs, block_time).unwrap(),
+            Some(2.0)
+        );
+
+        // seen before the genesis block
+        let txs = [(0, 2)];
+        assert_eq!(
+            avg_confirmation_blocks(&txs, block_time).unwrap(),
+            Some(3.0)
+        );
+    }
+}
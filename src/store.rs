@@ -1,12 +1,17 @@
 use std::cmp::Ordering;
 use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem::size_of;
+use std::ops::Bound;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use bitcoin::{Address, OutPoint, Txid};
 
+use crate::error::{OptionExt, Result};
 use crate::types::{MempoolEntry, ScriptHash, TxStatus};
+use crate::util::descriptor::Checksum;
 use crate::util::{remove_if, xpub::Bip32Origin};
+use crate::wal::WalEntry;
 use crate::wallet::KeyOrigin;
 
 #[cfg(feature = "track-spends")]
@@ -19,6 +24,9 @@ pub struct MemoryStore {
     mempool: HashMap<Txid, Option<MempoolEntry>>,
     #[cfg(feature = "track-spends")]
     txo_spends: HashMap<OutPoint, InPoint>,
+    // mutations recorded since the last take_journal(), for the write-ahead log (when enabled)
+    #[serde(skip)]
+    journal: Option<Vec<WalEntry>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -46,6 +54,8 @@ pub struct TxEntry {
     pub status: TxStatus,
     pub funding: HashMap<u32, FundingInfo>,
     pub spending: HashMap<u32, SpendingInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<Txid>,
 }
 
 impl TxEntry {
@@ -54,8 +64,15 @@ impl TxEntry {
             status,
             funding: HashMap::new(),
             spending: HashMap::new(),
+            replaces: None,
         }
     }
+    /// The net change to the wallet's balance inflicted by this transaction
+    pub fn balance_change(&self) -> i64 {
+        let funding_sum = self.funding.values().map(|f| f.1).sum::<u64>();
+        let spending_sum = self.spending.values().map(|s| s.2).sum::<u64>();
+        funding_sum as i64 - spending_sum as i64
+    }
     pub fn scripthashes(&self) -> HashSet<&ScriptHash> {
         let funding_scripthashes = self.funding.iter().map(|(_, f)| &f.0);
         let spending_scripthashes = self.spending.iter().map(|(_, s)| &s.0);
@@ -63,10 +80,10 @@ impl TxEntry {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FundingInfo(pub ScriptHash, pub u64);
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpendingInfo(pub ScriptHash, pub OutPoint, pub u64);
 
 impl MemoryStore {
@@ -74,6 +91,22 @@ impl MemoryStore {
         Default::default()
     }
 
+    /// Start recording index mutations, to be collected with `take_journal()`
+    pub fn enable_journal(&mut self) {
+        self.journal.get_or_insert_with(Vec::new);
+    }
+
+    /// Take the index mutations recorded since the last call
+    pub fn take_journal(&mut self) -> Vec<WalEntry> {
+        self.journal.as_mut().map_or_else(Vec::new, std::mem::take)
+    }
+
+    fn journal(&mut self, make_entry: impl FnOnce() -> WalEntry) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(make_entry());
+        }
+    }
+
     pub fn index_scripthash(
         &mut self,
         scripthash: &ScriptHash,
@@ -112,6 +145,7 @@ impl MemoryStore {
                 address,
                 origin
             );
+            self.journal(|| WalEntry::Scripthash(*scripthash, origin.to_label(), address.clone()));
         }
 
         !existed
@@ -137,6 +171,7 @@ impl MemoryStore {
             });
 
         if updated {
+            self.journal(|| WalEntry::Tx(*txid, status));
             match (status_change, status) {
                 // update existing transactions with an updated confirmation status
                 (Some(old_status), new_status) => {
@@ -163,6 +198,7 @@ impl MemoryStore {
         funding_info: FundingInfo,
     ) -> bool {
         trace!("index tx output {}:{}: {:?}", txid, vout, funding_info);
+        let amount = funding_info.1;
         let mut added = None;
 
         {
@@ -177,6 +213,7 @@ impl MemoryStore {
         }
 
         if let Some((scripthash, status)) = added {
+            self.journal(|| WalEntry::Funding(*txid, vout, FundingInfo(scripthash, amount)));
             self.index_history_entry(&scripthash, HistoryEntry::new(*txid, status));
             true
         } else {
@@ -187,6 +224,7 @@ impl MemoryStore {
     // index the full set of spending inputs for this transaction
     pub fn index_tx_inputs_spending(&mut self, txid: &Txid, spending: HashMap<u32, SpendingInfo>) {
         trace!("index new tx inputs spends {}: {:?}", txid, spending);
+        self.journal(|| WalEntry::Spending(*txid, spending.clone()));
 
         let (status, added_scripthashes) = {
             // the tx must already exists by now
@@ -204,6 +242,14 @@ impl MemoryStore {
         }
     }
 
+    // record that the transaction replaced a prior wallet transaction that spent the same inputs
+    pub fn index_tx_replacement(&mut self, txid: &Txid, replaced_txid: Txid) {
+        trace!("index tx replacement {} replacing {}", txid, replaced_txid);
+        self.journal(|| WalEntry::Replacement(*txid, replaced_txid));
+        // the tx must already exists by now
+        self.transactions.get_mut(txid).unwrap().replaces = Some(replaced_txid);
+    }
+
     fn index_history_entry(&mut self, scripthash: &ScriptHash, txhist: HistoryEntry) -> bool {
         trace!(
             "index history entry: scripthash={} txid={} status={:?}",
@@ -233,6 +279,7 @@ impl MemoryStore {
             spent_prevout,
             spending_input
         );
+        self.journal(|| WalEntry::TxoSpend(spent_prevout, spending_input.txid, spending_input.vin));
 
         let was_unspent = self
             .txo_spends
@@ -283,6 +330,7 @@ impl MemoryStore {
         // XXX should replaced transactions be kept around instead of purged entirely?
         if let Some(old_entry) = self.transactions.remove(txid) {
             info!("purge tx {:?}", txid);
+            self.journal(|| WalEntry::Purge(*txid));
 
             if old_entry.status.is_unconfirmed() {
                 assert!(self.mempool.remove(txid).is_some());
@@ -317,6 +365,11 @@ impl MemoryStore {
         }
     }
 
+    /// Get a reference to the mempool.
+    pub fn mempool(&self) -> &HashMap<Txid, Option<MempoolEntry>> {
+        &self.mempool
+    }
+
     /// Get a mutable reference to the mempool.
     pub fn mempool_mut(&mut self) -> &mut HashMap<Txid, Option<MempoolEntry>> {
         &mut self.mempool
@@ -362,6 +415,10 @@ impl MemoryStore {
         self.transactions.get(txid)
     }
 
+    pub fn tx_entries(&self) -> impl Iterator<Item = (&Txid, &TxEntry)> {
+        self.transactions.iter()
+    }
+
     pub fn get_tx_status(&self, txid: &Txid) -> Option<TxStatus> {
         Some(self.transactions.get(txid)?.status)
     }
@@ -400,12 +457,279 @@ impl MemoryStore {
         entries
     }
 
+    /// Get a page of up to `limit` history entries of the wallet's scripthashes following the
+    /// `after` entry (exclusive), ordered with oldest first. Also returns whether there are more
+    /// entries beyond the returned page. Pages hold at least one entry, as an empty page would
+    /// have no last entry to continue from.
+    pub fn get_wallet_history_page(
+        &self,
+        checksum: &Checksum,
+        after: Option<&HistoryEntry>,
+        limit: usize,
+    ) -> (Vec<&HistoryEntry>, bool) {
+        let limit = limit.max(1);
+        let mut entries = self
+            .wallet_script_entries(checksum)
+            .flat_map(|script_entry| match after {
+                Some(after) => script_entry
+                    .history
+                    .range((Bound::Excluded(after), Bound::Unbounded))
+                    .take(limit + 1)
+                    .collect::<Vec<_>>(),
+                None => script_entry.history.iter().take(limit + 1).collect(),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        entries.dedup();
+
+        let has_more = entries.len() > limit;
+        entries.truncate(limit);
+        (entries, has_more)
+    }
+
+    /// Get a page of the scripthash history entries confirmed at `from_height` or later (and all
+    /// unconfirmed ones), ordered with oldest first. Returns the height to fetch the next page
+    /// from, if there are more entries.
+    ///
+    /// Pages hold up to `count` entries, but are extended to never split the entries confirmed in
+    /// the same block (or the unconfirmed ones) across pages.
+    pub fn get_history_page(
+        &self,
+        scripthash: &ScriptHash,
+        from_height: u32,
+        count: usize,
+    ) -> (Vec<&HistoryEntry>, Option<u32>) {
+        let history = some_or_ret!(self.get_history(scripthash), (vec![], None));
+        let count = count.max(1);
+        let mut entries = history
+            .iter()
+            .skip_while(|entry| entry.status < TxStatus::Confirmed(from_height))
+            .peekable();
+
+        let mut page: Vec<&HistoryEntry> = vec![];
+        while let Some(entry) = entries.peek() {
+            // once full, only finish the current block before ending the page
+            if page.len() >= count && page.last().map_or(true, |last| last.status != entry.status) {
+                break;
+            }
+            page.push(entries.next().unwrap());
+        }
+
+        let next_height = match (page.last(), entries.peek()) {
+            (
+                Some(HistoryEntry {
+                    status: TxStatus::Confirmed(last_height),
+                    ..
+                }),
+                Some(_),
+            ) => Some(last_height + 1),
+            _ => None,
+        };
+        (page, next_height)
+    }
+
+    /// Get the most recent history entry of the wallet's scripthashes, if any
+    pub fn get_wallet_last_history(&self, checksum: &Checksum) -> Option<&HistoryEntry> {
+        self.wallet_script_entries(checksum)
+            .filter_map(|script_entry| script_entry.history.iter().next_back())
+            .max()
+    }
+
+    /// Get the full history of the wallet's scripthashes, ordered with oldest first
+    pub fn get_wallet_history(&self, checksum: &Checksum) -> Vec<&HistoryEntry> {
+        let mut entries = self
+            .wallet_script_entries(checksum)
+            .flat_map(|script_entry| script_entry.history.iter())
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        entries.dedup();
+        entries
+    }
+
+    /// Get the derivation index and address of the wallet's scripthashes that have any history
+    pub fn get_wallet_used_addresses(&self, checksum: &Checksum) -> Vec<(u32, Address)> {
+        self.wallet_script_entries(checksum)
+            .filter(|script_entry| !script_entry.history.is_empty())
+            .filter_map(|script_entry| match script_entry.origin {
+                KeyOrigin::Descriptor(_, index) => Some((index, script_entry.address.clone())),
+                KeyOrigin::Standalone => None,
+            })
+            .collect()
+    }
+
+    fn wallet_script_entries<'a: 'c, 'c>(
+        &'a self,
+        checksum: &'c Checksum,
+    ) -> impl Iterator<Item = &'a ScriptEntry> + 'c {
+        self.scripthashes
+            .values()
+            .filter(move |script_entry| match &script_entry.origin {
+                KeyOrigin::Descriptor(entry_checksum, _) => entry_checksum == checksum,
+                KeyOrigin::Standalone => false,
+            })
+    }
+
+    /// Get the (earliest, latest) block heights with confirmed activity, or None if there's none
+    pub fn get_activity_span(&self) -> Option<(u32, u32)> {
+        let heights = self
+            .transactions
+            .values()
+            .filter_map(|tx_entry| match tx_entry.status {
+                TxStatus::Confirmed(height) => Some(height),
+                TxStatus::Unconfirmed | TxStatus::Conflicted => None,
+            });
+        heights.fold(None, |span, height| match span {
+            None => Some((height, height)),
+            Some((first, last)) => Some((first.min(height), last.max(height))),
+        })
+    }
+
+    /// Take a snapshot of the indexed unspent outputs and transactions
+    pub fn snapshot(&self) -> IndexerSnapshot {
+        let spent: HashSet<OutPoint> = self
+            .transactions
+            .values()
+            .flat_map(|tx_entry| tx_entry.spending.values().map(|s| s.1))
+            .collect();
+
+        let utxos = self
+            .transactions
+            .iter()
+            .flat_map(|(txid, tx_entry)| {
+                tx_entry
+                    .funding
+                    .iter()
+                    .map(move |(vout, funding)| (OutPoint::new(*txid, *vout), funding.clone()))
+            })
+            .filter(|(outpoint, _)| !spent.contains(outpoint))
+            .collect();
+
+        let txs = self
+            .transactions
+            .iter()
+            .map(|(txid, tx_entry)| (*txid, tx_entry.status))
+            .collect();
+
+        IndexerSnapshot { utxos, txs }
+    }
+
     pub fn stats(&self) -> StoreStats {
         StoreStats {
             transaction_count: self.transactions.len(),
             scripthash_count: self.scripthashes.len(),
         }
     }
+
+    /// Get a rough estimate of the memory used by the index, in bytes. This accounts for the
+    /// tracked scripthashes and their history, the transactions and their funding/spending
+    /// entries (making up the utxo set) and the mempool, but not for the allocator's overhead.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let scripthashes = map_bytes(&self.scripthashes)
+            + self
+                .scripthashes
+                .values()
+                .map(|entry| {
+                    entry.address.script_pubkey().len()
+                        // BTreeSet nodes hold up to 11 entries, assume they're half full
+                        + entry.history.len() * size_of::<HistoryEntry>() * 2
+                })
+                .sum::<usize>();
+
+        let transactions = map_bytes(&self.transactions)
+            + self
+                .transactions
+                .values()
+                .map(|entry| map_bytes(&entry.funding) + map_bytes(&entry.spending))
+                .sum::<usize>();
+
+        #[allow(unused_mut)]
+        let mut total = scripthashes + transactions + map_bytes(&self.mempool);
+        #[cfg(feature = "track-spends")]
+        {
+            total += map_bytes(&self.txo_spends);
+        }
+        total
+    }
+
+    /// Re-apply a mutation read back from the write-ahead log
+    pub fn apply_wal_entry(&mut self, entry: WalEntry) -> Result<()> {
+        match entry {
+            WalEntry::Scripthash(scripthash, label, address) => {
+                let origin = KeyOrigin::from_label(&label).or_err("invalid wal key origin")?;
+                self.index_scripthash(&scripthash, &origin, &address);
+            }
+            WalEntry::Tx(txid, status) => {
+                self.upsert_tx(&txid, status);
+            }
+            WalEntry::Funding(txid, vout, funding_info) => {
+                ensure!(
+                    self.transactions.contains_key(&txid),
+                    "wal funding for unknown tx"
+                );
+                self.index_tx_output_funding(&txid, vout, funding_info);
+            }
+            WalEntry::Spending(txid, spending) => {
+                ensure!(
+                    self.transactions.contains_key(&txid),
+                    "wal spending for unknown tx"
+                );
+                self.index_tx_inputs_spending(&txid, spending);
+            }
+            WalEntry::Replacement(txid, replaced_txid) => {
+                ensure!(
+                    self.transactions.contains_key(&txid),
+                    "wal replacement for unknown tx"
+                );
+                self.index_tx_replacement(&txid, replaced_txid);
+            }
+            #[cfg(feature = "track-spends")]
+            WalEntry::TxoSpend(prevout, txid, vin) => {
+                self.index_txo_spend(prevout, InPoint::new(txid, vin));
+            }
+            #[cfg(not(feature = "track-spends"))]
+            WalEntry::TxoSpend(..) => (),
+            WalEntry::Purge(txid) => {
+                self.purge_tx(&txid);
+            }
+            // handled by the indexer
+            WalEntry::SyncedTip(_) | WalEntry::FrozenTxo(..) | WalEntry::ImmatureCoinbase(_) => (),
+        }
+        Ok(())
+    }
+
+    /// Get the list of mutations that rebuilds the current state from an empty store,
+    /// used to compact the write-ahead log
+    pub fn wal_entries(&self) -> Vec<WalEntry> {
+        let mut entries: Vec<WalEntry> = self
+            .scripthashes
+            .iter()
+            .map(|(scripthash, script_entry)| {
+                let label = script_entry.origin.to_label();
+                WalEntry::Scripthash(*scripthash, label, script_entry.address.clone())
+            })
+            .collect();
+
+        for (txid, tx_entry) in &self.transactions {
+            entries.push(WalEntry::Tx(*txid, tx_entry.status));
+            for (vout, funding_info) in &tx_entry.funding {
+                entries.push(WalEntry::Funding(*txid, *vout, funding_info.clone()));
+            }
+            if !tx_entry.spending.is_empty() {
+                entries.push(WalEntry::Spending(*txid, tx_entry.spending.clone()));
+            }
+            if let Some(replaced_txid) = tx_entry.replaces {
+                entries.push(WalEntry::Replacement(*txid, replaced_txid));
+            }
+        }
+
+        #[cfg(feature = "track-spends")]
+        for (prevout, spending_input) in &self.txo_spends {
+            let (txid, vin) = (spending_input.txid, spending_input.vin);
+            entries.push(WalEntry::TxoSpend(*prevout, txid, vin));
+        }
+
+        entries
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -457,6 +781,11 @@ impl ScriptInfo {
     }
 }
 
+// The memory allocated by a HashMap's table, including one byte of control metadata per bucket
+fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
 impl Ord for HistoryEntry {
     fn cmp(&self, other: &HistoryEntry) -> Ordering {
         self.status
@@ -475,3 +804,260 @@ pub struct StoreStats {
     pub transaction_count: usize,
     pub scripthash_count: usize,
 }
+
+/// A point-in-time snapshot of the index state, for debugging unexpected changes
+#[derive(Debug, Clone)]
+pub struct IndexerSnapshot {
+    pub utxos: HashMap<OutPoint, FundingInfo>,
+    pub txs: HashMap<Txid, TxStatus>,
+}
+
+/// The differences between two index snapshots, with the balance deltas of affected scripthashes
+#[derive(Debug, Default, Serialize)]
+pub struct StateDiff {
+    pub added_utxos: Vec<OutPoint>,
+    pub removed_utxos: Vec<OutPoint>,
+    pub new_txs: Vec<Txid>,
+    pub dropped_txs: Vec<Txid>,
+    pub balance_deltas: HashMap<ScriptHash, i64>,
+}
+
+impl IndexerSnapshot {
+    /// Get the changes that took place going from this snapshot to the `other` (more recent) one
+    pub fn diff(&self, other: &IndexerSnapshot) -> StateDiff {
+        let mut diff = StateDiff::default();
+
+        for (outpoint, FundingInfo(scripthash, amount)) in &other.utxos {
+            if !self.utxos.contains_key(outpoint) {
+                diff.added_utxos.push(*outpoint);
+                *diff.balance_deltas.entry(*scripthash).or_default() += *amount as i64;
+            }
+        }
+        for (outpoint, FundingInfo(scripthash, amount)) in &self.utxos {
+            if !other.utxos.contains_key(outpoint) {
+                diff.removed_utxos.push(*outpoint);
+                *diff.balance_deltas.entry(*scripthash).or_default() -= *amount as i64;
+            }
+        }
+        diff.new_txs = other
+            .txs
+            .keys()
+            .filter(|txid| !self.txs.contains_key(*txid))
+            .cloned()
+            .collect();
+        diff.dropped_txs = self
+            .txs
+            .keys()
+            .filter(|txid| !other.txs.contains_key(*txid))
+            .cloned()
+            .collect();
+
+        diff.balance_deltas.retain(|_, delta| *delta != 0);
+        diff.added_utxos.sort_unstable();
+        diff.removed_utxos.sort_unstable();
+        diff.new_txs.sort_unstable();
+        diff.dropped_txs.sort_unstable();
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::hex::FromHex;
+
+    #[test]
+    fn test_wallet_history_page() {
+        let mut store = MemoryStore::default();
+        let checksum: Checksum = "xjm8w0el".parse().unwrap();
+        let other_checksum: Checksum = "k38panl4".parse().unwrap();
+
+        let addresses = [
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+        ];
+        let origins = [
+            KeyOrigin::Descriptor(checksum.clone(), 0),
+            KeyOrigin::Descriptor(checksum.clone(), 1),
+            KeyOrigin::Descriptor(other_checksum, 0),
+        ];
+        let scripthashes: Vec<ScriptHash> = addresses
+            .iter()
+            .zip(origins.iter())
+            .map(|(address, origin)| {
+                let address: Address = address.parse().unwrap();
+                let scripthash = ScriptHash::from(&address);
+                store.index_scripthash(&scripthash, origin, &address);
+                scripthash
+            })
+            .collect();
+
+        // (txid, status, funded scripthash indexes)
+        let txs = [
+            (1, TxStatus::Confirmed(100), vec![0]),
+            (2, TxStatus::Confirmed(100), vec![1]),
+            (3, TxStatus::Confirmed(105), vec![0, 1]),
+            (4, TxStatus::Confirmed(106), vec![2]),
+            (5, TxStatus::Confirmed(110), vec![1]),
+            (6, TxStatus::Unconfirmed, vec![0]),
+        ];
+        for (n, status, funded) in &txs {
+            let txid = Txid::from_hex(&format!("{:02x}", n).repeat(32)).unwrap();
+            store.upsert_tx(&txid, *status);
+            for (vout, i) in funded.iter().enumerate() {
+                let funding_info = FundingInfo(scripthashes[*i], 1000);
+                store.index_tx_output_funding(&txid, vout as u32, funding_info);
+            }
+        }
+
+        let (first_page, has_more) = store.get_wallet_history_page(&checksum, None, 3);
+        assert!(has_more);
+        let (second_page, has_more) =
+            store.get_wallet_history_page(&checksum, first_page.last().cloned(), 3);
+        assert!(!has_more);
+
+        // all of the wallet's transactions show up exactly once, in order
+        let paged_txids: Vec<Txid> = first_page
+            .iter()
+            .chain(second_page.iter())
+            .map(|txhist| txhist.txid)
+            .collect();
+        let expected_txids: Vec<Txid> = [1, 2, 3, 5, 6]
+            .iter()
+            .map(|n| Txid::from_hex(&format!("{:02x}", n).repeat(32)).unwrap())
+            .collect();
+        assert_eq!(first_page.len(), 3);
+        assert_eq!(paged_txids, expected_txids);
+
+        // a zero limit is raised to a single entry
+        let (zero_page, has_more) = store.get_wallet_history_page(&checksum, None, 0);
+        assert!(has_more);
+        assert_eq!(zero_page, vec![first_page[0]]);
+    }
+
+    #[test]
+    fn test_activity_span() {
+        let mut store = MemoryStore::default();
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        assert_eq!(store.get_activity_span(), None);
+
+        let txid = |n: u8| Txid::from_hex(&format!("{:02x}", n).repeat(32)).unwrap();
+        let statuses = &[
+            TxStatus::Unconfirmed,
+            TxStatus::Confirmed(150),
+            TxStatus::Confirmed(120),
+            TxStatus::Confirmed(150),
+        ];
+        for (n, status) in statuses.iter().enumerate() {
+            let txid = txid(n as u8 + 1);
+            store.upsert_tx(&txid, *status);
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1000));
+            if n == 0 {
+                // unconfirmed activity alone doesn't count
+                assert_eq!(store.get_activity_span(), None);
+            }
+        }
+        assert_eq!(store.get_activity_span(), Some((120, 150)));
+    }
+
+    #[test]
+    fn test_history_page() {
+        let mut store = MemoryStore::default();
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let txid = |n: u8| Txid::from_hex(&format!("{:02x}", n).repeat(32)).unwrap();
+        let statuses = &[
+            TxStatus::Confirmed(100),
+            TxStatus::Confirmed(101),
+            TxStatus::Confirmed(101),
+            TxStatus::Confirmed(101),
+            TxStatus::Confirmed(105),
+            TxStatus::Unconfirmed,
+            TxStatus::Unconfirmed,
+        ];
+        for (n, status) in statuses.iter().enumerate() {
+            let txid = txid(n as u8 + 1);
+            store.upsert_tx(&txid, *status);
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1000));
+        }
+        let page_txids = |from_height, count| {
+            let (page, next_height) = store.get_history_page(&scripthash, from_height, count);
+            let txids: Vec<Txid> = page.iter().map(|txhist| txhist.txid).collect();
+            (txids, next_height)
+        };
+        let txids = |ns: &[u8]| ns.iter().map(|n| txid(*n)).collect::<Vec<Txid>>();
+
+        // pages are extended to include the rest of the last block's transactions
+        assert_eq!(page_txids(0, 2), (txids(&[1, 2, 3, 4]), Some(102)));
+        assert_eq!(page_txids(102, 2), (txids(&[5, 6, 7]), None));
+        assert_eq!(page_txids(101, 4), (txids(&[2, 3, 4, 5]), Some(106)));
+        // unconfirmed transactions are always included in the last page
+        assert_eq!(page_txids(106, 1), (txids(&[6, 7]), None));
+        assert_eq!(page_txids(0, 100), (txids(&[1, 2, 3, 4, 5, 6, 7]), None));
+
+        let other_address: Address = "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy".parse().unwrap();
+        let unknown = ScriptHash::from(&other_address);
+        assert_eq!(store.get_history_page(&unknown, 0, 10), (vec![], None));
+    }
+
+    #[test]
+    fn test_estimated_memory_bytes() {
+        let mut store = MemoryStore::default();
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        let before = store.estimated_memory_bytes();
+        assert!(before > 0);
+
+        for n in 1..=50u8 {
+            let txid = Txid::from_hex(&format!("{:02x}", n).repeat(32)).unwrap();
+            store.upsert_tx(&txid, TxStatus::Confirmed(n as u32));
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1000));
+        }
+        let after = store.estimated_memory_bytes();
+        // at least the size of the transaction entries and their history entries
+        let min_growth = 50 * (size_of::<(Txid, TxEntry)>() + size_of::<HistoryEntry>());
+        assert!(after >= before + min_growth);
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut store = MemoryStore::default();
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        let origin = KeyOrigin::Descriptor("xjm8w0el".parse().unwrap(), 0);
+        store.index_scripthash(&scripthash, &origin, &address);
+
+        let funding_txid = Txid::from_hex(&"01".repeat(32)).unwrap();
+        store.upsert_tx(&funding_txid, TxStatus::Confirmed(100));
+        store.index_tx_output_funding(&funding_txid, 0, FundingInfo(scripthash, 5000));
+        let before = store.snapshot();
+
+        // a payment spending the existing utxo and sending back change, plus a new incoming payment
+        let spending_txid = Txid::from_hex(&"02".repeat(32)).unwrap();
+        let prevout = OutPoint::new(funding_txid, 0);
+        store.upsert_tx(&spending_txid, TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&spending_txid, 1, FundingInfo(scripthash, 3000));
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 5000))];
+        store.index_tx_inputs_spending(&spending_txid, spending.into_iter().collect());
+        let after = store.snapshot();
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added_utxos, vec![OutPoint::new(spending_txid, 1)]);
+        assert_eq!(diff.removed_utxos, vec![prevout]);
+        assert_eq!(diff.new_txs, vec![spending_txid]);
+        assert!(diff.dropped_txs.is_empty());
+        assert_eq!(diff.balance_deltas.get(&scripthash), Some(&-2000));
+
+        // the reverse diff
+        let diff = after.diff(&before);
+        assert_eq!(diff.removed_utxos, vec![OutPoint::new(spending_txid, 1)]);
+        assert_eq!(diff.dropped_txs, vec![spending_txid]);
+        assert_eq!(diff.balance_deltas.get(&scripthash), Some(&2000));
+    }
+}
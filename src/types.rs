@@ -1,13 +1,20 @@
 use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicBool};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use bitcoin::{Address, BlockHash, Txid};
+use bitcoin::{Address, BlockHash, Script, Txid};
 use bitcoin_hashes::{sha256, Hash};
 use bitcoincore_rpc::json::GetMempoolEntryResult;
 
+use crate::error::OptionExt;
+use crate::util::descriptor::Checksum;
+
 pub use crate::util::bitcoincore_ext::RescanSince;
 
+// The number of confirmations needed before coinbase outputs can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+
 hash_newtype!(
     ScriptHash,
     sha256::Hash,
@@ -18,7 +25,13 @@ hash_newtype!(
 
 impl From<&Address> for ScriptHash {
     fn from(address: &Address) -> Self {
-        ScriptHash::hash(&address.script_pubkey().into_bytes())
+        ScriptHash::from(&address.script_pubkey())
+    }
+}
+
+impl From<&Script> for ScriptHash {
+    fn from(script: &Script) -> Self {
+        ScriptHash::hash(script.as_bytes())
     }
 }
 
@@ -31,7 +44,7 @@ impl From<Address> for ScriptHash {
 #[cfg(feature = "electrum")]
 hash_newtype!(StatusHash, sha256::Hash, 32, doc = "The status hash.");
 
-#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub struct BlockId(pub u32, pub BlockHash);
 
 impl std::fmt::Display for BlockId {
@@ -60,6 +73,28 @@ pub enum ScriptType {
     P2pkh,
     P2wpkh,
     P2shP2wpkh,
+    P2tr,
+    // multisig script types, only used as a hint for SLIP 132 cosigner keys
+    P2wsh,
+    P2shP2wsh,
+}
+
+impl ScriptType {
+    pub fn is_multisig(&self) -> bool {
+        matches!(self, ScriptType::P2wsh | ScriptType::P2shP2wsh)
+    }
+
+    /// The purpose of the BIP 44 (p2pkh), BIP 49 (p2sh-p2wpkh), BIP 84 (p2wpkh) or BIP 86 (p2tr)
+    /// account structure conventionally used for this script type. Not available for multisig.
+    pub fn bip_purpose(&self) -> Option<u32> {
+        match self {
+            ScriptType::P2pkh => Some(44),
+            ScriptType::P2shP2wpkh => Some(49),
+            ScriptType::P2wpkh => Some(84),
+            ScriptType::P2tr => Some(86),
+            ScriptType::P2wsh | ScriptType::P2shP2wsh => None,
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Copy, Hash)]
@@ -114,6 +149,25 @@ impl serde::Serialize for TxStatus {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for TxStatus {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<TxStatus, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match Option::<i64>::deserialize(deserializer)? {
+            Some(-1) => Ok(TxStatus::Conflicted),
+            None => Ok(TxStatus::Unconfirmed),
+            Some(height) if height >= 0 && height <= u32::MAX as i64 => {
+                Ok(TxStatus::Confirmed(height as u32))
+            }
+            Some(other) => Err(serde::de::Error::custom(format!(
+                "invalid tx status {}, expecting block height, null or -1",
+                other
+            ))),
+        }
+    }
+}
+
 impl Ord for TxStatus {
     fn cmp(&self, other: &TxStatus) -> Ordering {
         match (self, other) {
@@ -148,6 +202,8 @@ pub struct MempoolEntry {
     pub ancestor_fee: u64,
     /// Whether this transaction could be replaced due to BIP125 (replace-by-fee)
     pub bip125_replaceable: bool,
+    /// The time this transaction was first seen entering the mempool, as a unix timestamp
+    pub time: u64,
 }
 
 impl MempoolEntry {
@@ -169,6 +225,196 @@ impl MempoolEntry {
     }
 }
 
+/// How immature coinbase outputs are accounted for in the wallet balances
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImmatureCoinbaseMode {
+    /// Include in the confirmed balance, and report separately as the immature balance (the default)
+    Include,
+    /// Exclude from the balances entirely
+    Exclude,
+}
+
+impl std::str::FromStr for ImmatureCoinbaseMode {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        Ok(match s {
+            "include" => ImmatureCoinbaseMode::Include,
+            "exclude" => ImmatureCoinbaseMode::Exclude,
+            _ => bail!(
+                "invalid immature coinbase mode '{}', expecting 'include' or 'exclude'",
+                s
+            ),
+        })
+    }
+}
+
+/// How amounts are represented in serialized query results
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmountFormat {
+    /// As integer satoshis (the default), which avoids floating point precision issues
+    Sats,
+    /// As BTC decimal strings with 8 decimal places, like "0.00150000"
+    Btc,
+}
+
+impl std::str::FromStr for AmountFormat {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        Ok(match s {
+            "sats" => AmountFormat::Sats,
+            "btc" => AmountFormat::Btc,
+            _ => bail!("invalid amount format '{}', expecting 'sats' or 'btc'", s),
+        })
+    }
+}
+
+// serde provides no way to pass options through to the serializers, so the format is kept
+// as a process-wide setting
+static AMOUNTS_AS_BTC: AtomicBool = AtomicBool::new(false);
+
+/// Set the format used for serializing amounts in query results, for all `Query` instances
+pub fn set_amount_format(format: AmountFormat) {
+    AMOUNTS_AS_BTC.store(format == AmountFormat::Btc, atomic::Ordering::Relaxed);
+}
+
+pub fn amount_format() -> AmountFormat {
+    if AMOUNTS_AS_BTC.load(atomic::Ordering::Relaxed) {
+        AmountFormat::Btc
+    } else {
+        AmountFormat::Sats
+    }
+}
+
+/// Serialize a satoshi amount using the configured `AmountFormat`, for use with `serialize_with`
+pub fn serialize_amount<T, S>(amount: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Copy + Into<i128>,
+    S: serde::Serializer,
+{
+    serialize_amount_as((*amount).into(), amount_format(), serializer)
+}
+
+pub fn serialize_opt_amount<T, S>(amount: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Copy + Into<i128>,
+    S: serde::Serializer,
+{
+    match amount {
+        Some(amount) => serialize_amount(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_amount_as<S: serde::Serializer>(
+    sats: i128,
+    format: AmountFormat,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match format {
+        AmountFormat::Sats if sats < 0 => serializer.serialize_i64(sats as i64),
+        AmountFormat::Sats => serializer.serialize_u64(sats as u64),
+        AmountFormat::Btc => {
+            let sign = if sats < 0 { "-" } else { "" };
+            let abs = sats.abs();
+            serializer.collect_str(&format_args!(
+                "{}{}.{:08}",
+                sign,
+                abs / 100_000_000,
+                abs % 100_000_000
+            ))
+        }
+    }
+}
+
+/// A satoshi amount that serializes using the configured `AmountFormat`, for amounts that
+/// are returned on their own rather than as struct fields
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FormattedAmount<T>(pub T);
+
+impl<T: Copy + Into<i128>> Serialize for FormattedAmount<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_amount(&self.0, serializer)
+    }
+}
+
+/// An explicit (inclusive) range of derivation indexes to track for the wallet identified by
+/// `checksum`, instead of extending the watched range by the gap limit. Encoded as
+/// `<checksum>:<start>-<end>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorRange {
+    pub checksum: Checksum,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl std::str::FromStr for DescriptorRange {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        let parse = || -> Option<(&str, &str, &str)> {
+            let mut parts = s.splitn(2, ':');
+            let checksum = parts.next()?;
+            let mut range = parts.next()?.splitn(2, '-');
+            Some((checksum, range.next()?, range.next()?))
+        };
+        let (checksum, start, end) = parse().or_err(format!(
+            "invalid descriptor range '{}', expecting <checksum>:<start>-<end>",
+            s
+        ))?;
+        let (start, end) = (start.parse()?, end.parse()?);
+        ensure!(
+            start <= end,
+            "invalid descriptor range '{}', start > end",
+            s
+        );
+        Ok(DescriptorRange {
+            checksum: checksum.parse()?,
+            start,
+            end,
+        })
+    }
+}
+
+// Deserialize using the FromStr implementation
+impl<'de> serde::Deserialize<'de> for DescriptorRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A coarse confirmation status for UI rendering (e.g. color coding)
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfStatus {
+    /// Not confirmed yet
+    Unconfirmed,
+    /// Confirmed, but with fewer than the configured number of settled confirmations
+    Pending,
+    /// Confirmed with at least the configured number of settled confirmations
+    Confirmed,
+}
+
+impl ConfStatus {
+    // coinbase maturity is not considered here, see Query::confirmations_until_settled()
+    pub fn from_confirmations(confirmations: u32, settled_confs: u32) -> Self {
+        if confirmations == 0 {
+            ConfStatus::Unconfirmed
+        } else if confirmations < settled_confs {
+            ConfStatus::Pending
+        } else {
+            ConfStatus::Confirmed
+        }
+    }
+}
+
 impl From<GetMempoolEntryResult> for MempoolEntry {
     fn from(entry: GetMempoolEntryResult) -> Self {
         Self {
@@ -177,6 +423,44 @@ impl From<GetMempoolEntryResult> for MempoolEntry {
             ancestor_vsize: entry.ancestor_size,
             ancestor_fee: entry.fees.ancestor.as_sat(),
             bip125_replaceable: entry.bip125_replaceable,
+            time: entry.time,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::value::Serializer as ValueSerializer;
+
+    #[test]
+    fn test_amount_format() {
+        let serialize =
+            |sats: i128, format| serialize_amount_as(sats, format, ValueSerializer).unwrap();
+
+        assert_eq!(serialize(150_000, AmountFormat::Sats), json!(150_000));
+        assert_eq!(serialize(150_000, AmountFormat::Btc), json!("0.00150000"));
+        assert_eq!(
+            serialize(-2_100_000_000, AmountFormat::Sats),
+            json!(-2_100_000_000i64)
+        );
+        assert_eq!(
+            serialize(-2_100_000_000, AmountFormat::Btc),
+            json!("-21.00000000")
+        );
+        assert_eq!(serialize(0, AmountFormat::Btc), json!("0.00000000"));
+        // the full supply is represented exactly
+        let max_sats = 21_000_000 * 100_000_000;
+        assert_eq!(
+            serialize(max_sats, AmountFormat::Sats),
+            json!(2_100_000_000_000_000u64)
+        );
+        assert_eq!(
+            serialize(max_sats, AmountFormat::Btc),
+            json!("21000000.00000000")
+        );
+
+        assert_eq!("btc".parse::<AmountFormat>().unwrap(), AmountFormat::Btc);
+        assert!("bits".parse::<AmountFormat>().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io::Write;
+use std::net;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Serialize;
+
+use crate::error::{Context, Result};
+use crate::indexer::IndexChange;
+use crate::util::bitcoincore_ext::Progress;
+
+// The number of pending lines a client may lag behind by before getting disconnected
+const CLIENT_QUEUE_SIZE: usize = 1000;
+
+type Clients = Arc<Mutex<Vec<SyncSender<String>>>>;
+
+/// Streams index events and sync progress updates to clients connected over a unix socket,
+/// as newline-delimited JSON. Clients that can't keep up get disconnected.
+pub struct UnixSocketNotifier {
+    _thread: thread::JoinHandle<()>,
+    clients: Clients,
+}
+
+impl UnixSocketNotifier {
+    pub fn start(socket_path: PathBuf) -> Result<Self> {
+        // cleanup socket file from previous run
+        if let Ok(meta) = fs::metadata(&socket_path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(&socket_path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(&socket_path)
+            .with_context(|| format!("failed binding events socket on {:?}", socket_path))?;
+        info!("streaming events on unix socket {:?}", socket_path);
+
+        let clients = Clients::default();
+
+        Ok(Self {
+            _thread: {
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                debug!("events socket client connected");
+                                clients.lock().unwrap().push(spawn_writer(stream));
+                            }
+                            Err(e) => warn!("failed accepting events socket client: {:?}", e),
+                        }
+                    }
+                })
+            },
+            clients,
+        })
+    }
+
+    pub fn send_updates(&self, changelog: &[IndexChange]) {
+        send_lines(&self.clients, changelog);
+    }
+
+    /// Get a progress sender that streams the updates to the socket clients,
+    /// while also forwarding them to `progress_tx` (if provided)
+    pub fn progress_sender(
+        &self,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> mpsc::Sender<Progress> {
+        let (tx, rx) = mpsc::channel::<Progress>();
+        let clients = self.clients.clone();
+        thread::spawn(move || {
+            let mut progress_tx = progress_tx;
+            for progress in rx {
+                send_lines(&clients, &[progress]);
+                // stop forwarding once the receiving end is gone
+                if progress_tx
+                    .as_ref()
+                    .map_or(false, |tx| tx.send(progress).is_err())
+                {
+                    progress_tx = None;
+                }
+            }
+        });
+        tx
+    }
+}
+
+// Queue the serialized items for delivery, dropping disconnected clients and
+// clients whose queue is full
+fn send_lines<T: Serialize>(clients: &Clients, items: &[T]) {
+    let mut clients = clients.lock().unwrap();
+    if clients.is_empty() {
+        return;
+    }
+
+    let lines: Vec<String> = items
+        .iter()
+        .map(|item| serde_json::to_string(item).unwrap())
+        .collect();
+
+    clients.retain(|client| {
+        lines
+            .iter()
+            .all(|line| match client.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("disconnecting slow events socket client");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    debug!("events socket client disconnected");
+                    false
+                }
+            })
+    });
+}
+
+// Spawn a thread writing queued lines to the client. The thread exits once writing fails
+// or when the client is dropped (by dropping the returned sender).
+fn spawn_writer(mut stream: UnixStream) -> SyncSender<String> {
+    let (tx, rx) = mpsc::sync_channel::<String>(CLIENT_QUEUE_SIZE);
+    thread::spawn(move || {
+        for line in rx {
+            if writeln!(stream, "{}", line).is_err() {
+                break;
+            }
+        }
+        stream.shutdown(net::Shutdown::Both).ok();
+    });
+    tx
+}
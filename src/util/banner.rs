@@ -23,7 +23,7 @@ pub fn get_welcome_banner(query: &Query, omit_donation: bool) -> Result<String>
     let peers = rpc.get_peer_info()?;
     let hash_rate_7d = rpc.get_network_hash_ps(Some(1008), None)?;
     let uptime = dur_from_secs(rpc.uptime()?);
-    let tip = rpc.get_block_stats(&rpc.get_best_block_hash()?)?;
+    let tip = rpc.get_block_stats_by_hash(&rpc.get_best_block_hash()?)?;
 
     let est_fee = |target| {
         query
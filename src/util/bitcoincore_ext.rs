@@ -1,14 +1,24 @@
 use serde::{de, Serialize};
 use std::collections::HashMap;
 use std::fmt::{self, Formatter};
-use std::{sync::mpsc, thread, time};
+use std::sync::{mpsc, Mutex};
+use std::{io, thread, time};
 
-use bitcoin::Address;
+use bitcoin::{Address, Network};
 use bitcoincore_rpc::json::{self, ImportMultiRescanSince, ScanningDetails};
+use bitcoincore_rpc::jsonrpc::simple_http;
 use bitcoincore_rpc::{self as rpc, Client, Result as RpcResult, RpcApi};
 
 const WAIT_SYNC_INTERVAL: time::Duration = time::Duration::from_secs(5);
 const WAIT_SCAN_INTERVAL: time::Duration = time::Duration::from_millis(1500);
+const WARMUP_INITIAL_DELAY: time::Duration = time::Duration::from_millis(250);
+const WARMUP_MAX_DELAY: time::Duration = time::Duration::from_secs(10);
+
+// Returned while bitcoind is loading the block index, verifying blocks, etc.
+const RPC_IN_WARMUP: i32 = -28;
+// Returned by getblockstats for pruned blocks, along with the message below
+const RPC_MISC_ERROR: i32 = -1;
+const PRUNED_BLOCK_MESSAGE: &str = "Block not available (pruned data)";
 
 // Extensions for rust-bitcoincore-rpc
 
@@ -28,8 +38,18 @@ pub trait RpcApiExt: RpcApi {
         }
     }
 
+    // whether the wallet is a descriptor wallet. the `descriptors` field was added to getwalletinfo
+    // in Bitcoin Core 0.21.0, older versions only support legacy wallets.
+    fn is_descriptor_wallet(&self) -> RpcResult<bool> {
+        let info: serde_json::Value = self.call("getwalletinfo", &[])?;
+        Ok(info["descriptors"].as_bool().unwrap_or(false))
+    }
+
     // Only supports the fields we're interested in (so not currently upstremable)
-    fn get_block_stats(&self, blockhash: &bitcoin::BlockHash) -> RpcResult<GetBlockStatsResult> {
+    fn get_block_stats_by_hash(
+        &self,
+        blockhash: &bitcoin::BlockHash,
+    ) -> RpcResult<GetBlockStatsResult> {
         let fields = (
             "height",
             "time",
@@ -43,14 +63,101 @@ pub trait RpcApiExt: RpcApi {
         self.call("getblockstats", &[json!(blockhash), json!(fields)])
     }
 
+    // estimate the feerate (in sat/vB) needed to confirm within 1 to `num_blocks` blocks, based on
+    // the median feerate of the `num_blocks` most recent blocks. the estimate for each target is the
+    // lowest median feerate seen among that many of the most recent blocks. blocks with no
+    // transactions other than the coinbase are skipped, as are targets with no data to go by.
+    fn estimate_fee_from_blocks(&self, num_blocks: usize) -> RpcResult<Vec<(u64, f64)>> {
+        let tip_height = self.get_block_count()?;
+        let mut curve = vec![];
+        let mut lowest_median: Option<u64> = None;
+        for (i, height) in (0..=tip_height).rev().take(num_blocks).enumerate() {
+            let stats = self.get_block_stats_by_hash(&self.get_block_hash(height)?)?;
+            if stats.txs > 1 {
+                let median = stats.feerate_percentiles.2;
+                lowest_median = Some(lowest_median.map_or(median, |lowest| lowest.min(median)));
+            }
+            if let Some(lowest_median) = lowest_median {
+                curve.push((i as u64 + 1, lowest_median as f64));
+            }
+        }
+        Ok(curve)
+    }
+
+    // average the median feerate (in sat/vB) of the `num_blocks` most recent blocks, as a baseline of
+    // the feerates actually getting confirmed. blocks with no transactions other than the coinbase are
+    // skipped. returns None if there are no blocks left to average, or if the block stats are
+    // unavailable due to pruning.
+    fn recent_block_median_feerate(&self, num_blocks: u32) -> RpcResult<Option<f32>> {
+        let tip_height = self.get_block_count()?;
+        let mut medians = vec![];
+        for height in (0..=tip_height).rev().take(num_blocks as usize) {
+            let stats = match self.get_block_stats_by_hash(&self.get_block_hash(height)?) {
+                Ok(stats) => stats,
+                Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e)))
+                    if e.code == RPC_MISC_ERROR && e.message == PRUNED_BLOCK_MESSAGE =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            };
+            if stats.txs > 1 {
+                medians.push(stats.feerate_percentiles.2);
+            }
+        }
+        if medians.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            medians.iter().sum::<u64>() as f32 / medians.len() as f32,
+        ))
+    }
+
     // Only supports the fields we're interested in (so not currently upstremable)
     fn get_mempool_info(&self) -> RpcResult<GetMempoolInfoResult> {
         self.call("getmempoolinfo", &[])
     }
 
+    // retry with exponential backoff for as long as bitcoind is warming up. other errors
+    // (including connection failures) are returned immediately.
+    fn wait_warmup(&self) -> RpcResult<()> {
+        let mut delay = WARMUP_INITIAL_DELAY;
+        loop {
+            match self.get_block_count() {
+                Ok(_) => break Ok(()),
+                Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e)))
+                    if e.code == RPC_IN_WARMUP =>
+                {
+                    info!(target: "bwt", "waiting for bitcoind to warm up: {}", e.message);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(WARMUP_MAX_DELAY);
+                }
+                Err(e) => break Err(e),
+            }
+        }
+    }
+
+    // get the progress of the wallet rescan currently in progress, if any. always None for
+    // nodes that don't report the `scanning` status (pre v0.19).
+    fn wallet_scan_progress(&self) -> RpcResult<Option<f32>> {
+        Ok(match self.get_wallet_info()?.scanning {
+            Some(ScanningDetails::Scanning { progress, .. }) => Some(progress),
+            Some(ScanningDetails::NotScanning(_)) | None => None,
+        })
+    }
+
     fn wait_blockchain_sync(
         &self,
         progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> RpcResult<json::GetBlockchainInfoResult> {
+        self.wait_blockchain_sync_every(progress_tx, WAIT_SYNC_INTERVAL)
+    }
+
+    // like wait_blockchain_sync(), polling bitcoind for the sync status every `interval`
+    fn wait_blockchain_sync_every(
+        &self,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+        interval: time::Duration,
     ) -> RpcResult<json::GetBlockchainInfoResult> {
         Ok(loop {
             let info = self.get_blockchain_info()?;
@@ -75,7 +182,7 @@ pub trait RpcApiExt: RpcApi {
                     break info;
                 }
             }
-            thread::sleep(WAIT_SYNC_INTERVAL);
+            thread::sleep(interval);
         })
     }
 
@@ -83,57 +190,210 @@ pub trait RpcApiExt: RpcApi {
         &self,
         progress_tx: Option<mpsc::Sender<Progress>>,
     ) -> RpcResult<json::GetWalletInfoResult> {
-        let start = time::Instant::now();
-        let mut was_scanning = false;
-        Ok(loop {
-            let info = self.get_wallet_info()?;
-            match info.scanning {
-                None => {
-                    warn!("Your bitcoin node does not report the `scanning` status in `getwalletinfo`. It is recommended to upgrade to Bitcoin Core v0.19+ to enable this.");
-                    warn!("This is needed for bwt to wait for scanning to finish before starting up. Starting bwt while the node is scanning may lead to unexpected results. Continuing anyway...");
-                    break info;
+        self.wait_wallet_scan_every(progress_tx, WAIT_SCAN_INTERVAL)
+    }
+
+    // like wait_wallet_scan(), polling bitcoind for the scanning status every `interval`
+    fn wait_wallet_scan_every(
+        &self,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+        interval: time::Duration,
+    ) -> RpcResult<json::GetWalletInfoResult> {
+        Ok(wait_scan(self, progress_tx, interval, None)?.expect("cannot time out"))
+    }
+
+    // like wait_wallet_scan(), but gives up once scanning hasn't completed within `max_duration`,
+    // returning None in this case
+    fn wait_wallet_scan_timeout(
+        &self,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+        max_duration: time::Duration,
+    ) -> RpcResult<Option<json::GetWalletInfoResult>> {
+        wait_scan(self, progress_tx, WAIT_SCAN_INTERVAL, Some(max_duration))
+    }
+}
+
+impl RpcApiExt for Client {}
+
+/// A bitcoind RPC client, optionally rate-limited to avoid starving other users of a shared node
+#[derive(Debug)]
+pub struct RpcClient {
+    client: Client,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl RpcClient {
+    pub fn new(url: String, auth: rpc::Auth) -> RpcResult<Self> {
+        Ok(RpcClient {
+            client: Client::new(&url, auth)?,
+            rate_limiter: None,
+        })
+    }
+
+    /// Limit the rate of RPC calls to `max_calls_per_sec` (or lift the limit with `None`)
+    pub fn set_rate_limit(&mut self, max_calls_per_sec: Option<u32>) {
+        self.rate_limiter = max_calls_per_sec.map(RateLimiter::new);
+    }
+}
+
+impl RpcApi for RpcClient {
+    fn call<T: for<'a> serde::de::Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[serde_json::Value],
+    ) -> RpcResult<T> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire();
+        }
+        self.client.call(cmd, args)
+    }
+}
+
+impl RpcApiExt for RpcClient {}
+
+/// A token bucket rate limiter, allowing bursts of up to one second worth of calls
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    // the number of available tokens (negative when reserved by waiting callers), and the last refill time
+    bucket: Mutex<(f64, time::Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(max_calls_per_sec: u32) -> Self {
+        let rate = max_calls_per_sec.max(1) as f64;
+        RateLimiter {
+            rate,
+            bucket: Mutex::new((rate, time::Instant::now())),
+        }
+    }
+
+    /// Take a token, blocking the current thread until one becomes available
+    pub fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, last_refill) = &mut *bucket;
+            let now = time::Instant::now();
+            let elapsed = now.duration_since(*last_refill).as_secs_f64();
+            *tokens = (*tokens + elapsed * self.rate).min(self.rate) - 1.0;
+            *last_refill = now;
+            // the token is reserved right away, so that waiting callers are served in order
+            if *tokens < 0.0 {
+                time::Duration::from_secs_f64(-*tokens / self.rate)
+            } else {
+                return;
+            }
+        };
+        trace!("rate limiting rpc call for {:?}", wait);
+        thread::sleep(wait);
+    }
+}
+
+// Wait for bitcoind to finish scanning, polling every `interval` and giving up after
+// `max_duration` (if provided). Returns None if the wait timed out.
+fn wait_scan<R: RpcApi>(
+    rpc: &R,
+    progress_tx: Option<mpsc::Sender<Progress>>,
+    interval: time::Duration,
+    max_duration: Option<time::Duration>,
+) -> RpcResult<Option<json::GetWalletInfoResult>> {
+    let start = time::Instant::now();
+    let mut was_scanning = false;
+    loop {
+        let info = rpc.get_wallet_info()?;
+        match info.scanning {
+            None => {
+                warn!("Your bitcoin node does not report the `scanning` status in `getwalletinfo`. It is recommended to upgrade to Bitcoin Core v0.19+ to enable this.");
+                warn!("This is needed for bwt to wait for scanning to finish before starting up. Starting bwt while the node is scanning may lead to unexpected results. Continuing anyway...");
+                return Ok(Some(info));
+            }
+            Some(ScanningDetails::NotScanning(_)) => {
+                // wait_wallet_scan() could be called before scanning actually started,
+                // give it a few seconds to start up before giving up
+                if was_scanning || start.elapsed().as_secs() > 3 {
+                    return Ok(Some(info));
                 }
-                Some(ScanningDetails::NotScanning(_)) => {
-                    // wait_wallet_scan() could be called before scanning actually started,
-                    // give it a few seconds to start up before giving up
-                    if was_scanning || start.elapsed().as_secs() > 3 {
-                        break info;
+            }
+            Some(ScanningDetails::Scanning { progress, duration }) => {
+                was_scanning = true;
+                let duration = duration as u64;
+                let progress_n = progress as f32;
+                let eta = if progress_n > 0.0 {
+                    (duration as f32 / progress_n) as u64 - duration
+                } else {
+                    0
+                };
+
+                info!(target: "bwt",
+                    "waiting for bitcoind to finish scanning [done {:.1}%, running for {}m, eta {}m]",
+                    progress_n * 100.0, duration / 60, eta / 60
+                );
+
+                if let Some(ref progress_tx) = progress_tx {
+                    let progress = Progress::Scan { progress_n, eta };
+                    if progress_tx.send(progress).is_err() {
+                        return Ok(Some(info));
                     }
                 }
-                Some(ScanningDetails::Scanning { progress, duration }) => {
-                    was_scanning = true;
-                    let duration = duration as u64;
-                    let progress_n = progress as f32;
-                    let eta = if progress_n > 0.0 {
-                        (duration as f32 / progress_n) as u64 - duration
-                    } else {
-                        0
-                    };
-
-                    info!(target: "bwt",
-                        "waiting for bitcoind to finish scanning [done {:.1}%, running for {}m, eta {}m]",
-                        progress_n * 100.0, duration / 60, eta / 60
-                    );
-
-                    if let Some(ref progress_tx) = progress_tx {
-                        let progress = Progress::Scan { progress_n, eta };
-                        if progress_tx.send(progress).is_err() {
-                            break info;
-                        }
-                    }
+            }
+        };
+
+        let interval = match max_duration {
+            Some(max_duration) => match max_duration.checked_sub(start.elapsed()) {
+                Some(remaining) if remaining > time::Duration::from_secs(0) => {
+                    remaining.min(interval)
                 }
-            };
-            thread::sleep(WAIT_SCAN_INTERVAL);
-        })
+                _ => {
+                    warn!("timed out waiting for bitcoind to finish scanning");
+                    return Ok(None);
+                }
+            },
+            None => interval,
+        };
+        thread::sleep(interval);
     }
 }
 
-impl RpcApiExt for Client {}
+/// Check whether the RPC call failed because bitcoind did not respond within the transport's read
+/// timeout. The call might still be getting processed by bitcoind in this case.
+pub fn is_timeout_error(e: &rpc::Error) -> bool {
+    match e {
+        rpc::Error::JsonRpc(rpc::jsonrpc::Error::Transport(e)) => {
+            match e.downcast_ref::<simple_http::Error>() {
+                Some(simple_http::Error::Timeout) => true,
+                Some(simple_http::Error::SocketError(e)) => matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Get the network matching a bitcoind chain name (as reported by `getblockchaininfo`)
+///
+/// Signet is mapped to testnet, which shares its bip32 and address version bytes (bwt does not
+/// support `Network::Signet` as a separate network yet), so signet wallets are tracked with `--network testnet`.
+pub fn chain_network(chain: &str) -> Option<Network> {
+    Some(match chain {
+        "main" => Network::Bitcoin,
+        "test" | "signet" => Network::Testnet,
+        "regtest" => Network::Regtest,
+        _ => return None,
+    })
+}
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(tag = "category", content = "params")]
 pub enum Progress {
+    #[serde(rename = "SyncProgress")]
     Sync { progress_n: f32, tip: u64 },
+    #[serde(rename = "ScanProgress")]
     Scan { progress_n: f32, eta: u64 },
+    #[serde(rename = "ImportProgress")]
+    Import { done: usize, total: usize },
 }
 
 #[derive(Debug, Deserialize)]
@@ -159,6 +419,9 @@ pub struct GetBlockStatsResult {
 pub struct GetMempoolInfoResult {
     pub size: u64,
     pub bytes: u64,
+    pub usage: u64,
+    #[serde(rename = "maxmempool")]
+    pub max_mempool: u64,
     #[serde(
         rename = "mempoolminfee",
         with = "bitcoin::util::amount::serde::as_btc"
@@ -222,3 +485,272 @@ impl<'de> serde::Deserialize<'de> for RescanSince {
         deserializer.deserialize_any(Visitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // A mock RPC client that responds with a warmup error for the first `warmup_calls` calls
+    struct WarmingUpRpc {
+        warmup_calls: usize,
+        calls: Cell<usize>,
+    }
+
+    impl RpcApi for WarmingUpRpc {
+        fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            _cmd: &str,
+            _args: &[serde_json::Value],
+        ) -> RpcResult<T> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() <= self.warmup_calls {
+                Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(
+                    rpc::jsonrpc::error::RpcError {
+                        code: RPC_IN_WARMUP,
+                        message: "Loading block index...".into(),
+                        data: None,
+                    },
+                )))
+            } else {
+                Ok(serde_json::from_value(json!(100))?)
+            }
+        }
+    }
+    impl RpcApiExt for WarmingUpRpc {}
+
+    // A mock RPC client that reports an ongoing wallet scan for the first `scanning_calls` calls
+    struct ScanningRpc {
+        scanning_calls: usize,
+        calls: Cell<usize>,
+    }
+
+    impl RpcApi for ScanningRpc {
+        fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            _args: &[serde_json::Value],
+        ) -> RpcResult<T> {
+            assert_eq!(cmd, "getwalletinfo");
+            self.calls.set(self.calls.get() + 1);
+            let scanning = if self.calls.get() <= self.scanning_calls {
+                json!({ "duration": 30, "progress": 0.25 })
+            } else {
+                json!(false)
+            };
+            Ok(serde_json::from_value(json!({
+                "walletname": "bwt", "walletversion": 169900, "balance": 0.0,
+                "unconfirmed_balance": 0.0, "immature_balance": 0.0, "txcount": 0,
+                "keypoololdest": 0, "keypoolsize": 0, "keypoolsize_hd_internal": 0,
+                "paytxfee": 0.0, "private_keys_enabled": false, "avoid_reuse": false,
+                "scanning": scanning,
+            }))?)
+        }
+    }
+    impl RpcApiExt for ScanningRpc {}
+
+    // A mock RPC client serving the block stats of a chain with the given (txs, median feerate)
+    // blocks, using the height as the block hash. Blocks below `pruned_height` are pruned.
+    struct ChainRpc {
+        blocks: Vec<(u64, u64)>,
+        pruned_height: u64,
+    }
+
+    impl RpcApi for ChainRpc {
+        fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            args: &[serde_json::Value],
+        ) -> RpcResult<T> {
+            let result = match cmd {
+                "getblockcount" => json!(self.blocks.len() - 1),
+                "getblockhash" => json!(format!("{:064x}", args[0].as_u64().unwrap())),
+                "getblockstats" => {
+                    let height = u64::from_str_radix(args[0].as_str().unwrap(), 16).unwrap();
+                    if height < self.pruned_height {
+                        return Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(
+                            rpc::jsonrpc::error::RpcError {
+                                code: RPC_MISC_ERROR,
+                                message: PRUNED_BLOCK_MESSAGE.into(),
+                                data: None,
+                            },
+                        )));
+                    }
+                    let (txs, median) = self.blocks[height as usize];
+                    json!({
+                        "height": height, "time": 0, "txs": txs, "total_weight": 0,
+                        "total_size": 0, "totalfee": 0, "avgfeerate": median,
+                        "feerate_percentiles": [1, median / 2, median, median * 2, median * 3],
+                    })
+                }
+                _ => unreachable!(),
+            };
+            Ok(serde_json::from_value(result)?)
+        }
+    }
+    impl RpcApiExt for ChainRpc {}
+
+    #[test]
+    fn test_estimate_fee_from_blocks() {
+        let rpc = ChainRpc {
+            // the tip is last, the second to last block has no transactions besides the coinbase
+            blocks: vec![(5, 2), (5, 8), (3, 12), (1, 0), (10, 20)],
+            pruned_height: 0,
+        };
+        assert_eq!(
+            rpc.estimate_fee_from_blocks(4).unwrap(),
+            vec![(1, 20.0), (2, 20.0), (3, 12.0), (4, 8.0)]
+        );
+        // walking past the genesis block stops at the genesis block
+        assert_eq!(rpc.estimate_fee_from_blocks(10).unwrap().len(), 5);
+        assert_eq!(rpc.estimate_fee_from_blocks(10).unwrap()[4], (5, 2.0));
+
+        // targets are omitted until there's data to go by
+        let rpc = ChainRpc {
+            blocks: vec![(5, 2), (1, 0)],
+            pruned_height: 0,
+        };
+        assert_eq!(rpc.estimate_fee_from_blocks(2).unwrap(), vec![(2, 2.0)]);
+    }
+
+    #[test]
+    fn test_recent_block_median_feerate() {
+        let rpc = ChainRpc {
+            // the tip is last, the second to last block has no transactions besides the coinbase
+            blocks: vec![(5, 2), (5, 8), (3, 12), (1, 0), (10, 20)],
+            pruned_height: 0,
+        };
+        assert_eq!(rpc.recent_block_median_feerate(1).unwrap(), Some(20.0));
+        // the empty block is skipped
+        assert_eq!(rpc.recent_block_median_feerate(3).unwrap(), Some(16.0));
+        assert_eq!(
+            rpc.recent_block_median_feerate(4).unwrap(),
+            Some(40.0 / 3.0)
+        );
+        assert_eq!(rpc.recent_block_median_feerate(10).unwrap(), Some(10.5));
+
+        let rpc = ChainRpc {
+            blocks: vec![(5, 2), (5, 8), (1, 0)],
+            pruned_height: 1,
+        };
+        assert_eq!(rpc.recent_block_median_feerate(1).unwrap(), None);
+        assert_eq!(rpc.recent_block_median_feerate(2).unwrap(), Some(8.0));
+        // unavailable once reaching the pruned blocks
+        assert_eq!(rpc.recent_block_median_feerate(3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let max_calls_per_sec = 50;
+        let limiter = RateLimiter::new(max_calls_per_sec);
+
+        // the initial burst goes through right away
+        let start = time::Instant::now();
+        for _ in 0..max_calls_per_sec {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < time::Duration::from_millis(100));
+
+        // after which throughput is held back to the configured rate
+        let start = time::Instant::now();
+        for _ in 0..max_calls_per_sec {
+            limiter.acquire();
+        }
+        let calls_per_sec = max_calls_per_sec as f64 / start.elapsed().as_secs_f64();
+        assert!(calls_per_sec <= max_calls_per_sec as f64 * 1.05);
+    }
+
+    #[test]
+    fn test_wallet_scan_progress() {
+        let rpc = ScanningRpc {
+            scanning_calls: 1,
+            calls: Cell::new(0),
+        };
+        assert_eq!(rpc.wallet_scan_progress().unwrap(), Some(0.25));
+        assert_eq!(rpc.wallet_scan_progress().unwrap(), None);
+    }
+
+    #[test]
+    fn test_wait_wallet_scan_every() {
+        let rpc = ScanningRpc {
+            scanning_calls: 3,
+            calls: Cell::new(0),
+        };
+        let start = time::Instant::now();
+        let interval = time::Duration::from_millis(10);
+        rpc.wait_wallet_scan_every(None, interval).unwrap();
+        assert_eq!(rpc.calls.get(), 4);
+        assert!(start.elapsed() < WAIT_SCAN_INTERVAL);
+    }
+
+    #[test]
+    fn test_wait_wallet_scan_timeout() {
+        let rpc = ScanningRpc {
+            scanning_calls: usize::MAX,
+            calls: Cell::new(0),
+        };
+        let start = time::Instant::now();
+        let timeout = time::Duration::from_millis(100);
+        assert!(rpc
+            .wait_wallet_scan_timeout(None, timeout)
+            .unwrap()
+            .is_none());
+        assert!(start.elapsed() < WAIT_SCAN_INTERVAL);
+
+        let rpc = ScanningRpc {
+            scanning_calls: 1,
+            calls: Cell::new(0),
+        };
+        let timeout = time::Duration::from_secs(10);
+        let info = rpc.wait_wallet_scan_timeout(None, timeout).unwrap();
+        assert_eq!(rpc.calls.get(), 2);
+        assert!(info.is_some());
+    }
+
+    #[test]
+    fn test_is_timeout_error() {
+        let transport_error = |e: simple_http::Error| {
+            rpc::Error::JsonRpc(rpc::jsonrpc::Error::Transport(Box::new(e)))
+        };
+        assert!(is_timeout_error(&transport_error(
+            simple_http::Error::Timeout
+        )));
+        assert!(is_timeout_error(&transport_error(
+            simple_http::Error::SocketError(io::ErrorKind::WouldBlock.into())
+        )));
+        assert!(!is_timeout_error(&transport_error(
+            simple_http::Error::SocketError(io::ErrorKind::ConnectionRefused.into())
+        )));
+        assert!(!is_timeout_error(&transport_error(
+            simple_http::Error::HttpErrorCode(500)
+        )));
+    }
+
+    #[test]
+    fn test_chain_network() {
+        assert_eq!(chain_network("main"), Some(Network::Bitcoin));
+        assert_eq!(chain_network("regtest"), Some(Network::Regtest));
+        assert_eq!(chain_network("nope"), None);
+
+        // signet keys use the testnet version bytes and are accepted by a testnet configuration
+        assert_eq!(chain_network("signet"), Some(Network::Testnet));
+        let tpub = "tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp";
+        let tpub = tpub
+            .parse::<bitcoin::util::bip32::ExtendedPubKey>()
+            .unwrap();
+        assert!(crate::util::xpub::xpub_matches_network(
+            &tpub,
+            chain_network("signet").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_wait_warmup() {
+        let rpc = WarmingUpRpc {
+            warmup_calls: 2,
+            calls: Cell::new(0),
+        };
+        rpc.wait_warmup().unwrap();
+        assert_eq!(rpc.calls.get(), 3);
+    }
+}
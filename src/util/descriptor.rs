@@ -1,39 +1,185 @@
+use std::fmt;
 use std::iter::FromIterator;
+use std::result::Result as StdResult;
 use std::str::FromStr;
 
-use bitcoin::secp256k1::{self, Secp256k1};
-use bitcoin::{Address, Network};
-use miniscript::descriptor::{Descriptor, DescriptorPublicKey, DescriptorPublicKeyCtx};
+use serde::de;
 
-use crate::error::{Error, OptionExt, Result};
+use bitcoin::secp256k1::{self, Secp256k1};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, Fingerprint};
+use bitcoin::{Address, Network, PublicKey};
+use miniscript::descriptor::{
+    Descriptor, DescriptorPublicKey, DescriptorSinglePub, DescriptorTrait, DescriptorXKey, Wildcard,
+};
+use miniscript::policy::{Liftable, Semantic};
+use miniscript::{ForEachKey, TranslatePk};
+
+use crate::error::{BwtError, Context, Error, OptionExt, Result};
 use crate::util::xpub::{xpub_matches_network, Bip32Origin};
 
 lazy_static! {
-    static ref EC: Secp256k1<secp256k1::VerifyOnly> = Secp256k1::verification_only();
-    pub static ref DESC_CTX: DescriptorPublicKeyCtx<'static, secp256k1::VerifyOnly> =
-        DescriptorPublicKeyCtx::new(&EC, 0.into());
+    pub(crate) static ref EC: Secp256k1<secp256k1::VerifyOnly> = Secp256k1::verification_only();
 }
 
 pub type ExtendedDescriptor = Descriptor<DescriptorPublicKey>;
 
+/// A descriptor to track. Adds support for Bitcoin Core's `addr(<address>)` descriptors, which
+/// rust-miniscript does not support, for watching a single raw address.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum TrackedDescriptor {
+    Descriptor(ExtendedDescriptor),
+    Addr(Address),
+}
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Checksum(String);
 
 impl_string_serializer!(Checksum, c, c.0);
 
+// Deserialize using the FromStr implementation
+impl<'de> de::Deserialize<'de> for Checksum {
+    fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
 /// Derive the address at `index`, without creating a new Descriptor
 pub fn derive_address(desc: &ExtendedDescriptor, index: u32, network: Network) -> Option<Address> {
-    let ctx = DescriptorPublicKeyCtx::new(&EC, index.into());
-    desc.address(network, ctx)
+    let derived = desc.derived_descriptor(&*EC, index).ok()?;
+    derived.address(network).ok()
 }
 
 /// Derive the descriptor string at `index`, without creating a new Descriptor
 pub fn derive_desc_str(desc: &ExtendedDescriptor, index: u32) -> String {
     // A hack, but it works and its fast. ^_^
-    let desc_str = desc.to_string().replace("*", &index.to_string());
+    let desc_str = desc
+        .to_string_no_checksum()
+        .replace("*", &index.to_string());
     format!("{}#{}", desc_str, get_checksum(&desc_str))
 }
 
+/// Get the `addr(<address>)#checksum` descriptor string for `address`
+pub fn addr_desc_str(address: &Address) -> String {
+    let desc_str = format!("addr({})", address);
+    format!("{}#{}", desc_str, get_checksum(&desc_str))
+}
+
+/// Validate the descriptor's `#checksum` suffix if it has one, or append a freshly computed one
+/// if it doesn't. The result is suitable for passing to bitcoind, which requires checksums.
+pub fn ensure_checksum(desc_str: &str) -> Result<String> {
+    Ok(ExtendedDescriptor::parse_with_checksum(desc_str)?.to_string_with_checksum())
+}
+
+/// Encode the descriptor with `[fingerprint/path]` key origin information for its keys, for export
+/// to signers. Keys that have no origin information in the descriptor get it from the xpub itself,
+/// if it's a master or first-level key (deeper keys cannot be mapped to their master fingerprint).
+pub fn to_string_with_origins(desc: &ExtendedDescriptor) -> String {
+    fn with_origin(pk: &DescriptorPublicKey) -> Result<DescriptorPublicKey, ()> {
+        Ok(match pk {
+            DescriptorPublicKey::XPub(desc_xpub)
+                if desc_xpub.origin.is_none() && desc_xpub.xkey.depth <= 1 =>
+            {
+                let Bip32Origin(fingerprint, path) = (&desc_xpub.xkey).into();
+                DescriptorPublicKey::XPub(DescriptorXKey {
+                    origin: Some((fingerprint, path)),
+                    ..desc_xpub.clone()
+                })
+            }
+            pk => pk.clone(),
+        })
+    }
+    let desc: ExtendedDescriptor = desc.translate_pk(with_origin, with_origin).unwrap();
+    desc.to_string_with_checksum()
+}
+
+/// Get an upper bound on the weight of a satisfying witness for spending an output of the
+/// descriptor, or `None` if it cannot be computed by miniscript
+pub fn max_satisfaction_weight(desc: &ExtendedDescriptor) -> Option<usize> {
+    // the derivation index does not affect the weight, keys are always serialized with the same size
+    desc.max_satisfaction_weight().ok()
+}
+
+/// Check whether the two descriptors can derive the same scriptPubKeys, which would get their
+/// funds counted twice. Detects identical keys expressed differently (with or without key origin
+/// information, or as a parent xpub with a longer derivation path), as well as non-wildcard
+/// descriptors for one of the children of a wildcard descriptor.
+pub fn descriptors_overlap(a: &ExtendedDescriptor, b: &ExtendedDescriptor) -> bool {
+    if canonical_desc(a) == canonical_desc(b) {
+        return true;
+    }
+    match (is_wildcard_desc(a), is_wildcard_desc(b)) {
+        (true, false) => derives_child(a, b),
+        (false, true) => derives_child(b, a),
+        _ => false,
+    }
+}
+
+// Check whether the non-wildcard `child` descriptor matches one of the `parent` wildcard
+// descriptor's children, at the index of its keys' last derivation step
+fn derives_child(parent: &ExtendedDescriptor, child: &ExtendedDescriptor) -> bool {
+    let mut child_index = None;
+    tap_desc_pks(child, |pk| {
+        if let DescriptorPublicKey::XPub(desc_xpub) = pk {
+            if let Some(&index @ ChildNumber::Normal { .. }) =
+                desc_xpub.derivation_path.as_ref().last()
+            {
+                child_index.get_or_insert(index);
+            }
+        }
+    });
+    child_index.map_or(false, |index| {
+        canonical_desc(&parent.derive(index.into())) == canonical_desc(child)
+    })
+}
+
+// Get the canonical string representation of the descriptor, with the key origins dropped and
+// the xpubs derived along their derivation paths (up to the wildcard), stripped of their metadata
+fn canonical_desc(desc: &ExtendedDescriptor) -> String {
+    fn canonical_pk(pk: &DescriptorPublicKey) -> Result<DescriptorPublicKey, ()> {
+        Ok(match pk {
+            DescriptorPublicKey::XPub(desc_xpub) => {
+                // keep the key as-is if it cannot be derived (i.e. has hardened steps)
+                let mut xkey = desc_xpub
+                    .xkey
+                    .derive_pub(&*EC, &desc_xpub.derivation_path)
+                    .unwrap_or(desc_xpub.xkey);
+                xkey.depth = 0;
+                xkey.parent_fingerprint = Fingerprint::default();
+                xkey.child_number = ChildNumber::from(0);
+                DescriptorPublicKey::XPub(DescriptorXKey {
+                    origin: None,
+                    xkey,
+                    derivation_path: DerivationPath::from(vec![]),
+                    wildcard: desc_xpub.wildcard,
+                })
+            }
+            DescriptorPublicKey::SinglePub(desc_single) => {
+                DescriptorPublicKey::SinglePub(DescriptorSinglePub {
+                    origin: None,
+                    key: desc_single.key.clone(),
+                })
+            }
+        })
+    }
+    let desc: ExtendedDescriptor = desc.translate_pk(canonical_pk, canonical_pk).unwrap();
+    desc.to_string()
+}
+
+fn is_wildcard_desc(desc: &ExtendedDescriptor) -> bool {
+    let mut is_wildcard = false;
+    tap_desc_pks(desc, |pk| {
+        if let DescriptorPublicKey::XPub(desc_xpub) = pk {
+            is_wildcard = is_wildcard || desc_xpub.wildcard != Wildcard::None;
+        }
+    });
+    is_wildcard
+}
+
 #[derive(Debug, Clone)]
 pub struct DescKeyInfo {
     pub bip32_origin: Bip32Origin,
@@ -42,7 +188,7 @@ pub struct DescKeyInfo {
 
 impl From<&ExtendedDescriptor> for Checksum {
     fn from(desc: &ExtendedDescriptor) -> Self {
-        get_checksum(&desc.to_string())
+        get_checksum(&desc.to_string_no_checksum())
     }
 }
 
@@ -65,30 +211,15 @@ impl DescKeyInfo {
         let mut valid_networks = true;
         let mut keys_info = vec![];
 
-        tap_desc_pks(desc, |pk| match pk {
-            DescriptorPublicKey::XPub(desc_xpub) => {
-                // Get key origin information from the descriptor, fallback to extracting from the
-                // xpub itself.
-                let bip32_origin = desc_xpub
-                    .origin
-                    .as_ref()
-                    .map_or_else(|| (&desc_xpub.xkey).into(), Into::<Bip32Origin>::into)
-                    .extend(&desc_xpub.derivation_path);
-
+        tap_desc_pks(desc, |pk| {
+            if let Some(bip32_origin) = key_origin(pk) {
                 keys_info.push(DescKeyInfo {
                     bip32_origin,
-                    is_wildcard: desc_xpub.is_wildcard,
+                    is_wildcard: is_wildcard_key(pk),
                 });
-
-                valid_networks = valid_networks && xpub_matches_network(&desc_xpub.xkey, network);
             }
-            DescriptorPublicKey::SinglePub(desc_single) => {
-                if let Some(bip32_origin) = &desc_single.origin {
-                    keys_info.push(DescKeyInfo {
-                        bip32_origin: bip32_origin.into(),
-                        is_wildcard: false,
-                    });
-                }
+            if let DescriptorPublicKey::XPub(desc_xpub) = pk {
+                valid_networks = valid_networks && xpub_matches_network(&desc_xpub.xkey, network);
             }
         });
 
@@ -102,19 +233,136 @@ impl DescKeyInfo {
     }
 }
 
+/// Derive the public keys at `index` along with their bip32 origins, as needed for populating the
+/// bip32 derivation fields of PSBT inputs. Keys that have no origin information are skipped.
+pub fn derive_key_origins(desc: &ExtendedDescriptor, index: u32) -> Vec<(PublicKey, Bip32Origin)> {
+    let mut key_origins = vec![];
+    tap_desc_pks(desc, |pk| {
+        if let Some(bip32_origin) = key_origin(pk) {
+            let bip32_origin = if is_wildcard_key(pk) {
+                bip32_origin.child(index.into())
+            } else {
+                bip32_origin
+            };
+            // keys with hardened derivation steps cannot be derived and are skipped
+            if let Ok(pubkey) = pk.clone().derive(index).derive_public_key(&*EC) {
+                key_origins.push((pubkey, bip32_origin));
+            }
+        }
+    });
+    key_origins
+}
+
+// Get the key origin information from the descriptor, falling back to extracting it from the xpub
+// itself. Does not include the wildcard derivation step.
+fn key_origin(pk: &DescriptorPublicKey) -> Option<Bip32Origin> {
+    match pk {
+        DescriptorPublicKey::XPub(desc_xpub) => Some(
+            desc_xpub
+                .origin
+                .as_ref()
+                .map_or_else(|| (&desc_xpub.xkey).into(), Into::<Bip32Origin>::into)
+                .extend(&desc_xpub.derivation_path),
+        ),
+        DescriptorPublicKey::SinglePub(desc_single) => desc_single.origin.as_ref().map(Into::into),
+    }
+}
+
+fn is_wildcard_key(pk: &DescriptorPublicKey) -> bool {
+    matches!(pk, DescriptorPublicKey::XPub(desc_xpub) if desc_xpub.wildcard != Wildcard::None)
+}
+
+/// Get the block height at which an output funded at `funding_height` becomes spendable, taking
+/// `older()` relative timelocks and `after()` absolute timelocks into account. Returns `None` if the
+/// descriptor has a spending path that isn't timelocked. Time-based locks are not supported, spending
+/// paths that require them are ignored.
+pub fn timelock_spendable_at(desc: &ExtendedDescriptor, funding_height: u32) -> Option<u32> {
+    let policy = desc.lift().ok()?;
+    if is_satisfiable_at(&policy, 0, 0) {
+        return None;
+    }
+
+    let mut timelocks = (vec![], vec![]);
+    collect_timelocks(&policy, &mut timelocks);
+    let (relative, absolute) = timelocks;
+
+    let mut candidates: Vec<u32> = relative
+        .into_iter()
+        .filter(|t| t & SEQUENCE_LOCKTIME_TYPE_FLAG == 0)
+        .map(|t| funding_height + t)
+        .chain(
+            absolute
+                .into_iter()
+                .filter(|t| *t < LOCKTIME_THRESHOLD)
+                .map(|t| t.max(funding_height)),
+        )
+        .collect();
+    candidates.sort_unstable();
+
+    candidates
+        .into_iter()
+        .find(|height| is_satisfiable_at(&policy, height - funding_height, *height))
+}
+
+// BIP 68 type flag, indicating a time-based relative lock
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+// nLockTime values below this are interpreted as block heights
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+fn is_satisfiable_at(policy: &Semantic<DescriptorPublicKey>, age: u32, height: u32) -> bool {
+    match policy {
+        Semantic::Unsatisfiable => false,
+        Semantic::Older(t) => *t <= age,
+        Semantic::After(t) => *t <= height,
+        Semantic::Threshold(k, subs) => {
+            subs.iter()
+                .filter(|sub| is_satisfiable_at(sub, age, height))
+                .count()
+                >= *k
+        }
+        _ => true,
+    }
+}
+
+fn collect_timelocks(policy: &Semantic<DescriptorPublicKey>, timelocks: &mut (Vec<u32>, Vec<u32>)) {
+    match policy {
+        Semantic::Older(t) => timelocks.0.push(*t),
+        Semantic::After(t) => timelocks.1.push(*t),
+        Semantic::Threshold(_, subs) => subs
+            .iter()
+            .for_each(|sub| collect_timelocks(sub, timelocks)),
+        _ => (),
+    }
+}
+
 pub trait DescriptorChecksum: Sized {
     /// Encode to string with the `#checksum` suffix
     fn to_string_with_checksum(&self) -> String;
+    /// Encode to string without the `#checksum` suffix
+    fn to_string_no_checksum(&self) -> String;
     /// Parse a descriptor with an optional checksum suffix
     fn parse_with_checksum(s: &str) -> Result<Self>;
 }
 
 impl DescriptorChecksum for ExtendedDescriptor {
     fn to_string_with_checksum(&self) -> String {
-        format!("{}#{}", self, get_checksum(&self.to_string()))
+        let desc_str = self.to_string_no_checksum();
+        format!("{}#{}", desc_str, get_checksum(&desc_str))
+    }
+
+    fn to_string_no_checksum(&self) -> String {
+        // rust-miniscript always encodes descriptors with their checksum
+        let desc_str = self.to_string();
+        desc_str.split('#').next().unwrap().into()
     }
 
     fn parse_with_checksum(s: &str) -> Result<ExtendedDescriptor> {
+        // rust-miniscript rejects these with a cryptic error, detect them upfront
+        ensure!(
+            !has_hardened_wildcard(s),
+            BwtError::UnsupportedHardenedWildcard(s.into())
+        );
+
         let parts: Vec<&str> = s.splitn(2, '#').collect();
         if parts.len() == 2 {
             let desc_str = parts[0];
@@ -125,13 +373,13 @@ impl DescriptorChecksum for ExtendedDescriptor {
             // won't retain the checsum if the descriptor is encoded differently by rust-miniscript,
             // which would result in an unexpected behaviour.
             ensure!(
-                desc.to_string() == desc_str,
+                desc.to_string_no_checksum() == desc_str,
                 "Descriptors with explicit checksums must use canonical encoding. {} is expected to be encoded as `{}`",
                 provided_checksum,
-                desc.to_string()
+                desc.to_string_no_checksum()
             );
 
-            let actual_checksum = get_checksum(&desc.to_string());
+            let actual_checksum = get_checksum(&desc.to_string_no_checksum());
             ensure!(
                 provided_checksum == actual_checksum,
                 "Invalid descriptor checksum {}, expected {}",
@@ -145,30 +393,81 @@ impl DescriptorChecksum for ExtendedDescriptor {
     }
 }
 
+impl FromStr for TrackedDescriptor {
+    type Err = Error;
+
+    /// Parse a descriptor or an `addr()` descriptor, with an optional checksum suffix
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match parse_addr_desc(s)? {
+            Some(address) => TrackedDescriptor::Addr(address),
+            None => TrackedDescriptor::Descriptor(ExtendedDescriptor::parse_with_checksum(s)?),
+        })
+    }
+}
+
+impl fmt::Display for TrackedDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackedDescriptor::Descriptor(desc) => write!(f, "{}", desc.to_string_no_checksum()),
+            TrackedDescriptor::Addr(address) => write!(f, "addr({})", address),
+        }
+    }
+}
+
+// Deserialize using the FromStr implementation
+impl<'de> de::Deserialize<'de> for TrackedDescriptor {
+    fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+// Parse an `addr(<address>)` descriptor with an optional checksum suffix, or return None if this
+// isn't an `addr()` descriptor. The address network is not validated here.
+fn parse_addr_desc(s: &str) -> Result<Option<Address>> {
+    let s = s.trim();
+    if !s.starts_with("addr(") {
+        return Ok(None);
+    }
+    let mut parts = s.splitn(2, '#');
+    let desc_str = parts.next().req()?;
+    let address_str = desc_str
+        .strip_prefix("addr(")
+        .and_then(|s| s.strip_suffix(')'))
+        .or_err("Invalid addr() descriptor")?;
+    let address = Address::from_str(address_str).context("Invalid addr() descriptor address")?;
+
+    if let Some(provided_checksum) = parts.next() {
+        // addresses only use characters from the descriptor charset, computing the checksum can't fail
+        let provided_checksum = provided_checksum.parse::<Checksum>()?;
+        let actual_checksum = get_checksum(desc_str);
+        ensure!(
+            provided_checksum == actual_checksum,
+            "Invalid descriptor checksum {}, expected {}",
+            provided_checksum,
+            actual_checksum,
+        );
+    }
+    Ok(Some(address))
+}
+
+// Check for key expressions ending with a hardened wildcard (`/*'` or `/*h`), which
+// can only be derived from private keys
+fn has_hardened_wildcard(desc_str: &str) -> bool {
+    ["/*'", "/*h", "/*H"].iter().any(|w| desc_str.contains(w))
+}
+
 fn tap_desc_pks<F>(desc: &ExtendedDescriptor, mut tap_fn: F)
 where
     F: FnMut(&DescriptorPublicKey),
 {
-    // TODO this shouldn't call translate_pk() twice. this is tricky because both closure
-    // arguments (Fpk/Fpkh) require a mutable borrow on `tap_fn`.
-
-    desc.translate_pk(
-        |pk| {
-            tap_fn(pk);
-            Ok::<DescriptorPublicKey, ()>(pk.clone())
-        },
-        |pk| Ok::<DescriptorPublicKey, ()>(pk.clone()),
-    )
-    .unwrap();
-
-    desc.translate_pk(
-        |pk| Ok::<DescriptorPublicKey, ()>(pk.clone()),
-        |pk| {
-            tap_fn(pk);
-            Ok::<DescriptorPublicKey, ()>(pk.clone())
-        },
-    )
-    .unwrap();
+    desc.for_each_key(|key| {
+        tap_fn(key.as_key());
+        true
+    });
 }
 
 // Checksum code copied from https://github.com/bitcoindevkit/bdk/blob/master/src/descriptor/checksum.rs
@@ -234,3 +533,239 @@ fn poly_mod(mut c: u64, val: u64) -> u64 {
 
     c
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+
+    const XPUB1: &str = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+    const XPUB2: &str = "xpub661MyMwAqRbcEhsxS9g2qyYKSGA3seqWVNhmVhU27ddQx952PaZ6G4V26msGKrqYBjoBRwFyzaucPUkhw7DNaeMVUYJV1bqosxzVxToJdcy";
+
+    fn parse(desc_str: &str) -> ExtendedDescriptor {
+        desc_str
+            .replace("XPUB1", XPUB1)
+            .replace("XPUB2", XPUB2)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_hardened_wildcard() {
+        for desc_str in &["pkh(XPUB1/*')", "wpkh(XPUB1/0/*h)"] {
+            let desc_str = desc_str.replace("XPUB1", XPUB1);
+            let err = ExtendedDescriptor::parse_with_checksum(&desc_str).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<BwtError>(),
+                Some(BwtError::UnsupportedHardenedWildcard(_))
+            ));
+        }
+        // unhardened wildcards following hardened steps are unaffected (and fail elsewhere)
+        assert!(!has_hardened_wildcard(&format!("wpkh({}/0'/*)", XPUB1)));
+    }
+
+    #[test]
+    fn test_taproot_descriptor() {
+        // the BIP 86 test vectors for the "abandon abandon ... about" mnemonic
+        let xpub = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+        let desc_str = format!("tr([73c5da0a/86'/0'/0']{}/0/*)", xpub);
+        let desc = ExtendedDescriptor::parse_with_checksum(&desc_str).unwrap();
+        assert_eq!(
+            derive_address(&desc, 0, Network::Bitcoin)
+                .unwrap()
+                .to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        let change_desc =
+            ExtendedDescriptor::parse_with_checksum(&desc_str.replace("/0/*", "/1/*")).unwrap();
+        assert_eq!(
+            derive_address(&change_desc, 0, Network::Bitcoin)
+                .unwrap()
+                .to_string(),
+            "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7"
+        );
+        assert_eq!(max_satisfaction_weight(&desc), Some(65));
+
+        // the key origin is available for x-only taproot keys too
+        let origins = derive_key_origins(&desc, 0);
+        assert_eq!(origins.len(), 1);
+        assert_eq!(origins[0].1.to_string(), "73c5da0a/86'/0'/0'/0/0");
+    }
+
+    #[test]
+    fn test_addr_descriptor() {
+        let address = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        let desc_str = format!("addr({})", address);
+        match desc_str.parse::<TrackedDescriptor>().unwrap() {
+            TrackedDescriptor::Addr(tracked) => assert_eq!(tracked.to_string(), address),
+            TrackedDescriptor::Descriptor(_) => panic!("expected an addr() descriptor"),
+        }
+
+        // with a checksum
+        let with_checksum = format!("{}#{}", desc_str, get_checksum(&desc_str));
+        assert!(matches!(
+            with_checksum.parse::<TrackedDescriptor>(),
+            Ok(TrackedDescriptor::Addr(_))
+        ));
+        assert!(format!("{}#qqqqqqqq", desc_str)
+            .parse::<TrackedDescriptor>()
+            .is_err());
+        assert!("addr(bc1qnope)".parse::<TrackedDescriptor>().is_err());
+
+        // other descriptors are parsed by rust-miniscript
+        assert!(matches!(
+            format!("wpkh({}/0/*)", XPUB1).parse::<TrackedDescriptor>(),
+            Ok(TrackedDescriptor::Descriptor(_))
+        ));
+    }
+
+    #[test]
+    fn test_ensure_checksum() {
+        let desc_str = format!("wpkh({}/0/*)", XPUB1);
+        let with_checksum = ensure_checksum(&desc_str).unwrap();
+        let checksum = get_checksum(&desc_str);
+        assert_eq!(with_checksum, format!("{}#{}", desc_str, checksum));
+        assert_eq!(ensure_checksum(&with_checksum).unwrap(), with_checksum);
+
+        let wrong = format!("{}#qqqqqqqq", desc_str);
+        let err = ensure_checksum(&wrong).unwrap_err().to_string();
+        assert!(err.contains("qqqqqqqq") && err.contains(&checksum.0));
+    }
+
+    #[test]
+    fn test_to_string_with_origins() {
+        let fingerprint2 = XPUB2.parse::<ExtendedPubKey>().unwrap().fingerprint();
+
+        // origin info provided in the descriptor is retained, keys without it get it from the xpub
+        let desc = parse("wsh(multi(2,[d34db33f/48'/0'/0'/2']XPUB1/0/*,XPUB2/0/*))");
+        let desc_str = to_string_with_origins(&desc);
+        assert!(desc_str.contains(&format!("[d34db33f/48'/0'/0'/2']{}/0/*", XPUB1)));
+        assert!(desc_str.contains(&format!("[{}]{}/0/*", fingerprint2, XPUB2)));
+
+        // the result is a valid descriptor for the same scripts, with a checksum
+        let parsed = ExtendedDescriptor::parse_with_checksum(&desc_str).unwrap();
+        assert_eq!(
+            derive_address(&parsed, 5, Network::Bitcoin),
+            derive_address(&desc, 5, Network::Bitcoin)
+        );
+    }
+
+    #[test]
+    fn test_shared_xpub_paths() {
+        // the same xpub appearing at multiple paths gets derived independently for each
+        let desc = parse("wsh(multi(1,XPUB1/0/*,XPUB1/1/*))");
+        let xpub = XPUB1.parse::<ExtendedPubKey>().unwrap();
+        let derive_pk = |path: [u32; 2]| {
+            let path: Vec<ChildNumber> = path.iter().map(|&i| ChildNumber::from(i)).collect();
+            PublicKey::new(xpub.derive_pub(&*EC, &path).unwrap().public_key)
+        };
+        for &index in &[0, 7] {
+            let script = Builder::new()
+                .push_int(1)
+                .push_key(&derive_pk([0, index]))
+                .push_key(&derive_pk([1, index]))
+                .push_int(2)
+                .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+                .into_script();
+            assert_eq!(
+                derive_address(&desc, index, Network::Bitcoin),
+                Some(Address::p2wsh(&script, Network::Bitcoin))
+            );
+
+            let desc_str = derive_desc_str(&desc, index);
+            assert!(desc_str.contains(&format!("{}/0/{},{}/1/{}", XPUB1, index, XPUB1, index)));
+            let derived = ExtendedDescriptor::parse_with_checksum(&desc_str).unwrap();
+            assert_eq!(
+                derive_address(&derived, 0, Network::Bitcoin),
+                derive_address(&desc, index, Network::Bitcoin)
+            );
+        }
+
+        let keys_info = DescKeyInfo::extract(&desc, Network::Bitcoin).unwrap();
+        assert_eq!(keys_info.len(), 2);
+        assert_ne!(keys_info[0].bip32_origin.1, keys_info[1].bip32_origin.1);
+    }
+
+    #[test]
+    fn test_max_satisfaction_weight() {
+        // 4 bytes for the empty scriptSig, one varint for the witness stack length,
+        // a 73 bytes signature and a 33 bytes compressed public key with its push opcode
+        let p2wpkh = parse("wpkh(XPUB1/0/*)");
+        assert_eq!(max_satisfaction_weight(&p2wpkh), Some(4 + 1 + 73 + 34));
+
+        // 4 bytes for the empty scriptSig, a 105 bytes witness script prefixed by its
+        // length, the witness stack length, the CHECKMULTISIG dummy element and two signatures
+        let multisig = parse("wsh(multi(2,XPUB1/0/*,XPUB2/0/*,XPUB1/1/*))");
+        assert_eq!(
+            max_satisfaction_weight(&multisig),
+            Some(4 + 1 + 105 + 1 + 1 + 2 * 73)
+        );
+    }
+
+    #[test]
+    fn test_timelock_spendable_at() {
+        let csv = parse("wsh(and_v(v:pk(XPUB1/0/*),older(144)))");
+        assert_eq!(timelock_spendable_at(&csv, 100), Some(244));
+
+        let cltv = parse("wsh(and_v(v:pk(XPUB1/0/*),after(500)))");
+        assert_eq!(timelock_spendable_at(&cltv, 100), Some(500));
+        assert_eq!(timelock_spendable_at(&cltv, 600), Some(600));
+
+        // has a spending path that isn't timelocked
+        let either =
+            parse("wsh(or_d(multi(2,XPUB1/0/*,XPUB2/0/*),and_v(v:pk(XPUB1/1/*),older(144))))");
+        assert_eq!(timelock_spendable_at(&either, 100), None);
+
+        // the earliest of the timelocked spending paths is used
+        let both = parse(
+            "wsh(or_i(and_v(v:pk(XPUB1/0/*),older(1000)),and_v(v:pk(XPUB2/0/*),older(144))))",
+        );
+        assert_eq!(timelock_spendable_at(&both, 100), Some(244));
+
+        let unlocked = parse("wpkh(XPUB1/0/*)");
+        assert_eq!(timelock_spendable_at(&unlocked, 100), None);
+    }
+
+    #[test]
+    fn test_derive_key_origins() {
+        let fingerprint1 = XPUB1.parse::<ExtendedPubKey>().unwrap().fingerprint();
+        let fingerprint2 = XPUB2.parse::<ExtendedPubKey>().unwrap().fingerprint();
+
+        // the derived key matches the descriptor's script, with the wildcard step in its origin path
+        let desc = parse("wpkh([d34db33f/84'/0'/0']XPUB1/0/*)");
+        let key_origins = derive_key_origins(&desc, 5);
+        assert_eq!(key_origins.len(), 1);
+        let (pubkey, origin) = &key_origins[0];
+        assert_eq!(
+            Some(Address::p2wpkh(pubkey, Network::Bitcoin).unwrap()),
+            derive_address(&desc, 5, Network::Bitcoin)
+        );
+        assert_eq!(origin.to_string(), "d34db33f/84'/0'/0'/0/5");
+
+        // keys without origin information get it from the xpub
+        let multisig = parse("wsh(multi(2,XPUB1/1/*,XPUB2/0/*))");
+        let key_origins = derive_key_origins(&multisig, 7);
+        let origins: Vec<String> = key_origins.iter().map(|(_, o)| o.to_string()).collect();
+        assert_eq!(
+            origins,
+            vec![
+                format!("{}/1/7", fingerprint1),
+                format!("{}/0/7", fingerprint2)
+            ]
+        );
+        let pubkeys: Vec<PublicKey> = key_origins.into_iter().map(|(pk, _)| pk).collect();
+        let script = Builder::new()
+            .push_int(2)
+            .push_key(&pubkeys[0])
+            .push_key(&pubkeys[1])
+            .push_int(2)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(
+            Some(Address::p2wsh(&script, Network::Bitcoin)),
+            derive_address(&multisig, 7, Network::Bitcoin)
+        );
+    }
+}
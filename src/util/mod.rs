@@ -5,6 +5,7 @@ use std::{sync::mpsc, thread};
 use serde_json::Value;
 
 use bitcoin::Txid;
+use bitcoin_hashes::{sha256d, Hash};
 
 #[macro_use]
 mod macros;
@@ -14,35 +15,146 @@ pub mod bitcoincore_ext;
 pub mod descriptor;
 pub mod xpub;
 
-pub use bitcoincore_ext::RpcApiExt;
+pub use bitcoincore_ext::{RpcApiExt, RpcClient};
 
-const VSIZE_BIN_WIDTH: u32 = 50_000; // vbytes
+pub const VSIZE_BIN_WIDTH: u32 = 50_000; // vbytes
+
+// Feerate bands used by the exponential histogram mode, in sat/vB
+const FEERATE_BANDS: &[f32] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeHistogramMode {
+    /// Bin by fixed vsize width (the default)
+    Linear,
+    /// Bin by exponential feerate bands
+    Exponential,
+}
+
+impl std::str::FromStr for FeeHistogramMode {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        Ok(match s {
+            "linear" => FeeHistogramMode::Linear,
+            "exponential" => FeeHistogramMode::Exponential,
+            _ => bail!(
+                "invalid fee histogram mode '{}', expecting 'linear' or 'exponential'",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VsizeField {
+    /// Use `vsize`, falling back to `size` (the default)
+    Vsize,
+    /// Use `size`, falling back to `vsize`
+    Size,
+    /// Use `weight` divided by 4 (rounded up), falling back to `vsize` then `size`
+    Weight,
+}
+
+impl std::str::FromStr for VsizeField {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        Ok(match s {
+            "vsize" => VsizeField::Vsize,
+            "size" => VsizeField::Size,
+            "weight" => VsizeField::Weight,
+            _ => bail!(
+                "invalid vsize field '{}', expecting 'vsize', 'size' or 'weight'",
+                s
+            ),
+        })
+    }
+}
+
+impl VsizeField {
+    // Get the vsize of a `getrawmempool true` entry according to the field preference
+    fn get(&self, entry: &Value) -> Option<u64> {
+        let vsize = || entry["vsize"].as_u64();
+        let size = || entry["size"].as_u64();
+        match self {
+            VsizeField::Vsize => vsize().or_else(size),
+            VsizeField::Size => size().or_else(vsize),
+            VsizeField::Weight => entry["weight"]
+                .as_u64()
+                .map(|weight| (weight as f64 / 4.0).ceil() as u64)
+                .or_else(vsize)
+                .or_else(size),
+        }
+    }
+}
 
 // Make the fee histogram out of a list of `getrawmempool true` entries
-pub fn make_fee_histogram(mempool_entries: HashMap<Txid, Value>) -> Vec<(f32, u32)> {
+pub fn make_fee_histogram(
+    mempool_entries: HashMap<Txid, Value>,
+    mode: FeeHistogramMode,
+) -> Vec<(f32, u32)> {
+    make_fee_histogram_with_width(mempool_entries, mode, VsizeField::Vsize, VSIZE_BIN_WIDTH)
+}
+
+// Make the fee histogram using the given vsize field and a custom vsize bin width (in vbytes) for the
+// linear mode. The width does not affect the exponential mode, which bins by feerate. A zero width is
+// clamped to 1 vbyte.
+pub fn make_fee_histogram_with_width(
+    mempool_entries: HashMap<Txid, Value>,
+    mode: FeeHistogramMode,
+    vsize_field: VsizeField,
+    bin_width: u32,
+) -> Vec<(f32, u32)> {
     let mut entries: Vec<_> = mempool_entries
         .into_iter()
         .map(|(_, entry)| {
-            let vsize = entry["vsize"]
-                .as_u64()
-                .or_else(|| entry["size"].as_u64())
-                .unwrap(); // bitcoind is borked if this fails
+            let vsize = vsize_field.get(&entry).unwrap(); // bitcoind is borked if this fails
             let fee = entry["fee"].as_f64().unwrap();
             let feerate = fee as f32 / vsize as f32 * 100_000_000f32;
-            (vsize as u32, feerate)
+            (vsize as u32, package_feerate(&entry, feerate))
         })
         .collect();
 
-    // XXX should take unconfirmed parents feerates into account
-
     entries.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
 
+    match mode {
+        FeeHistogramMode::Linear => make_linear_histogram(entries, bin_width.max(1)),
+        FeeHistogramMode::Exponential => make_exponential_histogram(entries),
+    }
+}
+
+// Get the effective feerate of a `getrawmempool true` entry, taking unconfirmed ancestors and
+// descendants into account (CPFP). A transaction paying more than its ancestors is held back to
+// the feerate of its ancestor package, while a transaction paying less than its descendants is
+// pulled up to the feerate of its descendant package. Falls back to the individual `feerate` when
+// the package fields are unavailable.
+fn package_feerate(entry: &Value, feerate: f32) -> f32 {
+    // the package sizes are in vbytes and the package fees in satoshis
+    let package = |size_field: &str, fees_field: &str| {
+        let size = entry[size_field].as_u64()?;
+        let fees = entry[fees_field].as_u64()?;
+        (size > 0).do_then(|| fees as f32 / size as f32)
+    };
+    let ancestor_feerate = package("ancestorsize", "ancestorfees");
+    let descendant_feerate = package("descendantsize", "descendantfees");
+
+    match (ancestor_feerate, descendant_feerate) {
+        (Some(ancestor_feerate), _) if ancestor_feerate < feerate => ancestor_feerate,
+        (_, Some(descendant_feerate)) => feerate.max(descendant_feerate),
+        _ => feerate,
+    }
+}
+
+// Expects the (vsize, feerate) entries to be sorted by feerate
+fn make_linear_histogram(entries: Vec<(u32, f32)>, bin_width: u32) -> Vec<(f32, u32)> {
     let mut histogram = vec![];
     let mut bin_size = 0;
     let mut last_feerate = 0.0;
 
     for (vsize, feerate) in entries.into_iter().rev() {
-        if bin_size > VSIZE_BIN_WIDTH && (last_feerate - feerate).abs() > f32::EPSILON {
+        if bin_size > bin_width && (last_feerate - feerate).abs() > f32::EPSILON {
             // vsize of transactions paying >= last_feerate
             histogram.push((last_feerate, bin_size));
             bin_size = 0;
@@ -58,6 +170,57 @@ pub fn make_fee_histogram(mempool_entries: HashMap<Txid, Value>) -> Vec<(f32, u3
     histogram
 }
 
+// Expects the (vsize, feerate) entries to be sorted by feerate. Each bin is labeled with the lower
+// bound of its feerate band, transactions paying less than the lowest band are binned under 0.
+fn make_exponential_histogram(entries: Vec<(u32, f32)>) -> Vec<(f32, u32)> {
+    let mut histogram: Vec<(f32, u32)> = vec![];
+
+    for (vsize, feerate) in entries.into_iter().rev() {
+        let band = FEERATE_BANDS
+            .iter()
+            .rev()
+            .find(|band| feerate >= **band)
+            .cloned()
+            .unwrap_or(0.0);
+
+        match histogram.last_mut() {
+            Some((last_band, bin_size)) if (*last_band - band).abs() < f32::EPSILON => {
+                *bin_size += vsize
+            }
+            _ => histogram.push((band, vsize)),
+        }
+    }
+
+    histogram
+}
+
+fn merklize(left: sha256d::Hash, right: sha256d::Hash) -> sha256d::Hash {
+    let data = [&left[..], &right[..]].concat();
+    sha256d::Hash::hash(&data)
+}
+
+/// Build the merkle branch of the hash at `index` (the sibling hashes from the bottom up), along with the merkle root
+pub fn create_merkle_branch_and_root(
+    mut hashes: Vec<sha256d::Hash>,
+    mut index: usize,
+) -> (Vec<sha256d::Hash>, sha256d::Hash) {
+    let mut merkle = vec![];
+    while hashes.len() > 1 {
+        if hashes.len() % 2 != 0 {
+            let last = *hashes.last().unwrap();
+            hashes.push(last);
+        }
+        index = if index % 2 == 0 { index + 1 } else { index - 1 };
+        merkle.push(hashes[index]);
+        index /= 2;
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merklize(pair[0], pair[1]))
+            .collect()
+    }
+    (merkle, hashes[0])
+}
+
 pub fn remove_if<K, V>(hm: &mut HashMap<K, V>, key: K, predicate: impl Fn(&mut V) -> bool) -> bool
 where
     K: Eq + std::hash::Hash,
@@ -74,8 +237,15 @@ where
 
 // debounce a Sender to only emit events sent when `duration` seconds has passed since
 // the previous event, or after `duration` seconds elapses without new events coming in.
-pub fn debounce_sender(forward_tx: mpsc::Sender<()>, duration: u64) -> mpsc::Sender<()> {
+// if `max_wait` is set, events are emitted once they've been pending for `max_wait` seconds,
+// even if new events keep coming in.
+pub fn debounce_sender(
+    forward_tx: mpsc::Sender<()>,
+    duration: u64,
+    max_wait: Option<u64>,
+) -> mpsc::Sender<()> {
     let duration = Duration::from_secs(duration);
+    let max_wait = max_wait.map(Duration::from_secs);
     let (debounce_tx, debounce_rx) = mpsc::channel();
 
     thread::spawn(move || {
@@ -86,10 +256,24 @@ pub fn debounce_sender(forward_tx: mpsc::Sender<()>, duration: u64) -> mpsc::Sen
                 break 'outer;
             }
             if tick_start.elapsed() < duration {
+                let pending_since = Instant::now();
                 // if duration hasn't passed, debounce for another `duration` seconds
                 loop {
-                    trace!(target: "bwt::real-time", "debouncing sync for {:?}", duration);
-                    match debounce_rx.recv_timeout(duration) {
+                    // but no longer than the time remaining until `max_wait`
+                    let timeout = match max_wait {
+                        Some(max_wait) => match max_wait.checked_sub(pending_since.elapsed()) {
+                            Some(remaining) if remaining > Duration::from_secs(0) => {
+                                remaining.min(duration)
+                            }
+                            _ => {
+                                trace!(target: "bwt::real-time", "max debounce wait reached");
+                                break;
+                            }
+                        },
+                        None => duration,
+                    };
+                    trace!(target: "bwt::real-time", "debouncing sync for {:?}", timeout);
+                    match debounce_rx.recv_timeout(timeout) {
                         // if we receive another message within the `duration`, debounce and start over again
                         Ok(()) => continue,
                         // if we timed-out, we're good!
@@ -109,15 +293,38 @@ pub fn debounce_sender(forward_tx: mpsc::Sender<()>, duration: u64) -> mpsc::Sen
     debounce_tx
 }
 
+#[cfg(feature = "tokio")]
+lazy_static! {
+    // The runtime shared by all block_on_future() calls, created on first use
+    static ref RUNTIME: std::sync::Mutex<tokio::runtime::Runtime> = std::sync::Mutex::new(
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    );
+    // A runtime handle provided by the embedder, used instead of the shared runtime when set
+    static ref RUNTIME_HANDLE: std::sync::RwLock<Option<tokio::runtime::Handle>> =
+        std::sync::RwLock::new(None);
+}
+
 /// Wait for the future to resolve, blocking the current thread until it does
+///
+/// Uses the runtime handle set with `set_runtime_handle()` if there is one, or a shared runtime otherwise.
 #[cfg(feature = "tokio")]
 pub fn block_on_future<F: std::future::Future>(future: F) -> F::Output {
-    let mut rt = tokio::runtime::Builder::new()
-        .basic_scheduler()
-        .enable_all()
-        .build()
-        .unwrap();
-    rt.block_on(future)
+    if let Some(handle) = RUNTIME_HANDLE.read().unwrap().clone() {
+        return handle.block_on(future);
+    }
+    RUNTIME.lock().unwrap().block_on(future)
+}
+
+/// Use an existing runtime for `block_on_future()` instead of the shared one bwt creates.
+///
+/// The runtime has to drive its I/O and timers on its own (i.e. a threaded scheduler runtime).
+#[cfg(feature = "tokio")]
+pub fn set_runtime_handle(handle: tokio::runtime::Handle) {
+    *RUNTIME_HANDLE.write().unwrap() = Some(handle);
 }
 
 pub trait BoolThen {
@@ -144,3 +351,219 @@ impl BoolThen for bool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_mempool(entries: &[(u64, f64)]) -> HashMap<Txid, Value> {
+        entries
+            .iter()
+            .enumerate()
+            .map(|(i, (vsize, feerate))| {
+                let txid = Txid::hash(&i.to_le_bytes());
+                let fee = *vsize as f64 * feerate / 100_000_000f64;
+                (txid, json!({ "vsize": vsize, "fee": fee }))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_exponential_fee_histogram() {
+        let mempool = make_mempool(&[
+            (1000, 0.5),
+            (2000, 1.0),
+            (2000, 1.5),
+            (500, 3.0),
+            (4000, 12.0),
+            (3000, 24.0),
+            (1000, 150.0),
+            (800, 2000.0),
+        ]);
+        let histogram = make_fee_histogram(mempool, FeeHistogramMode::Exponential);
+        assert_eq!(
+            histogram,
+            vec![
+                (1000.0, 800),
+                (100.0, 1000),
+                (10.0, 7000),
+                (2.0, 500),
+                (1.0, 4000),
+                (0.0, 1000),
+            ]
+        );
+
+        // the linear mode remains the default and is unaffected
+        let mempool = make_mempool(&[(30_000, 5.0), (30_000, 3.0), (10_000, 1.0)]);
+        let histogram = make_fee_histogram(mempool, FeeHistogramMode::Linear);
+        assert_eq!(histogram, vec![(3.0, 60_000), (1.0, 10_000)]);
+    }
+
+    #[test]
+    fn test_fee_histogram_width() {
+        let mempool = || {
+            make_mempool(&[
+                (4000, 1.0),
+                (3000, 2.0),
+                (3000, 3.0),
+                (2000, 4.0),
+                (1000, 5.0),
+            ])
+        };
+        let histogram = |bin_width| {
+            make_fee_histogram_with_width(
+                mempool(),
+                FeeHistogramMode::Linear,
+                VsizeField::Vsize,
+                bin_width,
+            )
+        };
+
+        // everything fits within the default width, flushed as a single final partial bin
+        assert_eq!(histogram(VSIZE_BIN_WIDTH), vec![(1.0, 13_000)]);
+        assert_eq!(
+            make_fee_histogram(mempool(), FeeHistogramMode::Linear),
+            histogram(VSIZE_BIN_WIDTH)
+        );
+
+        // bins are closed once they exceed the width, the remainder is flushed as the final bin
+        assert_eq!(histogram(5000), vec![(3.0, 6000), (1.0, 7000)]);
+
+        // a zero width is clamped, giving every feerate its own bin
+        assert_eq!(histogram(0), histogram(1));
+        let bin_sizes: Vec<u32> = histogram(0).into_iter().map(|(_, size)| size).collect();
+        assert_eq!(bin_sizes, vec![1000, 2000, 3000, 3000, 4000]);
+    }
+
+    #[test]
+    fn test_vsize_field() {
+        let entry = json!({ "vsize": 150, "size": 250, "weight": 597, "fee": 0.0001 });
+        assert_eq!(VsizeField::Vsize.get(&entry), Some(150));
+        assert_eq!(VsizeField::Size.get(&entry), Some(250));
+        // rounded up
+        assert_eq!(VsizeField::Weight.get(&entry), Some(150));
+
+        // fallbacks for entries missing the preferred field
+        let entry = json!({ "size": 250, "fee": 0.0001 });
+        assert_eq!(VsizeField::Vsize.get(&entry), Some(250));
+        assert_eq!(VsizeField::Weight.get(&entry), Some(250));
+        let entry = json!({ "vsize": 150, "fee": 0.0001 });
+        assert_eq!(VsizeField::Size.get(&entry), Some(150));
+
+        // the preference affects the histogram feerates
+        let mempool = || {
+            let entry = json!({ "vsize": 100, "size": 200, "weight": 400, "fee": 0.00001 });
+            vec![(Txid::hash(b"tx"), entry)].into_iter().collect()
+        };
+        let histogram = |field| {
+            let histogram = make_fee_histogram_with_width(
+                mempool(),
+                FeeHistogramMode::Linear,
+                field,
+                VSIZE_BIN_WIDTH,
+            );
+            let rounded = |(feerate, size): (f32, u32)| (feerate.round(), size);
+            histogram.into_iter().map(rounded).collect::<Vec<_>>()
+        };
+        assert_eq!(histogram(VsizeField::Vsize), vec![(10.0, 100)]);
+        assert_eq!(histogram(VsizeField::Size), vec![(5.0, 200)]);
+        assert_eq!(histogram(VsizeField::Weight), vec![(10.0, 100)]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_block_on_future() {
+        // the shared runtime is reused across calls, including from other threads
+        assert_eq!(block_on_future(async { 1 + 1 }), 2);
+        let thread = std::thread::spawn(|| {
+            block_on_future(async { tokio::time::delay_for(Duration::from_millis(10)).await });
+            3
+        });
+        assert_eq!(thread.join().unwrap(), 3);
+        assert_eq!(block_on_future(async { 4 }), 4);
+    }
+
+    #[test]
+    fn test_package_feerate() {
+        // a 1 sat/vB parent (200 vB) bumped by a 19 sat/vB child (200 vB), for a 10 sat/vB package
+        let parent = json!({
+            "vsize": 200, "fee": 0.000002,
+            "ancestorsize": 200, "ancestorfees": 200,
+            "descendantsize": 400, "descendantfees": 4000,
+        });
+        let child = json!({
+            "vsize": 200, "fee": 0.000038,
+            "ancestorsize": 400, "ancestorfees": 4000,
+            "descendantsize": 200, "descendantfees": 3800,
+        });
+        assert_eq!(package_feerate(&parent, 1.0), 10.0);
+        assert_eq!(package_feerate(&child, 19.0), 10.0);
+
+        // both end up binned at the package feerate
+        let mempool = vec![
+            (Txid::hash(b"parent"), parent),
+            (Txid::hash(b"child"), child),
+        ]
+        .into_iter()
+        .collect();
+        let histogram = make_fee_histogram(mempool, FeeHistogramMode::Exponential);
+        assert_eq!(histogram, vec![(10.0, 400)]);
+
+        // the individual feerate is used when the package fields are missing
+        assert_eq!(
+            package_feerate(&json!({ "vsize": 200, "fee": 0.000002 }), 1.0),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_debounce_max_wait() {
+        let (forward_tx, forward_rx) = mpsc::channel();
+        let debounce_tx = debounce_sender(forward_tx, 1, Some(2));
+
+        // flood the sender faster than the debounce duration
+        let start = Instant::now();
+        let flood = thread::spawn(move || {
+            while start.elapsed() < Duration::from_secs(4) {
+                debounce_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        // a sync gets triggered once the events were pending for `max_wait`
+        forward_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3));
+        flood.join().unwrap();
+    }
+
+    #[test]
+    fn test_merkle_branch() {
+        let hashes: Vec<sha256d::Hash> = (0..5u8).map(|i| sha256d::Hash::hash(&[i])).collect();
+        let (_, root) = create_merkle_branch_and_root(hashes.clone(), 0);
+
+        for pos in 0..hashes.len() {
+            let (branch, branch_root) = create_merkle_branch_and_root(hashes.clone(), pos);
+            assert_eq!(branch_root, root);
+            assert_eq!(branch.len(), 3);
+            // folding the branch from the leaf up should arrive at the root
+            let (folded, _) = branch
+                .iter()
+                .fold((hashes[pos], pos), |(hash, index), sibling| {
+                    let parent = if index % 2 == 0 {
+                        merklize(hash, *sibling)
+                    } else {
+                        merklize(*sibling, hash)
+                    };
+                    (parent, index / 2)
+                });
+            assert_eq!(folded, root);
+        }
+
+        // a single hash is its own root
+        assert_eq!(
+            create_merkle_branch_and_root(vec![hashes[0]], 0),
+            (vec![], hashes[0])
+        );
+    }
+}
@@ -4,12 +4,16 @@ use std::str::FromStr;
 
 pub use serde::de;
 
-use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
-use bitcoin::{util::base58, Network};
-use miniscript::descriptor::{Descriptor, DescriptorPublicKey, DescriptorXKey};
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::util::bip32::{self, ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
+use bitcoin::{util::base58, Address, Network, PublicKey};
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey, DescriptorXKey, ShInner, Wildcard};
 
+use bitcoin_hashes::hex::ToHex;
+
+use crate::error::{OptionExt, Result};
 use crate::types::ScriptType;
-use crate::util::descriptor::ExtendedDescriptor;
+use crate::util::descriptor::{ExtendedDescriptor, EC};
 use crate::util::BoolThen;
 
 pub fn xpub_matches_network(xpub: &ExtendedPubKey, network: Network) -> bool {
@@ -18,7 +22,13 @@ pub fn xpub_matches_network(xpub: &ExtendedPubKey, network: Network) -> bool {
 }
 
 /// An extended public key with an associated script type.
-/// Used to represent SLIP 32 [xyz]pubs, as well as simple p2*pkh descriptors.
+/// Used to represent SLIP 32 [xyz]pubs, as well as simple p2*pkh and p2tr descriptors.
+///
+/// SLIP 132 has no version bytes for Taproot, P2tr keys can only be obtained from a `tr()`
+/// descriptor using `try_from_desc()`.
+///
+/// SLIP 132 multisig cosigner keys ([YZUV]pubs) are recognized too, but cannot be turned into
+/// a standalone descriptor and are meant to be used within a multisig descriptor instead.
 #[derive(Clone)]
 pub struct XyzPubKey {
     script_type: ScriptType,
@@ -31,38 +41,210 @@ impl_debug_display!(XyzPubKey);
 #[derive(Clone, Debug)]
 pub struct Bip32Origin(pub Fingerprint, pub DerivationPath);
 
+#[derive(thiserror::Error, Debug)]
+pub enum XyzPubKeyError {
+    #[error("unknown extended public key version bytes {}", .0.to_hex())]
+    UnknownVersion([u8; 4]),
+
+    #[error("this looks like a {0}; bwt only supports Bitcoin")]
+    AltcoinKey(&'static str),
+
+    #[error("invalid extended public key length of {0} bytes, expecting 78")]
+    InvalidLength(usize),
+
+    #[error("this looks like a multisig {0}, not a single-sig key. use it within a multisig descriptor instead")]
+    MultisigKey(&'static str),
+
+    #[error("invalid base58 encoding: {0}")]
+    Base58(#[from] base58::Error),
+
+    #[error("invalid bip32 key: {0}")]
+    Bip32(#[from] bip32::Error),
+}
+
 impl XyzPubKey {
-    pub fn as_descriptor(&self, derivation_path: DerivationPath) -> ExtendedDescriptor {
+    pub fn script_type(&self) -> ScriptType {
+        self.script_type
+    }
+
+    pub fn xpub(&self) -> &ExtendedPubKey {
+        &self.xpub
+    }
+
+    /// Whether this is a cosigner key intended for use in a multisig descriptor
+    pub fn is_multisig(&self) -> bool {
+        self.script_type.is_multisig()
+    }
+
+    pub fn as_descriptor(&self, derivation_path: DerivationPath) -> Result<ExtendedDescriptor> {
         let bip32_origin = (self.xpub.depth > 0).do_then(|| {
             (
                 self.xpub.parent_fingerprint,
                 [self.xpub.child_number][..].into(),
             )
         });
+        self.build_descriptor(bip32_origin, derivation_path)
+    }
+
+    /// The inverse of as_descriptor(): recover the key and the derivation path from a single-key
+    /// descriptor with an unhardened wildcard, like `wpkh(xpub/0/*)` or `tr(xpub/0/*)`.
+    /// Returns None for other descriptors, including taproot descriptors with script paths.
+    pub fn try_from_desc(desc: &ExtendedDescriptor) -> Option<(Self, DerivationPath)> {
+        let (script_type, desc_key) = match desc {
+            Descriptor::Pkh(pkh) => (ScriptType::P2pkh, pkh.as_inner()),
+            Descriptor::Wpkh(wpkh) => (ScriptType::P2wpkh, wpkh.as_inner()),
+            Descriptor::Sh(sh) => match sh.as_inner() {
+                ShInner::Wpkh(wpkh) => (ScriptType::P2shP2wpkh, wpkh.as_inner()),
+                _ => return None,
+            },
+            Descriptor::Tr(tr) if tr.taptree().is_none() => (ScriptType::P2tr, tr.internal_key()),
+            _ => return None,
+        };
+        match desc_key {
+            DescriptorPublicKey::XPub(desc_xpub) if desc_xpub.wildcard == Wildcard::Unhardened => {
+                let xyzpub = XyzPubKey {
+                    script_type,
+                    xpub: desc_xpub.xkey,
+                };
+                Some((xyzpub, desc_xpub.derivation_path.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Like as_descriptor(), but with the full key origin (typically the master fingerprint and
+    /// the path to the xpub) provided by the caller, for descriptors usable by PSBT signers
+    pub fn as_descriptor_with_origin(
+        &self,
+        origin: Bip32Origin,
+        derivation_path: DerivationPath,
+    ) -> Result<ExtendedDescriptor> {
+        self.build_descriptor(Some((origin.0, origin.1)), derivation_path)
+    }
+
+    fn build_descriptor(
+        &self,
+        origin: Option<(Fingerprint, DerivationPath)>,
+        derivation_path: DerivationPath,
+    ) -> Result<ExtendedDescriptor> {
+        self.ensure_singlesig()?;
 
         let desc_key = DescriptorPublicKey::XPub(DescriptorXKey {
-            origin: bip32_origin,
+            origin,
             xkey: self.xpub,
             derivation_path,
-            is_wildcard: true,
+            wildcard: Wildcard::Unhardened,
         });
 
-        match self.script_type {
-            ScriptType::P2pkh => Descriptor::Pkh(desc_key),
-            ScriptType::P2wpkh => Descriptor::Wpkh(desc_key),
-            ScriptType::P2shP2wpkh => Descriptor::ShWpkh(desc_key),
-        }
+        Ok(match self.script_type {
+            ScriptType::P2pkh => Descriptor::new_pkh(desc_key),
+            ScriptType::P2wpkh => Descriptor::new_wpkh(desc_key)?,
+            ScriptType::P2shP2wpkh => Descriptor::new_sh_wpkh(desc_key)?,
+            ScriptType::P2tr => Descriptor::new_tr(desc_key, None)?,
+            ScriptType::P2wsh | ScriptType::P2shP2wsh => unreachable!(),
+        })
+    }
+
+    /// Derive the addresses of `count` consecutive child keys, starting at index `start`.
+    /// The range may not include hardened indexes.
+    pub fn derive_addresses(
+        &self,
+        start: u32,
+        count: u32,
+        network: Network,
+    ) -> Result<Vec<Address>> {
+        self.ensure_singlesig()?;
+        ensure!(
+            start as u64 + count as u64 <= 1 << 31,
+            "cannot derive addresses for indexes {}..{}, the range includes hardened indexes",
+            start,
+            start as u64 + count as u64
+        );
+
+        (start..start + count)
+            .map(|index| {
+                let child = self
+                    .xpub
+                    .ckd_pub(&EC, ChildNumber::from_normal_idx(index)?)?;
+                let pubkey = PublicKey::new(child.public_key);
+                Ok(match self.script_type {
+                    ScriptType::P2pkh => Address::p2pkh(&pubkey, network),
+                    ScriptType::P2wpkh => Address::p2wpkh(&pubkey, network)?,
+                    ScriptType::P2shP2wpkh => Address::p2shwpkh(&pubkey, network)?,
+                    ScriptType::P2tr => {
+                        let internal_key = XOnlyPublicKey::from(child.public_key);
+                        Address::p2tr(&EC, internal_key, None, network)
+                    }
+                    ScriptType::P2wsh | ScriptType::P2shP2wsh => unreachable!(),
+                })
+            })
+            .collect()
+    }
+
+    fn ensure_singlesig(&self) -> StdResult<(), XyzPubKeyError> {
+        let prefix = match (self.script_type, self.xpub.network) {
+            (ScriptType::P2wsh, Network::Bitcoin) => "Zpub",
+            (ScriptType::P2shP2wsh, Network::Bitcoin) => "Ypub",
+            (ScriptType::P2wsh, _) => "Vpub",
+            (ScriptType::P2shP2wsh, _) => "Upub",
+            _ => return Ok(()),
+        };
+        Err(XyzPubKeyError::MultisigKey(prefix))
     }
 }
 
+/// Build the conventional BIP 44/49/84/86 descriptor for the `chain` (0 for receive, 1 for change)
+/// of an account, like `wpkh([fingerprint/84'/0'/account']xpub/0/*)` for p2wpkh.
+///
+/// Hardened children cannot be derived from public keys, so the account-level xpub (at depth 3) has to
+/// be provided along with the fingerprint of the master key (see `ExtendedPubKey::fingerprint()`).
+/// The coin type is determined by the xpub's network.
+pub fn account_descriptor(
+    master_fingerprint: Fingerprint,
+    account_xpub: &ExtendedPubKey,
+    account: u32,
+    script_type: ScriptType,
+    chain: u32,
+) -> Result<ExtendedDescriptor> {
+    let purpose = script_type
+        .bip_purpose()
+        .or_err("no account structure convention for multisig script types")?;
+    let account_cn = ChildNumber::from_hardened_idx(account)?;
+    ensure!(
+        account_xpub.depth == 3 && account_xpub.child_number == account_cn,
+        "expected the account-level xpub for account {}' (at depth 3), not a key at depth {} with child number {}",
+        account,
+        account_xpub.depth,
+        account_xpub.child_number
+    );
+    let coin_type = match account_xpub.network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+
+    let origin_path = vec![
+        ChildNumber::from_hardened_idx(purpose)?,
+        ChildNumber::from_hardened_idx(coin_type)?,
+        account_cn,
+    ];
+    let xyzpub = XyzPubKey {
+        script_type,
+        xpub: *account_xpub,
+    };
+    xyzpub.as_descriptor_with_origin(
+        Bip32Origin(master_fingerprint, origin_path.into()),
+        vec![ChildNumber::from_normal_idx(chain)?].into(),
+    )
+}
+
 impl FromStr for XyzPubKey {
-    type Err = base58::Error;
+    type Err = XyzPubKeyError;
 
-    fn from_str(inp: &str) -> StdResult<XyzPubKey, base58::Error> {
+    fn from_str(inp: &str) -> StdResult<XyzPubKey, XyzPubKeyError> {
         let mut data = base58::from_check(inp)?;
 
         if data.len() != 78 {
-            return Err(base58::Error::InvalidLength(data.len()));
+            return Err(XyzPubKeyError::InvalidLength(data.len()));
         }
 
         // rust-bitcoin's bip32 implementation does not support ypubs/zpubs.
@@ -132,30 +314,63 @@ impl serde::Serialize for Bip32Origin {
     }
 }
 
-fn parse_xyz_version(version: &[u8]) -> StdResult<(Network, ScriptType), base58::Error> {
+fn parse_xyz_version(version: &[u8]) -> StdResult<(Network, ScriptType), XyzPubKeyError> {
+    if let Some(coin_key) = altcoin_key_name(version) {
+        return Err(XyzPubKeyError::AltcoinKey(coin_key));
+    }
+
     Ok(match version {
         [0x04u8, 0x88, 0xB2, 0x1E] => (Network::Bitcoin, ScriptType::P2pkh),
         [0x04u8, 0xB2, 0x47, 0x46] => (Network::Bitcoin, ScriptType::P2wpkh),
         [0x04u8, 0x9D, 0x7C, 0xB2] => (Network::Bitcoin, ScriptType::P2shP2wpkh),
+        // SLIP 132 multisig Zpub and Ypub
+        [0x02u8, 0xAA, 0x7E, 0xD3] => (Network::Bitcoin, ScriptType::P2wsh),
+        [0x02u8, 0x95, 0xB4, 0x3F] => (Network::Bitcoin, ScriptType::P2shP2wsh),
 
         [0x04u8, 0x35, 0x87, 0xCF] => (Network::Testnet, ScriptType::P2pkh),
         [0x04u8, 0x5F, 0x1C, 0xF6] => (Network::Testnet, ScriptType::P2wpkh),
         [0x04u8, 0x4A, 0x52, 0x62] => (Network::Testnet, ScriptType::P2shP2wpkh),
+        // SLIP 132 multisig Vpub and Upub
+        [0x02u8, 0x57, 0x54, 0x83] => (Network::Testnet, ScriptType::P2wsh),
+        [0x02u8, 0x42, 0x89, 0xEF] => (Network::Testnet, ScriptType::P2shP2wsh),
+
+        _ => {
+            let mut unknown = [0u8; 4];
+            unknown.copy_from_slice(version);
+            return Err(XyzPubKeyError::UnknownVersion(unknown));
+        }
+    })
+}
 
-        _ => return Err(base58::Error::InvalidVersion(version.to_vec())),
+// Identify the extended key version bytes of some commonly used non-Bitcoin coins,
+// to provide users with a more helpful error message
+fn altcoin_key_name(version: &[u8]) -> Option<&'static str> {
+    Some(match version {
+        [0x01u8, 0x9D, 0xA4, 0x62] => "Litecoin Ltub",
+        [0x01u8, 0x9D, 0x9C, 0xFE] => "Litecoin Ltpv",
+        [0x01u8, 0xB2, 0x6E, 0xF6] => "Litecoin Mtub",
+        [0x01u8, 0xB2, 0x67, 0x92] => "Litecoin Mtpv",
+        [0x04u8, 0x36, 0xF6, 0xE1] => "Litecoin testnet ttub",
+        [0x04u8, 0x36, 0xEF, 0x7D] => "Litecoin testnet ttpv",
+        [0x02u8, 0xFA, 0xCA, 0xFD] => "Dogecoin dgub",
+        [0x02u8, 0xFA, 0xC3, 0x98] => "Dogecoin dgpv",
+        [0x02u8, 0xFE, 0x52, 0xCC] => "Dash drkp",
+        [0x02u8, 0xFE, 0x52, 0xF8] => "Dash drkv",
+        _ => return None,
     })
 }
 
 fn get_xpub_p2pkh_version(network: Network) -> [u8; 4] {
     match network {
         Network::Bitcoin => [0x04u8, 0x88, 0xB2, 0x1E],
-        Network::Testnet | Network::Regtest => [0x04u8, 0x35, 0x87, 0xCF],
+        Network::Testnet | Network::Signet | Network::Regtest => [0x04u8, 0x35, 0x87, 0xCF],
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::descriptor::{self, DescriptorChecksum};
 
     #[test]
     fn test_xyzpub_to_desc() {
@@ -174,9 +389,258 @@ mod tests {
         ];
         for (xyz_str, expected_desc) in &test_cases {
             let xyzpub = xyz_str.parse::<XyzPubKey>().unwrap();
-            let desc = xyzpub.as_descriptor([][..].into());
+            let desc = xyzpub.as_descriptor([][..].into()).unwrap();
+
+            assert_eq!(desc.to_string_no_checksum(), *expected_desc);
+        }
+    }
+
+    #[test]
+    fn test_as_descriptor_with_origin() {
+        let zpub = "zpub6jftahH18ngZwwDgquxFKyv4bUWuqfwm2xtt4yt7Ek53uFigQNhhrT1EgGDZWXJBZ2dV2nyr5oesnRoUsuVz72hBc5C2YDzXuKFsrTu7JHp";
+        let xyzpub = zpub.parse::<XyzPubKey>().unwrap();
+        let origin_path: DerivationPath = "m/84'/0'/0'".parse().unwrap();
+        let origin = Bip32Origin("d34db33f".parse().unwrap(), origin_path);
+        let desc = xyzpub
+            .as_descriptor_with_origin(origin, [0.into()][..].into())
+            .unwrap();
+
+        assert_eq!(
+            desc.to_string_no_checksum(),
+            format!("wpkh([d34db33f/84'/0'/0']{}/0/*)", xyzpub.xpub)
+        );
+        // the origin does not affect the derived scripts
+        assert_eq!(
+            descriptor::derive_address(&desc, 3, Network::Bitcoin),
+            descriptor::derive_address(
+                &xyzpub.as_descriptor([0.into()][..].into()).unwrap(),
+                3,
+                Network::Bitcoin
+            )
+        );
+    }
+
+    #[test]
+    fn test_altcoin_key_detection() {
+        let xpub = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+        let with_version = |version: [u8; 4]| {
+            let mut data = base58::from_check(xpub).unwrap();
+            data.splice(0..4, version.iter().cloned());
+            base58::check_encode_slice(&data)
+        };
+
+        let ltub = with_version([0x01, 0x9D, 0xA4, 0x62]);
+        assert!(ltub.starts_with("Ltub"));
+        let err = ltub.parse::<XyzPubKey>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "this looks like a Litecoin Ltub; bwt only supports Bitcoin"
+        );
+
+        let mtub = with_version([0x01, 0xB2, 0x6E, 0xF6]);
+        assert!(mtub.starts_with("Mtub"));
+        let err = mtub.parse::<XyzPubKey>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "this looks like a Litecoin Mtub; bwt only supports Bitcoin"
+        );
+
+        // unrecognized version bytes are still reported as such
+        let unknown = with_version([0x01, 0x02, 0x03, 0x04]);
+        let err = unknown.parse::<XyzPubKey>().unwrap_err();
+        assert!(matches!(
+            err,
+            XyzPubKeyError::UnknownVersion([0x01, 0x02, 0x03, 0x04])
+        ));
+    }
+
+    #[test]
+    fn test_multisig_xyzpub() {
+        let xpub = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+        let tpub = "tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp";
+        let with_version = |xpub: &str, version: [u8; 4]| {
+            let mut data = base58::from_check(xpub).unwrap();
+            data.splice(0..4, version.iter().cloned());
+            base58::check_encode_slice(&data)
+        };
+
+        let test_cases = [
+            (xpub, [0x02, 0xAA, 0x7E, 0xD3], "Zpub", ScriptType::P2wsh),
+            (
+                xpub,
+                [0x02, 0x95, 0xB4, 0x3F],
+                "Ypub",
+                ScriptType::P2shP2wsh,
+            ),
+            (tpub, [0x02, 0x57, 0x54, 0x83], "Vpub", ScriptType::P2wsh),
+            (
+                tpub,
+                [0x02, 0x42, 0x89, 0xEF],
+                "Upub",
+                ScriptType::P2shP2wsh,
+            ),
+        ];
+        for (xpub, version, prefix, script_type) in &test_cases {
+            let key_str = with_version(xpub, *version);
+            assert!(key_str.starts_with(prefix));
+
+            let xyzpub = key_str.parse::<XyzPubKey>().unwrap();
+            assert!(xyzpub.is_multisig());
+            assert_eq!(xyzpub.script_type(), *script_type);
+            assert_eq!(xyzpub.xpub().to_string(), *xpub);
+            // multisig keys are never wrapped into a single-sig descriptor
+            let err = xyzpub.as_descriptor([][..].into()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "this looks like a multisig {}, not a single-sig key. use it within a multisig descriptor instead",
+                    prefix
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_derive_addresses() {
+        let zpub = "zpub6jftahH18ngZwwDgquxFKyv4bUWuqfwm2xtt4yt7Ek53uFigQNhhrT1EgGDZWXJBZ2dV2nyr5oesnRoUsuVz72hBc5C2YDzXuKFsrTu7JHp";
+        let xyzpub = zpub.parse::<XyzPubKey>().unwrap();
+
+        // matches the addresses derived through the descriptor
+        let desc = xyzpub.as_descriptor([][..].into()).unwrap();
+        let addresses = xyzpub.derive_addresses(5, 3, Network::Bitcoin).unwrap();
+        assert_eq!(addresses.len(), 3);
+        for (i, address) in addresses.iter().enumerate() {
+            let expected =
+                descriptor::derive_address(&desc, 5 + i as u32, Network::Bitcoin).unwrap();
+            assert_eq!(*address, expected);
+        }
+        assert!(xyzpub
+            .derive_addresses(0, 0, Network::Bitcoin)
+            .unwrap()
+            .is_empty());
+
+        // ranges reaching into hardened indexes are rejected
+        let last_normal = (1 << 31) - 1;
+        assert_eq!(
+            xyzpub
+                .derive_addresses(last_normal, 1, Network::Bitcoin)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(xyzpub
+            .derive_addresses(last_normal, 2, Network::Bitcoin)
+            .is_err());
+        assert!(xyzpub
+            .derive_addresses(u32::MAX, 1, Network::Bitcoin)
+            .is_err());
+    }
+
+    #[test]
+    fn test_account_descriptor() {
+        // the BIP 44/49/84/86 test vectors for the "abandon abandon ... about" mnemonic
+        let master_fingerprint: Fingerprint = "73c5da0a".parse().unwrap();
+        let test_cases = [
+            (ScriptType::P2pkh,
+             "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj",
+             "pkh([73c5da0a/44'/0'/0']{}/0/*)",
+             "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA"),
+            (ScriptType::P2shP2wpkh,
+             "ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLDWCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP",
+             "sh(wpkh([73c5da0a/49'/0'/0']{}/0/*))",
+             "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf"),
+            (ScriptType::P2wpkh,
+             "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs",
+             "wpkh([73c5da0a/84'/0'/0']{}/0/*)",
+             "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"),
+            (ScriptType::P2tr,
+             "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ",
+             "tr([73c5da0a/86'/0'/0']{}/0/*)",
+             "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"),
+        ];
+        for (script_type, xyz_str, expected_desc, first_address) in &test_cases {
+            let xpub = *xyz_str.parse::<XyzPubKey>().unwrap().xpub();
+            let desc = account_descriptor(master_fingerprint, &xpub, 0, *script_type, 0).unwrap();
+
+            assert_eq!(
+                desc.to_string_no_checksum(),
+                expected_desc.replace("{}", &xpub.to_string())
+            );
+            assert_eq!(
+                descriptor::derive_address(&desc, 0, Network::Bitcoin)
+                    .unwrap()
+                    .to_string(),
+                *first_address
+            );
+        }
+
+        let xpub = *test_cases[2].1.parse::<XyzPubKey>().unwrap().xpub();
+        // the change chain
+        let desc = account_descriptor(master_fingerprint, &xpub, 0, ScriptType::P2wpkh, 1).unwrap();
+        assert_eq!(
+            desc.to_string_no_checksum(),
+            format!("wpkh([73c5da0a/84'/0'/0']{}/1/*)", xpub)
+        );
+
+        // the xpub has to match the account, and has to be at the account level
+        assert!(account_descriptor(master_fingerprint, &xpub, 1, ScriptType::P2wpkh, 0).is_err());
+        let master_xpub = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+        let master_xpub = *master_xpub.parse::<XyzPubKey>().unwrap().xpub();
+        assert!(
+            account_descriptor(master_fingerprint, &master_xpub, 0, ScriptType::P2wpkh, 0).is_err()
+        );
+        // no convention for multisig, and the chain cannot be hardened
+        assert!(account_descriptor(master_fingerprint, &xpub, 0, ScriptType::P2wsh, 0).is_err());
+        assert!(
+            account_descriptor(master_fingerprint, &xpub, 0, ScriptType::P2wpkh, 1 << 31).is_err()
+        );
+    }
+
+    #[test]
+    fn test_try_from_desc() {
+        let xpub = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+        let test_cases = [
+            ("pkh(XPUB/0/*)", ScriptType::P2pkh),
+            ("wpkh(XPUB/0/*)", ScriptType::P2wpkh),
+            ("sh(wpkh(XPUB/0/*))", ScriptType::P2shP2wpkh),
+            ("tr(XPUB/0/*)", ScriptType::P2tr),
+        ];
+        for (desc_str, script_type) in &test_cases {
+            let desc_str = desc_str.replace("XPUB", xpub);
+            let desc = ExtendedDescriptor::from_str(&desc_str).unwrap();
+            let (xyzpub, derivation_path) = XyzPubKey::try_from_desc(&desc).unwrap();
+            assert_eq!(xyzpub.script_type(), *script_type);
+            assert_eq!(xyzpub.xpub().to_string(), xpub);
+            assert_eq!(derivation_path, [0.into()][..].into());
+
+            // round-trips back into the same descriptor
+            let rebuilt = xyzpub.as_descriptor(derivation_path).unwrap();
+            assert_eq!(rebuilt.to_string_no_checksum(), desc_str);
+            // the xpub's direct children match the descriptor without the derivation path
+            let root_desc = xyzpub.as_descriptor([][..].into()).unwrap();
+            let addresses = xyzpub.derive_addresses(0, 2, Network::Bitcoin).unwrap();
+            for (index, address) in addresses.iter().enumerate() {
+                let expected =
+                    descriptor::derive_address(&root_desc, index as u32, Network::Bitcoin);
+                assert_eq!(Some(address), expected.as_ref());
+            }
+        }
+
+        // taproot addresses use bech32m
+        let desc = format!("tr({}/0/*)", xpub).parse().unwrap();
+        let (xyzpub, _) = XyzPubKey::try_from_desc(&desc).unwrap();
+        let address = &xyzpub.derive_addresses(0, 1, Network::Bitcoin).unwrap()[0];
+        assert!(address.to_string().starts_with("bc1p"));
 
-            assert_eq!(desc.to_string(), *expected_desc);
+        // descriptors that aren't single-key are not representable
+        for desc_str in &[
+            "wsh(multi(1,XPUB/0/*))",
+            "wpkh(XPUB/0/0)",
+            "tr(XPUB/0/*,pk(XPUB/1/*))",
+        ] {
+            let desc_str = desc_str.replace("XPUB", xpub);
+            let desc = ExtendedDescriptor::from_str(&desc_str).unwrap();
+            assert!(XyzPubKey::try_from_desc(&desc).is_none());
         }
     }
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::{Address, OutPoint, Txid};
+
+use crate::error::{Context, Result};
+use crate::store::{FundingInfo, SpendingInfo};
+use crate::types::{BlockId, ScriptHash, TxStatus};
+
+// Don't bother compacting the log before it grows past this number of entries
+const COMPACT_MIN_ENTRIES: usize = 5000;
+
+/// A mutation applied to the in-memory index, as recorded in the write-ahead log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalEntry {
+    // the key origin is stored as its label, see KeyOrigin::to_label()
+    Scripthash(ScriptHash, String, Address),
+    Tx(Txid, TxStatus),
+    Funding(Txid, u32, FundingInfo),
+    Spending(Txid, HashMap<u32, SpendingInfo>),
+    Replacement(Txid, Txid),
+    Purge(Txid),
+    // the spending input is stored as its (txid, vin)
+    TxoSpend(OutPoint, Txid, u32),
+    // an output that was frozen (or unfrozen) for coin control
+    FrozenTxo(OutPoint, bool),
+    // the full set of immature coinbase outputs, as (outpoint, wallet checksum, amount, block height)
+    ImmatureCoinbase(Vec<(OutPoint, String, u64, u32)>),
+    // marks the chain tip the preceding mutations were synced up to,
+    // or None if the next sync should start from scratch
+    SyncedTip(Option<BlockId>),
+}
+
+/// An append-only log of index mutations, stored as newline-delimited JSON.
+///
+/// The log is replayed on startup to restore the index without re-syncing it from scratch,
+/// and is periodically compacted into the list of entries that rebuilds the current state.
+pub struct Wal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    // entries written since the last compaction, and the number of entries it was compacted to
+    entries: usize,
+    compacted_entries: usize,
+}
+
+impl Wal {
+    /// Open the log at `path` (creating it if it doesn't exist) and read back its entries.
+    ///
+    /// An incomplete trailing line (left behind by a crash mid-write) is discarded.
+    pub fn open(path: &Path) -> Result<(Self, Vec<WalEntry>)> {
+        let mut entries = vec![];
+        if path.exists() {
+            let reader = BufReader::new(File::open(path).context("failed opening wal")?);
+            let mut lines = reader.lines().peekable();
+            while let Some(line) = lines.next() {
+                let line = line.context("failed reading wal")?;
+                match serde_json::from_str(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) if lines.peek().is_none() => {
+                        warn!("discarding incomplete wal entry: {}", e);
+                    }
+                    Err(e) => bail!("invalid wal entry {:?}: {}", line, e),
+                }
+            }
+        }
+        debug!("read {} wal entries from {:?}", entries.len(), path);
+
+        // rewrite the log to drop the discarded trailing line (if any) before appending to it
+        let mut wal = Wal {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(Self::open_append(path)?),
+            entries: 0,
+            compacted_entries: 0,
+        };
+        wal.compact(&entries)?;
+        Ok((wal, entries))
+    }
+
+    /// Append entries to the log. They are flushed to disk before returning.
+    pub fn append(&mut self, entries: &[WalEntry]) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.writer, entry)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.entries += entries.len();
+        Ok(())
+    }
+
+    /// Whether the log grew large enough (relative to the state it describes) to be compacted
+    pub fn needs_compaction(&self) -> bool {
+        self.entries > self.compacted_entries.max(COMPACT_MIN_ENTRIES)
+    }
+
+    /// Replace the log with the given entries, atomically
+    pub fn compact(&mut self, entries: &[WalEntry]) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp_writer = BufWriter::new(File::create(&tmp_path)?);
+            for entry in entries {
+                serde_json::to_writer(&mut tmp_writer, entry)?;
+                tmp_writer.write_all(b"\n")?;
+            }
+            tmp_writer.flush()?;
+            tmp_writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path).context("failed replacing wal")?;
+
+        self.writer = BufWriter::new(Self::open_append(&self.path)?);
+        self.entries = 0;
+        self.compacted_entries = entries.len();
+        debug!("compacted wal to {} entries", entries.len());
+        Ok(())
+    }
+
+    fn open_append(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("failed opening wal")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::wallet::KeyOrigin;
+    use bitcoin_hashes::hex::FromHex;
+
+    #[test]
+    fn test_wal_replay() {
+        let path = std::env::temp_dir().join(format!("bwt-test-wal-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let address: Address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse().unwrap();
+        let scripthash = ScriptHash::from(&address);
+        let origin = KeyOrigin::Descriptor("xjm8w0el".parse().unwrap(), 0);
+        let txid = |n: u8| Txid::from_hex(&format!("{:02x}", n).repeat(32)).unwrap();
+
+        let mut store = MemoryStore::new();
+        store.enable_journal();
+        let (mut wal, replayed) = Wal::open(&path).unwrap();
+        assert!(replayed.is_empty());
+
+        store.index_scripthash(&scripthash, &origin, &address);
+        store.upsert_tx(&txid(1), TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&txid(1), 0, FundingInfo(scripthash, 5000));
+        store.upsert_tx(&txid(1), TxStatus::Confirmed(100));
+        store.upsert_tx(&txid(2), TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&txid(2), 1, FundingInfo(scripthash, 3000));
+        let prevout = OutPoint::new(txid(1), 0);
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 5000))];
+        store.index_tx_inputs_spending(&txid(2), spending.into_iter().collect());
+        store.upsert_tx(&txid(3), TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&txid(3), 0, FundingInfo(scripthash, 1000));
+        store.purge_tx(&txid(3));
+        wal.append(&store.take_journal()).unwrap();
+
+        // simulate a crash, leaving a partially written entry behind
+        drop(wal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"tx\":[\"0404").unwrap();
+        drop(file);
+
+        let replay = |path: &Path| {
+            let (wal, entries) = Wal::open(path).unwrap();
+            let mut restored = MemoryStore::new();
+            for entry in entries {
+                restored.apply_wal_entry(entry).unwrap();
+            }
+            (wal, restored)
+        };
+        let (mut wal, restored) = replay(&path);
+        assert_eq!(json!(restored), json!(store));
+        assert!(restored
+            .snapshot()
+            .diff(&store.snapshot())
+            .new_txs
+            .is_empty());
+        assert!(restored.get_tx_entry(&txid(3)).is_none());
+
+        // compacting preserves the state
+        wal.compact(&store.wal_entries()).unwrap();
+        drop(wal);
+        let (_, restored) = replay(&path);
+        assert_eq!(json!(restored), json!(store));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_indexer_entries() {
+        let path = std::env::temp_dir().join(format!("bwt-test-wal-idx-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let outpoint = OutPoint::new(Txid::from_hex(&"01".repeat(32)).unwrap(), 0);
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        wal.append(&[
+            WalEntry::FrozenTxo(outpoint, true),
+            WalEntry::ImmatureCoinbase(vec![(outpoint, "xjm8w0el".into(), 5000, 100)]),
+        ])
+        .unwrap();
+        drop(wal);
+
+        // entries that are not part of the store are read back as-is, to be restored by the indexer
+        let (_, entries) = Wal::open(&path).unwrap();
+        match &entries[..] {
+            [WalEntry::FrozenTxo(frozen, true), WalEntry::ImmatureCoinbase(coinbases)] => {
+                assert_eq!(*frozen, outpoint);
+                assert_eq!(coinbases, &[(outpoint, "xjm8w0el".into(), 5000, 100)]);
+            }
+            _ => panic!("unexpected entries {:?}", entries),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,21 +1,36 @@
 use serde::Serialize;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::result::Result as StdResult;
-
-use bitcoin::{Address, Network};
-use bitcoincore_rpc::json::{ImportMultiRequest, ImportMultiRequestScriptPubkey};
-use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
+use std::sync::mpsc;
+
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::util::address::AddressType;
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, Fingerprint};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Address, Network, PublicKey, Script, Transaction, TxIn, TxOut};
+use bitcoincore_rpc::json::{
+    ImportMultiOptions, ImportMultiRequest, ImportMultiRequestScriptPubkey, ImportMultiRescanSince,
+    ImportMultiResult,
+};
+use bitcoincore_rpc::{self as rpc, RpcApi};
 
 use crate::error::{Context, Result};
-use crate::store::MemoryStore;
+use crate::store::{FundingInfo, MemoryStore};
 use crate::types::RescanSince;
-use crate::util::descriptor::{self, Checksum, DescKeyInfo, ExtendedDescriptor, DESC_CTX};
+use crate::util::bitcoincore_ext::Progress;
+use crate::util::descriptor::{
+    self, Checksum, DescKeyInfo, DescriptorChecksum, ExtendedDescriptor, TrackedDescriptor,
+};
 use crate::util::xpub::{Bip32Origin, XyzPubKey};
-use crate::util::RpcApiExt;
+use crate::util::{RpcApiExt, RpcClient};
 use crate::Config;
 
 const LABEL_PREFIX: &str = "bwt";
 
+// The number of addresses submitted to bitcoind per importmulti call
+const IMPORT_BATCH_SIZE: usize = 500;
+
 #[derive(Debug)]
 pub struct WalletWatcher {
     network: Network,
@@ -25,6 +40,9 @@ pub struct WalletWatcher {
 
     /// Standalone addresses pending import
     pending_standalone_imports: Vec<AddressImport>,
+
+    /// Whether the bitcoind wallet is a descriptor wallet, determined by `check_imports()`
+    descriptor_wallet: bool,
 }
 
 type AddressImport = (Address, RescanSince);
@@ -53,16 +71,29 @@ impl WalletWatcher {
             );
         }
 
+        for (checksum_a, checksum_b) in find_overlapping(&wallets) {
+            warn!(
+                "descriptors {} and {} derive overlapping addresses, their funds will be counted twice",
+                checksum_a, checksum_b
+            );
+        }
+
         Ok(Self {
             network,
             wallets,
             pending_standalone_imports: addresses,
+            descriptor_wallet: false,
         })
     }
 
     pub fn from_config(config: &Config) -> Result<Self> {
         let mut wallets = vec![];
         for desc in &config.descriptors {
+            // addr() descriptors are included in config.addresses()
+            let desc = match desc {
+                TrackedDescriptor::Descriptor(desc) => desc,
+                TrackedDescriptor::Addr(_) => continue,
+            };
             wallets.push(
                 Wallet::from_descriptor(
                     desc.clone(),
@@ -88,6 +119,22 @@ impl WalletWatcher {
             );
         }
 
+        for range in &config.descriptor_ranges {
+            let wallet = wallets
+                .iter_mut()
+                .find(|wallet| wallet.checksum == range.checksum)
+                .with_context(|| format!("no descriptor with checksum {}", range.checksum))?;
+            wallet.set_range(range.start, range.end)?;
+        }
+
+        for checksum in &config.descriptor_active {
+            let wallet = wallets
+                .iter_mut()
+                .find(|wallet| wallet.checksum == *checksum)
+                .with_context(|| format!("no descriptor with checksum {}", checksum))?;
+            wallet.set_active()?;
+        }
+
         let addresses = config
             .addresses()?
             .into_iter()
@@ -110,25 +157,33 @@ impl WalletWatcher {
         self.wallets.get(checksum)
     }
 
-    // Mark an address as funded
-    pub fn mark_funded(&mut self, origin: &KeyOrigin) {
+    // Mark an address as funded. Returns whether this moved the watched window past the imported
+    // range, which happens when an address within `gap_limit` of the edge gets used.
+    pub fn mark_funded(&mut self, origin: &KeyOrigin) -> bool {
         if let KeyOrigin::Descriptor(checksum, index) = origin {
             if let Some(wallet) = self.wallets.get_mut(checksum) {
                 if wallet.max_imported_index.map_or(true, |max| *index > max) {
-                    wallet.max_imported_index = Some(*index);
+                    wallet.set_max_imported_index(*index);
                 }
 
                 if wallet.max_funded_index.map_or(true, |max| *index > max) {
                     wallet.max_funded_index = Some(*index);
+                    return wallet.pending_import_range().is_some();
                 }
             }
         }
+        false
     }
 
     // check previous imports and update max_imported_index
     pub fn check_imports(&mut self, rpc: &RpcClient) -> Result<()> {
         debug!("checking previous imports");
 
+        self.descriptor_wallet = rpc.is_descriptor_wallet()?;
+        if !self.descriptor_wallet && self.wallets.values().any(|wallet| wallet.active) {
+            warn!("active descriptors are only supported with descriptor wallets, the bitcoind wallet is a legacy one and will not use them for address generation");
+        }
+
         // Lookup descriptor wallet imports and update their max imported index
         let mut imported_indexes: HashMap<Checksum, u32> = HashMap::new();
         let labels = rpc.list_labels().map_err(labels_error)?;
@@ -149,7 +204,7 @@ impl WalletWatcher {
                 max_imported_index
             );
             let wallet = self.wallets.get_mut(&checksum).unwrap();
-            wallet.max_imported_index = Some(max_imported_index);
+            wallet.set_max_imported_index(max_imported_index);
 
             // if anything was imported at all, assume the initial sync was completed. this might
             // not hold true if bwt shuts down while syncing, but this only means that we'll use
@@ -173,17 +228,21 @@ impl WalletWatcher {
         Ok(())
     }
 
-    pub fn do_imports(&mut self, rpc: &RpcClient, rescan: bool) -> Result<bool> {
+    /// Import the pending addresses into bitcoind, reporting `Progress::Import` updates
+    /// to `progress_tx` as they get submitted
+    pub fn do_imports(
+        &mut self,
+        rpc: &RpcClient,
+        rescan: bool,
+        progress_tx: Option<&mpsc::Sender<Progress>>,
+    ) -> Result<bool> {
         let mut import_reqs = vec![];
+        let mut desc_reqs = vec![];
         let mut pending_updates = vec![];
 
         for (checksum, wallet) in self.wallets.iter_mut() {
-            let watch_index = wallet.watch_index();
-            if wallet.max_imported_index.map_or(true, |i| watch_index > i) {
-                let start_index = wallet
-                    .max_imported_index
-                    .map_or(0, |max_imported| max_imported + 1);
-
+            if let Some((start_index, watch_index)) = wallet.pending_import_range() {
+                let rescan = rescan || wallet.pending_rescan;
                 debug!(
                     "importing {} range {}-{} with rescan={}",
                     checksum, start_index, watch_index, rescan,
@@ -191,6 +250,11 @@ impl WalletWatcher {
 
                 import_reqs.append(&mut wallet.make_imports(start_index, watch_index, rescan));
 
+                // descriptor wallets also get the ranged descriptor itself, carrying its active flag
+                if self.descriptor_wallet && wallet.is_wildcard {
+                    desc_reqs.push(wallet.make_desc_import(watch_index));
+                }
+
                 pending_updates.push((wallet, watch_index));
             } else if !wallet.done_initial_import {
                 trace!("done initial import for {}", checksum,);
@@ -215,11 +279,15 @@ impl WalletWatcher {
                 "importing batch of {} addresses... (this may take awhile)",
                 import_reqs.len()
             );
-            batch_import(rpc, import_reqs)?;
+            batch_import(rpc, import_reqs, self.descriptor_wallet, progress_tx)?;
+            if !desc_reqs.is_empty() {
+                import_ranged_descriptors(rpc, desc_reqs)?;
+            }
             debug!("done importing batch");
 
             for (wallet, imported_index) in pending_updates {
-                wallet.max_imported_index = Some(imported_index);
+                wallet.set_max_imported_index(imported_index);
+                wallet.pending_rescan = false;
             }
 
             // we don't need to keep standalone addresses around once they get imported
@@ -229,6 +297,82 @@ impl WalletWatcher {
         Ok(has_imports)
     }
 
+    /// Find the wallet address matching `script` at an index beyond the imported range, looking
+    /// up to `gap_limit` indexes past it.
+    pub fn find_out_of_window(&mut self, script: &Script) -> Option<KeyOrigin> {
+        self.wallets.iter_mut().find_map(|(checksum, wallet)| {
+            let index = wallet.lookahead_index(script)?;
+            Some(KeyOrigin::Descriptor(checksum.clone(), index))
+        })
+    }
+
+    /// Find the wallet address matching `script` within the imported range, regardless of whether
+    /// it has any history
+    pub fn find_imported(&self, script: &Script) -> Option<KeyOrigin> {
+        self.wallets.iter().find_map(|(checksum, wallet)| {
+            let index = wallet.imported_index(script)?;
+            Some(KeyOrigin::Descriptor(checksum.clone(), index))
+        })
+    }
+
+    /// Extend the watched window to include an out-of-window index found to be in use
+    ///
+    /// The new indexes will get imported with a rescan on the next sync run.
+    pub fn extend_window(&mut self, origin: &KeyOrigin) {
+        if let KeyOrigin::Descriptor(checksum, index) = origin {
+            if let Some(wallet) = self.wallets.get_mut(checksum) {
+                warn!(
+                    "found a spend from {}/{}, which is beyond the {} tracked addresses. the gap limit ({}) is likely too small, consider increasing it.",
+                    checksum, index, wallet.max_imported_index.map_or(0, |max| max + 1), wallet.gap_limit,
+                );
+                if wallet.max_funded_index.map_or(true, |max| *index > max) {
+                    wallet.max_funded_index = Some(*index);
+                }
+                wallet.pending_rescan = true;
+            }
+        }
+    }
+
+    /// Get the wallets that own inputs spent by the PSBT and could contribute signatures to it.
+    ///
+    /// Inputs are matched by looking up their prevouts in the index, as well as by matching the
+    /// PSBT's bip32 key origins against the origins of the wallets' keys.
+    pub fn psbt_signable_wallets(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        store: &MemoryStore,
+    ) -> Vec<Checksum> {
+        let mut checksums = HashSet::new();
+
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs) {
+            if let Some(FundingInfo(scripthash, _)) = store.lookup_txo_fund(&txin.previous_output) {
+                if let Some(script_info) = store.get_script_info(&scripthash) {
+                    if let KeyOrigin::Descriptor(checksum, _) = script_info.origin {
+                        checksums.insert(checksum);
+                    }
+                }
+            }
+
+            // taproot inputs carry their key origins separately, along with the leaf hashes
+            let key_sources = input.bip32_derivation.values().chain(
+                input
+                    .tap_key_origins
+                    .values()
+                    .map(|(_, key_source)| key_source),
+            );
+            for (fingerprint, path) in key_sources {
+                checksums.extend(
+                    self.wallets
+                        .iter()
+                        .filter(|(_, wallet)| wallet.owns_bip32_origin(fingerprint, path))
+                        .map(|(checksum, _)| checksum.clone()),
+                );
+            }
+        }
+
+        checksums.into_iter().collect()
+    }
+
     /// Add an address to be tracked
     ///
     /// The address will be added to the list of pending imports and will get imported on the next sync run.
@@ -242,6 +386,79 @@ impl WalletWatcher {
             .push((address, rescan_since));
         Ok(())
     }
+
+    /// Increase the gap limit for all wallets
+    ///
+    /// Only the newly added indexes will get imported on the next sync run. Decreasing the gap
+    /// limit is a no-op, addresses that were already imported remain tracked.
+    pub fn set_gap_limit(&mut self, gap_limit: u32) {
+        for (checksum, wallet) in self.wallets.iter_mut() {
+            if gap_limit > wallet.gap_limit {
+                info!(
+                    "increasing gap limit for {} from {} to {}",
+                    checksum, wallet.gap_limit, gap_limit
+                );
+                wallet.gap_limit = gap_limit;
+                wallet.initial_import_size = wallet.initial_import_size.max(gap_limit);
+            }
+        }
+    }
+
+    /// Switch all wallets to `network`, to recover from a misconfigured network
+    ///
+    /// The wallet addresses get re-derived for the new network and re-imported (with a rescan) on the
+    /// next sync run. Testnet and regtest share the same bip32 and base58 version bytes, which makes
+    /// it possible to switch between them freely. Switching to/from mainnet requires the wallet keys
+    /// and the standalone addresses pending import to match the new network, and fails otherwise.
+    pub fn set_network(&mut self, network: Network) -> Result<()> {
+        // validate everything upfront, leaving the current state intact in case of failure
+        let mut wallets = self.wallets.clone();
+        for (checksum, wallet) in wallets.iter_mut() {
+            wallet
+                .set_network(network)
+                .with_context(|| format!("cannot switch wallet {} to {}", checksum, network))?;
+        }
+
+        let shares_versions =
+            |a: Network, b: Network| a == b || (a != Network::Bitcoin && b != Network::Bitcoin);
+        let pending_standalone_imports = self
+            .pending_standalone_imports
+            .iter()
+            .map(|(address, rescan_since)| {
+                ensure!(
+                    shares_versions(address.network, network),
+                    "cannot switch address {} to {}",
+                    address,
+                    network
+                );
+                let address = Address {
+                    network,
+                    payload: address.payload.clone(),
+                };
+                Ok((address, *rescan_since))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        info!("switching network from {} to {}", self.network, network);
+        self.network = network;
+        self.wallets = wallets;
+        self.pending_standalone_imports = pending_standalone_imports;
+        Ok(())
+    }
+}
+
+// Find the pairs of wallets whose descriptors can derive the same scriptPubKeys
+fn find_overlapping(wallets: &HashMap<Checksum, Wallet>) -> Vec<(&Checksum, &Checksum)> {
+    let wallets: Vec<&Wallet> = wallets.values().collect();
+    let mut overlapping = vec![];
+    for (i, wallet_a) in wallets.iter().enumerate() {
+        for wallet_b in &wallets[i + 1..] {
+            if descriptor::descriptors_overlap(&wallet_a.desc, &wallet_b.desc) {
+                overlapping.push((&wallet_a.checksum, &wallet_b.checksum));
+            }
+        }
+    }
+    overlapping
 }
 
 #[derive(Debug, Clone)]
@@ -255,9 +472,20 @@ pub struct Wallet {
 
     gap_limit: u32,
     initial_import_size: u32,
+    // an explicit (inclusive) range of indexes to track, instead of extending by the gap limit
+    range: Option<(u32, u32)>,
     max_funded_index: Option<u32>,
     max_imported_index: Option<u32>,
     done_initial_import: bool,
+    pending_rescan: bool,
+    // whether bitcoind should use the descriptor for address generation (descriptor wallets only)
+    active: bool,
+
+    // scripts derived within the imported range, for resolving the ownership of outputs
+    imported: HashMap<Script, u32>,
+    // scripts derived beyond the imported range, for detecting out-of-window spends
+    lookahead: HashMap<Script, u32>,
+    lookahead_end: u32,
 }
 
 impl Wallet {
@@ -287,13 +515,33 @@ impl Wallet {
             gap_limit,
             // setting initial_import_size < gap_limit makes no sense, the user probably meant to increase both
             initial_import_size: initial_import_size.max(gap_limit),
+            range: None,
             rescan_since,
             done_initial_import: false,
             max_funded_index: None,
             max_imported_index: None,
+            pending_rescan: false,
+            active: false,
+            imported: HashMap::new(),
+            lookahead: HashMap::new(),
+            lookahead_end: 0,
         })
     }
 
+    // re-derive the wallet for `network`, resetting its import state to have it re-imported from scratch
+    fn set_network(&mut self, network: Network) -> Result<()> {
+        self.keys_info = DescKeyInfo::extract(&self.desc, network)?;
+        self.network = network;
+        self.done_initial_import = false;
+        self.max_funded_index = None;
+        self.max_imported_index = None;
+        self.pending_rescan = true;
+        self.imported.clear();
+        self.lookahead.clear();
+        self.lookahead_end = 0;
+        Ok(())
+    }
+
     pub fn from_xpub(
         xpub: XyzPubKey,
         network: Network,
@@ -304,7 +552,7 @@ impl Wallet {
         Ok(vec![
             // external chain (receive)
             Self::from_descriptor(
-                xpub.as_descriptor([0.into()][..].into()),
+                xpub.as_descriptor([0.into()][..].into())?,
                 network,
                 gap_limit,
                 initial_import_size,
@@ -312,7 +560,7 @@ impl Wallet {
             )?,
             // internal chain (change)
             Self::from_descriptor(
-                xpub.as_descriptor([1.into()][..].into()),
+                xpub.as_descriptor([1.into()][..].into())?,
                 network,
                 gap_limit,
                 initial_import_size,
@@ -321,11 +569,50 @@ impl Wallet {
         ])
     }
 
+    /// Track the fixed (inclusive) range of indexes `start..=end`, instead of extending the
+    /// watched range by the gap limit
+    pub fn set_range(&mut self, start: u32, end: u32) -> Result<()> {
+        ensure!(
+            self.is_wildcard,
+            "Cannot set a range for the non-ranged descriptor {}",
+            self.checksum
+        );
+        ensure!(
+            start <= end && self.is_valid_index(end),
+            "Invalid range {}-{} for descriptor {}",
+            start,
+            end,
+            self.checksum
+        );
+        self.range = Some((start, end));
+        Ok(())
+    }
+
+    /// Import the descriptor as an active one, to have bitcoind use it for address generation.
+    /// Only ranged descriptors can be active.
+    pub fn set_active(&mut self) -> Result<()> {
+        ensure!(
+            self.is_wildcard,
+            "Cannot set the non-ranged descriptor {} as active",
+            self.checksum
+        );
+        self.active = true;
+        Ok(())
+    }
+
+    // The first index to track
+    fn start_index(&self) -> u32 {
+        self.range.map_or(0, |(start, _)| start)
+    }
+
     /// Returns the maximum index that needs to be watched
     fn watch_index(&self) -> u32 {
         if !self.is_wildcard {
             return 0;
         }
+        if let Some((_, end)) = self.range {
+            return end;
+        }
 
         let chunk_size = if self.done_initial_import {
             self.gap_limit
@@ -337,6 +624,69 @@ impl Wallet {
             .map_or(chunk_size - 1, |max| max + chunk_size)
     }
 
+    /// Returns the (inclusive) range of indexes that are pending import, if any
+    fn pending_import_range(&self) -> Option<(u32, u32)> {
+        let watch_index = self.watch_index();
+        match self.max_imported_index {
+            Some(max_imported) if max_imported >= watch_index => None,
+            Some(max_imported) => Some((max_imported + 1, watch_index)),
+            None => Some((self.start_index(), watch_index)),
+        }
+    }
+
+    /// Returns the (inclusive) range of indexes that were actually imported into bitcoind, if any
+    ///
+    /// This may lag behind the logical watch window while the pending imports are in progress.
+    /// Note that indexes funded via `mark_funded()` could also exceed it.
+    pub fn imported_range(&self) -> Option<(u32, u32)> {
+        self.max_imported_index
+            .map(|max_imported| (self.start_index(), max_imported))
+    }
+
+    /// Update the imported range to end at `max_imported_index`, deriving the scripts of the
+    /// newly imported indexes
+    fn set_max_imported_index(&mut self, max_imported_index: u32) {
+        let start = self
+            .imported_range()
+            .map_or(self.start_index(), |(_, end)| end + 1);
+        for index in start..=max_imported_index {
+            let script_pubkey = self.derive_address(index).script_pubkey();
+            self.imported.insert(script_pubkey, index);
+        }
+        if max_imported_index < start {
+            self.imported
+                .retain(|_, index| *index <= max_imported_index);
+        }
+        self.max_imported_index = Some(max_imported_index);
+    }
+
+    /// Returns the index of `script` if it is derived within the imported range
+    fn imported_index(&self, script: &Script) -> Option<u32> {
+        self.imported.get(script).copied()
+    }
+
+    /// Returns the index of `script` if it is derived within `gap_limit` indexes past the imported range
+    fn lookahead_index(&mut self, script: &Script) -> Option<u32> {
+        // ranged wallets are never extended past their range
+        if !self.is_wildcard || self.range.is_some() {
+            return None;
+        }
+        let start = self.max_imported_index.map_or(0, |max| max + 1);
+        let end = start + self.gap_limit;
+
+        // derived scripts are cached and only the missing indexes are derived
+        for index in self.lookahead_end.max(start)..end {
+            let script_pubkey = self.derive_address(index).script_pubkey();
+            self.lookahead.insert(script_pubkey, index);
+        }
+        self.lookahead_end = self.lookahead_end.max(end);
+
+        self.lookahead
+            .get(script)
+            .copied()
+            .filter(|index| *index >= start)
+    }
+
     fn make_imports(
         &self,
         start_index: u32,
@@ -358,6 +708,18 @@ impl Wallet {
             .collect()
     }
 
+    // The `importdescriptors` request for the ranged descriptor, up to `end_index`. Its addresses
+    // are imported (and rescanned for) separately with their labels, so its timestamp is `now`.
+    fn make_desc_import(&self, end_index: u32) -> serde_json::Value {
+        json!({
+            "desc": self.desc.to_string_with_checksum(),
+            "active": self.active,
+            "internal": self.is_internal_chain(),
+            "range": [self.start_index(), end_index],
+            "timestamp": ImportMultiRescanSince::Now,
+        })
+    }
+
     pub fn derive_address(&self, index: u32) -> Address {
         descriptor::derive_address(&self.desc, index, self.network)
             .expect("constructed Wallet must have address representation")
@@ -367,17 +729,43 @@ impl Wallet {
         descriptor::derive_desc_str(&self.desc, index)
     }
 
+    /// Get the fixed (non-wildcard) descriptor at `index`
+    pub fn derive_desc(&self, index: u32) -> ExtendedDescriptor {
+        self.desc.derive(index)
+    }
+
+    /// Get the height at which an output funded at `funding_height` becomes spendable,
+    /// or `None` if spending it is not subject to a timelock
+    pub fn spendable_at(&self, funding_height: u32) -> Option<u32> {
+        descriptor::timelock_spendable_at(&self.desc, funding_height)
+    }
+
+    /// An upper bound on the weight of the witness required to spend one of the wallet's outputs,
+    /// for estimating the fees of transactions spending them
+    pub fn max_satisfaction_weight(&self) -> Option<usize> {
+        descriptor::max_satisfaction_weight(&self.desc)
+    }
+
+    /// Get the next unused index. For ranged wallets, this is the last index in the range
+    /// once all of it was used.
     pub fn get_next_index(&self) -> u32 {
         if self.is_wildcard {
-            self.max_funded_index
-                .map_or(0, |max_funded_index| max_funded_index + 1)
+            let next_index = self
+                .max_funded_index
+                .map_or(0, |max_funded_index| max_funded_index + 1);
+            match self.range {
+                Some((start, end)) => next_index.max(start).min(end),
+                None => next_index,
+            }
         } else {
             0
         }
     }
 
     pub fn is_valid_index(&self, index: u32) -> bool {
-        if self.is_wildcard {
+        if let Some((start, end)) = self.range {
+            index >= start && index <= end
+        } else if self.is_wildcard {
             // non-hardended derivation only
             index & (1 << 31) == 0
         } else {
@@ -390,7 +778,7 @@ impl Wallet {
         let max_funded_index = self.max_funded_index?;
 
         Some(if self.is_wildcard {
-            (0..=max_funded_index)
+            (self.start_index()..=max_funded_index)
                 .map(|derivation_index| self.derive_address(derivation_index))
                 .fold((0, 0), |(curr_gap, max_gap), address| {
                     if store.has_history(&address.into()) {
@@ -405,6 +793,16 @@ impl Wallet {
         })
     }
 
+    /// Get the fraction of the imported addresses that have any history, or None if nothing was
+    /// imported yet. A high utilization suggests that a larger gap limit may be needed.
+    pub fn utilization(&self, store: &MemoryStore) -> Option<f32> {
+        let (start, end) = self.imported_range()?;
+        let used = (start..=end)
+            .filter(|index| store.has_history(&self.derive_address(*index).into()))
+            .count();
+        Some(used as f32 / (end - start + 1) as f32)
+    }
+
     /// Get the bip32 origins of the public keys used at the provided index
     pub fn bip32_origins(&self, index: u32) -> Vec<Bip32Origin> {
         self.keys_info
@@ -418,39 +816,312 @@ impl Wallet {
             })
             .collect()
     }
+
+    /// Get the public keys used at the provided index along with their bip32 origins
+    pub fn derive_key_origins(&self, index: u32) -> Vec<(PublicKey, Bip32Origin)> {
+        descriptor::derive_key_origins(&self.desc, index)
+    }
+
+    /// Check whether both wallets use the same keys and only differ in the final derivation step
+    /// of their keys, like the external and internal chains of the same xpub
+    pub fn is_same_account(&self, other: &Wallet) -> bool {
+        if self.checksum == other.checksum {
+            return true;
+        }
+        if std::mem::discriminant(&self.desc) != std::mem::discriminant(&other.desc)
+            || self.keys_info.is_empty()
+            || self.keys_info.len() != other.keys_info.len()
+        {
+            return false;
+        }
+        self.keys_info.iter().zip(&other.keys_info).all(|(a, b)| {
+            let (Bip32Origin(a_fingerprint, a_path), Bip32Origin(b_fingerprint, b_path)) =
+                (&a.bip32_origin, &b.bip32_origin);
+            let (a_path, b_path) = (a_path.as_ref(), b_path.as_ref());
+            a_fingerprint == b_fingerprint
+                && a.is_wildcard == b.is_wildcard
+                && !a_path.is_empty()
+                && a_path.len() == b_path.len()
+                && a_path[..a_path.len() - 1] == b_path[..b_path.len() - 1]
+        })
+    }
+
+    /// Check whether this is the internal (change) chain, following the BIP 44 convention of
+    /// deriving it from the `1` child of the account keys
+    pub fn is_internal_chain(&self) -> bool {
+        let internal = ChildNumber::Normal { index: 1 };
+        !self.keys_info.is_empty()
+            && self.keys_info.iter().all(|i| {
+                let Bip32Origin(_, path) = &i.bip32_origin;
+                i.is_wildcard && path.as_ref().last() == Some(&internal)
+            })
+    }
+
+    /// Check whether any of the keys used at the provided index derive from the `prefix` path
+    pub fn derives_from_path(&self, index: u32, prefix: &DerivationPath) -> bool {
+        self.bip32_origins(index)
+            .iter()
+            .any(|Bip32Origin(_, path)| path.as_ref().starts_with(prefix.as_ref()))
+    }
+
+    /// Check whether the bip32 key origin belongs to one of the keys used by this wallet
+    pub fn owns_bip32_origin(&self, fingerprint: &Fingerprint, path: &DerivationPath) -> bool {
+        let path = path.as_ref();
+        self.keys_info.iter().any(|i| {
+            let Bip32Origin(key_fingerprint, key_path) = &i.bip32_origin;
+            let key_path = key_path.as_ref();
+            if key_fingerprint != fingerprint || !path.starts_with(key_path) {
+                return false;
+            }
+            if i.is_wildcard {
+                // wildcard keys are matched against their non-hardened children
+                matches!(&path[key_path.len()..], [ChildNumber::Normal { .. }])
+            } else {
+                path.len() == key_path.len()
+            }
+        })
+    }
 }
 
-fn batch_import(rpc: &RpcClient, import_reqs: Vec<(Address, RescanSince, String)>) -> Result<()> {
-    // XXX use importmulti with ranged descriptors? the key derivation info won't be
-    //     directly available on `listtransactions` and would require an additional rpc all.
+// Amounts that are a multiple of this are considered round (0.0001 BTC)
+const ROUND_AMOUNT_SATS: u64 = 10_000;
+
+/// Heuristically determine whether the output at `vout` is likely to be the change output of a
+/// transaction spending from `wallet`, for outputs not known to be owned by it (e.g. when the wallet's
+/// change goes to a descriptor that is not tracked by bwt). `owned_vouts` are the outputs known to
+/// be owned by the wallet. The heuristic is skipped when there are any, as the change is then known.
+///
+/// This is a heuristic and can be wrong both ways. An output is considered likely change when it
+/// is the only one paying to the wallet's script type, or the only one with a non-round amount.
+pub fn is_likely_change(vout: u32, tx: &Transaction, wallet: &Wallet, owned_vouts: &[u32]) -> bool {
+    if !owned_vouts.is_empty() {
+        return false;
+    }
+    let txout = some_or_ret!(tx.output.get(vout as usize), false);
+    let others: Vec<&TxOut> = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != vout as usize)
+        .map(|(_, o)| o)
+        .collect();
+    if others.is_empty() {
+        return false;
+    }
 
-    let results = rpc.import_multi(
-        &import_reqs
+    let wallet_type = script_type(&wallet.derive_address(0).script_pubkey());
+    let type_match = wallet_type.is_some()
+        && script_type(&txout.script_pubkey) == wallet_type
+        && others
             .iter()
-            .map(|(address, rescan, label)| {
-                trace!("importing {} as {}", address, label,);
-
-                ImportMultiRequest {
-                    label: Some(&label),
-                    watchonly: Some(true),
-                    timestamp: (*rescan).into(),
-                    script_pubkey: Some(ImportMultiRequestScriptPubkey::Address(&address)),
-                    ..Default::default()
+            .all(|o| script_type(&o.script_pubkey) != wallet_type);
+
+    let is_round = |amount: u64| amount % ROUND_AMOUNT_SATS == 0;
+    let round_match = !is_round(txout.value) && others.iter().all(|o| is_round(o.value));
+
+    type_match || round_match
+}
+
+// The minimal number of equal-value outputs and of inputs for a transaction to look like a coinjoin
+const COINJOIN_MIN_EQUAL_OUTPUTS: usize = 3;
+const COINJOIN_MIN_INPUTS: usize = 2;
+
+/// Heuristically determine whether `tx` looks like a coinjoin, having multiple inputs and many
+/// outputs sharing the same value.
+///
+/// This is a heuristic and can be wrong both ways. Batched payments of equal amounts will be flagged,
+/// while coinjoin constructions that don't use equal-value outputs will not.
+pub fn is_coinjoin_like(tx: &Transaction) -> bool {
+    if tx.input.len() < COINJOIN_MIN_INPUTS {
+        return false;
+    }
+    let mut value_counts: HashMap<u64, usize> = HashMap::new();
+    for txout in &tx.output {
+        *value_counts.entry(txout.value).or_insert(0) += 1;
+    }
+    value_counts
+        .values()
+        .any(|count| *count >= COINJOIN_MIN_EQUAL_OUTPUTS)
+}
+
+/// Get the candidate scriptPubKeys of the prevout spent by `txin`, reconstructed from its
+/// scriptSig and witness. Supports p2pkh, p2sh, p2wpkh, p2wsh and their nested variants.
+/// P2tr key-path spends only carry a signature, their output key cannot be reconstructed.
+pub fn input_script_candidates(txin: &TxIn) -> Vec<Script> {
+    // the address is only used to construct the script, the network does not matter
+    let network = Network::Bitcoin;
+    let last_push = txin
+        .script_sig
+        .instructions()
+        .last()
+        .and_then(|ins| match ins {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes.to_vec()),
+            _ => None,
+        });
+
+    let mut candidates = vec![];
+    match (txin.witness.last(), last_push) {
+        // nested segwit, the scriptSig pushes the witness program as the redeem script
+        (Some(_), Some(redeem_script)) => candidates
+            .extend(Address::p2sh(&redeem_script.into(), network).map(|a| a.script_pubkey())),
+        (Some(last_item), None) => {
+            match PublicKey::from_slice(last_item) {
+                Ok(pubkey) if txin.witness.len() == 2 => {
+                    candidates.extend(Address::p2wpkh(&pubkey, network).map(|a| a.script_pubkey()))
                 }
-            })
-            .collect::<Vec<_>>(),
-        None,
-    )?;
+                _ => candidates
+                    .push(Address::p2wsh(&last_item.to_vec().into(), network).script_pubkey()),
+            };
+        }
+        (None, Some(last_push)) => {
+            if let Ok(pubkey) = PublicKey::from_slice(&last_push) {
+                candidates.push(Address::p2pkh(&pubkey, network).script_pubkey());
+            }
+            candidates.extend(Address::p2sh(&last_push.into(), network).map(|a| a.script_pubkey()));
+        }
+        (None, None) => (),
+    }
+    candidates
+}
+
+fn script_type(script: &Script) -> Option<AddressType> {
+    // the address type does not depend on the network
+    Address::from_script(script, Network::Bitcoin)?.address_type()
+}
+
+/// Validate descriptors and xpubs without touching bitcoind, for fast-fail startup checks
+///
+/// Returns the result for each entry. Entries containing parentheses are parsed as descriptors
+/// (with an optional `#checksum` suffix), or as xpubs otherwise. This checks for valid encoding,
+/// checksum, network match and derivability.
+pub fn validate_config(entries: &[String], network: Network) -> Vec<(String, Result<()>)> {
+    entries
+        .iter()
+        .map(|entry| (entry.clone(), validate_entry(entry, network)))
+        .collect()
+}
+
+fn validate_entry(entry: &str, network: Network) -> Result<()> {
+    // the gap limit and rescan policy don't affect validation
+    if entry.contains('(') {
+        match entry.parse::<TrackedDescriptor>()? {
+            TrackedDescriptor::Descriptor(desc) => {
+                Wallet::from_descriptor(desc, network, 1, 1, RescanSince::Now)?;
+            }
+            TrackedDescriptor::Addr(address) => ensure!(
+                address.network == network,
+                "Invalid network for address {}",
+                address
+            ),
+        }
+    } else {
+        let xyzpub = entry.parse::<XyzPubKey>()?;
+        Wallet::from_xpub(xyzpub, network, 1, 1, RescanSince::Now)?;
+    }
+    Ok(())
+}
+
+// Import the addresses in chunks of `IMPORT_BATCH_SIZE`, using `importmulti` for legacy wallets
+// or `importdescriptors` with `addr()` descriptors for descriptor wallets
+fn batch_import(
+    rpc: &impl RpcApi,
+    mut import_reqs: Vec<(Address, RescanSince, String)>,
+    descriptor_wallet: bool,
+    progress_tx: Option<&mpsc::Sender<Progress>>,
+) -> Result<()> {
+    // XXX use importmulti with ranged descriptors? the key derivation info won't be
+    //     directly available on `listtransactions` and would require an additional rpc all.
+
+    // the addresses are submitted in chunks to report progress, with only the last one triggering
+    // a rescan. bitcoind rescans from the earliest timestamp in the chunk for all of the wallet's
+    // addresses, so the requests are sorted to have the earliest timestamp in the last chunk.
+    import_reqs.sort_by_key(|(_, rescan, _)| {
+        Reverse(match rescan {
+            RescanSince::Timestamp(timestamp) => *timestamp,
+            RescanSince::Now => u64::MAX,
+        })
+    });
+
+    let total = import_reqs.len();
+    let mut done = 0;
+
+    for chunk in import_reqs.chunks(IMPORT_BATCH_SIZE) {
+        done += chunk.len();
+        if let Some(progress_tx) = progress_tx {
+            // the receiver may have stopped listening, ignore errors
+            progress_tx.send(Progress::Import { done, total }).ok();
+        }
+
+        let results: Vec<ImportMultiResult> = if descriptor_wallet {
+            let reqs = chunk
+                .iter()
+                .map(|(address, rescan, label)| {
+                    trace!("importing {} as {}", address, label,);
+
+                    // importdescriptors has no rescan option and always rescans from the earliest
+                    // timestamp, so all chunks but the last are imported as of `now`
+                    let timestamp = if done == total {
+                        (*rescan).into()
+                    } else {
+                        ImportMultiRescanSince::Now
+                    };
+                    json!({
+                        "desc": descriptor::addr_desc_str(address),
+                        "label": label,
+                        "timestamp": timestamp,
+                    })
+                })
+                .collect::<Vec<_>>();
+            rpc.call("importdescriptors", &[json!(reqs)])?
+        } else {
+            rpc.import_multi(
+                &chunk
+                    .iter()
+                    .map(|(address, rescan, label)| {
+                        trace!("importing {} as {}", address, label,);
+
+                        ImportMultiRequest {
+                            label: Some(&label),
+                            watchonly: Some(true),
+                            timestamp: (*rescan).into(),
+                            script_pubkey: Some(ImportMultiRequestScriptPubkey::Address(&address)),
+                            ..Default::default()
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+                Some(&ImportMultiOptions {
+                    rescan: Some(done == total),
+                }),
+            )?
+        };
+
+        for (i, result) in results.iter().enumerate() {
+            if !result.success {
+                let req = chunk.get(i).unwrap(); // should not fail unless bitcoind is messing with us
+                bail!("import for {:?} failed: {:?}", req, result);
+            } else if !result.warnings.is_empty() {
+                debug!("import succeed with warnings: {:?}", result);
+            }
+        }
+        debug!("imported {}/{} addresses", done, total);
+    }
+
+    Ok(())
+}
 
-    for (i, result) in results.iter().enumerate() {
+// Import the ranged descriptors of a descriptor wallet, created by `Wallet::make_desc_import()`
+fn import_ranged_descriptors(rpc: &impl RpcApi, desc_reqs: Vec<serde_json::Value>) -> Result<()> {
+    let results: Vec<ImportMultiResult> = rpc.call("importdescriptors", &[json!(desc_reqs)])?;
+    for (req, result) in desc_reqs.iter().zip(results) {
         if !result.success {
-            let req = import_reqs.get(i).unwrap(); // should not fail unless bitcoind is messing with us
-            bail!("import for {:?} failed: {:?}", req, result);
+            bail!("import for {} failed: {:?}", req["desc"], result);
         } else if !result.warnings.is_empty() {
-            debug!("import succeed with warnings: {:?}", result);
+            warn!(
+                "import for {} succeed with warnings: {:?}",
+                req["desc"], result
+            );
         }
     }
-
     Ok(())
 }
 
@@ -524,7 +1195,7 @@ impl Serialize for Wallet {
     where
         S: serde::Serializer,
     {
-        let desc_str = format!("{}#{}", self.desc, self.checksum);
+        let desc_str = self.desc.to_string_with_checksum();
         let bip32_origins: Vec<_> = self.keys_info.iter().map(|i| &i.bip32_origin).collect();
 
         let mut rgb = serializer.serialize_struct("Wallet", 3)?;
@@ -537,10 +1208,9 @@ impl Serialize for Wallet {
         rgb.serialize_field("done_initial_import", &self.done_initial_import)?;
         rgb.serialize_field("max_funded_index", &self.max_funded_index)?;
         rgb.serialize_field("max_imported_index", &self.max_imported_index)?;
-        rgb.serialize_field(
-            "satisfaction_weight",
-            &self.desc.max_satisfaction_weight(*DESC_CTX),
-        )?;
+        rgb.serialize_field("imported_range", &self.imported_range())?;
+        rgb.serialize_field("satisfaction_weight", &self.max_satisfaction_weight())?;
+        rgb.serialize_field("active", &self.active)?;
 
         if self.is_wildcard {
             rgb.serialize_field("gap_limit", &self.gap_limit)?;
@@ -550,3 +1220,721 @@ impl Serialize for Wallet {
         rgb.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::base58;
+    use bitcoin::util::bip32::ExtendedPubKey;
+    use bitcoin::{OutPoint, Txid, Witness};
+    use bitcoin_hashes::hex::FromHex;
+
+    use crate::types::{DescriptorRange, ScriptHash, TxStatus};
+    use crate::util::descriptor::DescriptorChecksum;
+    use crate::util::xpub::XyzPubKeyError;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+
+    fn make_wallets(xpub: &ExtendedPubKey) -> Vec<Wallet> {
+        let xyzpub = xpub.to_string().parse::<XyzPubKey>().unwrap();
+        Wallet::from_xpub(xyzpub, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap()
+    }
+
+    #[test]
+    fn test_psbt_signable_wallets() {
+        let secp = Secp256k1::verification_only();
+        let xpub1 = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let xpub2 = xpub1.ckd_pub(&secp, 7.into()).unwrap();
+
+        let mut wallets = make_wallets(&xpub1);
+        wallets.append(&mut make_wallets(&xpub2));
+        let receive_checksum = wallets[2].checksum.clone();
+        let watcher = WalletWatcher::new(Network::Bitcoin, wallets, vec![]).unwrap();
+
+        // spend an input from the receive chain of the second xpub
+        let path: DerivationPath = [7.into(), 0.into(), 5.into()][..].into();
+        let pubkey = xpub1.derive_pub(&secp, &path).unwrap().public_key;
+        let unsigned_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Witness::default(),
+            }],
+            output: vec![],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(unsigned_tx).unwrap();
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(pubkey, (xpub1.fingerprint(), path));
+
+        let signable = watcher.psbt_signable_wallets(&psbt, &MemoryStore::new());
+        assert_eq!(signable, vec![receive_checksum]);
+    }
+
+    #[test]
+    fn test_is_internal_chain() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let wallets = make_wallets(&xpub);
+        assert!(!wallets[0].is_internal_chain());
+        assert!(wallets[1].is_internal_chain());
+        assert!(wallets[0].is_same_account(&wallets[1]));
+    }
+
+    #[test]
+    fn test_derives_from_path() {
+        let make_wallet = |account: u32| {
+            let desc = format!("wpkh({}/{}/*)", XPUB, account).parse().unwrap();
+            Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap()
+        };
+        let (wallet5, wallet6) = (make_wallet(5), make_wallet(6));
+        let prefix5: DerivationPath = "m/5".parse().unwrap();
+
+        assert!(wallet5.derives_from_path(3, &prefix5));
+        assert!(wallet5.derives_from_path(3, &"m/5/3".parse().unwrap()));
+        assert!(!wallet5.derives_from_path(4, &"m/5/3".parse().unwrap()));
+        assert!(!wallet6.derives_from_path(3, &prefix5));
+        assert!(wallet6.derives_from_path(3, &"m".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_overlapping_descriptors() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let child_xpub = xpub
+            .derive_pub(&Secp256k1::verification_only(), &[ChildNumber::from(0)])
+            .unwrap();
+        let origin = format!("[{}/0]", xpub.fingerprint());
+        // the overlapping pairs of checksums, registering a wallet for each descriptor
+        let overlapping = |descs: &[&String]| {
+            let wallets = descs
+                .iter()
+                .map(|desc| {
+                    let desc: ExtendedDescriptor = desc.parse().unwrap();
+                    Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now)
+                        .unwrap()
+                })
+                .collect();
+            let watcher = WalletWatcher::new(Network::Bitcoin, wallets, vec![]).unwrap();
+            find_overlapping(watcher.wallets())
+                .into_iter()
+                .map(|(a, b)| {
+                    let pair: HashSet<Checksum> = vec![a.clone(), b.clone()].into_iter().collect();
+                    pair
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the same keys, expressed as a child xpub with or without its key origin
+        let parent_desc = format!("wpkh({}/0/*)", xpub);
+        let child_desc = format!("wpkh({}{}/*)", origin, child_xpub);
+        let expected: HashSet<Checksum> = vec![
+            Checksum::from(&parent_desc.parse::<ExtendedDescriptor>().unwrap()),
+            Checksum::from(&child_desc.parse::<ExtendedDescriptor>().unwrap()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(overlapping(&[&parent_desc, &child_desc]), vec![expected]);
+        let child_desc_no_origin = format!("wpkh({}/*)", child_xpub);
+        assert_eq!(overlapping(&[&child_desc_no_origin, &child_desc]).len(), 1);
+
+        // a single address of the wildcard descriptor
+        let single_desc = format!("wpkh({}/0/7)", xpub);
+        assert_eq!(overlapping(&[&parent_desc, &single_desc]).len(), 1);
+
+        // different chains or script types don't overlap
+        for desc in &[
+            format!("wpkh({}/1/*)", xpub),
+            format!("wpkh({}/*)", xpub),
+            format!("sh(wpkh({}/0/*))", xpub),
+        ] {
+            assert!(overlapping(&[&parent_desc, desc]).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_set_network() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let tpub = ExtendedPubKey {
+            network: Network::Testnet,
+            ..xpub
+        };
+        let desc = format!("wpkh({}/0/*)", tpub).parse().unwrap();
+        let wallet =
+            Wallet::from_descriptor(desc, Network::Regtest, 20, 20, RescanSince::Now).unwrap();
+        let address = wallet.derive_address(3);
+        let mut watcher = WalletWatcher::new(Network::Regtest, vec![wallet], vec![]).unwrap();
+        watcher.wallets.values_mut().for_each(|wallet| {
+            wallet.max_imported_index = Some(19);
+            wallet.done_initial_import = true;
+        });
+        assert!(address.to_string().starts_with("bcrt1"));
+
+        // switching to testnet re-derives the addresses and schedules their re-import
+        watcher.set_network(Network::Testnet).unwrap();
+        let wallet = watcher.wallets().values().next().unwrap();
+        let testnet_address = wallet.derive_address(3);
+        assert!(testnet_address.to_string().starts_with("tb1"));
+        assert_eq!(testnet_address.network, Network::Testnet);
+        assert_eq!(testnet_address.script_pubkey(), address.script_pubkey());
+        assert_eq!(wallet.pending_import_range(), Some((0, 19)));
+        assert!(wallet.pending_rescan);
+
+        // the testnet keys cannot be used on mainnet, the watcher is left intact
+        assert!(watcher.set_network(Network::Bitcoin).is_err());
+        assert_eq!(watcher.network, Network::Testnet);
+        assert!(watcher
+            .wallets()
+            .values()
+            .all(|w| w.network == Network::Testnet));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let desc = format!("wpkh({}/0/*)", XPUB);
+        let entries = vec![
+            XPUB.to_string(),
+            desc.parse::<ExtendedDescriptor>()
+                .unwrap()
+                .to_string_with_checksum(),
+            // invalid checksum
+            format!("{}#{}", desc, "qqqqqqqq"),
+            // invalid xpub encoding
+            format!("{}x", XPUB),
+            // no address representation
+            format!("pk({}/0/*)", XPUB),
+            // hardened derivation can't be derived from the xpub
+            format!("wpkh({}/0h/*)", XPUB),
+            "addr(bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq)".to_string(),
+        ];
+        let results = validate_config(&entries, Network::Bitcoin);
+        let valid: Vec<bool> = results.iter().map(|(_, result)| result.is_ok()).collect();
+        assert_eq!(valid, vec![true, true, false, false, false, false, true]);
+        assert_eq!(results[0].0, entries[0]);
+
+        // the same entries are invalid for the wrong network
+        let results = validate_config(&[&entries[..2], &entries[6..]].concat(), Network::Testnet);
+        assert!(results.iter().all(|(_, result)| result.is_err()));
+
+        // invalid xpubs are reported with the XyzPubKeyError reason
+        let mut zpub_data = base58::from_check(XPUB).unwrap();
+        zpub_data.splice(0..4, [0x02, 0xAA, 0x7E, 0xD3].iter().cloned());
+        let zpub = base58::check_encode_slice(&zpub_data);
+        let results = validate_config(&[zpub, entries[3].clone()], Network::Bitcoin);
+        let xpub_err = |i: usize| {
+            let err = results[i].1.as_ref().unwrap_err();
+            err.downcast_ref::<XyzPubKeyError>().unwrap().to_string()
+        };
+        assert_eq!(
+            xpub_err(0),
+            "this looks like a multisig Zpub, not a single-sig key. use it within a multisig descriptor instead"
+        );
+        assert!(xpub_err(1).starts_with("invalid base58 encoding"));
+    }
+
+    #[test]
+    fn test_gap_limit_increase() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, make_wallets(&xpub), vec![]).unwrap();
+
+        // simulate a completed initial import of the first 20 addresses
+        for wallet in watcher.wallets.values_mut() {
+            assert_eq!(wallet.pending_import_range(), Some((0, 19)));
+            wallet.max_imported_index = Some(19);
+            wallet.done_initial_import = true;
+        }
+
+        // only the newly added indexes should be imported
+        watcher.set_gap_limit(30);
+        for wallet in watcher.wallets.values() {
+            assert_eq!(wallet.pending_import_range(), Some((20, 29)));
+        }
+
+        // decreasing the gap limit should not affect anything
+        for wallet in watcher.wallets.values_mut() {
+            wallet.max_imported_index = Some(29);
+        }
+        watcher.set_gap_limit(10);
+        for wallet in watcher.wallets.values() {
+            assert_eq!(wallet.gap_limit, 30);
+            assert_eq!(wallet.pending_import_range(), None);
+        }
+    }
+
+    #[test]
+    fn test_imported_range() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut wallet = make_wallets(&xpub).remove(0);
+        assert_eq!(wallet.imported_range(), None);
+
+        // complete the initial import
+        let (start, end) = wallet.pending_import_range().unwrap();
+        wallet.max_imported_index = Some(end);
+        wallet.done_initial_import = true;
+        assert_eq!(wallet.imported_range(), Some((start, 19)));
+
+        // funding an address extends the logical window, but not the imported range
+        wallet.max_funded_index = Some(15);
+        assert_eq!(wallet.pending_import_range(), Some((20, 35)));
+        assert_eq!(wallet.imported_range(), Some((0, 19)));
+
+        // once the next chunk gets imported, the imported range matches the window
+        let (_, end) = wallet.pending_import_range().unwrap();
+        wallet.max_imported_index = Some(end);
+        assert_eq!(wallet.pending_import_range(), None);
+        assert_eq!(wallet.imported_range(), Some((0, 35)));
+    }
+
+    #[test]
+    fn test_window_extension() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, make_wallets(&xpub), vec![]).unwrap();
+        for wallet in watcher.wallets.values_mut() {
+            wallet.max_imported_index = Some(19);
+            wallet.done_initial_import = true;
+        }
+        let checksum = watcher.wallets.keys().next().unwrap().clone();
+        let origin = |index| KeyOrigin::Descriptor(checksum.clone(), index);
+
+        // using the highest imported address extends the window with another batch of imports
+        assert!(watcher.mark_funded(&origin(19)));
+        let wallet = watcher.get(&checksum).unwrap();
+        assert_eq!(wallet.pending_import_range(), Some((20, 39)));
+        assert!(!wallet.pending_rescan);
+
+        // funding lower indexes doesn't, nor does funding the same index again
+        watcher
+            .wallets
+            .get_mut(&checksum)
+            .unwrap()
+            .max_imported_index = Some(39);
+        assert!(!watcher.mark_funded(&origin(10)));
+        assert!(!watcher.mark_funded(&origin(19)));
+        assert_eq!(watcher.get(&checksum).unwrap().pending_import_range(), None);
+
+        // standalone addresses have no window
+        assert!(!watcher.mark_funded(&KeyOrigin::Standalone));
+    }
+
+    #[test]
+    fn test_descriptor_range() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut wallet = make_wallets(&xpub).remove(0);
+        let range: DescriptorRange = format!("{}:5-9", wallet.checksum).parse().unwrap();
+        wallet.set_range(range.start, range.end).unwrap();
+        assert!(wallet.set_range(9, 5).is_err());
+
+        // the whole range gets imported at once, regardless of the gap limit
+        assert_eq!(wallet.pending_import_range(), Some((5, 9)));
+        wallet.max_imported_index = Some(9);
+        wallet.done_initial_import = true;
+        assert_eq!(wallet.imported_range(), Some((5, 9)));
+        assert_eq!(wallet.get_next_index(), 5);
+        assert!(
+            !wallet.is_valid_index(4) && wallet.is_valid_index(9) && !wallet.is_valid_index(10)
+        );
+
+        // using the last index does not extend the range
+        wallet.max_funded_index = Some(9);
+        assert_eq!(wallet.pending_import_range(), None);
+        assert_eq!(wallet.get_next_index(), 9);
+        let script = wallet.derive_address(10).script_pubkey();
+        assert_eq!(wallet.lookahead_index(&script), None);
+
+        // ranges can only be set for ranged descriptors
+        let desc = format!("wpkh({}/0/1)", XPUB).parse().unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap();
+        assert!(wallet.set_range(0, 5).is_err());
+    }
+
+    #[test]
+    fn test_utilization() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut wallet = make_wallets(&xpub).remove(0);
+        let mut store = MemoryStore::new();
+        assert_eq!(wallet.utilization(&store), None);
+
+        wallet.max_imported_index = Some(19);
+        assert_eq!(wallet.utilization(&store), Some(0.0));
+
+        for index in &[0, 1, 2, 7, 11] {
+            let address = wallet.derive_address(*index);
+            let scripthash = ScriptHash::from(&address);
+            let txid = Txid::from_hex(&format!("{:02x}", index).repeat(32)).unwrap();
+            let origin = KeyOrigin::Descriptor(wallet.checksum.clone(), *index);
+            store.index_scripthash(&scripthash, &origin, &address);
+            store.upsert_tx(&txid, TxStatus::Confirmed(100));
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1000));
+        }
+        assert_eq!(wallet.utilization(&store), Some(0.25));
+    }
+
+    #[test]
+    fn test_out_of_window_detection() {
+        let secp = Secp256k1::verification_only();
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, make_wallets(&xpub), vec![]).unwrap();
+        for wallet in watcher.wallets.values_mut() {
+            wallet.max_imported_index = Some(19);
+            wallet.done_initial_import = true;
+        }
+
+        let spend_from = |index: u32| {
+            let path: DerivationPath = [0.into(), index.into()][..].into();
+            let pubkey = xpub.derive_pub(&secp, &path).unwrap().public_key;
+            TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Builder::new()
+                    .push_slice(&[0x30; 72])
+                    .push_key(&PublicKey::new(pubkey))
+                    .into_script(),
+                sequence: 0xffffffff,
+                witness: Witness::default(),
+            }
+        };
+        let find = |watcher: &mut WalletWatcher, txin: &TxIn| {
+            input_script_candidates(txin)
+                .iter()
+                .find_map(|script| watcher.find_out_of_window(script))
+        };
+
+        // indexes within the imported range or too far beyond it are not detected
+        assert_eq!(find(&mut watcher, &spend_from(5)), None);
+        assert_eq!(find(&mut watcher, &spend_from(45)), None);
+
+        let origin = find(&mut watcher, &spend_from(25)).unwrap();
+        let checksum = match origin {
+            KeyOrigin::Descriptor(ref checksum, index) => {
+                assert_eq!(index, 25);
+                checksum.clone()
+            }
+            KeyOrigin::Standalone => unreachable!(),
+        };
+
+        // extending the window makes the index pending import with a rescan
+        watcher.extend_window(&origin);
+        let wallet = watcher.get(&checksum).unwrap();
+        assert_eq!(wallet.pending_import_range(), Some((20, 45)));
+        assert!(wallet.pending_rescan);
+    }
+
+    #[test]
+    fn test_find_imported() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, make_wallets(&xpub), vec![]).unwrap();
+        let (checksum, wallet) = watcher.wallets.iter().next().unwrap();
+        let checksum = checksum.clone();
+        let imported = wallet.derive_address(5).script_pubkey();
+        let beyond = wallet.derive_address(25).script_pubkey();
+
+        // nothing is recognized before the initial import
+        assert_eq!(watcher.find_imported(&imported), None);
+
+        for wallet in watcher.wallets.values_mut() {
+            wallet.set_max_imported_index(19);
+        }
+        assert_eq!(
+            watcher.find_imported(&imported),
+            Some(KeyOrigin::Descriptor(checksum.clone(), 5))
+        );
+        assert_eq!(watcher.find_imported(&beyond), None);
+
+        // scripts detected out-of-window are recognized once they get imported
+        assert!(watcher.find_out_of_window(&beyond).is_some());
+        assert_eq!(watcher.find_imported(&beyond), None);
+        for wallet in watcher.wallets.values_mut() {
+            wallet.set_max_imported_index(39);
+        }
+        assert_eq!(
+            watcher.find_imported(&imported),
+            Some(KeyOrigin::Descriptor(checksum.clone(), 5))
+        );
+        assert_eq!(
+            watcher.find_imported(&beyond),
+            Some(KeyOrigin::Descriptor(checksum.clone(), 25))
+        );
+
+        // and forgotten when the imported range shrinks
+        for wallet in watcher.wallets.values_mut() {
+            wallet.set_max_imported_index(9);
+        }
+        assert_eq!(watcher.find_imported(&beyond), None);
+        assert_eq!(
+            watcher.find_imported(&imported),
+            Some(KeyOrigin::Descriptor(checksum, 5))
+        );
+    }
+
+    // A mock RPC client that records the calls made to it and accepts all imports
+    #[derive(Default)]
+    struct MockRpc {
+        calls: std::cell::RefCell<Vec<(String, usize)>>,
+        args: std::cell::RefCell<Vec<Vec<serde_json::Value>>>,
+    }
+
+    impl RpcApi for MockRpc {
+        fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            args: &[serde_json::Value],
+        ) -> rpc::Result<T> {
+            let reqs_len = args[0].as_array().map_or(0, |reqs| reqs.len());
+            self.calls.borrow_mut().push((cmd.to_string(), reqs_len));
+            self.args.borrow_mut().push(args.to_vec());
+            let results = vec![json!({ "success": true }); reqs_len];
+            Ok(serde_json::from_value(json!(results))?)
+        }
+    }
+
+    #[test]
+    fn test_batch_import_rpc_calls() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let xyzpub = xpub.to_string().parse::<XyzPubKey>().unwrap();
+        let wallet = Wallet::from_xpub(xyzpub, Network::Bitcoin, 20, 500, RescanSince::Now)
+            .unwrap()
+            .remove(0);
+
+        let (start_index, end_index) = wallet.pending_import_range().unwrap();
+        assert_eq!((start_index, end_index), (0, 499));
+        let import_reqs = wallet.make_imports(start_index, end_index, false);
+
+        // the 500-address window fits within a single chunk of IMPORT_BATCH_SIZE addresses
+        let rpc = MockRpc::default();
+        batch_import(&rpc, import_reqs.clone(), false, None).unwrap();
+        assert_eq!(*rpc.calls.borrow(), vec![("importmulti".to_string(), 500)]);
+
+        // larger windows are split into multiple chunks, one importmulti call each
+        let mut import_reqs = import_reqs;
+        import_reqs.extend(wallet.make_imports(500, 500, false));
+        let rpc = MockRpc::default();
+        batch_import(&rpc, import_reqs, false, None).unwrap();
+        assert_eq!(
+            *rpc.calls.borrow(),
+            vec![
+                ("importmulti".to_string(), 500),
+                ("importmulti".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_import_progress() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let xyzpub = xpub.to_string().parse::<XyzPubKey>().unwrap();
+        let rescan_since = RescanSince::Timestamp(1_600_000_000);
+        let wallet = Wallet::from_xpub(xyzpub, Network::Bitcoin, 20, 1100, rescan_since)
+            .unwrap()
+            .remove(0);
+
+        let (start_index, end_index) = wallet.pending_import_range().unwrap();
+        let mut import_reqs = wallet.make_imports(start_index, end_index, true);
+        // an address with an earlier rescan timestamp than the rest
+        let address = wallet.derive_address(9999);
+        import_reqs.insert(0, (address, RescanSince::Timestamp(1), "bwt".into()));
+
+        let rpc = MockRpc::default();
+        let (progress_tx, progress_rx) = mpsc::channel();
+        batch_import(&rpc, import_reqs, false, Some(&progress_tx)).unwrap();
+
+        let calls = rpc.calls.borrow();
+        let lens: Vec<_> = calls.iter().map(|(_, len)| *len).collect();
+        assert_eq!(lens, vec![500, 500, 101]);
+
+        // only the last chunk rescans, from the earliest timestamp
+        let args = rpc.args.borrow();
+        let rescans: Vec<_> = args.iter().map(|args| args[1]["rescan"].clone()).collect();
+        assert_eq!(rescans, vec![json!(false), json!(false), json!(true)]);
+        let last_timestamps: Vec<_> = args[2][0]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|req| req["timestamp"].clone())
+            .collect();
+        assert!(last_timestamps.contains(&json!(1)));
+
+        drop(progress_tx);
+        let progress: Vec<_> = progress_rx
+            .iter()
+            .map(|progress| match progress {
+                Progress::Import { done, total } => (done, total),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(progress, vec![(500, 1101), (1000, 1101), (1101, 1101)]);
+    }
+
+    #[test]
+    fn test_batch_import_descriptor_wallet() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let xyzpub = xpub.to_string().parse::<XyzPubKey>().unwrap();
+        let rescan_since = RescanSince::Timestamp(1_600_000_000);
+        let wallet = Wallet::from_xpub(xyzpub, Network::Bitcoin, 20, 600, rescan_since)
+            .unwrap()
+            .remove(0);
+        let import_reqs = wallet.make_imports(0, 599, true);
+
+        let rpc = MockRpc::default();
+        batch_import(&rpc, import_reqs, true, None).unwrap();
+        let calls = rpc.calls.borrow();
+        assert_eq!(
+            *calls,
+            vec![
+                ("importdescriptors".to_string(), 500),
+                ("importdescriptors".to_string(), 100)
+            ]
+        );
+
+        // addresses are imported as labeled addr() descriptors, with only the last chunk rescanning
+        let args = rpc.args.borrow();
+        let first_req = &args[0][0][0];
+        let address = wallet.derive_address(0);
+        assert_eq!(
+            first_req["desc"],
+            json!(descriptor::addr_desc_str(&address))
+        );
+        assert!(first_req["desc"].as_str().unwrap().starts_with("addr("));
+        assert_eq!(
+            first_req["label"],
+            json!(format!("bwt/{}/0", wallet.checksum))
+        );
+        assert_eq!(first_req["timestamp"], json!("now"));
+        assert_eq!(args[1][0][0]["timestamp"], json!(1_600_000_000));
+    }
+
+    #[test]
+    fn test_active_descriptor() {
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let mut wallets = make_wallets(&xpub);
+        wallets[1].set_active().unwrap();
+
+        let inactive_req = wallets[0].make_desc_import(19);
+        assert_eq!(inactive_req["active"], json!(false));
+        assert_eq!(inactive_req["internal"], json!(false));
+        assert_eq!(inactive_req["range"], json!([0, 19]));
+        assert_eq!(inactive_req["timestamp"], json!("now"));
+        let desc_str = inactive_req["desc"].as_str().unwrap();
+        assert!(desc_str.ends_with(&format!("#{}", wallets[0].checksum)));
+
+        let active_req = wallets[1].make_desc_import(19);
+        assert_eq!(active_req["active"], json!(true));
+        assert_eq!(active_req["internal"], json!(true));
+
+        let rpc = MockRpc::default();
+        import_ranged_descriptors(&rpc, vec![inactive_req, active_req]).unwrap();
+        assert_eq!(
+            *rpc.calls.borrow(),
+            vec![("importdescriptors".to_string(), 2)]
+        );
+
+        // non-ranged descriptors cannot be active
+        let desc = ExtendedDescriptor::parse_with_checksum(&format!("pkh({})", xpub)).unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap();
+        assert!(wallet.set_active().is_err());
+    }
+
+    #[test]
+    fn test_is_likely_change() {
+        // a p2pkh wallet
+        let xpub = XPUB.parse::<ExtendedPubKey>().unwrap();
+        let wallet = &make_wallets(&xpub)[0];
+
+        let p2pkh = wallet.derive_address(10).script_pubkey();
+        let p2wpkh = Address::p2wpkh(&PublicKey::new(xpub.public_key), Network::Bitcoin)
+            .unwrap()
+            .script_pubkey();
+        let make_tx = |output: Vec<TxOut>| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output,
+        };
+        let txout = |value, script_pubkey: &Script| TxOut {
+            value,
+            script_pubkey: script_pubkey.clone(),
+        };
+
+        // round amount: both outputs pay to the wallet's script type, the non-round one is change
+        let tx = make_tx(vec![txout(5_000_000, &p2pkh), txout(1_234_567, &p2pkh)]);
+        assert!(!is_likely_change(0, &tx, wallet, &[]));
+        assert!(is_likely_change(1, &tx, wallet, &[]));
+
+        // matching script type: both amounts are non-round, the one matching the wallet is change
+        let tx = make_tx(vec![txout(1_234_567, &p2wpkh), txout(7_654_321, &p2pkh)]);
+        assert!(!is_likely_change(0, &tx, wallet, &[]));
+        assert!(is_likely_change(1, &tx, wallet, &[]));
+
+        // neither heuristic applies
+        let tx = make_tx(vec![txout(1_234_567, &p2pkh), txout(7_654_321, &p2pkh)]);
+        assert!(!is_likely_change(0, &tx, wallet, &[]));
+        assert!(!is_likely_change(1, &tx, wallet, &[]));
+
+        // identical outputs are told apart by their index, neither is the only non-round one
+        let tx = make_tx(vec![
+            txout(1_234_567, &p2pkh),
+            txout(1_234_567, &p2pkh),
+            txout(5_000_000, &p2pkh),
+        ]);
+        assert!(!is_likely_change(0, &tx, wallet, &[]));
+        assert!(!is_likely_change(1, &tx, wallet, &[]));
+
+        // skipped when the wallet is known to own a change output
+        let tx = make_tx(vec![
+            txout(5_000_000, &p2pkh),
+            txout(1_234_567, &p2pkh),
+            txout(7_654_321, &p2pkh),
+        ]);
+        assert!(!is_likely_change(1, &tx, wallet, &[2]));
+
+        // a single output is never considered change
+        let tx = make_tx(vec![txout(1_234_567, &p2pkh)]);
+        assert!(!is_likely_change(0, &tx, wallet, &[]));
+    }
+
+    #[test]
+    fn test_is_coinjoin_like() {
+        let script = Builder::new().push_int(1).into_script();
+        let make_tx = |inputs: usize, values: &[u64]| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..inputs)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Txid::default(), vout as u32),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: script.clone(),
+                })
+                .collect(),
+        };
+
+        // equal-value outputs with their change
+        let tx = make_tx(
+            4,
+            &[1_000_000, 1_000_000, 1_000_000, 1_000_000, 23_456, 78_901],
+        );
+        assert!(is_coinjoin_like(&tx));
+
+        // not enough equal-value outputs
+        let tx = make_tx(4, &[1_000_000, 1_000_000, 23_456, 78_901]);
+        assert!(!is_coinjoin_like(&tx));
+
+        // a single input
+        let tx = make_tx(1, &[1_000_000, 1_000_000, 1_000_000]);
+        assert!(!is_coinjoin_like(&tx));
+    }
+}
@@ -1,10 +1,26 @@
-use std::sync::mpsc;
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use ::reqwest::blocking as reqwest;
+use ::reqwest::{blocking as reqwest, header::CONTENT_TYPE};
+use bitcoin_hashes::{hex::ToHex, sha256, Hash, HashEngine, Hmac, HmacEngine};
 
 use crate::indexer::IndexChange;
 
+// The maximum number of failed deliveries kept around for retrying. The oldest ones get dropped once full.
+const MAX_QUEUE_SIZE: usize = 1000;
+
+// The delay before the first retry, doubled after every additional failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+// Sent with every request, starting at 1 for the first attempt
+const ATTEMPT_HEADER: &str = "X-Bwt-Attempt";
+
+// The HMAC-SHA256 of the request body, sent when a webhook secret is configured
+const SIGNATURE_HEADER: &str = "X-BWT-Signature";
+
 pub struct WebHookNotifier {
     _thread: thread::JoinHandle<()>,
     tx: mpsc::Sender<Vec<IndexChange>>,
@@ -12,7 +28,7 @@ pub struct WebHookNotifier {
 }
 
 impl WebHookNotifier {
-    pub fn start(urls: Vec<String>) -> Self {
+    pub fn start(urls: Vec<String>, secret: Option<String>, max_attempts: u32) -> Self {
         let (tx, rx) = mpsc::channel::<Vec<IndexChange>>();
         let num_urls = urls.len();
 
@@ -21,19 +37,41 @@ impl WebHookNotifier {
             // TODO use reqwest's non-blocking mode
             _thread: thread::spawn(move || {
                 let client = reqwest::Client::new();
-                while let Ok(changelog) = rx.recv() {
-                    for url in &urls {
-                        // XXX attach full tx info json to webhook request?
-                        debug!("notifying {} with {} events", url, changelog.len());
-                        client
-                            .post(url)
-                            .json(&changelog)
-                            .send()
-                            .map(|r| debug!("notifying {} succeed: {:?}", url, r.status()))
-                            .map_err(|e| warn!("notifying {} failed: {:?}", url, e))
-                            .ok();
+                let mut queue = RetryQueue::new(max_attempts);
+                loop {
+                    // wait for new updates, or until the next retry is due
+                    let changelog = match queue.next_due() {
+                        Some(due) => {
+                            match rx.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                                Ok(changelog) => Some(changelog),
+                                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                            }
+                        }
+                        None => match rx.recv() {
+                            Ok(changelog) => Some(changelog),
+                            Err(mpsc::RecvError) => break,
+                        },
+                    };
+
+                    if let Some(changelog) = changelog {
+                        let payload = Arc::new(Payload::new(&changelog, secret.as_deref()));
+                        for url_index in 0..urls.len() {
+                            let delivery = Delivery::new(url_index, payload.clone());
+                            deliver(&client, &urls, &mut queue, delivery);
+                        }
+                    }
+
+                    while let Some(delivery) = queue.pop_due(Instant::now()) {
+                        deliver(&client, &urls, &mut queue, delivery);
                     }
                 }
+                if !queue.is_empty() {
+                    warn!(
+                        "webhooks shutting down with {} undelivered notification(s)",
+                        queue.len()
+                    );
+                }
                 trace!("webhooks shutting down");
             }),
             tx,
@@ -52,3 +90,218 @@ impl WebHookNotifier {
         self.tx.send(changelog.to_vec()).unwrap();
     }
 }
+
+// Attempt sending the delivery, queueing it for a retry on failure
+fn deliver(
+    client: &reqwest::Client,
+    urls: &[String],
+    queue: &mut RetryQueue,
+    mut delivery: Delivery,
+) {
+    let url = &urls[delivery.url_index];
+    delivery.attempt += 1;
+
+    // XXX attach full tx info json to webhook request?
+    debug!(
+        "notifying {} with {} events (attempt {})",
+        url, delivery.payload.num_events, delivery.attempt
+    );
+    let mut request = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(ATTEMPT_HEADER, delivery.attempt.to_string());
+    if let Some(signature) = &delivery.payload.signature {
+        request = request.header(SIGNATURE_HEADER, signature.as_str());
+    }
+    let error = match request.body(delivery.payload.body.clone()).send() {
+        Ok(resp) if resp.status().is_success() => {
+            debug!("notifying {} succeed: {:?}", url, resp.status());
+            return;
+        }
+        Ok(resp) => format!("status {}", resp.status()),
+        Err(e) => format!("{:?}", e),
+    };
+
+    if let Some(retry_in) = queue.retry(delivery, Instant::now()) {
+        warn!(
+            "notifying {} failed: {}, retrying in {:?}",
+            url, error, retry_in
+        );
+    } else {
+        warn!(
+            "notifying {} failed: {}, giving up after {} attempt(s)",
+            url, error, queue.max_attempts
+        );
+    }
+}
+
+/// The serialized request body, shared by the deliveries to all urls
+#[derive(Debug)]
+struct Payload {
+    body: String,
+    num_events: usize,
+    signature: Option<String>,
+}
+
+impl Payload {
+    fn new(changelog: &[IndexChange], secret: Option<&str>) -> Self {
+        let body = serde_json::to_string(changelog).unwrap();
+        Payload {
+            signature: secret.map(|secret| sign(secret, &body)),
+            num_events: changelog.len(),
+            body,
+        }
+    }
+}
+
+// Sign the body using HMAC-SHA256, in the same `sha256=<hex>` format used by GitHub's webhooks
+fn sign(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    format!("sha256={}", Hmac::from_engine(engine).to_hex())
+}
+
+#[derive(Debug)]
+struct Delivery {
+    url_index: usize,
+    payload: Arc<Payload>,
+    // the number of attempts made so far
+    attempt: u32,
+    due: Instant,
+}
+
+impl Delivery {
+    fn new(url_index: usize, payload: Arc<Payload>) -> Self {
+        Delivery {
+            url_index,
+            payload,
+            attempt: 0,
+            due: Instant::now(),
+        }
+    }
+}
+
+/// An in-memory queue of failed deliveries waiting to be retried, bounded to `MAX_QUEUE_SIZE`
+#[derive(Debug)]
+struct RetryQueue {
+    pending: VecDeque<Delivery>,
+    max_attempts: u32,
+}
+
+impl RetryQueue {
+    fn new(max_attempts: u32) -> Self {
+        RetryQueue {
+            pending: VecDeque::new(),
+            // the first attempt is always made
+            max_attempts: max_attempts.max(1),
+        }
+    }
+
+    /// Queue the failed delivery for another attempt, returning the delay until it's due,
+    /// or `None` if it was dropped for reaching the maximum number of attempts.
+    fn retry(&mut self, mut delivery: Delivery, now: Instant) -> Option<Duration> {
+        if delivery.attempt >= self.max_attempts {
+            return None;
+        }
+        let delay = backoff(delivery.attempt);
+        delivery.due = now + delay;
+
+        if self.pending.len() >= MAX_QUEUE_SIZE {
+            let dropped = self.pending.pop_front().unwrap();
+            warn!(
+                "webhooks retry queue is full, dropping notification with {} events after {} attempt(s)",
+                dropped.payload.num_events, dropped.attempt
+            );
+        }
+        self.pending.push_back(delivery);
+        Some(delay)
+    }
+
+    /// Take the next delivery that is due for a retry, if any
+    fn pop_due(&mut self, now: Instant) -> Option<Delivery> {
+        let index = self.pending.iter().position(|d| d.due <= now)?;
+        self.pending.remove(index)
+    }
+
+    /// The time at which the earliest pending delivery becomes due
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|d| d.due).min()
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// The delay before retrying a delivery that failed `attempt` times
+fn backoff(attempt: u32) -> Duration {
+    let factor = 1u32
+        .checked_shl(attempt.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    INITIAL_BACKOFF
+        .checked_mul(factor)
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(10), Duration::from_secs(512));
+        assert_eq!(backoff(11), MAX_BACKOFF);
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_retry_queue() {
+        let now = Instant::now();
+        let mut queue = RetryQueue::new(3);
+        let payload = Arc::new(Payload::new(&[], None));
+        let failed = |attempt| Delivery {
+            attempt,
+            ..Delivery::new(0, payload.clone())
+        };
+
+        assert_eq!(queue.retry(failed(1), now), Some(Duration::from_secs(1)));
+        assert_eq!(queue.retry(failed(2), now), Some(Duration::from_secs(2)));
+        // dropped after the maximum number of attempts
+        assert_eq!(queue.retry(failed(3), now), None);
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.next_due(), Some(now + Duration::from_secs(1)));
+        assert!(queue.pop_due(now).is_none());
+        let due = queue.pop_due(now + Duration::from_secs(1)).unwrap();
+        assert_eq!(due.attempt, 1);
+        assert_eq!(queue.next_due(), Some(now + Duration::from_secs(2)));
+
+        // the oldest deliveries get dropped once the queue is full
+        for _ in 0..MAX_QUEUE_SIZE {
+            queue.retry(failed(1), now);
+        }
+        assert_eq!(queue.len(), MAX_QUEUE_SIZE);
+        assert!(queue.pending.iter().all(|d| d.attempt == 1));
+    }
+
+    #[test]
+    fn test_sign() {
+        // test vector from RFC 4231
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(Payload::new(&[], None).signature.is_none());
+        assert_eq!(
+            Payload::new(&[], Some("Jefe")).signature,
+            Some(sign("Jefe", "[]"))
+        );
+    }
+}
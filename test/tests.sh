@@ -65,6 +65,11 @@ if [[ $FEATURES == *"http"* ]]; then
   tx=`get /tx/$txid`
   test `jq -r .balance_change <<< "$tx"` == 123400000
   test `jq -r .txid <<< "$tx"` == $txid
+  test `jq -r .coinjoin_like <<< "$tx"` == false
+
+  echo - Testing /tx/:txid/hex
+  test `btc decoderawtransaction $(get /tx/$txid/hex) | jq -r .txid` == $txid
+  test `get /tx/$(printf '0%.0s' {1..64})/hex -o /dev/null -w '%{http_code}'` == 404
 
   echo - Testing /address/:address
   test `get_jq .origin /address/$addr | cut -d/ -f2` == 0
@@ -75,9 +80,19 @@ if [[ $FEATURES == *"http"* ]]; then
   echo - Testing /address/:address/txs
   test `get_jq .[0].funding[0].address /address/$addr/txs` == $addr
 
+  echo - Testing /address/:address/descriptor
+  desc=`get /address/$addr/descriptor`
+  test `btc deriveaddresses "$desc" | jq -r .[0]` == $addr
+
   echo - Testing /address/:address/utxos
   test `get_jq '.[] | select(.block_height == null) | .amount' /address/$addr/utxos` == 567800000
 
+  echo - Testing /wallet/:checksum/utxo-counts
+  # the wallet has one confirmed and one pending output at this point
+  utxo_counts=`get /wallet/$(get_jq .origin /address/$addr | cut -d/ -f1)/utxo-counts`
+  test `jq -r .confirmed <<< "$utxo_counts"` == 1
+  test `jq -r .unconfirmed <<< "$utxo_counts"` == 1
+
   echo - Testing /stream
   btc sendtoaddress $addr 9.777 &
   # collect events for 1 second
@@ -90,6 +105,477 @@ if [[ $FEATURES == *"http"* ]]; then
   txid=`jq -r .[0] <<< "$evt_Transaction"`
   test `get_jq .funding[0].amount /tx/$txid` == 977700000
   test `jq -r .[0] <<< "$evt_TxoFunded" | cut -d: -f1` == $txid
+
+  echo - Testing /stream filtered by address
+  btc sendtoaddress $addr 0.1234 &
+  zero_scripthash=`printf '0%.0s' {1..64}`
+  events=`get "/stream?address=$addr&scripthash=$zero_scripthash" --max-time 1 | grep '^data:' | cut -d: -f2-`
+  test `jq -s 'map(select(.category == "Transaction")) | length' <<< "$events"` == 1
+  test `jq -s "map(.params[1] | select(type == \"string\")) | unique == [\"$(get_jq .scripthash /address/$addr)\"]" <<< "$events"` == true
+
+  echo - Testing /wallets/summary
+  summary=`get /wallets/summary`
+  test `jq -r length <<< "$summary"` == 4
+  wallet=`get_jq .origin /address/$addr | cut -d/ -f1`
+  test `jq -r ".[] | select(.checksum == \"$wallet\") | .confirmed_balance" <<< "$summary"` == 123400000
+  test `jq -r ".[] | select(.checksum == \"$wallet\") | .unconfirmed_balance" <<< "$summary"` == 1545500000
+
+  echo - Testing /wallet/:checksum/fiat-value
+  # the fiat value equals the balance in BTC times the rate
+  fiat_value=`get "/wallet/$wallet/fiat-value?rate=30000"`
+  test `jq -n "($fiat_value - 16.689 * 30000 | fabs) < 0.000001"` == true
+  portfolio_value=`get "/wallets/fiat-value?rate=30000"`
+  portfolio_btc=`jq 'map(.confirmed_balance + .unconfirmed_balance) | add / 100000000' <<< "$summary"`
+  test `jq -n "($portfolio_value - $portfolio_btc * 30000 | fabs) < 0.000001"` == true
+
+  echo - Testing /mempool/min-feerate
+  # the mempool is not full, this should match the default regtest min relay fee of 1 sat/vB
+  test `get_jq '. == 1' /mempool/min-feerate` == true
+
+  echo - Testing /mempool/fees
+  test `get_jq '.relay_fee == 1 and .mempool_min_fee == 1 and .min_feerate == 1' /mempool/fees` == true
+  test `get_jq '.histogram | type' /mempool/fees` == array
+
+  echo - Testing frozen outputs
+  frozen_txid=`jq -r .[0].txid <<< "$txs"`
+  frozen_vout=`get_jq .funding[0].vout /tx/$frozen_txid`
+  balance=`get_jq . /wallet/$wallet/balance`
+  test `curl -s -X POST http://$BWT_HTTP_ADDR/txo/$frozen_txid/$frozen_vout/freeze` == true
+  test `get_jq '.[0]' /txos/frozen` == $frozen_txid:$frozen_vout
+  test `get_jq . /wallet/$wallet/balance` == $(( $balance - 123400000 ))
+  test `jq -r ".[] | select(.checksum == \"$wallet\") | .confirmed_balance" <(get /wallets/summary)` == 0
+  test `curl -s -X DELETE http://$BWT_HTTP_ADDR/txo/$frozen_txid/$frozen_vout/freeze` == true
+  test `get_jq . /wallet/$wallet/balance` == $balance
+
+  echo - Testing POST /batch
+  scripthash=`get_jq .scripthash /address/$addr`
+  batch=`get /batch -X POST -H 'Content-Type: application/json' -d "[
+    {\"method\":\"scripthash.stats\",\"params\":{\"scripthash\":\"$scripthash\"}},
+    {\"method\":\"scripthash.utxos\",\"params\":{\"scripthash\":\"$scripthash\",\"min_conf\":1}},
+    {\"method\":\"tx\",\"params\":{\"txid\":\"$(printf '0%.0s' {1..64})\"}},
+    {\"method\":\"unknown\"}
+  ]"`
+  test `jq -r length <<< "$batch"` == 4
+  test `jq -r .[0].result.tx_count <<< "$batch"` == `get_jq .tx_count /address/$addr/stats`
+  test `jq -c .[1].result <<< "$batch"` == `jq -c . <(get "/address/$addr/utxos?min_conf=1")`
+  test `jq -r .[2].error.code <<< "$batch"` == 404
+  test `jq -r .[3].error.code <<< "$batch"` == 400
+
+  echo - Testing /tx/:txid/merkle-proof
+  proof_txid=`get_jq '[ .[] | select(.block_height != null) ][-1].txid' /txs`
+  proof_height=`get_jq .block_height /tx/$proof_txid`
+  test `get_jq .block_height /tx/$proof_txid/merkle-proof` == $proof_height
+  test `get_jq .pos /tx/$proof_txid/merkle-proof` == `btc getblock $(btc getblockhash $proof_height) | jq '.tx | index("'$proof_txid'")'`
+  batch=`get /batch -X POST -H 'Content-Type: application/json' -d "[
+    {\"method\":\"tx.merkle-proof\",\"params\":{\"txid\":\"$proof_txid\"}},
+    {\"method\":\"tx.merkle-proof\",\"params\":{\"txid\":\"$proof_txid\"}}
+  ]"`
+  test `jq -c .[1].result <<< "$batch"` == `jq -c . <(get /tx/$proof_txid/merkle-proof)`
+  unconfirmed_txid=`btc sendtoaddress $addr 0.01`
+  sleep 1
+  test `curl -s -o /dev/null -w '%{http_code}' http://$BWT_HTTP_ADDR/tx/$unconfirmed_txid/merkle-proof` == 400
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+
+  echo - Testing /tx/:txid feerate
+  btc settxfee 0.0002 > /dev/null
+  feerate_txid=`btc sendtoaddress $addr 0.1`
+  btc settxfee 0 > /dev/null
+  sleep 1
+  test `get_jq '.feerate | floor' /tx/$feerate_txid` == 20
+  # the external prevouts of confirmed received transactions cannot be resolved without txindex
+  received_txid=`jq -r .[0].txid <<< "$txs"`
+  test `get_jq '.block_height != null and (has("feerate") | not)' /tx/$received_txid` == true
+  # the feerate is only included for single transaction lookups
+  test `get_jq 'map(has("feerate")) | any' /txs/since/0` == false
+
+  echo - Testing /wallet/:checksum/last-activity
+  # the wallet has unconfirmed transactions, which count as activity happening now
+  test `get_jq .block_height /wallet/$wallet/last-activity` == null
+  test `get_jq .time /wallet/$wallet/last-activity` -ge $(( `date +%s` - 5 ))
+
+  echo - Testing imported_range
+  test `get_jq '.imported_range[1]' /wallet/$wallet` == `get_jq .max_imported_index /wallet/$wallet`
+  test `get_jq '.imported_range[1]' /wallet/$wallet` -gt `get_jq .max_funded_index /wallet/$wallet`
+
+  echo - Testing /wallet/:checksum/tx/:txid/direction
+  test `get_jq . /wallet/$wallet/tx/$txid/direction` == incoming
+  txid=`ele1 broadcast $(ele1 payto $(btc getnewaddress) 0.1)`
+  sleep 1
+  test `get_jq . /wallet/$wallet/tx/$txid/direction` == outgoing
+
+  echo - Testing /tx/:txid/wallet-inputs
+  test `get_jq length /tx/$txid/wallet-inputs` -gt 0
+  test `get_jq length /tx/$txid/wallet-inputs` == `get_jq '.spending | length' /tx/$txid`
+  test `get_jq '.[0].prevout' /tx/$txid/wallet-inputs` == `get_jq '.spending | sort_by(.vin) | .[0].prevout' /tx/$txid`
+  test `get_jq '.[0].derivation_index' /tx/$txid/wallet-inputs` == `get_jq '.spending | sort_by(.vin) | .[0].origin | split("/")[1]' /tx/$txid`
+  test `get_jq 'map(.amount) | add' /tx/$txid/wallet-inputs` == `get_jq '.spending | map(.amount) | add' /tx/$txid`
+
+  echo - Testing /tx/:txid/wallet-outputs
+  # the outgoing transaction has one external output and one change output back to the wallet
+  test `get_jq length /tx/$txid/wallet-outputs` == 2
+  test `get_jq 'map(select(. == null)) | length' /tx/$txid/wallet-outputs` == 1
+  test `get_jq 'map(select(. != null)) | .[0].wallet' /tx/$txid/wallet-outputs` == $wallet
+  test `get_jq 'map(select(. != null)) | .[0].is_change' /tx/$txid/wallet-outputs` == true
+  tx_hex=`get /tx/$txid/hex`
+  raw_outputs=`get /tx/wallet-outputs -X POST -H 'Content-Type: application/json' -d "{\"tx_hex\":\"$tx_hex\"}"`
+  test "`jq -c . <<< "$raw_outputs"`" == "`get /tx/$txid/wallet-outputs | jq -c .`"
+
+  txid=`ele1 broadcast $(ele1 payto $(ele1 getunusedaddress) 0.1)`
+  sleep 1
+  test `get_jq . /wallet/$wallet/tx/$txid/direction` == self_transfer
+
+  echo - Testing abandoned transactions
+  total_balance() { get_jq 'map(.confirmed_balance + .unconfirmed_balance) | add' /wallets/summary; }
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  balance_before=`total_balance`
+  txid=`ele1 broadcast $(ele1 payto $(btc getnewaddress) 0.5)`
+  sleep 1
+  test `total_balance` != $balance_before
+  # evict the transaction from the mempool by expiring it, then abandon it
+  btc setmocktime $(( `date +%s` + 1300000 ))
+  btc sendtoaddress `btc getnewaddress` 0.1 > /dev/null
+  bitcoin-cli -datadir=$BTC_DIR -rpcwallet=bwt abandontransaction $txid
+  btc setmocktime 0
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get /tx/$txid -o /dev/null -w '%{http_code}'` == 404
+  test `total_balance` == $balance_before
+
+  echo - Testing dropped mempool transactions
+  txid=`ele1 broadcast $(ele1 payto $(btc getnewaddress) 0.5)`
+  sleep 1
+  test `get /tx/$txid -o /dev/null -w '%{http_code}'` == 200
+  # evict the transaction from the mempool by expiring it, without abandoning it
+  btc setmocktime $(( `date +%s` + 1300000 ))
+  btc sendtoaddress `btc getnewaddress` 0.1 > /dev/null
+  btc setmocktime 0
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get /tx/$txid -o /dev/null -w '%{http_code}'` == 404
+  test `total_balance` == $balance_before
+
+  echo - Testing /rescan
+  balance_before=`total_balance`
+  test `get /rescan -X POST -o /dev/null -w '%{http_code}'` == 202
+  for _ in {1..20}; do [ `get_jq .in_progress /rescan` == false ] && break; sleep 0.5; done
+  test `get_jq .in_progress /rescan` == false
+  sleep 1
+  test `total_balance` == $balance_before
+  # real-time indexing resumes following the rescan
+  txid=`btc sendtoaddress $addr 0.1`
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get /tx/$txid -o /dev/null -w '%{http_code}'` == 200
+  test `total_balance` != $balance_before
+
+  echo - Testing /network
+  # switching away from the network bitcoind is running on is rejected
+  test `get "/network?network=testnet" -X POST -o /dev/null -w '%{http_code}'` == 400
+  test `get "/network?network=bitcoin" -X POST -o /dev/null -w '%{http_code}'` == 400
+  test `total_balance` != 0
+
+  echo - Testing /wallet/:checksum/net-flow
+  # confirm the pending transactions before the start of the window
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 2
+  window_start=`date +%s`
+  sleep 1
+  flow_addr=`ele1 getunusedaddress`
+  in_txid=`btc sendtoaddress $flow_addr 0.3`
+  sleep 1
+  # spend from the receive address, so that the outgoing tx is part of the wallet's history
+  out_txid=`ele1 broadcast $(ele1 payto --from_addr $flow_addr $(btc getnewaddress) 0.2)`
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  in_change=`get_jq .balance_change /tx/$in_txid`
+  out_change=`get_jq .balance_change /tx/$out_txid`
+  test $in_change == 30000000
+  test $out_change -lt -20000000
+  test `get "/wallet/$wallet/net-flow?from=$window_start"` == $(( in_change + out_change ))
+  # transactions confirmed before the window are excluded
+  test `get "/wallet/$wallet/net-flow?to=$window_start"` == $(( `get /wallet/$wallet/net-flow` - in_change - out_change ))
+  # the output spent by out_txid cannot be frozen
+  spent_vout=`get_jq .funding[0].vout /tx/$in_txid`
+  test `get /txo/$in_txid/$spent_vout/freeze -X POST -o /dev/null -w '%{http_code}'` == 400
+
+  echo - Testing /wallet/:checksum/overpaid-txs
+  normal_txid=`ele1 broadcast $(ele1 payto --fee 0.00001 $(btc getnewaddress) 0.01)`
+  overpaid_txid=`ele1 broadcast $(ele1 payto --fee 0.001 $(btc getnewaddress) 0.01)`
+  sleep 1
+  overpaid=`get "/wallet/$wallet/overpaid-txs?feerate=50"`
+  test `jq '[ .[] | select(.txid == "'$overpaid_txid'") ] | length' <<< "$overpaid"` == 1
+  test `jq '[ .[] | select(.txid == "'$normal_txid'") ] | length' <<< "$overpaid"` == 0
+  test `jq 'map(.feerate > 50) | all' <<< "$overpaid"` == true
+
+  echo - Testing /wallet/:checksum/unconfirmed-vsize
+  # the two transactions above are the only pending wallet transactions
+  vsize1=`btc getmempoolentry $normal_txid | jq .vsize`
+  vsize2=`btc getmempoolentry $overpaid_txid | jq .vsize`
+  test `get /wallet/$wallet/unconfirmed-vsize` == $(( vsize1 + vsize2 ))
+
+  echo - Testing /wallet/:checksum/pending
+  sleep 1
+  pending=`get /wallet/$wallet/pending`
+  test `jq 'length' <<< "$pending"` == 2
+  test `jq '.[] | select(.txid == "'$overpaid_txid'") | .age > 0' <<< "$pending"` == true
+  # rounded to avoid floating point mismatches
+  overpaid_feerate=`btc getmempoolentry $overpaid_txid | jq '.fees.base * 100000000 / .vsize | round'`
+  test `jq '.[] | select(.txid == "'$overpaid_txid'") | .feerate | round' <<< "$pending"` == $overpaid_feerate
+
+  echo - Testing /wallet/:checksum/external-addresses
+  external_before=`get /wallet/$wallet/external-addresses`
+  ele1 broadcast $(ele1 payto $(btc getnewaddress) 0.1) > /dev/null
+  ele1 broadcast $(ele1 payto $(btc getnewaddress) 0.1) > /dev/null
+  sleep 1
+  test `get /wallet/$wallet/external-addresses` == $(( external_before + 2 ))
+
+  echo - Testing /utxos/dust
+  dust_txid=`btc sendtoaddress $(ele1 getunusedaddress) 0.000006`
+  sleep 1
+  dust=`get /utxos/dust`
+  test `jq '[ .[] | select(.txid == "'$dust_txid'") ] | length' <<< "$dust"` == 1
+  test `jq 'map(.amount < 1000) | all' <<< "$dust"` == true
+  # not flagged below a lower threshold
+  test `get_jq '[ .[] | select(.txid == "'$dust_txid'") ] | length' "/utxos/dust?threshold=600"` == 0
+
+  echo - Testing /wallet/:checksum/observed-gap
+  # use the unfunded ele2 wallet, with 29 unused addresses between indexes 0 and 30
+  gap_addr=`ele2 getunusedaddress`
+  btc sendtoaddress $gap_addr 0.1 > /dev/null
+  sleep 1
+  wallet2=`get_jq .origin /address/$gap_addr | cut -d/ -f1`
+  test `get /wallet/$wallet2/observed-gap` == 0
+  # index 30 is past the initial import window (of 30), raise the gap limit to have it imported
+  test `get "/gap-limit?gap_limit=30" -X POST -o /dev/null -w '%{http_code}'` == 202
+  for i in `seq 10`; do [ `get_jq .max_imported_index /wallet/$wallet2` == 30 ] && break; sleep 0.5; done
+  test `get_jq .max_imported_index /wallet/$wallet2` == 30
+  btc sendtoaddress `get_jq .address /wallet/$wallet2/30` 0.1 > /dev/null
+  sleep 1
+  test `get /wallet/$wallet2/observed-gap` == 29
+  test `get "/wallet/$wallet2/observed-gap?change=true"` == 0
+
+  echo - Testing /wallet/:checksum/utilization
+  # indexes 0 and 30 are used out of the imported ones
+  imported=$(( `get_jq '.imported_range[1]' /wallet/$wallet2` + 1 ))
+  test `jq -n "($(get /wallet/$wallet2/utilization) - 2 / $imported) | fabs < 0.0001"` == true
+  test `get "/wallet/$wallet2/utilization?change=true"` == 0.0
+
+  echo - Testing /wallet/:checksum/used-addresses
+  used=`get /wallet/$wallet/used-addresses`
+  # includes the used addresses of both the receive and change chains
+  test `jq '[ .[] | select(.is_change) ] | length' <<< "$used"` -gt 0
+  test `jq '[ .[] | select(.is_change | not) ] | length' <<< "$used"` -gt 1
+  test `jq '[ .[] | select(.address == "'$addr'") ] | length' <<< "$used"` == 1
+  # but not the unused ones
+  unused_addr=`get_jq .address /wallet/$wallet/$(get /wallet/$wallet/next)`
+  test `jq '[ .[] | select(.address == "'$unused_addr'") ] | length' <<< "$used"` == 0
+  for used_addr in `jq -r '.[].address' <<< "$used"`; do
+    test `get_jq .tx_count /address/$used_addr/stats` -gt 0
+  done
+
+  echo - Testing spends from out-of-window addresses
+  oow_index=$(( `get_jq .max_imported_index /wallet/$wallet` + 5 ))
+  oow_addr=`get_jq .address /wallet/$wallet/$oow_index`
+  btc createwallet oow > /dev/null
+  oow() { bitcoin-cli -datadir=$BTC_DIR -rpcwallet=oow "$@"; }
+  oow importprivkey `ele1 getprivatekeyforpath m/0/$oow_index | cut -d: -f2` "" false
+  btc sendtoaddress $oow_addr 1 > /dev/null
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  test `get_jq .max_funded_index /wallet/$wallet` -lt $oow_index
+  # spend the out-of-window output to an in-window address, which bwt should detect and index
+  txid=`oow sendtoaddress $(ele1 getunusedaddress) 0.5`
+  sleep 2
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get_jq .max_funded_index /wallet/$wallet` == $oow_index
+  test `get_jq '.spending | length' /tx/$txid` == 1
+  test `get_jq .balance_change /tx/$txid` -lt -50000000
+
+  echo - Testing window extension when using the edge address
+  edge_index=`get_jq .max_imported_index /wallet/$wallet`
+  edge_addr=`get_jq .address /wallet/$wallet/$edge_index`
+  beyond_addr=`get_jq .address /wallet/$wallet/$(( edge_index + 3 ))`
+  # pay both in separate transactions confirming in the same block, before the addresses past the edge get imported
+  btc sendtoaddress $edge_addr 0.01 > /dev/null
+  beyond_txid=`btc sendtoaddress $beyond_addr 0.01`
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 2
+  test `get_jq .max_funded_index /wallet/$wallet` == $(( edge_index + 3 ))
+  test `get_jq .tx_count /address/$beyond_addr/stats` == 1
+  test `get_jq .block_height /tx/$beyond_txid` == `btc getblockcount`
+
+  echo - Testing RBF replacement linking
+  rbf_index=$(( `get_jq .max_funded_index /wallet/$wallet` + 1 ))
+  oow importprivkey `ele1 getprivatekeyforpath m/0/$rbf_index | cut -d: -f2` "" false
+  btc sendtoaddress `get_jq .address /wallet/$wallet/$rbf_index` 1 > /dev/null
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  # send more than the other oow output holds, so that the wallet output must be spent
+  orig_txid=`oow -named sendtoaddress address=$(btc getnewaddress) amount=0.9 replaceable=true`
+  sleep 1
+  test `get_jq .replaces /tx/$orig_txid` == null
+  bumped_txid=`oow bumpfee $orig_txid | jq -r .txid`
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get_jq .replaces /tx/$bumped_txid` == $orig_txid
+  test `get /tx/$orig_txid -o /dev/null -w '%{http_code}'` == 404
+
+  echo - Testing /txo/:txid/:vout/confirmations-until-settled
+  txid=`btc sendtoaddress $addr 1.5`
+  blockhash=`btc generatetoaddress 1 $(btc getnewaddress) | jq -r .[0]`
+  coinbase_txid=`btc getblock $blockhash | jq -r .tx[0]`
+  sleep 1
+  vout=`get_jq .funding[0].vout /tx/$txid`
+  test `get /txo/$coinbase_txid/0/confirmations-until-settled` == 100
+  test `get /txo/$txid/$vout/confirmations-until-settled` == 5
+
+  echo - Testing immature coinbase balance
+  confirmed_before=`get_jq '.[] | select(.checksum == "'$wallet'") | .confirmed_balance' /wallets/summary`
+  btc generatetoaddress 1 `ele1 getunusedaddress` > /dev/null
+  sleep 1
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  immature=`get_jq '.[] | select(.checksum == "'$wallet'") | .immature_balance' /wallets/summary`
+  test $immature -gt 0
+  test `get_jq '.[] | select(.checksum == "'$wallet'") | .confirmed_balance' /wallets/summary` == $(( confirmed_before + immature ))
+
+  echo - Testing /utxos/immature-coinbase
+  test `get_jq 'map(select(.checksum == "'$wallet'") | .amount) | add' /utxos/immature-coinbase` == $immature
+  test `get_jq '.[-1].confirmations' /utxos/immature-coinbase` == 1
+
+  echo - Testing /activity-span
+  span_txid=`btc sendtoaddress $(ele1 getunusedaddress) 0.1`
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  test `get_jq .last_height /activity-span` == `btc getblockcount`
+  test `get_jq .last_height /activity-span` == `get_jq .block_height /tx/$span_txid`
+  test `get_jq .first_height /activity-span` == `get_jq '[ .[].block_height | select(. != null) ] | min' /txs`
+
+  echo - Testing /blocks/recent
+  tip_hash=`btc getbestblockhash`
+  test `get_jq '.[-1].hash' /blocks/recent` == $tip_hash
+  test `get_jq '.[-1].height' /blocks/recent` == `btc getblockcount`
+  test `get_jq '.[-2].hash' /blocks/recent` == `btc getblockhash $(( $(btc getblockcount) - 1 ))`
+  test `get_jq 'length' /blocks/recent` -le 100
+  # reorg the tip out and replace it with two new blocks
+  btc invalidateblock $tip_hash
+  new_tip_hash=`btc generatetoaddress 2 $(btc getnewaddress) | jq -r .[1]`
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get_jq '.[-1].hash' /blocks/recent` == $new_tip_hash
+  test `get_jq '.[-1].height' /blocks/recent` == `btc getblockcount`
+  test `get_jq '[ .[].hash ] | index("'$tip_hash'")' /blocks/recent` == null
+
+  echo - Testing reorged confirmations
+  reorg_txid=`btc sendtoaddress $addr 0.321`
+  reorg_block=`btc generatetoaddress 1 $(btc getnewaddress) | jq -r .[0]`
+  sleep 1
+  test `get_jq .block_height /tx/$reorg_txid` == `btc getblockcount`
+  # orphan the block and keep the transaction out of the replacement blocks
+  btc invalidateblock $reorg_block
+  btc prioritisetransaction $reorg_txid 0 -10000000 > /dev/null
+  btc generatetoaddress 2 `btc getnewaddress` > /dev/null
+  nc -U $BWT_SOCKET < /dev/null > /dev/null 2>&1
+  sleep 1
+  test `get_jq .block_height /tx/$reorg_txid` == null
+  # get it re-confirmed in the active chain
+  btc prioritisetransaction $reorg_txid 0 10000000 > /dev/null
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  test `get_jq .block_height /tx/$reorg_txid` == `btc getblockcount`
+
+  echo - Testing /wallet/:checksum/consolidation-txs
+  for i in 1 2 3; do btc sendtoaddress `ele1 getunusedaddress` 0.05 > /dev/null; done
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  # sweep all the wallet coins into a single output
+  consolidation_addr=`ele1 getunusedaddress`
+  consolidation_txid=`ele1 broadcast $(ele1 payto $consolidation_addr !)`
+  sleep 1
+  consolidation_inputs=`get_jq '.spending | length' /tx/$consolidation_txid`
+  test $consolidation_inputs -ge 3
+  test `get_jq 'index("'$consolidation_txid'")' "/wallet/$wallet/consolidation-txs?min_inputs=3"` != null
+  test `get_jq 'index("'$consolidation_txid'")' "/wallet/$wallet/consolidation-txs?min_inputs=$(( consolidation_inputs + 1 ))"` == null
+  # incoming payments spend no wallet inputs
+  test `get_jq 'index("'$reorg_txid'")' "/wallet/$wallet/consolidation-txs?min_inputs=1"` == null
+
+  echo - Testing /wallet/:checksum/psbt-utxos
+  psbt_utxo=`get_jq '.[] | select(.outpoint == "'$consolidation_txid':0")' /wallet/$wallet/psbt-utxos`
+  test `jq -r .script_pubkey <<< "$psbt_utxo"` == `btc validateaddress $consolidation_addr | jq -r .scriptPubKey`
+  test `jq -r .value <<< "$psbt_utxo"` == `get_jq .amount /txo/$consolidation_txid/0`
+  # the derivation info matches the key origins of the owning descriptor
+  test `jq -r '.bip32_derivation[0] | .fingerprint + (.path | ltrimstr("m"))' <<< "$psbt_utxo"` == `get_jq .bip32_origins[0] /address/$consolidation_addr`
+  test `jq -r .bip32_derivation[0].pubkey <<< "$psbt_utxo"` == `ele1 getpubkeys $consolidation_addr | jq -r .[0]`
+
+  # descriptor wallets are only available from Bitcoin Core v0.21
+  if [ `btc getnetworkinfo | jq .version` -ge 210000 ]; then
+    echo - Testing active descriptors
+    btc createwallet bwt-desc true true "" false true > /dev/null
+    descw() { bitcoin-cli -datadir=$BTC_DIR -rpcwallet=bwt-desc "$@"; }
+    descs=`get /wallets | jq -r '[ .[] | select(.is_wildcard) | .desc ][0:2][]'`
+    active_desc=`sed -n 1p <<< "$descs"`
+    inactive_desc=`sed -n 2p <<< "$descs"`
+    # run a second bwt instance against the descriptor wallet until it completes the initial import
+    ${BWT_BIN:-cargo run -q --no-default-features --features $FEATURES --} --no-startup-banner --network regtest \
+      --bitcoind-dir $BTC_DIR --bitcoind-url http://localhost:$BTC_RPC_PORT/ --bitcoind-wallet bwt-desc \
+      $([[ $FEATURES == *"electrum"* ]] && echo "--electrum-addr 127.0.0.1:30603") --http-addr 127.0.0.1:3061 \
+      --descriptor "$active_desc" --descriptor "$inactive_desc" --descriptor-active ${active_desc#*#} \
+      > /dev/null 2>&1 &
+    desc_bwt_pid=$!
+    listed_ranged() { descw listdescriptors | jq '[ .descriptors[] | select(.range) ]'; }
+    for i in `seq 300`; do [ `listed_ranged | jq length` == 2 ] && break; sleep 1; done
+    kill $desc_bwt_pid
+    test `descw getwalletinfo | jq .descriptors` == true
+    listed=`listed_ranged`
+    test `jq -r '.[] | select(.active) | .desc' <<< "$listed" | cut -d'#' -f1` == ${active_desc%#*}
+    test `jq -r '.[] | select(.active | not) | .desc' <<< "$listed" | cut -d'#' -f1` == ${inactive_desc%#*}
+    # the derived addresses are imported with their bwt labels
+    test `descw listlabels | jq '[ .[] | select(startswith("bwt/")) ] | length'` -gt 0
+  fi
+fi
+
+# Test unix socket event stream
+echo = Running unix events socket tests =
+echo - Testing event delivery
+events=`mktemp`
+# collect events for 3 seconds
+timeout 3 nc -U $BWT_EVENTS_SOCKET < /dev/null > $events &
+nc_pid=$!
+sleep 0.5
+txid=`btc sendtoaddress $addr 0.777`
+wait $nc_pid || true
+test `jq -r 'select(.category == "Transaction") | .params[0]' $events | grep -c $txid` == 1
+test `jq -r 'select(.category == "TxoFunded") | .params[0]' $events | cut -d: -f1 | grep -c $txid` -ge 1
+rm $events
+
+if [[ $FEATURES == *"http"* ]]; then
+  echo - Testing /gap-limit import progress
+  events=`mktemp`
+  gap_limit=$(( `get_jq .gap_limit /wallet/$wallet` + 30 ))
+  timeout 3 nc -U $BWT_EVENTS_SOCKET < /dev/null > $events &
+  nc_pid=$!
+  sleep 0.5
+  test `get "/gap-limit?gap_limit=$gap_limit" -X POST -o /dev/null -w '%{http_code}'` == 202
+  wait $nc_pid || true
+  test `get_jq .gap_limit /wallet/$wallet` == $gap_limit
+  # only the indexes added by the increase got imported
+  test `get_jq .max_imported_index /wallet/$wallet` == $(( `get_jq .max_funded_index /wallet/$wallet` + gap_limit ))
+  test `jq -r 'select(.category == "ImportProgress") | .params.done == .params.total' $events | tail -n1` == true
+  rm $events
+fi
+
+# Test one-shot mode
+if [[ $FEATURES == *"http"* ]]; then
+  echo = Running one-shot mode tests =
+  echo - Testing one-shot sync
+  bwt_cmd=${BWT_BIN:-cargo run -q --no-default-features --features $FEATURES --}
+  dump=`timeout 300 $bwt_cmd --one-shot --network regtest \
+    --bitcoind-dir $BTC_DIR --bitcoind-url http://localhost:$BTC_RPC_PORT/ --bitcoind-wallet bwt \
+    --initial-import-size 30 --xpub $(ele1 getmpk) --xpub $(ele2 getmpk) 2> /dev/null`
+  test `jq -r '.transactions | length' <<< "$dump"` == `get_jq '.transactions | length' /dump`
+  test `jq -r '.scripthashes | length' <<< "$dump"` == `get_jq '.scripthashes | length' /dump`
 fi
 
 echo -e "\e[32mAll tests pass.\e[0m"